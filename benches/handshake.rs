@@ -0,0 +1,42 @@
+//! Benchmarks of the client messages besides the key derivation.
+//!
+//! The server requests a single iteration, so PBKDF2 doesn't dominate the measurements. This
+//! needs the `test-util` feature: `cargo bench --features test-util`.
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use scram::client::ServerFirst;
+use scram::ScramClient;
+
+/// Returns the state after the first client message and the matching first server message.
+fn server_first() -> (ServerFirst<'static>, String) {
+    let client = ScramClient::new("user", "pencil", None).insecure_allow_low_iterations();
+    let (client, client_first) = client.client_first();
+    let nonce = client_first.rsplit("r=").next().unwrap();
+    let server_first = format!(
+        "r={}%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=1",
+        nonce
+    );
+    (client, server_first)
+}
+
+fn client_first(c: &mut Criterion) {
+    c.bench_function("client_first", |b| {
+        b.iter_batched(
+            || ScramClient::new("user", "pencil", None),
+            |client| client.client_first(),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn handle_server_first(c: &mut Criterion) {
+    c.bench_function("handle_server_first", |b| {
+        b.iter_batched(
+            server_first,
+            |(client, server_first)| client.handle_server_first(black_box(&server_first)),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+criterion_group!(benches, client_first, handle_server_first);
+criterion_main!(benches);
@@ -0,0 +1,229 @@
+//! A [`SecretBackend`] reading the secrets of a SCRAM server from the key/value secrets engine
+//! (version 2) of HashiCorp Vault.
+//!
+//! All secrets are kept at one path, with the secret names as keys and the base64 encoded secrets
+//! as values:
+//!
+//! ``` text
+//! vault kv put secret/imap scram-enumeration-key=...
+//! ```
+//!
+//! ``` rust,no_run
+//! use scram::secrets::ServerSecrets;
+//! use scram_vault::VaultBackend;
+//!
+//! let backend = VaultBackend::new("https://vault:8200", "hvs.token", "secret", "imap");
+//! let secrets = ServerSecrets::load(&backend).unwrap();
+//! ```
+//!
+//! This crate isn't a feature of `scram`, so the HTTP and JSON dependencies never become
+//! dependencies of servers which don't use Vault.
+#![deny(missing_docs)]
+use std::{error, fmt, io};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use scram::secrets::{Secret, SecretBackend};
+use serde_json::Value;
+
+/// Reads secrets from a path of a key/value secrets engine of Vault.
+#[derive(Clone)]
+pub struct VaultBackend {
+    url: String,
+    token: String,
+    namespace: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl VaultBackend {
+    /// Creates a backend reading the secrets at `path` of the engine mounted at `mount` of the
+    /// Vault server at `address`, authenticated with `token`.
+    pub fn new(address: &str, token: &str, mount: &str, path: &str) -> Self {
+        VaultBackend {
+            url: format!(
+                "{}/v1/{}/data/{}",
+                address.trim_end_matches('/'),
+                mount.trim_matches('/'),
+                path.trim_matches('/')
+            ),
+            token: token.to_owned(),
+            namespace: None,
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    /// Sets the Vault Enterprise namespace of the secrets engine.
+    pub fn with_namespace(mut self, namespace: &str) -> Self {
+        self.namespace = Some(namespace.to_owned());
+        self
+    }
+
+    /// Sets the agent sending the requests, for example to configure timeouts or TLS.
+    pub fn with_agent(mut self, agent: ureq::Agent) -> Self {
+        self.agent = agent;
+        self
+    }
+}
+
+impl fmt::Debug for VaultBackend {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("VaultBackend")
+            .field("url", &self.url)
+            .field("namespace", &self.namespace)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SecretBackend for VaultBackend {
+    type Error = VaultError;
+
+    /// Returns `Ok(None)` if the path or the key doesn't exist.
+    fn fetch(&self, name: &str) -> Result<Option<Secret>, VaultError> {
+        let mut request = self.agent.get(&self.url).set("X-Vault-Token", &self.token);
+        if let Some(ref namespace) = self.namespace {
+            request = request.set("X-Vault-Namespace", namespace);
+        }
+        let body = match request.call() {
+            Ok(response) => response.into_string().map_err(VaultError::Io)?,
+            Err(ureq::Error::Status(404, _)) => return Ok(None),
+            Err(err) => return Err(VaultError::Http(Box::new(err))),
+        };
+        parse_secret(&body, name)
+    }
+}
+
+/// Extracts the secret `name` from the body of a response of the key/value secrets engine.
+fn parse_secret(body: &str, name: &str) -> Result<Option<Secret>, VaultError> {
+    let body: Value = serde_json::from_str(body)
+        .map_err(|err| VaultError::Response(format!("invalid JSON: {}", err)))?;
+    let value = match body.pointer("/data/data") {
+        Some(Value::Object(data)) => data.get(name),
+        _ => return Err(VaultError::Response("missing data".to_owned())),
+    };
+    match value {
+        Some(Value::String(value)) => STANDARD
+            .decode(value)
+            .map(|bytes| Some(Secret::new(bytes)))
+            .map_err(|_| VaultError::Response(format!("{} isn't valid base64", name))),
+        Some(_) => Err(VaultError::Response(format!("{} isn't a string", name))),
+        None => Ok(None),
+    }
+}
+
+/// The error of a failed lookup in Vault.
+#[derive(Debug)]
+pub enum VaultError {
+    /// The request failed or Vault answered with an error status.
+    Http(Box<ureq::Error>),
+    /// Reading the response failed.
+    Io(io::Error),
+    /// The response didn't hold the secret in the expected format.
+    Response(String),
+}
+
+impl fmt::Display for VaultError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VaultError::Http(ref err) => write!(fmt, "Vault request failed: {}", err),
+            VaultError::Io(ref err) => write!(fmt, "Reading the Vault response failed: {}", err),
+            VaultError::Response(ref reason) => write!(fmt, "Invalid Vault response: {}", reason),
+        }
+    }
+}
+
+impl error::Error for VaultError {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use scram::secrets::{Secret, SecretBackend, ServerSecrets, ENUMERATION_KEY};
+
+    use super::{parse_secret, VaultBackend, VaultError};
+
+    #[test]
+    fn test_parse_secret() {
+        let body = r#"{"data": {"data": {"scram-enumeration-key": "cGVwcGVy", "other": 1}, "metadata": {}}}"#;
+        assert_eq!(
+            parse_secret(body, ENUMERATION_KEY).unwrap(),
+            Some(Secret::new(b"pepper".to_vec()))
+        );
+        assert_eq!(parse_secret(body, "missing").unwrap(), None);
+        assert!(matches!(
+            parse_secret(body, "other"),
+            Err(VaultError::Response(_))
+        ));
+        assert!(matches!(
+            parse_secret(r#"{"errors": []}"#, ENUMERATION_KEY),
+            Err(VaultError::Response(_))
+        ));
+    }
+
+    /// Answers every request with `status` and `body`, and returns the request lines and headers.
+    fn serve(
+        status: &'static str,
+        body: &'static str,
+        requests: usize,
+    ) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut lines = Vec::new();
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    lines.push(line.trim().to_owned());
+                }
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+            lines
+        });
+        (address, handle)
+    }
+
+    #[test]
+    fn test_fetch() {
+        let body = r#"{"data": {"data": {"scram-enumeration-key": "cGVwcGVycGVwcGVycGVwcGVycGVwcGVycGVwcGVycGVwcGVy"}}}"#;
+        let (address, server) = serve("200 OK", body, 1);
+        let backend =
+            VaultBackend::new(&address, "token", "/secret/", "imap").with_namespace("ops");
+        let secrets = ServerSecrets::load(&backend).unwrap();
+        assert_eq!(secrets.enumeration_key(), Some(&b"pepper".repeat(6)[..]));
+        let lines = server.join().unwrap();
+        assert!(lines.contains(&"GET /v1/secret/data/imap HTTP/1.1".to_owned()));
+        assert!(lines
+            .iter()
+            .any(|line| line.eq_ignore_ascii_case("X-Vault-Token: token")));
+        assert!(lines
+            .iter()
+            .any(|line| line.eq_ignore_ascii_case("X-Vault-Namespace: ops")));
+
+        let (address, server) = serve("404 Not Found", r#"{"errors": []}"#, 1);
+        let backend = VaultBackend::new(&address, "token", "secret", "imap");
+        assert_eq!(backend.fetch(ENUMERATION_KEY).unwrap(), None);
+        server.join().unwrap();
+
+        let (address, server) = serve("403 Forbidden", r#"{"errors": ["permission denied"]}"#, 1);
+        let backend = VaultBackend::new(&address, "token", "secret", "imap");
+        assert!(matches!(
+            backend.fetch(ENUMERATION_KEY),
+            Err(VaultError::Http(_))
+        ));
+        server.join().unwrap();
+    }
+}
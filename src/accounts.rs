@@ -0,0 +1,345 @@
+//! The credentials of many accounts, resolved by host, service and username.
+//!
+//! Applications which authenticate to many servers, like mail clients or multi-tenant pollers,
+//! keep the password of every account together with its cached salted passwords and pinned
+//! server parameters. A [`CredentialStore`] resolves the [`Account`] of a connection, and
+//! [`Account::client`] starts the handshake with everything the account was configured with:
+//!
+//! ``` rust,no_run
+//! use scram::accounts::{Account, CredentialStore, MemoryCredentialStore};
+//!
+//! let store = MemoryCredentialStore::new();
+//! store.insert(Account::new("imap.example.com", "imap", "user", "password"));
+//! store.insert(Account::new("smtp.example.com", "smtp", "user", "other password"));
+//!
+//! let account = store.resolve("imap.example.com", "imap", "user").unwrap();
+//! let (client, client_first) = account.client().client_first();
+//! ```
+//!
+//! This module is available with the `accounts` feature. The cache of salted passwords and the
+//! pin store of an account additionally require the `cache` and the `pinning` feature.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+#[cfg(any(test, feature = "cache", feature = "pinning"))]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+#[cfg(any(test, feature = "cache"))]
+use crate::cache::CredentialCache;
+use crate::client::ScramClient;
+#[cfg(any(test, feature = "pinning"))]
+use crate::pinning::PinStore;
+use crate::utils::zeroize_string;
+
+/// The credentials of a user of a service at a host, with the stores the handshakes of the
+/// account use.
+///
+/// The password is zeroized when the account is dropped.
+#[derive(Clone)]
+pub struct Account {
+    host: String,
+    service: String,
+    username: String,
+    password: String,
+    authzid: Option<String>,
+    #[cfg(any(test, feature = "cache"))]
+    cache: Option<Arc<CredentialCache>>,
+    #[cfg(any(test, feature = "pinning"))]
+    pins: Option<Arc<dyn PinStore>>,
+}
+
+impl Account {
+    /// Creates the account of `username` with `password` for `service` at `host`. The service is
+    /// a name chosen by the application, for example the protocol.
+    pub fn new(host: &str, service: &str, username: &str, password: &str) -> Self {
+        Account {
+            host: host.to_string(),
+            service: service.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            authzid: None,
+            #[cfg(any(test, feature = "cache"))]
+            cache: None,
+            #[cfg(any(test, feature = "pinning"))]
+            pins: None,
+        }
+    }
+
+    /// Authorizes the handshakes of the account as `authzid`, see [`ScramClient::new`].
+    pub fn with_authzid(mut self, authzid: &str) -> Self {
+        self.authzid = Some(authzid.to_string());
+        self
+    }
+
+    /// Passes `cache` to the handshakes of the account, see
+    /// [`ScramClient::with_cache`](crate::ScramClient::with_cache).
+    ///
+    /// This method is only available with the `cache` feature.
+    #[cfg(any(test, feature = "cache"))]
+    pub fn with_cache(mut self, cache: Arc<CredentialCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Checks the parameters of the server against the pins in `store` under the host of the
+    /// account, see [`ScramClient::with_pin_store`](crate::ScramClient::with_pin_store).
+    ///
+    /// This method is only available with the `pinning` feature.
+    #[cfg(any(test, feature = "pinning"))]
+    pub fn with_pin_store(mut self, store: Arc<dyn PinStore>) -> Self {
+        self.pins = Some(store);
+        self
+    }
+
+    /// Returns the host.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Returns the service.
+    pub fn service(&self) -> &str {
+        &self.service
+    }
+
+    /// Returns the username.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// Returns the authorization identity, if there is one.
+    pub fn authzid(&self) -> Option<&str> {
+        self.authzid.as_deref()
+    }
+
+    /// Returns the initial state of a handshake with the credentials and the stores of the
+    /// account.
+    ///
+    /// # Panics
+    ///
+    /// Panics like [`ScramClient::new`].
+    pub fn client(&self) -> ScramClient<'_> {
+        let client = ScramClient::new(
+            self.username.as_str(),
+            self.password.as_str(),
+            self.authzid.as_deref(),
+        );
+        #[cfg(any(test, feature = "cache"))]
+        let client = match self.cache {
+            Some(ref cache) => client.with_cache(cache),
+            None => client,
+        };
+        #[cfg(any(test, feature = "pinning"))]
+        let client = match self.pins {
+            Some(ref pins) => client.with_pin_store(pins.clone(), &self.host),
+            None => client,
+        };
+        client
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        zeroize_string(&mut self.password);
+    }
+}
+
+impl fmt::Debug for Account {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Account")
+            .field("host", &self.host)
+            .field("service", &self.service)
+            .field("username", &self.username)
+            .field("authzid", &self.authzid)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A store of the [`Account`]s of a client, see the [module documentation](self).
+///
+/// The store may be shared by the clients of all threads, so it takes `&self`.
+pub trait CredentialStore: Send + Sync {
+    /// Returns the account of `username` for `service` at `host`, if there is one.
+    fn resolve(&self, host: &str, service: &str, username: &str) -> Option<Account>;
+}
+
+/// A [`CredentialStore`] which keeps the accounts in memory.
+#[derive(Default)]
+pub struct MemoryCredentialStore {
+    accounts: Mutex<BTreeMap<(String, String, String), Account>>,
+}
+
+impl MemoryCredentialStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        MemoryCredentialStore::default()
+    }
+
+    /// Adds `account`, replacing the account of the same user for the same service and host.
+    pub fn insert(&self, account: Account) {
+        let key = (
+            account.host.clone(),
+            account.service.clone(),
+            account.username.clone(),
+        );
+        self.accounts().insert(key, account);
+    }
+
+    /// Removes the account of `username` for `service` at `host`.
+    pub fn remove(&self, host: &str, service: &str, username: &str) {
+        self.accounts().remove(&key(host, service, username));
+    }
+
+    /// Returns the usernames of the accounts for `service` at `host`, for example to let the
+    /// user choose one.
+    pub fn usernames(&self, host: &str, service: &str) -> Vec<String> {
+        self.accounts()
+            .values()
+            .filter(|account| account.host == host && account.service == service)
+            .map(|account| account.username.clone())
+            .collect()
+    }
+
+    /// Returns the number of accounts.
+    pub fn len(&self) -> usize {
+        self.accounts().len()
+    }
+
+    /// Returns whether the store has no accounts.
+    pub fn is_empty(&self) -> bool {
+        self.accounts().is_empty()
+    }
+
+    fn accounts(&self) -> MutexGuard<'_, BTreeMap<(String, String, String), Account>> {
+        // The map is consistent after every operation, so a poisoned lock can be used.
+        self.accounts
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CredentialStore for MemoryCredentialStore {
+    fn resolve(&self, host: &str, service: &str, username: &str) -> Option<Account> {
+        self.accounts().get(&key(host, service, username)).cloned()
+    }
+}
+
+impl fmt::Debug for MemoryCredentialStore {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MemoryCredentialStore")
+            .field("accounts", &self.accounts().values().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Returns the key of an account in a [`MemoryCredentialStore`].
+fn key(host: &str, service: &str, username: &str) -> (String, String, String) {
+    (host.to_string(), service.to_string(), username.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::num::NonZeroU32;
+    use core::time::Duration;
+
+    use super::{Account, CredentialStore, MemoryCredentialStore};
+    use crate::cache::CredentialCache;
+    use crate::pinning::{MemoryPinStore, PinStore};
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+    use crate::ScramClient;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username != "user" {
+                return None;
+            }
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("password", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    /// Runs the handshake of `client` and returns the status of the server.
+    fn handshake(server: &ScramServer<TestProvider>, client: ScramClient) -> AuthenticationStatus {
+        let (client, client_first) = client.client_first();
+        let server_first = server.handle_client_first(&client_first).unwrap();
+        let (server_client_final, server_first) = server_first.server_first();
+        let client = client.handle_server_first(&server_first).unwrap();
+        let (client, client_final) = client.client_final();
+        let server_final = server_client_final
+            .handle_client_final(&client_final)
+            .unwrap();
+        let (status, server_final) = server_final.server_final();
+        if status == AuthenticationStatus::Authenticated {
+            client.handle_server_final(&server_final).unwrap();
+        }
+        status
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryCredentialStore::new();
+        assert!(store.is_empty());
+        store.insert(Account::new("host", "imap", "user", "password"));
+        store.insert(Account::new("host", "imap", "other", "password"));
+        store.insert(Account::new("host", "smtp", "user", "secret").with_authzid("admin"));
+        assert_eq!(store.len(), 3);
+        assert_eq!(store.usernames("host", "imap"), ["other", "user"]);
+
+        let account = store.resolve("host", "smtp", "user").unwrap();
+        assert_eq!(account.host(), "host");
+        assert_eq!(account.service(), "smtp");
+        assert_eq!(account.username(), "user");
+        assert_eq!(account.authzid(), Some("admin"));
+        assert!(store.resolve("other", "smtp", "user").is_none());
+
+        // Inserting an account again replaces it.
+        store.insert(Account::new("host", "smtp", "user", "password"));
+        assert_eq!(store.len(), 3);
+        assert_eq!(
+            store.resolve("host", "smtp", "user").unwrap().authzid(),
+            None
+        );
+        store.remove("host", "imap", "other");
+        assert_eq!(store.usernames("host", "imap"), ["user"]);
+
+        let debug = format!("{:?}", store);
+        assert!(debug.contains("\"smtp\""));
+        assert!(!debug.contains("password"));
+    }
+
+    #[test]
+    fn test_client() {
+        let server = ScramServer::new(TestProvider);
+        let cache = Arc::new(CredentialCache::new(&[7; 32], Duration::from_secs(60)));
+        let pins = Arc::new(MemoryPinStore::new());
+        let store = MemoryCredentialStore::new();
+        store.insert(
+            Account::new("host", "imap", "user", "password")
+                .with_cache(cache.clone())
+                .with_pin_store(pins.clone()),
+        );
+        store.insert(Account::new("host", "smtp", "user", "wrong"));
+
+        let account = store.resolve("host", "imap", "user").unwrap();
+        for _ in 0..2 {
+            assert_eq!(
+                handshake(&server, account.client()),
+                AuthenticationStatus::Authenticated
+            );
+        }
+        assert_eq!(cache.stats().hits, 1);
+        assert!(pins.get("host", "user").is_some());
+
+        let account = store.resolve("host", "smtp", "user").unwrap();
+        assert_eq!(
+            handshake(&server, account.client()),
+            AuthenticationStatus::NotAuthenticated
+        );
+    }
+}
@@ -0,0 +1,176 @@
+//! A SCRAM server for the integration tests of clients, built with the `testserver` feature.
+//!
+//! The server speaks a line based protocol over TCP. It greets every connection with the
+//! advertised mechanisms, `MECHANISMS SCRAM-SHA-256`, and expects the name of the chosen mechanism
+//! as the first line of the client. The messages of the handshake follow, one per line. Errors are
+//! reported as `ERR <reason>` before the connection is closed.
+//!
+//! ```text
+//! scram-testserver [--listen ADDR] [--user NAME] [--password PASSWORD] [--iterations N]
+//!                  [--mechanism NAME]... [--fault FAULT] [--once]
+//! ```
+//!
+//! The address the server listens on is printed as `listening on <ADDR>`, so a test suite can
+//! listen on port 0 and read the port. The faults are those of `scram::mock::Fault`:
+//! `wrong-server-signature`, `reused-nonce`, `truncated-message` and `bogus-base64`.
+use std::env;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::NonZeroU32;
+use std::process;
+use std::thread;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use scram::mock::{Fault, MockServer};
+use scram::Mechanism;
+
+/// The settings from the command line.
+#[derive(Clone, Debug)]
+struct Config {
+    listen: String,
+    user: String,
+    password: String,
+    iterations: NonZeroU32,
+    mechanisms: Vec<String>,
+    fault: Option<Fault>,
+    once: bool,
+}
+
+impl Config {
+    /// Parses the command line arguments.
+    fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Config, String> {
+        let mut config = Config {
+            listen: "127.0.0.1:0".to_string(),
+            user: "user".to_string(),
+            password: "pencil".to_string(),
+            iterations: NonZeroU32::new(4096).unwrap(),
+            mechanisms: Vec::new(),
+            fault: None,
+            once: false,
+        };
+        while let Some(arg) = args.next() {
+            if arg == "--once" {
+                config.once = true;
+                continue;
+            }
+            let value = args
+                .next()
+                .ok_or_else(|| format!("missing value of {}", arg))?;
+            match arg.as_str() {
+                "--listen" => config.listen = value,
+                "--user" => config.user = value,
+                "--password" => config.password = value,
+                "--iterations" => {
+                    config.iterations = value
+                        .parse()
+                        .map_err(|_| format!("invalid iteration count {}", value))?
+                }
+                "--mechanism" => config.mechanisms.push(value),
+                "--fault" => config.fault = Some(parse_fault(&value)?),
+                _ => return Err(format!("unknown argument {}", arg)),
+            }
+        }
+        if config.mechanisms.is_empty() {
+            config.mechanisms = Mechanism::DEFAULT
+                .iter()
+                .map(|mechanism| mechanism.name().to_string())
+                .collect();
+        }
+        Ok(config)
+    }
+}
+
+/// Returns the fault with the kebab-case `name`.
+fn parse_fault(name: &str) -> Result<Fault, String> {
+    match name {
+        "wrong-server-signature" => Ok(Fault::WrongServerSignature),
+        "reused-nonce" => Ok(Fault::ReusedNonce),
+        "truncated-message" => Ok(Fault::TruncatedMessage),
+        "bogus-base64" => Ok(Fault::BogusBase64),
+        _ => Err(format!("unknown fault {}", name)),
+    }
+}
+
+/// Reads a line without the line ending, or fails with `UnexpectedEof` if the client hung up.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Runs a handshake with the client on `stream`.
+fn serve(config: &Config, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    writeln!(writer, "MECHANISMS {}", config.mechanisms.join(" "))?;
+    let mechanism = read_line(&mut reader)?;
+    let supported = match Mechanism::from_name(&mechanism) {
+        Some(supported) if config.mechanisms.contains(&mechanism) => supported,
+        _ => return writeln!(writer, "ERR unsupported mechanism {}", mechanism),
+    };
+
+    let mut salt = vec![0; 16];
+    OsRng.fill_bytes(&mut salt);
+    let mut server =
+        MockServer::with_parameters(&config.user, &config.password, salt, config.iterations)
+            .with_mechanism(supported);
+    if let Some(fault) = config.fault {
+        server = server.with_fault(fault);
+    }
+    let server_first = match server.server_first(&read_line(&mut reader)?) {
+        Ok(message) => message,
+        Err(err) => return writeln!(writer, "ERR {}", err),
+    };
+    writeln!(writer, "{}", server_first)?;
+    match server.server_final(&read_line(&mut reader)?) {
+        Ok(message) => writeln!(writer, "{}", message),
+        Err(err) => writeln!(writer, "ERR {}", err),
+    }
+}
+
+fn main() {
+    let config = match Config::parse(env::args().skip(1)) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("scram-testserver: {}", err);
+            process::exit(2);
+        }
+    };
+    let listener = TcpListener::bind(&config.listen).unwrap_or_else(|err| {
+        eprintln!(
+            "scram-testserver: can't listen on {}: {}",
+            config.listen, err
+        );
+        process::exit(1);
+    });
+    match listener.local_addr() {
+        Ok(addr) => println!("listening on {}", addr),
+        Err(err) => eprintln!("scram-testserver: {}", err),
+    }
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("scram-testserver: {}", err);
+                continue;
+            }
+        };
+        if config.once {
+            if let Err(err) = serve(&config, stream) {
+                eprintln!("scram-testserver: {}", err);
+            }
+            return;
+        }
+        let config = config.clone();
+        thread::spawn(move || {
+            if let Err(err) = serve(&config, stream) {
+                eprintln!("scram-testserver: {}", err);
+            }
+        });
+    }
+}
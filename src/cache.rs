@@ -0,0 +1,848 @@
+//! An encrypted cache of salted passwords which survives restarts of the client.
+//!
+//! Deriving the salted password with PBKDF2 is the expensive part of every client handshake, and
+//! it's repeated for every process start and every reconnect. A [`CredentialCache`] keeps the
+//! salted passwords of previous handshakes and can be persisted in a file encrypted with
+//! ChaCha20-Poly1305 under a key provided by the caller, for example from the keyring of the
+//! operating system. It's passed to [`ScramClient::with_cache`](crate::ScramClient::with_cache):
+//!
+//! ``` rust,no_run
+//! use std::time::Duration;
+//! use scram::cache::CredentialCache;
+//! use scram::ScramClient;
+//!
+//! # let key = [0; 32];
+//! let cache = CredentialCache::load("credentials.cache", &key, Duration::from_secs(86400))
+//!     .unwrap_or_else(|_| CredentialCache::new(&key, Duration::from_secs(86400)));
+//! let client = ScramClient::new("user", "password", None).with_cache(&cache);
+//! // Run the handshake.
+//! cache.save("credentials.cache").unwrap();
+//! ```
+//!
+//! Entries are looked up by the mechanism, the username and the password, so the salted passwords
+//! of every mechanism and [`ScramHash`] are cached separately. They are only used if the server
+//! sends the same salt and iteration count as when they were derived. A changed salt or iteration
+//! count replaces the entry. Entries older than the time to live are derived anew. An
+//! [`Eviction`] policy bounds the number of entries, and [`CredentialCache::stats`] shows whether
+//! the cache helps.
+//!
+//! With the `mlock` feature the salted passwords are locked into memory, so they aren't written to
+//! the swap file. [`CredentialCache::encrypt_in_memory`] additionally keeps them encrypted while
+//! they aren't used, which matters for long-lived processes such as connection poolers whose
+//! memory may end up in a core dump.
+//!
+//! This module is available with the `cache` feature.
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
+use core::num::NonZeroU32;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::rngs::OsRng;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::digest::SHA256_OUTPUT_LEN;
+use ring::hmac::{self, Key, HMAC_SHA256};
+
+use crate::config::CacheConfig;
+use crate::entropy::EntropySource;
+use crate::file::write_atomically;
+use crate::hash::{ScramHash, MAX_OUTPUT_LEN};
+use crate::mlock::LockedBytes;
+use crate::utils::{salt_password, zeroize, Output};
+
+/// The first bytes of a cache file, which are also authenticated with the entries.
+const MAGIC: &[u8] = b"scram-cache-v1\n";
+
+/// The length of an entry without the salted password and the salt: the account, the time it was
+/// stored, the iteration count, the salt length and the length of the salted password.
+const ENTRY_LEN: usize = SHA256_OUTPUT_LEN + 8 + 4 + 2 + 1;
+
+/// The length of the Poly1305 tag of a salted password encrypted in memory.
+const TAG_LEN: usize = 16;
+
+/// Derives the key for `purpose` from the key of the cache, so the file encryption and the
+/// account MAC never use the same key.
+fn subkey(key: &[u8; 32], purpose: &[u8]) -> [u8; SHA256_OUTPUT_LEN] {
+    let tag = hmac::sign(&Key::new(HMAC_SHA256, key), purpose);
+    tag.as_ref().try_into().expect("SHA-256 output")
+}
+
+/// A salted password with the parameters it was derived with.
+struct Entry {
+    salt: Vec<u8>,
+    iterations: u32,
+    /// The salted password, or its nonce, ciphertext and tag under the memory key.
+    salted_password: LockedBytes,
+    /// Seconds since the Unix epoch.
+    stored_at: u64,
+    /// The tick at which the entry was stored, for [`Eviction::Bounded`].
+    inserted: u64,
+    /// The tick of the last lookup, for [`Eviction::Lru`].
+    last_used: u64,
+}
+
+/// How a [`CredentialCache`] limits the number of its entries. Expired entries are removed first
+/// with every policy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Eviction {
+    /// Entries are only removed after the time to live.
+    #[default]
+    TtlOnly,
+    /// At most this number of entries are kept. The entry stored first is evicted.
+    Bounded(usize),
+    /// At most this number of entries are kept. The entry which was least recently used is
+    /// evicted.
+    Lru(usize),
+}
+
+/// The values of the counters of a [`CredentialCache`] at one point in time.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CacheStats {
+    /// The number of salted passwords found in the cache.
+    pub hits: u64,
+    /// The number of salted passwords which were derived, because the cache had no entry, an
+    /// expired one or one with another salt or iteration count.
+    pub misses: u64,
+    /// The number of entries removed because they expired or to stay within the capacity.
+    pub evictions: u64,
+}
+
+/// Salted passwords of previous handshakes, see the [module documentation](self).
+///
+/// The cache can be shared by the clients of all threads.
+pub struct CredentialCache {
+    key: LessSafeKey,
+    account_key: Key,
+    /// The process-local key the salted passwords are encrypted with in memory, if enabled.
+    memory_key: Option<LessSafeKey>,
+    ttl: Duration,
+    eviction: Eviction,
+    entries: Mutex<BTreeMap<[u8; SHA256_OUTPUT_LEN], Entry>>,
+    ticks: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl CredentialCache {
+    /// Creates an empty cache. `key` encrypts the cache file, and entries older than `ttl` aren't
+    /// used.
+    pub fn new(key: &[u8; 32], ttl: Duration) -> Self {
+        let mut cache_key = subkey(key, b"aead");
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, &cache_key).expect("32 byte key");
+        zeroize(&mut cache_key);
+        let mut account_key = subkey(key, b"account");
+        let account_hmac_key = Key::new(HMAC_SHA256, &account_key);
+        zeroize(&mut account_key);
+        CredentialCache {
+            key: LessSafeKey::new(unbound),
+            // The accounts are identified by a MAC, so the file doesn't reveal the usernames.
+            account_key: account_hmac_key,
+            memory_key: None,
+            ttl,
+            eviction: Eviction::TtlOnly,
+            entries: Mutex::new(BTreeMap::new()),
+            ticks: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            evictions: AtomicU64::new(0),
+        }
+    }
+
+    /// Creates an empty cache with the settings of `config`. `key` encrypts the cache file.
+    pub fn with_config(key: &[u8; 32], config: &CacheConfig) -> Self {
+        CredentialCache::new(key, config.ttl()).configure(config)
+    }
+
+    /// Sets how the number of entries is limited. The default is [`Eviction::TtlOnly`]. Entries
+    /// beyond the new capacity, for example of a loaded cache, are evicted right away.
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        self.eviction = eviction;
+        self.evict(&mut self.entries());
+        self
+    }
+
+    /// Keeps the salted passwords encrypted in memory with ChaCha20-Poly1305 under a random key
+    /// which never leaves the process. They are only decrypted while a handshake or
+    /// [`save`](Self::save) uses them, so a memory dump doesn't contain them in plaintext unless
+    /// it also yields the key. Entries which are already cached, for example of a loaded cache, are
+    /// encrypted right away. The cache file isn't affected.
+    pub fn encrypt_in_memory(mut self) -> Self {
+        if self.memory_key.is_some() {
+            return self;
+        }
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        let unbound = UnboundKey::new(&CHACHA20_POLY1305, &key).expect("32 byte key");
+        zeroize(&mut key);
+        self.memory_key = Some(LessSafeKey::new(unbound));
+        let mut entries = self.entries();
+        for (account, entry) in entries.iter_mut() {
+            // The entries were stored before the key existed, so they are in plaintext.
+            entry.salted_password = self.seal(account, &entry.salted_password);
+        }
+        drop(entries);
+        self
+    }
+
+    /// Returns a snapshot of the hit, miss and eviction counters.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads the cache from the file at `path`. A missing file results in an empty cache.
+    ///
+    /// # Return value
+    ///
+    /// An error of the kind `io::ErrorKind::InvalidData` is returned if the file isn't a cache,
+    /// was modified or was encrypted with another key. Expired entries are dropped.
+    pub fn load<P: AsRef<Path>>(path: P, key: &[u8; 32], ttl: Duration) -> io::Result<Self> {
+        let cache = CredentialCache::new(key, ttl);
+        let mut data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(cache),
+            Err(err) => return Err(err),
+        };
+        let result = cache.decrypt(&mut data);
+        zeroize(&mut data);
+        result.map(|()| cache)
+    }
+
+    /// Like [`load`](Self::load), but with the settings of `config`.
+    pub fn load_with_config<P: AsRef<Path>>(
+        path: P,
+        key: &[u8; 32],
+        config: &CacheConfig,
+    ) -> io::Result<Self> {
+        CredentialCache::load(path, key, config.ttl()).map(|cache| cache.configure(config))
+    }
+
+    /// Applies the eviction policy and the in-memory encryption of `config`.
+    fn configure(self, config: &CacheConfig) -> Self {
+        let eviction = match config.capacity {
+            Some(capacity) if config.lru => Eviction::Lru(capacity),
+            Some(capacity) => Eviction::Bounded(capacity),
+            None => Eviction::TtlOnly,
+        };
+        let cache = self.eviction(eviction);
+        if config.encrypt_in_memory {
+            cache.encrypt_in_memory()
+        } else {
+            cache
+        }
+    }
+
+    /// Writes the unexpired entries to the file at `path`, replacing it atomically. The file is
+    /// written to the disk before it replaces the old one, and is only readable by its owner on
+    /// Unix. Concurrent saves don't interfere, the last one replaces the file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path.as_ref(), &self.encrypt())
+    }
+
+    /// Removes the entry of `authcid` with `password` for the `mechanism` name, for example after
+    /// the server rejected the proof computed from it. The name is the one returned by
+    /// [`ScramHash::name`], like `Mechanism::ScramSha256.name()`.
+    pub fn invalidate(&self, mechanism: &str, authcid: &str, password: &str) {
+        let id = self.account_id(mechanism, authcid, password);
+        self.entries().remove(&id);
+    }
+
+    /// Removes all entries.
+    pub fn clear(&self) {
+        self.entries().clear();
+    }
+
+    /// Returns the number of entries, including expired ones which weren't removed yet.
+    pub fn len(&self) -> usize {
+        self.entries().len()
+    }
+
+    /// Returns whether the cache has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries().is_empty()
+    }
+
+    /// Returns the account of `authcid` with `password` for the `mechanism` name, which a client
+    /// looks up its salted password in.
+    pub(crate) fn account(&self, mechanism: &str, authcid: &str, password: &str) -> Account<'_> {
+        Account {
+            cache: self,
+            id: self.account_id(mechanism, authcid, password),
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, BTreeMap<[u8; SHA256_OUTPUT_LEN], Entry>> {
+        // The map is consistent after every operation, so a poisoned lock can be used.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn account_id(
+        &self,
+        mechanism: &str,
+        authcid: &str,
+        password: &str,
+    ) -> [u8; SHA256_OUTPUT_LEN] {
+        let mut context = hmac::Context::with_key(&self.account_key);
+        context.update(mechanism.as_bytes());
+        context.update(&[0]);
+        context.update(authcid.as_bytes());
+        context.update(&[0]);
+        context.update(password.as_bytes());
+        context.sign().as_ref().try_into().expect("SHA-256 output")
+    }
+
+    /// Returns the salted password of `account` as it's stored in an entry, encrypted under the
+    /// memory key if there is one. The account is authenticated, so entries can't be swapped.
+    fn seal(&self, account: &[u8; SHA256_OUTPUT_LEN], salted_password: &[u8]) -> LockedBytes {
+        let memory_key = match &self.memory_key {
+            Some(memory_key) => memory_key,
+            None => return LockedBytes::new(salted_password),
+        };
+        let mut sealed = [0; NONCE_LEN + MAX_OUTPUT_LEN + TAG_LEN];
+        let sealed = &mut sealed[..NONCE_LEN + salted_password.len() + TAG_LEN];
+        let (nonce, rest) = sealed.split_at_mut(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at_mut(salted_password.len());
+        OsRng.fill_bytes(nonce);
+        ciphertext.copy_from_slice(salted_password);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce length");
+        let sealed_tag = memory_key
+            .seal_in_place_separate_tag(nonce, Aad::from(account), ciphertext)
+            .expect("salted password smaller than the ChaCha20-Poly1305 limit");
+        tag.copy_from_slice(sealed_tag.as_ref());
+        LockedBytes::new(sealed)
+    }
+
+    /// Returns the salted password of the `entry` of `account`, decrypting it if needed.
+    fn unseal(&self, account: &[u8; SHA256_OUTPUT_LEN], entry: &Entry) -> Output {
+        let memory_key = match &self.memory_key {
+            Some(memory_key) => memory_key,
+            None => return Output::copy_from(&entry.salted_password),
+        };
+        let (nonce, ciphertext) = entry.salted_password.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("nonce length");
+        let mut buffer = [0; MAX_OUTPUT_LEN + TAG_LEN];
+        let buffer = &mut buffer[..ciphertext.len()];
+        buffer.copy_from_slice(ciphertext);
+        let salted_password = memory_key
+            .open_in_place(nonce, Aad::from(account), buffer)
+            .map(|plaintext| Output::copy_from(plaintext))
+            .expect("entry encrypted with the memory key");
+        zeroize(buffer);
+        salted_password
+    }
+
+    fn expired(&self, entry: &Entry, now: u64) -> bool {
+        now.saturating_sub(entry.stored_at) >= self.ttl.as_secs()
+    }
+
+    /// Returns the next tick of the clock ordering the lookups.
+    fn tick(&self) -> u64 {
+        self.ticks.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Removes entries until the capacity of the eviction policy isn't exceeded, expired ones
+    /// first.
+    fn evict(&self, entries: &mut BTreeMap<[u8; SHA256_OUTPUT_LEN], Entry>) {
+        let capacity = match self.eviction {
+            Eviction::TtlOnly => return,
+            Eviction::Bounded(capacity) | Eviction::Lru(capacity) => capacity,
+        };
+        if entries.len() <= capacity {
+            return;
+        }
+        let now = now();
+        let before = entries.len();
+        entries.retain(|_, entry| !self.expired(entry, now));
+        while entries.len() > capacity {
+            let victim = entries.iter().min_by_key(|(_, entry)| match self.eviction {
+                Eviction::Lru(_) => entry.last_used,
+                _ => entry.inserted,
+            });
+            let id = *victim.expect("more entries than the capacity").0;
+            entries.remove(&id);
+        }
+        let evicted = (before - entries.len()) as u64;
+        self.evictions.fetch_add(evicted, Ordering::Relaxed);
+    }
+
+    /// Returns the magic, the nonce and the encrypted unexpired entries.
+    fn encrypt(&self) -> Vec<u8> {
+        let now = now();
+        let mut plaintext = Vec::new();
+        for (account, entry) in self.entries().iter() {
+            let salt_len = match u16::try_from(entry.salt.len()) {
+                Ok(salt_len) if !self.expired(entry, now) => salt_len,
+                _ => continue,
+            };
+            plaintext.extend_from_slice(account);
+            plaintext.extend_from_slice(&entry.stored_at.to_be_bytes());
+            plaintext.extend_from_slice(&entry.iterations.to_be_bytes());
+            plaintext.extend_from_slice(&salt_len.to_be_bytes());
+            let salted_password = self.unseal(account, entry);
+            // At most `MAX_OUTPUT_LEN` bytes.
+            plaintext.push(salted_password.len() as u8);
+            plaintext.extend_from_slice(&salted_password);
+            plaintext.extend_from_slice(&entry.salt);
+        }
+        let mut nonce = [0; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let tag = self
+            .key
+            .seal_in_place_separate_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(MAGIC),
+                &mut plaintext,
+            )
+            .expect("cache smaller than the ChaCha20-Poly1305 limit");
+        let mut data = MAGIC.to_vec();
+        data.extend_from_slice(&nonce);
+        data.append(&mut plaintext);
+        data.extend_from_slice(tag.as_ref());
+        data
+    }
+
+    /// Decrypts `data` in place and adds its unexpired entries.
+    fn decrypt(&self, data: &mut [u8]) -> io::Result<()> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid credential cache");
+        if data.len() < MAGIC.len() + NONCE_LEN || !data.starts_with(MAGIC) {
+            return Err(invalid());
+        }
+        let (nonce, ciphertext) = data[MAGIC.len()..].split_at_mut(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| invalid())?;
+        let mut plaintext: &[u8] = self
+            .key
+            .open_in_place(nonce, Aad::from(MAGIC), ciphertext)
+            .map_err(|_| invalid())?;
+        let now = now();
+        let mut entries = self.entries();
+        while !plaintext.is_empty() {
+            if plaintext.len() < ENTRY_LEN {
+                return Err(invalid());
+            }
+            let (fixed, rest) = plaintext.split_at(ENTRY_LEN);
+            let (account, fixed) = fixed.split_at(SHA256_OUTPUT_LEN);
+            let (stored_at, fixed) = fixed.split_at(8);
+            let (iterations, fixed) = fixed.split_at(4);
+            let (salt_len, salted_password_len) = fixed.split_at(2);
+            let salt_len = usize::from(u16::from_be_bytes(salt_len.try_into().unwrap()));
+            let salted_password_len = usize::from(salted_password_len[0]);
+            if salted_password_len > MAX_OUTPUT_LEN || rest.len() < salted_password_len + salt_len {
+                return Err(invalid());
+            }
+            let (salted_password, rest) = rest.split_at(salted_password_len);
+            let (salt, rest) = rest.split_at(salt_len);
+            plaintext = rest;
+            let account = account.try_into().unwrap();
+            let mut entry = Entry {
+                salt: salt.to_vec(),
+                iterations: u32::from_be_bytes(iterations.try_into().unwrap()),
+                salted_password: self.seal(&account, salted_password),
+                stored_at: u64::from_be_bytes(stored_at.try_into().unwrap()),
+                inserted: 0,
+                last_used: 0,
+            };
+            if !self.expired(&entry, now) {
+                entry.inserted = self.tick();
+                entry.last_used = entry.inserted;
+                entries.insert(account, entry);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The entry of a username and password in a [`CredentialCache`].
+#[derive(Debug)]
+pub(crate) struct Account<'a> {
+    cache: &'a CredentialCache,
+    id: [u8; SHA256_OUTPUT_LEN],
+}
+
+impl<'a> Account<'a> {
+    /// Returns the salted password for `salt` and `iterations` with `hash`, from the cache if
+    /// possible. Otherwise it's derived and stored, replacing an entry with other parameters.
+    pub(crate) fn salted_password<H: ScramHash + ?Sized>(
+        &self,
+        hash: &H,
+        password: &str,
+        iterations: NonZeroU32,
+        salt: &[u8],
+    ) -> Output {
+        let cache = self.cache;
+        let now = now();
+        if let Some(entry) = cache.entries().get_mut(&self.id) {
+            if cache.expired(entry, now) {
+                cache.evictions.fetch_add(1, Ordering::Relaxed);
+            } else if entry.salt == salt && entry.iterations == iterations.get() {
+                let salted_password = cache.unseal(&self.id, entry);
+                // A custom hash may share the name of another one.
+                if salted_password.len() == hash.output_len() {
+                    cache.hits.fetch_add(1, Ordering::Relaxed);
+                    entry.last_used = cache.tick();
+                    return salted_password;
+                }
+            }
+        }
+        cache.misses.fetch_add(1, Ordering::Relaxed);
+        // The lock isn't held during the derivation, so other handshakes aren't blocked.
+        let salted_password = salt_password(hash, password, iterations, salt);
+        let tick = cache.tick();
+        let entry = Entry {
+            salt: salt.to_vec(),
+            iterations: iterations.get(),
+            salted_password: cache.seal(&self.id, &salted_password),
+            stored_at: now,
+            inserted: tick,
+            last_used: tick,
+        };
+        let mut entries = cache.entries();
+        entries.insert(self.id, entry);
+        cache.evict(&mut entries);
+        salted_password
+    }
+}
+
+impl fmt::Debug for CredentialCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CredentialCache")
+            .field("ttl", &self.ttl)
+            .field("eviction", &self.eviction)
+            .field("encrypt_in_memory", &self.memory_key.is_some())
+            .field("entries", &self.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Returns the seconds since the Unix epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use core::convert::TryInto;
+    use core::num::NonZeroU32;
+    use core::time::Duration;
+    use std::fs;
+    use std::io;
+
+    use ring::hmac::{self, Key, HMAC_SHA256};
+
+    use super::{subkey, CacheStats, CredentialCache, Eviction};
+    use crate::config::CacheConfig;
+    use crate::utils::{hash_password, hash_password_for};
+    use crate::Mechanism;
+
+    const KEY: [u8; 32] = [7; 32];
+    const DAY: Duration = Duration::from_secs(86400);
+
+    fn iterations(iterations: u32) -> NonZeroU32 {
+        NonZeroU32::new(iterations).unwrap()
+    }
+
+    const SHA256: &str = "SCRAM-SHA-256";
+
+    fn salted_password_for(
+        cache: &CredentialCache,
+        mechanism: Mechanism,
+        authcid: &str,
+        password: &str,
+        iterations: u32,
+        salt: &[u8],
+    ) -> Vec<u8> {
+        cache
+            .account(mechanism.name(), authcid, password)
+            .salted_password(
+                &mechanism,
+                password,
+                NonZeroU32::new(iterations).unwrap(),
+                salt,
+            )
+            .to_vec()
+    }
+
+    fn salted_password(
+        cache: &CredentialCache,
+        authcid: &str,
+        password: &str,
+        iterations: u32,
+        salt: &[u8],
+    ) -> [u8; 32] {
+        let salted_password = salted_password_for(
+            cache,
+            Mechanism::ScramSha256,
+            authcid,
+            password,
+            iterations,
+            salt,
+        );
+        salted_password.try_into().unwrap()
+    }
+
+    #[test]
+    fn test_subkeys() {
+        assert_ne!(subkey(&KEY, b"aead"), subkey(&KEY, b"account"));
+        assert_ne!(subkey(&KEY, b"aead"), KEY);
+        // The account ids aren't a MAC under the key of the cache file.
+        let cache = CredentialCache::new(&KEY, DAY);
+        let message = b"SCRAM-SHA-256\0user\0pencil";
+        let tag = hmac::sign(&Key::new(HMAC_SHA256, &KEY), message);
+        assert_ne!(
+            &cache.account_id(SHA256, "user", "pencil")[..],
+            tag.as_ref()
+        );
+        let tag = hmac::sign(&Key::new(HMAC_SHA256, &subkey(&KEY, b"account")), message);
+        assert_eq!(
+            &cache.account_id(SHA256, "user", "pencil")[..],
+            tag.as_ref()
+        );
+    }
+
+    #[test]
+    fn test_salted_password() {
+        let cache = CredentialCache::new(&KEY, DAY);
+        let expected = hash_password("pencil", iterations(4096), b"salt");
+        assert_eq!(
+            salted_password(&cache, "user", "pencil", 4096, b"salt"),
+            expected
+        );
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            salted_password(&cache, "user", "pencil", 4096, b"salt"),
+            expected
+        );
+
+        // Other parameters replace the entry, another password adds one.
+        let expected = hash_password("pencil", iterations(4097), b"salt");
+        assert_eq!(
+            salted_password(&cache, "user", "pencil", 4097, b"salt"),
+            expected
+        );
+        assert_eq!(cache.len(), 1);
+        salted_password(&cache, "user", "other", 4096, b"salt");
+        assert_eq!(cache.len(), 2);
+        cache.invalidate(SHA256, "user", "pencil");
+        assert_eq!(cache.len(), 1);
+        cache.clear();
+        assert!(cache.is_empty());
+        let stats = CacheStats {
+            hits: 1,
+            misses: 3,
+            evictions: 0,
+        };
+        assert_eq!(cache.stats(), stats);
+    }
+
+    #[test]
+    fn test_mechanisms() {
+        let cache = CredentialCache::new(&KEY, DAY);
+        for &mechanism in Mechanism::ALL {
+            let expected = hash_password_for(mechanism, "pencil", iterations(4096), b"salt");
+            for _ in 0..2 {
+                let salted_password =
+                    salted_password_for(&cache, mechanism, "user", "pencil", 4096, b"salt");
+                assert_eq!(salted_password, expected);
+            }
+        }
+        assert_eq!(cache.len(), Mechanism::ALL.len());
+        assert_eq!(cache.stats().hits, Mechanism::ALL.len() as u64);
+        cache.invalidate(Mechanism::ScramSha512.name(), "user", "pencil");
+        assert_eq!(cache.len(), Mechanism::ALL.len() - 1);
+
+        // The salted passwords of all lengths are saved, in plaintext and encrypted in memory.
+        let directory =
+            std::env::temp_dir().join(format!("scram-mechanisms-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("credentials.cache");
+        salted_password_for(
+            &cache,
+            Mechanism::ScramSha512,
+            "user",
+            "pencil",
+            4096,
+            b"salt",
+        );
+        let cache = cache.encrypt_in_memory();
+        cache.save(&path).unwrap();
+        let loaded = CredentialCache::load(&path, &KEY, DAY).unwrap();
+        assert_eq!(loaded.len(), Mechanism::ALL.len());
+        for &mechanism in Mechanism::ALL {
+            let expected = hash_password_for(mechanism, "pencil", iterations(4096), b"salt");
+            let salted_password =
+                salted_password_for(&loaded, mechanism, "user", "pencil", 4096, b"salt");
+            assert_eq!(salted_password, expected);
+        }
+        assert_eq!(loaded.stats().hits, Mechanism::ALL.len() as u64);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_eviction() {
+        let fill = |cache: &CredentialCache| {
+            // "b" is the least recently used entry, "a" the one stored first.
+            for user in ["a", "b", "a", "c"] {
+                salted_password(cache, user, "pencil", 1, b"salt");
+            }
+        };
+        let contains = |cache: &CredentialCache, user: &str| {
+            let id = cache.account_id(SHA256, user, "pencil");
+            cache.entries().contains_key(&id)
+        };
+
+        let cache = CredentialCache::new(&KEY, DAY).eviction(Eviction::Lru(2));
+        fill(&cache);
+        assert!(contains(&cache, "a") && contains(&cache, "c"));
+        let stats = CacheStats {
+            hits: 1,
+            misses: 3,
+            evictions: 1,
+        };
+        assert_eq!(cache.stats(), stats);
+
+        let cache = CredentialCache::new(&KEY, DAY).eviction(Eviction::Bounded(2));
+        fill(&cache);
+        assert!(contains(&cache, "b") && contains(&cache, "c"));
+
+        let cache = CredentialCache::new(&KEY, DAY);
+        fill(&cache);
+        assert_eq!(cache.len(), 3);
+        let cache = cache.eviction(Eviction::Lru(1));
+        assert!(contains(&cache, "c"));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn test_with_config() {
+        let config = CacheConfig {
+            capacity: Some(2),
+            encrypt_in_memory: true,
+            ..CacheConfig::default()
+        };
+        let cache = CredentialCache::with_config(&KEY, &config);
+        assert_eq!(cache.ttl, DAY);
+        assert_eq!(cache.eviction, Eviction::Lru(2));
+        assert!(cache.memory_key.is_some());
+        let config = CacheConfig {
+            capacity: Some(2),
+            lru: false,
+            ..CacheConfig::default()
+        };
+        let cache = CredentialCache::with_config(&KEY, &config);
+        assert_eq!(cache.eviction, Eviction::Bounded(2));
+        assert!(cache.memory_key.is_none());
+        let cache = CredentialCache::with_config(&KEY, &CacheConfig::default());
+        assert_eq!(cache.eviction, Eviction::TtlOnly);
+    }
+
+    #[test]
+    fn test_encrypt_in_memory() {
+        let cache = CredentialCache::new(&KEY, DAY);
+        let expected = salted_password(&cache, "user", "pencil", 4096, b"salt");
+        let cache = cache.encrypt_in_memory();
+        let id = cache.account_id(SHA256, "user", "pencil");
+        let stored = cache.entries()[&id].salted_password.to_vec();
+        assert_eq!(stored.len(), 12 + 32 + 16);
+        assert!(!stored.windows(32).any(|window| window == expected));
+        assert_eq!(
+            salted_password(&cache, "user", "pencil", 4096, b"salt"),
+            expected
+        );
+        assert_eq!(cache.stats().hits, 1);
+        let expected = hash_password("other", iterations(4096), b"salt");
+        assert_eq!(
+            salted_password(&cache, "user", "other", 4096, b"salt"),
+            expected
+        );
+        assert_eq!(
+            salted_password(&cache, "user", "other", 4096, b"salt"),
+            expected
+        );
+
+        // The file is written in plaintext under the file key, and loads into either mode.
+        let directory = std::env::temp_dir().join(format!("scram-memory-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("credentials.cache");
+        cache.save(&path).unwrap();
+        let loaded = CredentialCache::load(&path, &KEY, DAY).unwrap();
+        let id = loaded.account_id(SHA256, "user", "other");
+        assert_eq!(*loaded.entries()[&id].salted_password, expected);
+        let loaded = CredentialCache::load(&path, &KEY, DAY)
+            .unwrap()
+            .encrypt_in_memory();
+        assert_eq!(
+            salted_password(&loaded, "user", "other", 4096, b"salt"),
+            expected
+        );
+        assert_eq!(loaded.stats().hits, 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_expired() {
+        let cache = CredentialCache::new(&KEY, Duration::from_secs(0));
+        salted_password(&cache, "user", "pencil", 4096, b"salt");
+        let entries = cache.entries();
+        let entry = entries.values().next().unwrap();
+        assert!(cache.expired(entry, entry.stored_at));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let directory = std::env::temp_dir().join(format!("scram-cache-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("credentials.cache");
+        assert!(CredentialCache::load(&path, &KEY, DAY).unwrap().is_empty());
+
+        let cache = CredentialCache::new(&KEY, DAY);
+        let expected = salted_password(&cache, "user", "pencil", 4096, b"salt");
+        salted_password(&cache, "other", "pencil", 4096, &[1; 300]);
+        cache.save(&path).unwrap();
+        cache.save(&path).unwrap();
+        // Only the file itself is left, without a temporary file.
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = CredentialCache::load(&path, &KEY, DAY).unwrap();
+        assert_eq!(loaded.len(), 2);
+        let entries = loaded.entries();
+        let id = loaded.account_id(SHA256, "user", "pencil");
+        assert_eq!(*entries[&id].salted_password, expected);
+        assert_eq!(entries[&id].salt, b"salt");
+        drop(entries);
+
+        let err = CredentialCache::load(&path, &[8; 32], DAY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let mut data = fs::read(&path).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        fs::write(&path, &data).unwrap();
+        let err = CredentialCache::load(&path, &KEY, DAY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Expired entries aren't loaded.
+        cache.save(&path).unwrap();
+        let loaded = CredentialCache::load(&path, &KEY, Duration::from_secs(0)).unwrap();
+        assert!(loaded.is_empty());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! The channel-binding types of the `-PLUS` mechanisms, with the data of the TLS connection.
+//!
+//! A client which binds the authentication to the TLS channel passes a [`ChannelBinding`] to
+//! [`ScramClient::with_channel_binding`](crate::ScramClient::with_channel_binding). The crate
+//! sends the `p=` flag of the type in the gs2 header and the data of the connection in the `c=`
+//! attribute of the final message:
+//!
+//! ```
+//! use scram::channel_binding::ChannelBinding;
+//! use scram::ScramClient;
+//!
+//! // The first Finished message of the TLS handshake, as returned by the TLS library.
+//! let finished = [0x4f; 12];
+//! let client = ScramClient::new("user", "password", None)
+//!     .with_channel_binding(ChannelBinding::TlsUnique(&finished));
+//! let (_, client_first) = client.client_first();
+//! assert!(client_first.starts_with("p=tls-unique,,n=user,"));
+//! ```
+//!
+//! The client only uses the `-PLUS` variant of a mechanism with channel binding, for example
+//! `SCRAM-SHA-256-PLUS` instead of `SCRAM-SHA-256`.
+//!
+//! With `tls-exporter`, the binding of RFC9266 for TLS 1.3, the caller passes the bytes the TLS
+//! library exports with [`TLS_EXPORTER_LABEL`], no context and [`TLS_EXPORTER_LENGTH`]:
+//!
+//! ```
+//! use scram::channel_binding::{ChannelBinding, TLS_EXPORTER_LABEL, TLS_EXPORTER_LENGTH};
+//! use scram::ScramClient;
+//!
+//! # fn export_keying_material(_: &str, _: Option<&[u8]>, length: usize) -> Vec<u8> {
+//! #     vec![0; length]
+//! # }
+//! let exporter = export_keying_material(TLS_EXPORTER_LABEL, None, TLS_EXPORTER_LENGTH);
+//! let client = ScramClient::new("user", "password", None)
+//!     .with_channel_binding(ChannelBinding::TlsExporter(&exporter));
+//! let (_, client_first) = client.client_first();
+//! assert!(client_first.starts_with("p=tls-exporter,,n=user,"));
+//! ```
+//!
+//! Servers pass the channel-binding data of the connection to
+//! [`ConnectionContext::with_channel_binding_data`](crate::ConnectionContext::with_channel_binding_data).
+//! For `tls-server-end-point` that's the hash of their certificate, which
+//! [`tls_server_end_point`] computes.
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+
+use ring::digest::{self, Algorithm, SHA256, SHA384, SHA512};
+
+/// The label of the TLS exporter of `tls-exporter`, see RFC9266 section 2.
+pub const TLS_EXPORTER_LABEL: &str = "EXPORTER-Channel-Binding";
+
+/// The length of the TLS exporter of `tls-exporter` in bytes, see RFC9266 section 2.
+pub const TLS_EXPORTER_LENGTH: usize = 32;
+
+/// A channel-binding type with the channel-binding data of the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelBinding<'a> {
+    /// `tls-unique` of RFC5929 section 3: the first Finished message of the latest TLS handshake
+    /// of the connection. It's only defined for TLS 1.2 and earlier, and only secure with the
+    /// extended master secret of RFC7627.
+    TlsUnique(&'a [u8]),
+    /// `tls-server-end-point` of RFC5929 section 4: the DER encoded certificate of the server.
+    /// The channel-binding data is the hash of the certificate, see [`tls_server_end_point`]. It's
+    /// available with every TLS version.
+    TlsServerEndPoint(&'a [u8]),
+    /// `tls-exporter` of RFC9266: the exporter of the TLS connection with the label
+    /// [`TLS_EXPORTER_LABEL`], no context and the length [`TLS_EXPORTER_LENGTH`]. It's the
+    /// binding for TLS 1.3, and only secure with TLS 1.2 with the extended master secret of
+    /// RFC7627.
+    TlsExporter(&'a [u8]),
+}
+
+impl<'a> ChannelBinding<'a> {
+    /// Returns the name of the channel-binding type, which the client sends in the `p=` flag of
+    /// the gs2 header.
+    pub fn cb_name(&self) -> &'static str {
+        match *self {
+            ChannelBinding::TlsUnique(_) => "tls-unique",
+            ChannelBinding::TlsServerEndPoint(_) => "tls-server-end-point",
+            ChannelBinding::TlsExporter(_) => "tls-exporter",
+        }
+    }
+
+    /// Returns the channel-binding data the client sends in the `c=` attribute after the gs2
+    /// header, or `None` if it can't be computed, see [`tls_server_end_point`].
+    pub fn data(&self) -> Option<Cow<'a, [u8]>> {
+        match *self {
+            ChannelBinding::TlsUnique(finished) => Some(Cow::Borrowed(finished)),
+            ChannelBinding::TlsExporter(exporter) => Some(Cow::Borrowed(exporter)),
+            ChannelBinding::TlsServerEndPoint(certificate) => {
+                tls_server_end_point(certificate).map(Cow::Owned)
+            }
+        }
+    }
+}
+
+/// A [`ChannelBinding`] which owns its data, for example as extracted from a TLS connection by
+/// the `rustls` module.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OwnedChannelBinding {
+    /// See [`ChannelBinding::TlsUnique`].
+    TlsUnique(Vec<u8>),
+    /// See [`ChannelBinding::TlsServerEndPoint`].
+    TlsServerEndPoint(Vec<u8>),
+    /// See [`ChannelBinding::TlsExporter`].
+    TlsExporter(Vec<u8>),
+}
+
+impl OwnedChannelBinding {
+    /// Returns the binding borrowing the data, to pass to
+    /// [`ScramClient::with_channel_binding`](crate::ScramClient::with_channel_binding).
+    pub fn binding(&self) -> ChannelBinding<'_> {
+        match *self {
+            OwnedChannelBinding::TlsUnique(ref finished) => ChannelBinding::TlsUnique(finished),
+            OwnedChannelBinding::TlsServerEndPoint(ref certificate) => {
+                ChannelBinding::TlsServerEndPoint(certificate)
+            }
+            OwnedChannelBinding::TlsExporter(ref exporter) => ChannelBinding::TlsExporter(exporter),
+        }
+    }
+}
+
+impl<'a> From<ChannelBinding<'a>> for OwnedChannelBinding {
+    fn from(binding: ChannelBinding<'a>) -> Self {
+        match binding {
+            ChannelBinding::TlsUnique(finished) => {
+                OwnedChannelBinding::TlsUnique(finished.to_vec())
+            }
+            ChannelBinding::TlsServerEndPoint(certificate) => {
+                OwnedChannelBinding::TlsServerEndPoint(certificate.to_vec())
+            }
+            ChannelBinding::TlsExporter(exporter) => {
+                OwnedChannelBinding::TlsExporter(exporter.to_vec())
+            }
+        }
+    }
+}
+
+/// Returns the `tls-server-end-point` channel-binding data of the DER encoded `certificate`, the
+/// hash of the certificate with the hash function of its signature algorithm. Certificates
+/// signed with MD5 or SHA-1 are hashed with SHA-256, as RFC5929 section 4.1 requires.
+///
+/// # Return value
+///
+/// Returns `None` if the certificate can't be parsed, or if its signature algorithm has no hash
+/// function like Ed25519, for which RFC5929 doesn't define the channel binding, or a hash
+/// function `ring` doesn't implement like SHA-224.
+pub fn tls_server_end_point(certificate: &[u8]) -> Option<Vec<u8>> {
+    let algorithm = signature_hash(certificate)?;
+    Some(digest::digest(algorithm, certificate).as_ref().to_vec())
+}
+
+/// The DER tag of a `SEQUENCE`.
+const SEQUENCE: u8 = 0x30;
+
+/// The DER tag of an `OBJECT IDENTIFIER`.
+const OID: u8 = 0x06;
+
+/// The DER tag of the `hashAlgorithm` of `RSASSA-PSS-params`, `[0] EXPLICIT`.
+const PSS_HASH_ALGORITHM: u8 = 0xa0;
+
+/// The DER encoded object identifier prefix of the signature algorithms of PKCS #1,
+/// 1.2.840.113549.1.1.
+const PKCS1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01];
+
+/// The DER encoded object identifier prefix of the ECDSA signature algorithms of RFC5758,
+/// 1.2.840.10045.4.3.
+const ECDSA_SHA2: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03];
+
+/// The DER encoded object identifier prefix of the hash functions of NIST, 2.16.840.1.101.3.4.2.
+const NIST_HASH: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02];
+
+/// The DER encoded object identifier prefix of the DSA signature algorithms of NIST,
+/// 2.16.840.1.101.3.4.3.
+const NIST_DSA: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x03];
+
+/// The DER encoded object identifiers of the signature algorithms with MD5 or SHA-1 and of SHA-1
+/// itself.
+const WEAK_HASHES: &[&[u8]] = &[
+    // md5WithRSAEncryption, 1.2.840.113549.1.1.4
+    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x04],
+    // sha1WithRSAEncryption, 1.2.840.113549.1.1.5
+    &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x05],
+    // ecdsa-with-SHA1, 1.2.840.10045.4.1
+    &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x01],
+    // id-dsa-with-sha1, 1.2.840.10040.4.3
+    &[0x2a, 0x86, 0x48, 0xce, 0x38, 0x04, 0x03],
+    // id-sha1, 1.3.14.3.2.26
+    &[0x2b, 0x0e, 0x03, 0x02, 0x1a],
+];
+
+/// Returns the hash function for the `tls-server-end-point` data of the DER encoded
+/// `certificate`, from its `signatureAlgorithm`.
+fn signature_hash(certificate: &[u8]) -> Option<&'static Algorithm> {
+    let (certificate, _) = read(certificate, SEQUENCE)?;
+    let (_tbs_certificate, rest) = read(certificate, SEQUENCE)?;
+    let (signature_algorithm, _) = read(rest, SEQUENCE)?;
+    let (oid, parameters) = read(signature_algorithm, OID)?;
+    if WEAK_HASHES.contains(&oid) {
+        return Some(&SHA256);
+    }
+    if let Some(suffix) = oid.strip_prefix(PKCS1) {
+        return match suffix {
+            // sha256WithRSAEncryption, sha384WithRSAEncryption and sha512WithRSAEncryption
+            [0x0b] => Some(&SHA256),
+            [0x0c] => Some(&SHA384),
+            [0x0d] => Some(&SHA512),
+            // id-RSASSA-PSS, with the hash function in its parameters
+            [0x0a] => pss_hash(parameters),
+            _ => None,
+        };
+    }
+    match oid.strip_prefix(ECDSA_SHA2).or(oid.strip_prefix(NIST_DSA)) {
+        // with SHA-256, SHA-384 and SHA-512
+        Some([0x02]) => Some(&SHA256),
+        Some([0x03]) => Some(&SHA384),
+        Some([0x04]) => Some(&SHA512),
+        _ => None,
+    }
+}
+
+/// Returns the hash function of the DER encoded `RSASSA-PSS-params` of RFC4055, which default to
+/// SHA-1.
+fn pss_hash(parameters: &[u8]) -> Option<&'static Algorithm> {
+    let (parameters, _) = read(parameters, SEQUENCE)?;
+    let hash_algorithm = match read(parameters, PSS_HASH_ALGORITHM) {
+        Some((hash_algorithm, _)) => hash_algorithm,
+        None => return Some(&SHA256),
+    };
+    let (hash_algorithm, _) = read(hash_algorithm, SEQUENCE)?;
+    let (oid, _) = read(hash_algorithm, OID)?;
+    if WEAK_HASHES.contains(&oid) {
+        return Some(&SHA256);
+    }
+    match oid.strip_prefix(NIST_HASH)? {
+        // id-sha256, id-sha384 and id-sha512
+        [0x01] => Some(&SHA256),
+        [0x02] => Some(&SHA384),
+        [0x03] => Some(&SHA512),
+        _ => None,
+    }
+}
+
+/// Reads a DER value with the single byte `tag` from the start of `input`, and returns its
+/// contents and the rest of the input.
+fn read(input: &[u8], tag: u8) -> Option<(&[u8], &[u8])> {
+    let (&first, input) = input.split_first()?;
+    if first != tag {
+        return None;
+    }
+    let (&length, mut input) = input.split_first()?;
+    let length = if length < 0x80 {
+        usize::from(length)
+    } else {
+        // The long form, with up to four bytes for the length.
+        let count = usize::from(length & 0x7f);
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let (bytes, rest) = input.split_at(count);
+        input = rest;
+        bytes
+            .iter()
+            .fold(0, |length, &byte| length << 8 | usize::from(byte))
+    };
+    if input.len() < length {
+        return None;
+    }
+    Some(input.split_at(length))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use ring::digest::{digest, Algorithm, SHA256, SHA384, SHA512};
+
+    use super::{tls_server_end_point, ChannelBinding, OwnedChannelBinding};
+
+    /// Returns the DER encoding of a value with `tag` and `contents`.
+    fn der(tag: u8, contents: &[u8]) -> Vec<u8> {
+        let mut value = vec![tag];
+        if contents.len() < 0x80 {
+            value.push(contents.len() as u8);
+        } else {
+            value.extend_from_slice(&[0x82, (contents.len() >> 8) as u8, contents.len() as u8]);
+        }
+        value.extend_from_slice(contents);
+        value
+    }
+
+    /// Returns a certificate with a large `tbsCertificate` and the `signatureAlgorithm` of
+    /// `algorithm`. Only its structure matters for the channel binding.
+    fn certificate(algorithm: &[u8]) -> Vec<u8> {
+        let mut contents = der(0x30, &[0; 300]);
+        contents.extend(der(0x30, algorithm));
+        contents.extend(der(0x03, &[0, 1, 2, 3]));
+        der(0x30, &contents)
+    }
+
+    /// Returns a `signatureAlgorithm` with the object identifier `oid` and `parameters`.
+    fn algorithm(oid: &[u8], parameters: &[u8]) -> Vec<u8> {
+        let mut algorithm = der(0x06, oid);
+        algorithm.extend_from_slice(parameters);
+        algorithm
+    }
+
+    fn check(algorithm: &[u8], hash: Option<&'static Algorithm>) {
+        let certificate = certificate(algorithm);
+        let expected = hash.map(|hash| digest(hash, &certificate).as_ref().to_vec());
+        assert_eq!(tls_server_end_point(&certificate), expected);
+    }
+
+    #[test]
+    fn test_signature_algorithms() {
+        let rsa = |suffix| algorithm(&[42, 134, 72, 134, 247, 13, 1, 1, suffix], &[5, 0]);
+        check(&rsa(0x04), Some(&SHA256));
+        check(&rsa(0x05), Some(&SHA256));
+        check(&rsa(0x0b), Some(&SHA256));
+        check(&rsa(0x0c), Some(&SHA384));
+        check(&rsa(0x0d), Some(&SHA512));
+        // sha224WithRSAEncryption
+        check(&rsa(0x0e), None);
+
+        let ecdsa = |suffix| algorithm(&[42, 134, 72, 206, 61, 4, 3, suffix], &[]);
+        check(&ecdsa(0x02), Some(&SHA256));
+        check(&ecdsa(0x03), Some(&SHA384));
+        check(&ecdsa(0x04), Some(&SHA512));
+        check(
+            &algorithm(&[42, 134, 72, 206, 61, 4, 1], &[]),
+            Some(&SHA256),
+        );
+        // Ed25519
+        check(&algorithm(&[43, 101, 112], &[]), None);
+    }
+
+    #[test]
+    fn test_pss() {
+        let pss = |parameters: &[u8]| {
+            algorithm(
+                &[42, 134, 72, 134, 247, 13, 1, 1, 10],
+                &der(0x30, parameters),
+            )
+        };
+        let hash = |oid: &[u8]| der(0xa0, &der(0x30, &algorithm(oid, &[5, 0])));
+        let sha384 = hash(&[96, 134, 72, 1, 101, 3, 4, 2, 2]);
+        check(&pss(&sha384), Some(&SHA384));
+        let sha512 = hash(&[96, 134, 72, 1, 101, 3, 4, 2, 3]);
+        check(&pss(&sha512), Some(&SHA512));
+        let sha1 = hash(&[43, 14, 3, 2, 26]);
+        check(&pss(&sha1), Some(&SHA256));
+        // The default hash function is SHA-1.
+        check(&pss(&[]), Some(&SHA256));
+    }
+
+    #[test]
+    fn test_invalid_certificate() {
+        assert_eq!(tls_server_end_point(&[]), None);
+        assert_eq!(tls_server_end_point(&[0x30, 0x03, 0x30]), None);
+        assert_eq!(
+            tls_server_end_point(&[0x30, 0x84, 0xff, 0xff, 0xff, 0xff]),
+            None
+        );
+        let mut truncated = certificate(&algorithm(&[42, 134, 72, 206, 61, 4, 3, 2], &[]));
+        truncated.truncate(310);
+        assert_eq!(tls_server_end_point(&truncated), None);
+    }
+
+    #[test]
+    fn test_channel_binding() {
+        let certificate = certificate(&algorithm(&[42, 134, 72, 206, 61, 4, 3, 3], &[]));
+        let binding = ChannelBinding::TlsServerEndPoint(&certificate);
+        assert_eq!(binding.cb_name(), "tls-server-end-point");
+        assert_eq!(binding.data().unwrap().len(), 48);
+        assert_eq!(
+            ChannelBinding::TlsServerEndPoint(b"not a certificate").data(),
+            None
+        );
+        let binding = ChannelBinding::TlsUnique(b"finished");
+        assert_eq!(binding.cb_name(), "tls-unique");
+        assert_eq!(binding.data().as_deref(), Some(&b"finished"[..]));
+        let binding = ChannelBinding::TlsExporter(&[7; 32]);
+        assert_eq!(binding.cb_name(), "tls-exporter");
+        assert_eq!(binding.data().as_deref(), Some(&[7; 32][..]));
+
+        let owned = OwnedChannelBinding::from(binding);
+        assert_eq!(owned, OwnedChannelBinding::TlsExporter(vec![7; 32]));
+        assert_eq!(owned.binding(), binding);
+    }
+}
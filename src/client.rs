@@ -1,16 +1,46 @@
-use std::borrow::Cow;
-use std::num::NonZeroU32;
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+#[cfg(any(test, feature = "pinning"))]
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::ops::Deref;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
-use base64::Engine;
-use base64::engine::general_purpose::STANDARD;
-use rand::distributions::{Distribution, Uniform};
-use rand::{rngs::OsRng, Rng};
-use ring::digest::SHA256_OUTPUT_LEN;
-use ring::hmac;
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
 
-use error::{Error, Field, Kind};
-use utils::{find_proofs, hash_password};
-use NONCE_LENGTH;
+#[cfg(any(test, feature = "cache"))]
+use crate::cache::{Account, CredentialCache};
+use crate::channel_binding::ChannelBinding;
+use crate::confidentiality::Confidentiality;
+use crate::config::ScramConfig;
+use crate::credentials::PasswordChange;
+use crate::deadline::Deadline;
+use crate::entropy::EntropySource;
+use crate::error::{ChannelBindingError, Error, Excerpt, Field, Kind};
+use crate::grammar::{self, ServerFinalMessage, ServerFirstMessage, Verification};
+use crate::gs2::{ChannelBindingFlag, Gs2Header};
+use crate::hash::ScramHash;
+use crate::interop::{self, encoded_len, InteropWarning, Lenience, ServerMessage};
+use crate::mechanism::Mechanism;
+#[cfg(any(test, feature = "nfc"))]
+use crate::nfc;
+#[cfg(any(test, feature = "pinning"))]
+use crate::pinning::{Pending, PinStore, Pinning};
+#[cfg(any(test, feature = "saslprep"))]
+use crate::saslprep::{self, saslprep};
+use crate::summary::Summary;
+use crate::trace;
+#[cfg(any(test, feature = "pinning"))]
+use crate::utils::unescape_username;
+use crate::utils::{
+    escape_username, find_proofs, generate_nonce, generate_nonce_of, salt_password, zeroize,
+    zeroize_string, Output,
+};
+use crate::{MIN_ITERATIONS, NONCE_LENGTH};
 
 #[deprecated(
     since = "0.2.0",
@@ -19,63 +49,90 @@ use NONCE_LENGTH;
 pub type ClientFirst<'a> = ScramClient<'a>;
 
 /// Parses a `server_first_message` returning a (none, salt, iterations) tuple if successful.
-fn parse_server_first(data: &str) -> Result<(&str, Vec<u8>, NonZeroU32), Error> {
-    if data.len() < 2 {
-        return Err(Error::Protocol(Kind::ExpectedField(Field::Nonce)));
+#[cfg(any(test, feature = "conformance", feature = "test-util"))]
+pub(crate) fn parse_server_first(data: &str) -> Result<(&str, Vec<u8>, NonZeroU32), Error> {
+    let message = ServerFirstMessage::parse(data)?;
+    let salt = Lenience::default()
+        .decode(message.salt, Field::Salt, &mut Vec::new())
+        .map_err(|err| Error::base64(Field::Salt, err))?;
+    Ok((message.nonce, salt, message.iterations))
+}
+
+/// Parses a `server-final-message` returning the decoded verifier. Extension attributes after the
+/// verifier are ignored.
+#[cfg(any(test, feature = "conformance", feature = "test-util"))]
+pub(crate) fn parse_server_final(
+    data: &str,
+    lenience: &Lenience,
+    warnings: &mut Vec<InteropWarning>,
+) -> Result<Vec<u8>, Error> {
+    let message = ServerFinalMessage::parse(data)?;
+    decode_verifier(message.verification, lenience, warnings)
+}
+
+/// Decodes the verifier of a `server-final-message`, or returns the error sent by the server.
+fn decode_verifier(
+    verification: Verification,
+    lenience: &Lenience,
+    warnings: &mut Vec<InteropWarning>,
+) -> Result<Vec<u8>, Error> {
+    match verification {
+        Verification::Verifier(verifier) => lenience
+            .decode(verifier, Field::VerifyOrError, warnings)
+            .map_err(|err| Error::base64(Field::VerifyOrError, err)),
+        Verification::Error(error) => Err(Error::authentication(error)),
     }
-    let mut parts = data.split(',').peekable();
-    match parts.peek() {
-        Some(part) if &part.as_bytes()[..2] == b"m=" => {
-            return Err(Error::UnsupportedExtension);
-        }
-        Some(_) => {}
-        None => {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Nonce)));
-        }
+}
+
+/// The password of a client, borrowed from the caller or owned. An owned password is overwritten
+/// with zeros when it's dropped.
+#[derive(Clone)]
+struct Password<'a>(Cow<'a, str>);
+
+impl<'a> Deref for Password<'a> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
     }
-    let nonce = match parts.next() {
-        Some(part) if &part.as_bytes()[..2] == b"r=" => &part[2..],
-        _ => {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Nonce)));
-        }
-    };
-    let salt = match parts.next() {
-        Some(part) if &part.as_bytes()[..2] == b"s=" => STANDARD.decode(part[2..].as_bytes())
-            .map_err(|_| Error::Protocol(Kind::InvalidField(Field::Salt)))?,
-        _ => {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Salt)));
-        }
-    };
-    let iterations = match parts.next() {
-        Some(part) if &part.as_bytes()[..2] == b"i=" => part[2..]
-            .parse()
-            .map_err(|_| Error::Protocol(Kind::InvalidField(Field::Iterations)))?,
-        _ => {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Iterations)));
-        }
-    };
-    Ok((nonce, salt, iterations))
 }
 
-fn parse_server_final(data: &str) -> Result<Vec<u8>, Error> {
-    if data.len() < 2 {
-        return Err(Error::Protocol(Kind::ExpectedField(Field::VerifyOrError)));
+impl<'a> Drop for Password<'a> {
+    fn drop(&mut self) {
+        if let Cow::Owned(ref mut password) = self.0 {
+            zeroize_string(password);
+        }
     }
-    match &data[..2] {
-        "v=" => STANDARD.decode(&data.as_bytes()[2..])
-            .map_err(|_| Error::Protocol(Kind::InvalidField(Field::VerifyOrError))),
-        "e=" => Err(Error::Authentication(data[2..].to_string())),
-        _ => Err(Error::Protocol(Kind::ExpectedField(Field::VerifyOrError))),
+}
+
+impl<'a> fmt::Debug for Password<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Password(..)")
     }
 }
 
 /// The initial state of the SCRAM mechanism. It's the entry point for a SCRAM handshake.
+///
+/// The states of the handshake compute it with the hash function `H`, the mechanism chosen with
+/// [`with_mechanism`](Self::with_mechanism) or a [`ScramHash`] passed to
+/// [`with_hash`](Self::with_hash).
 #[derive(Debug)]
-pub struct ScramClient<'a> {
-    gs2header: Cow<'static, str>,
-    password: &'a str,
+pub struct ScramClient<'a, H = Mechanism> {
+    hash: H,
+    gs2header: Gs2Header<'a>,
+    password: Password<'a>,
     nonce: String,
-    authcid: &'a str,
+    authcid: Cow<'a, str>,
+    min_iterations: u32,
+    lenience: Lenience,
+    deadline: Deadline,
+    require_confidentiality: bool,
+    confidentiality: Confidentiality,
+    channel_binding_data: Option<Vec<u8>>,
+    #[cfg(any(test, feature = "cache"))]
+    cache: Option<&'a CredentialCache>,
+    #[cfg(any(test, feature = "pinning"))]
+    pinning: Option<Pinning>,
 }
 
 impl<'a> ScramClient<'a> {
@@ -86,13 +143,33 @@ impl<'a> ScramClient<'a> {
     /// * authcid - An username used for authentication.
     /// * password - A password used to prove that the user is authentic.
     /// * authzid - An username used for authorization. This can be used to impersonate as `authzid`
-    /// using the credentials of `authcid`. If `authzid` is `None` the authorized username will be
-    /// the same as the authenticated username.
+    ///   using the credentials of `authcid`. If `authzid` is `None` the authorized username will be
+    ///   the same as the authenticated username.
+    ///
+    /// `authcid` and `password` are borrowed `&str`s or owned `String`s, for example read from a
+    /// configuration file. An owned password is overwritten with zeros when the handshake no
+    /// longer needs it. For an owned `authzid` use [`ClientFirstTemplate::try_new_owned`].
+    ///
+    /// With the `saslprep` feature the usernames and the password are prepared with SASLprep in
+    /// `Mode::Lenient`, as RFC5802 requires. Text which can't be prepared is sent unchanged. Use
+    /// [`ClientFirstTemplate::try_new_saslprep`] to reject it instead.
     ///
     /// # Return value
     ///
     /// An I/O error is returned if the internal random number generator couldn't be constructed.
-    pub fn new(authcid: &'a str, password: &'a str, authzid: Option<&'a str>) -> Self {
+    ///
+    /// A username with a NUL character, which can't be sent, is reported by
+    /// [`try_client_first`](Self::try_client_first). Use [`ClientFirstTemplate::try_new`] to
+    /// reject it right away.
+    ///
+    /// This method is only available with the `std` feature. Without it, use
+    /// [`with_rng`](Self::with_rng).
+    #[cfg(feature = "std")]
+    pub fn new(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+    ) -> Self {
         Self::with_rng(authcid, password, authzid, &mut OsRng)
     }
 
@@ -104,65 +181,619 @@ impl<'a> ScramClient<'a> {
     /// * authcid - An username used for authentication.
     /// * password - A password used to prove that the user is authentic.
     /// * authzid - An username used for authorization. This can be used to impersonate as `authzid`
-    /// using the credentials of `authcid`. If `authzid` is `None` the authorized username will be
-    /// the same as the authenticated username.
-    /// * rng: A random number generator used to generate random nonces. Please only use a
-    /// cryptographically secure random number generator!
-    pub fn with_rng<R: Rng + ?Sized>(
-        authcid: &'a str,
-        password: &'a str,
+    ///   using the credentials of `authcid`. If `authzid` is `None` the authorized username will be
+    ///   the same as the authenticated username.
+    /// * rng: A source of randomness used to generate random nonces, either a random number
+    ///   generator or a custom [`EntropySource`]. Please only use a
+    ///   cryptographically secure random number generator!
+    ///
+    /// Usernames are checked like those passed to [`new`](Self::new).
+    pub fn with_rng<R: EntropySource + ?Sized>(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
         authzid: Option<&'a str>,
         rng: &mut R,
     ) -> Self {
-        let gs2header: Cow<'static, str> = match authzid {
-            Some(authzid) => format!("n,a={},", authzid).into(),
-            None => "n,,".into(),
-        };
-        let nonce: String = Uniform::from(33..125)
-            .sample_iter(rng)
-            .map(|x: u8| if x > 43 { (x + 1) as char } else { x as char })
-            .take(NONCE_LENGTH)
-            .collect();
+        Self::with_nonce(authcid, password, authzid, generate_nonce(rng))
+    }
+
+    /// Constructs an initial state which sends an empty username, for servers which establish the
+    /// identity out of band, for example with a TLS client certificate. `password` and `authzid`
+    /// have the same meaning as for [`new`](Self::new).
+    ///
+    /// RFC5802 requires a username, so only servers configured for this, like a
+    /// [`ScramServer`](crate::ScramServer) with
+    /// [`allow_empty_authcid`](crate::ScramServer::allow_empty_authcid), accept the handshake.
+    ///
+    /// This method is only available with the `std` feature. Without it, use
+    /// [`ClientFirstTemplate::with_external_identity`].
+    #[cfg(feature = "std")]
+    pub fn with_external_identity(
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+    ) -> Self {
+        ClientFirstTemplate::with_external_identity(password, authzid).client()
+    }
+
+    /// Constructs an initial state using the given client nonce.
+    pub(crate) fn with_nonce(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+        nonce: String,
+    ) -> Self {
+        ClientFirstTemplate::new(authcid, password, authzid).into_client(nonce)
+    }
+
+    /// Runs the handshake with `mechanism` instead of SCRAM-SHA-256, for example with
+    /// `Mechanism::ScramSha512` if the server advertised `SCRAM-SHA-512`.
+    pub fn with_mechanism(self, mechanism: Mechanism) -> Self {
+        self.with_hash(mechanism)
+    }
+
+    /// Returns the mechanism of the handshake.
+    pub fn mechanism(&self) -> Mechanism {
+        self.hash
+    }
+}
+
+impl<'a, H: ScramHash> ScramClient<'a, H> {
+    /// Runs the handshake with the hash function `hash`, for hash functions which aren't built
+    /// in or other implementations of the built-in ones. The mechanism name the application
+    /// negotiates with the server is [`ScramHash::name`]. See the [`hash`](crate::hash) module.
+    pub fn with_hash<G: ScramHash>(self, hash: G) -> ScramClient<'a, G> {
         ScramClient {
-            gs2header,
-            password,
-            authcid,
-            nonce,
+            hash,
+            gs2header: self.gs2header,
+            password: self.password,
+            nonce: self.nonce,
+            authcid: self.authcid,
+            min_iterations: self.min_iterations,
+            lenience: self.lenience,
+            deadline: self.deadline,
+            require_confidentiality: self.require_confidentiality,
+            confidentiality: self.confidentiality,
+            channel_binding_data: self.channel_binding_data,
+            #[cfg(any(test, feature = "cache"))]
+            cache: self.cache,
+            #[cfg(any(test, feature = "pinning"))]
+            pinning: self.pinning,
         }
     }
 
+    /// Accepts iteration counts below the minimum of 4096 from the server, down to a single
+    /// iteration. This is only available with the `test-util` feature and allows test suites to
+    /// run handshakes fast. Never use this outside of tests, it disables the protection against
+    /// servers requesting weak parameters.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn insecure_allow_low_iterations(mut self) -> Self {
+        self.min_iterations = 1;
+        self
+    }
+
+    /// Enables workarounds for servers which don't follow RFC5802 exactly. See the
+    /// [`interop`] module.
+    pub fn lenient(mut self, lenience: Lenience) -> Self {
+        self.lenience = lenience;
+        self
+    }
+
+    /// Fails the handshake with `Error::Timeout` if a server message is handled after `deadline`,
+    /// for drivers which keep the states across slow network round-trips.
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Deadline::at(deadline);
+        self
+    }
+
+    /// Looks up the salted password in `cache` and stores it there after deriving it, so
+    /// handshakes with the same mechanism, credentials, salt and iteration count skip the key
+    /// derivation. The entries are keyed by the [`ScramHash::name`] of the hash function.
+    ///
+    /// This method is only available with the `cache` feature.
+    #[cfg(any(test, feature = "cache"))]
+    pub fn with_cache(mut self, cache: &'a CredentialCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Checks the salt and the iteration count the server sends against the values pinned in
+    /// `store` for the user at `host`, and pins them once the server is verified. See the
+    /// [`pinning`](crate::pinning) module.
+    ///
+    /// This method is only available with the `pinning` feature.
+    #[cfg(any(test, feature = "pinning"))]
+    pub fn with_pin_store(mut self, store: Arc<dyn PinStore>, host: &str) -> Self {
+        let username = unescape_username(&self.authcid).expect("username escaped on construction");
+        self.pinning = Some(Pinning::new(store, host, &username));
+        self
+    }
+
+    /// Applies the client settings of `config`: the minimum iteration count and the channel
+    /// binding. The nonce was generated when the client was constructed, so the nonce length only
+    /// applies to the clients of a [`ClientFirstTemplate::with_config`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` isn't valid, see [`ScramConfig::validate`].
+    pub fn with_config(mut self, config: &'a ScramConfig) -> Self {
+        config.validate().expect("invalid configuration");
+        self.min_iterations = config.min_iterations;
+        if let Some(cb_type) = config.cb_type() {
+            self.gs2header.channel_binding = ChannelBindingFlag::Required(cb_type);
+        }
+        self
+    }
+
+    /// Requests channel binding of the type `cb_type`, for example `"tls-exporter"` or
+    /// `"tls-server-end-point"`, for the `-PLUS` variant of the mechanism.
+    ///
+    /// The channel-binding data is usually only known after the TLS handshake, so it's passed to
+    /// [`ServerFirst::channel_binding_data`] after the first client message was built. Without the
+    /// data [`ServerFirst::handle_server_first`] fails with
+    /// `Error::ChannelBinding(ChannelBindingError::MissingData)`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cb_type` is empty or contains other characters than ASCII letters, digits, `.`
+    /// and `-`.
+    pub fn channel_binding(mut self, cb_type: &'a str) -> Self {
+        assert!(grammar::is_cb_name(cb_type), "invalid channel-binding type");
+        self.gs2header.channel_binding = ChannelBindingFlag::Required(cb_type);
+        self
+    }
+
+    /// Binds the handshake to the TLS channel with `binding`, for the `-PLUS` variant of the
+    /// mechanism. Unlike [`channel_binding`](Self::channel_binding), the channel-binding data is
+    /// passed with the type. See the [`channel_binding`](crate::channel_binding) module.
+    ///
+    /// If the data can't be computed, for example from a server certificate signed with Ed25519,
+    /// [`ServerFirst::handle_server_first`] fails with
+    /// `Error::ChannelBinding(ChannelBindingError::MissingData)`.
+    pub fn with_channel_binding(mut self, binding: ChannelBinding<'_>) -> Self {
+        self.gs2header.channel_binding = ChannelBindingFlag::Required(binding.cb_name());
+        self.channel_binding_data = binding.data().map(Cow::into_owned);
+        self
+    }
+
+    /// Announces with the `y` flag that the client supports channel binding, but doesn't use it
+    /// since the server didn't advertise a `-PLUS` variant of the mechanism. A server which does
+    /// support channel binding, so the advertisement was stripped by an attacker, fails the
+    /// handshake with `Error::ChannelBinding(ChannelBindingError::Downgrade)` and sends
+    /// `e=server-does-support-channel-binding`, which the client returns as
+    /// `Error::Authentication`. [`Error::from_server_error_value`] maps it back.
+    ///
+    /// Replaces a channel binding requested before and zeroizes its data. Like with `n`, the `c=`
+    /// attribute of the final client message only encodes the GS2 header, `eSws` for `y,,`.
+    pub fn supports_channel_binding(mut self) -> Self {
+        self.gs2header.channel_binding = ChannelBindingFlag::NotUsed;
+        if let Some(ref mut data) = self.channel_binding_data.take() {
+            zeroize(data);
+        }
+        self
+    }
+
+    /// Refuses to start the handshake unless the encryption of the transport was asserted with
+    /// [`confidentiality`](Self::confidentiality). See [`Confidentiality`].
+    pub fn require_confidentiality(mut self) -> Self {
+        self.require_confidentiality = true;
+        self
+    }
+
+    /// Asserts whether the transport is encrypted.
+    pub fn confidentiality(mut self, confidentiality: Confidentiality) -> Self {
+        self.confidentiality = confidentiality;
+        self
+    }
+
+    /// Aborts the handshake and zeroizes the nonce and the channel-binding data. See [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        zeroize_string(&mut self.nonce);
+        if let Some(ref mut data) = self.channel_binding_data {
+            zeroize(data);
+        }
+        Aborted::new()
+    }
+
     /// Returns the next state and the first client message.
     ///
     /// Call the [`ServerFirst::handle_server_first`] method to continue the SCRAM handshake.
-    pub fn client_first(self) -> (ServerFirst<'a>, String) {
-        let escaped_authcid: Cow<'a, str> =
-            if self.authcid.chars().any(|chr| chr == ',' || chr == '=') {
-                self.authcid.into()
-            } else {
-                self.authcid.replace(',', "=2C").replace('=', "=3D").into()
-            };
-        let client_first_bare = format!("n={},r={}", escaped_authcid, self.nonce);
-        let client_first = format!("{}{}", self.gs2header, client_first_bare);
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`require_confidentiality`](Self::require_confidentiality) was called, but the
+    /// encryption of the transport wasn't asserted, or if a username contains a NUL character.
+    /// Use [`try_client_first`](Self::try_client_first) if the assertion depends on the
+    /// connection or the usernames on untrusted input.
+    pub fn client_first(self) -> (ServerFirst<'a, H>, String) {
+        match self.try_client_first() {
+            Ok(client_first) => client_first,
+            Err(err) => panic!("can't send client-first-message: {}", err),
+        }
+    }
+
+    /// Like [`client_first`](Self::client_first), but fails with
+    /// `Error::ConfidentialityNotAsserted` or with `Error::Protocol(Kind::InvalidField(_, _))`
+    /// naming `Field::Authcid` or `Field::Authzid` instead of panicking.
+    pub fn try_client_first(self) -> Result<(ServerFirst<'a, H>, String), Error> {
+        self.confidentiality.check(self.require_confidentiality)?;
+        // Escaping keeps a NUL, so the escaped username is checked.
+        grammar::check_username(&self.authcid, Field::Authcid)?;
+        let _phase = trace::phase(self.hash.name(), "client_first");
+        let mut client_first_bare =
+            String::with_capacity(5 + self.authcid.len() + self.nonce.len());
+        client_first_bare.push_str("n=");
+        client_first_bare.push_str(&self.authcid);
+        client_first_bare.push_str(",r=");
+        client_first_bare.push_str(&self.nonce);
+        let gs2header = self.gs2header.serialize()?;
+        let mut client_first = String::with_capacity(gs2header.len() + client_first_bare.len());
+        client_first.push_str(&gs2header);
+        client_first.push_str(&client_first_bare);
+        #[cfg(any(test, feature = "cache"))]
+        let cache = self
+            .cache
+            .map(|cache| cache.account(self.hash.name(), &self.authcid, &self.password));
         let server_first = ServerFirst {
-            gs2header: self.gs2header,
+            hash: self.hash,
+            gs2header,
             password: self.password,
             client_nonce: self.nonce,
             client_first_bare,
+            min_iterations: self.min_iterations,
+            lenience: self.lenience,
+            channel_binding_data: self.channel_binding_data,
+            deadline: self.deadline,
+            #[cfg(any(test, feature = "cache"))]
+            cache,
+            #[cfg(any(test, feature = "pinning"))]
+            pinning: self.pinning,
         };
-        (server_first, client_first)
+        trace::sent(client_first.len());
+        Ok((server_first, client_first))
+    }
+}
+
+/// Prepares `text` with SASLprep in [`Mode::Lenient`](saslprep::Mode::Lenient) if the `saslprep`
+/// feature is enabled. Text which can't be prepared is returned unchanged, as some servers don't
+/// prepare the credentials either.
+#[cfg(feature = "saslprep")]
+fn prepare(text: Cow<'_, str>) -> Cow<'_, str> {
+    match text {
+        Cow::Borrowed(text) => saslprep::prepare_or_keep(text),
+        Cow::Owned(mut text) => match saslprep(&text, saslprep::Mode::Lenient) {
+            Ok(Cow::Owned(prepared)) => {
+                // The text may be a password.
+                zeroize_string(&mut text);
+                Cow::Owned(prepared)
+            }
+            _ => Cow::Owned(text),
+        },
+    }
+}
+
+#[cfg(not(feature = "saslprep"))]
+fn prepare(text: Cow<'_, str>) -> Cow<'_, str> {
+    text
+}
+
+/// Precomputed parts of the first client message for repeated handshakes with the same
+/// credentials, for example when a driver reconnects.
+///
+/// The username is escaped and the gs2 header is checked once. Every handshake created from the
+/// template only generates a fresh nonce.
+///
+/// ``` rust,no_run
+/// use scram::client::ClientFirstTemplate;
+///
+/// let template = ClientFirstTemplate::new("user", "password", None);
+/// for _ in 0..3 {
+///     let (scram, client_first) = template.client().client_first();
+///     // Run the handshake on a new connection.
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ClientFirstTemplate<'a> {
+    mechanism: Mechanism,
+    gs2header: Gs2Header<'a>,
+    password: Password<'a>,
+    authcid: Cow<'a, str>,
+    min_iterations: u32,
+    nonce_length: usize,
+}
+
+impl<'a> ClientFirstTemplate<'a> {
+    /// Creates a template from the credentials. The arguments have the same meaning as those of
+    /// [`ScramClient::new`], and are prepared with SASLprep like those.
+    ///
+    /// A username with a NUL character, which can't be sent, is reported by
+    /// [`ScramClient::try_client_first`] of the clients. Use [`try_new`](Self::try_new) to reject
+    /// it right away.
+    pub fn new(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+    ) -> Self {
+        ClientFirstTemplate::build_unchecked(
+            prepare(authcid.into()),
+            prepare(password.into()),
+            authzid.map(|authzid| prepare(Cow::Borrowed(authzid))),
+        )
+    }
+
+    /// Like [`new`](Self::new), but fails with `Error::Protocol(Kind::InvalidField(_, _))` naming
+    /// `Field::Authcid` or `Field::Authzid` if a username can't be sent.
+    pub fn try_new(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+    ) -> Result<Self, Error> {
+        ClientFirstTemplate::build(
+            prepare(authcid.into()),
+            prepare(password.into()),
+            authzid.map(|authzid| prepare(Cow::Borrowed(authzid))),
+        )
+    }
+
+    /// Like [`try_new`](Self::try_new), but takes all credentials as owned `String`s, for example
+    /// read from a configuration file, so the template doesn't borrow anything.
+    pub fn try_new_owned(
+        authcid: String,
+        password: String,
+        authzid: Option<String>,
+    ) -> Result<ClientFirstTemplate<'static>, Error> {
+        ClientFirstTemplate::build(
+            prepare(authcid.into()),
+            prepare(password.into()),
+            authzid.map(|authzid| prepare(Cow::Owned(authzid))),
+        )
+    }
+
+    /// Like [`try_new`](Self::try_new), but normalizes the usernames and the password to
+    /// Unicode Normalization Form C instead of preparing them with SASLprep, for servers which
+    /// store normalized credentials. See the [`nfc`] module.
+    ///
+    /// # Return value
+    ///
+    /// Fails with `Error::Protocol(Kind::InvalidField(_, _))` naming `Field::Authcid` or
+    /// `Field::Authzid` if a username can't be normalized or sent, and with
+    /// `Error::InvalidPassword` if the password can't be normalized.
+    ///
+    /// This method is only available with the `nfc` feature.
+    #[cfg(any(test, feature = "nfc"))]
+    pub fn try_new_nfc(
+        authcid: &'a str,
+        password: &'a str,
+        authzid: Option<&'a str>,
+    ) -> Result<Self, Error> {
+        let username = |username, field| {
+            nfc::normalize(username).map_err(|_| Error::Protocol(Kind::InvalidField(field, None)))
+        };
+        let authcid = username(authcid, Field::Authcid)?;
+        let authzid = authzid
+            .map(|authzid| username(authzid, Field::Authzid))
+            .transpose()?;
+        let password = nfc::normalize(password).map_err(|_| Error::InvalidPassword)?;
+        ClientFirstTemplate::build(authcid, password, authzid)
+    }
+
+    /// Like [`try_new`](Self::try_new), but prepares the usernames and the password with
+    /// SASLprep in `mode` and rejects them if they can't be prepared, as RFC5802 requires. Clients usually prepare them in
+    /// [`Mode::Lenient`](saslprep::Mode::Lenient). See the
+    /// [`saslprep` module](crate::saslprep).
+    ///
+    /// # Return value
+    ///
+    /// Fails with `Error::Protocol(Kind::InvalidField(_, _))` naming `Field::Authcid` or
+    /// `Field::Authzid` if a username can't be prepared or sent, and with
+    /// `Error::InvalidPassword` if the password can't be prepared.
+    ///
+    /// This method is only available with the `saslprep` feature.
+    #[cfg(any(test, feature = "saslprep"))]
+    pub fn try_new_saslprep(
+        authcid: &'a str,
+        password: &'a str,
+        authzid: Option<&'a str>,
+        mode: saslprep::Mode,
+    ) -> Result<Self, Error> {
+        let username = |username, field| {
+            saslprep(username, mode).map_err(|_| Error::Protocol(Kind::InvalidField(field, None)))
+        };
+        let authcid = username(authcid, Field::Authcid)?;
+        let authzid = authzid
+            .map(|authzid| username(authzid, Field::Authzid))
+            .transpose()?;
+        let password = saslprep(password, mode).map_err(|_| Error::InvalidPassword)?;
+        ClientFirstTemplate::build(authcid, password, authzid)
+    }
+
+    /// Creates a template which sends `gs2_header` as built by the caller instead of a header
+    /// built from the arguments, for GS2 bridges and proxies which control the header. The
+    /// `c=` attribute of the final message encodes the same header.
+    ///
+    /// The header must be a complete `gs2-header` including the trailing comma, for example
+    /// `"n,a=admin,"`. A required channel binding, `p=`, expects the channel-binding data through
+    /// [`ServerFirst::channel_binding_data`] like [`ScramClient::channel_binding`]. Methods which
+    /// set the channel binding, like [`with_config`](Self::with_config), replace the flag of the
+    /// header. `authcid` and `password` are prepared like those of [`new`](Self::new), the
+    /// header is sent as it is.
+    ///
+    /// # Return value
+    ///
+    /// Fails with the errors of [`Gs2Header::parse`] if the header isn't valid, with
+    /// `Error::Protocol(Kind::InvalidField(Field::GS2Header, _))` if anything follows it, and like
+    /// [`try_new`](Self::try_new) if `authcid` can't be sent.
+    pub fn try_with_gs2_header(
+        authcid: impl Into<Cow<'a, str>>,
+        password: impl Into<Cow<'a, str>>,
+        gs2_header: &'a str,
+    ) -> Result<Self, Error> {
+        let mut template =
+            ClientFirstTemplate::build(prepare(authcid.into()), prepare(password.into()), None)?;
+        let (gs2header, rest) = Gs2Header::parse(gs2_header)?;
+        if !rest.is_empty() {
+            let excerpt = Excerpt::new(None, rest);
+            return Err(Error::Protocol(Kind::InvalidField(
+                Field::GS2Header,
+                Some(excerpt),
+            )));
+        }
+        template.gs2header = gs2header;
+        Ok(template)
+    }
+
+    /// Creates a template which sends an empty username. See
+    /// [`ScramClient::with_external_identity`].
+    pub fn with_external_identity(
+        password: impl Into<Cow<'a, str>>,
+        authzid: Option<&'a str>,
+    ) -> Self {
+        ClientFirstTemplate::new("", password, authzid)
+    }
+
+    fn build(
+        authcid: Cow<'a, str>,
+        password: Cow<'a, str>,
+        authzid: Option<Cow<'a, str>>,
+    ) -> Result<Self, Error> {
+        // The template owns the password, so an owned password is zeroized if the usernames are
+        // invalid.
+        let template = ClientFirstTemplate::build_unchecked(authcid, password, authzid);
+        grammar::check_username(&template.authcid, Field::Authcid)?;
+        template.gs2header.serialize()?;
+        Ok(template)
+    }
+
+    /// Like [`build`](Self::build), but leaves checking the usernames to
+    /// [`ScramClient::try_client_first`].
+    fn build_unchecked(
+        authcid: Cow<'a, str>,
+        password: Cow<'a, str>,
+        authzid: Option<Cow<'a, str>>,
+    ) -> Self {
+        let mut gs2header = Gs2Header::new(ChannelBindingFlag::NotSupported, None);
+        // An empty `authzid` can't be sent, it means the authenticated username like `None`.
+        gs2header.authzid = authzid.filter(|authzid| !authzid.is_empty());
+        let authcid = match authcid {
+            Cow::Borrowed(authcid) => escape_username(authcid),
+            Cow::Owned(authcid) => escape_username(&authcid).into_owned().into(),
+        };
+        ClientFirstTemplate {
+            mechanism: Mechanism::ScramSha256,
+            gs2header,
+            password: Password(password),
+            authcid,
+            min_iterations: MIN_ITERATIONS,
+            nonce_length: NONCE_LENGTH,
+        }
+    }
+
+    /// Runs the handshakes of the template with `mechanism` instead of SCRAM-SHA-256. See
+    /// [`ScramClient::with_mechanism`].
+    pub fn with_mechanism(mut self, mechanism: Mechanism) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    /// Applies the client settings of `config` to the clients created from the template: the
+    /// minimum iteration count, the nonce length and the channel binding.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` isn't valid, see [`ScramConfig::validate`].
+    pub fn with_config(mut self, config: &'a ScramConfig) -> Self {
+        config.validate().expect("invalid configuration");
+        self.min_iterations = config.min_iterations;
+        self.nonce_length = config.nonce_length;
+        if let Some(cb_type) = config.cb_type() {
+            self.gs2header.channel_binding = ChannelBindingFlag::Required(cb_type);
+        }
+        self
+    }
+
+    /// Creates the initial state of a new handshake with a nonce from the operating system's
+    /// random number generator.
+    ///
+    /// This method is only available with the `std` feature. Without it, use
+    /// [`client_with_rng`](Self::client_with_rng).
+    #[cfg(feature = "std")]
+    pub fn client(&self) -> ScramClient<'a> {
+        self.client_with_rng(&mut OsRng)
+    }
+
+    /// Creates the initial state of a new handshake with a nonce generated from `rng`.
+    pub fn client_with_rng<R: EntropySource + ?Sized>(&self, rng: &mut R) -> ScramClient<'a> {
+        self.client_with_nonce(generate_nonce_of(rng, self.nonce_length))
+    }
+
+    /// Creates the initial state of a new handshake with the given client nonce.
+    fn client_with_nonce(&self, nonce: String) -> ScramClient<'a> {
+        self.clone().into_client(nonce)
+    }
+
+    /// Like [`client_with_nonce`](Self::client_with_nonce), but moves the credentials into the
+    /// state instead of copying them.
+    fn into_client(self, nonce: String) -> ScramClient<'a> {
+        ScramClient {
+            hash: self.mechanism,
+            gs2header: self.gs2header,
+            password: self.password,
+            authcid: self.authcid,
+            nonce,
+            min_iterations: self.min_iterations,
+            lenience: Lenience::default(),
+            deadline: Deadline::default(),
+            require_confidentiality: false,
+            confidentiality: Confidentiality::default(),
+            channel_binding_data: None,
+            #[cfg(any(test, feature = "cache"))]
+            cache: None,
+            #[cfg(any(test, feature = "pinning"))]
+            pinning: None,
+        }
     }
 }
 
 /// The second state of the SCRAM mechanism after the first client message was computed.
 #[derive(Debug)]
-pub struct ServerFirst<'a> {
-    gs2header: Cow<'static, str>,
-    password: &'a str,
+pub struct ServerFirst<'a, H = Mechanism> {
+    hash: H,
+    gs2header: String,
+    password: Password<'a>,
     client_nonce: String,
     client_first_bare: String,
+    min_iterations: u32,
+    lenience: Lenience,
+    channel_binding_data: Option<Vec<u8>>,
+    deadline: Deadline,
+    #[cfg(any(test, feature = "cache"))]
+    cache: Option<Account<'a>>,
+    #[cfg(any(test, feature = "pinning"))]
+    pinning: Option<Pinning>,
 }
 
-impl<'a> ServerFirst<'a> {
+impl<'a, H: ScramHash> ServerFirst<'a, H> {
+    /// Supplies the channel-binding data of the connection, for example the TLS exporter value,
+    /// for the type requested with [`ScramClient::channel_binding`]. The data is ignored if no
+    /// channel binding was requested, and replaces the data of
+    /// [`ScramClient::with_channel_binding`].
+    pub fn channel_binding_data(mut self, data: &[u8]) -> Self {
+        self.channel_binding_data = Some(data.to_vec());
+        self
+    }
+
+    /// Aborts the handshake and zeroizes the nonce and the channel-binding data. See [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        zeroize_string(&mut self.client_nonce);
+        zeroize_string(&mut self.client_first_bare);
+        if let Some(ref mut data) = self.channel_binding_data {
+            zeroize(data);
+        }
+        Aborted::new()
+    }
+
     /// Processes the first answer from the server and returns the next state or an error. If an
     /// error is returned the SCRAM handshake is aborted.
     ///
@@ -174,28 +805,118 @@ impl<'a> ServerFirst<'a> {
     ///
     /// * Error::Protocol
     /// * Error::UnsupportedExtension
-    pub fn handle_server_first(self, server_first: &str) -> Result<ClientFinal, Error> {
-        let (nonce, salt, iterations) = parse_server_first(server_first)?;
+    /// * Error::ChannelBinding(ChannelBindingError::MissingData)
+    /// * Error::Timeout
+    /// * Error::VerifierChanged
+    ///
+    /// An iteration count below 4096 is rejected as `Error::Protocol(Kind::InvalidField(_, _))`.
+    pub fn handle_server_first(self, server_first: &str) -> Result<ClientFinal<H>, Error> {
+        let _phase = trace::phase(self.hash.name(), "handle_server_first");
+        trace::received(server_first.len());
+        let result = self.process_server_first(server_first);
+        trace::outcome(&result);
+        result
+    }
+
+    /// Returns the escaped username, which `client_first_bare` starts with.
+    fn authcid(&self) -> &str {
+        let authcid = &self.client_first_bare["n=".len()..];
+        authcid.split(',').next().unwrap_or(authcid)
+    }
+
+    fn process_server_first(self, server_first: &str) -> Result<ClientFinal<H>, Error> {
+        self.deadline.check()?;
+        let mut warnings = Vec::new();
+        let server_first = self
+            .lenience
+            .trim(server_first, ServerMessage::First, &mut warnings);
+        let ServerFirstMessage {
+            nonce,
+            salt,
+            iterations,
+            extensions,
+        } = ServerFirstMessage::parse(server_first)?;
+        if !extensions.is_empty() {
+            interop::warn(
+                &mut warnings,
+                InteropWarning::UnknownAttributes(ServerMessage::First),
+            );
+        }
+        let salt = self
+            .lenience
+            .decode(salt, Field::Salt, &mut warnings)
+            .map_err(|err| Error::base64(Field::Salt, err))?;
         if !nonce.starts_with(&self.client_nonce) {
             return Err(Error::Protocol(Kind::InvalidNonce));
         }
-        let salted_password = hash_password(self.password, iterations, &salt);
-        let (client_proof, server_signature): ([u8; SHA256_OUTPUT_LEN], hmac::Tag) = find_proofs(
-            &self.gs2header,
+        if iterations.get() < self.min_iterations {
+            let excerpt = Excerpt::new(Some('i'), &iterations.to_string());
+            return Err(Error::Protocol(Kind::InvalidField(
+                Field::Iterations,
+                Some(excerpt),
+            )));
+        }
+        #[cfg(any(test, feature = "pinning"))]
+        let pending = self
+            .pinning
+            .as_ref()
+            .map(|pinning| pinning.check(&salt, iterations))
+            .transpose()?;
+        trace::iterations(iterations.get());
+        // A header from `try_with_gs2_header` may start with the nonstandard flag.
+        let cbind_data: &[u8] = if self.gs2header.trim_start_matches("F,").starts_with("p=") {
+            self.channel_binding_data
+                .as_deref()
+                .ok_or(Error::ChannelBinding(ChannelBindingError::MissingData))?
+        } else {
+            &[]
+        };
+        #[cfg(any(test, feature = "cache"))]
+        let salted_password = match self.cache {
+            Some(ref account) => {
+                account.salted_password(&self.hash, &self.password, iterations, &salt)
+            }
+            None => salt_password(&self.hash, &self.password, iterations, &salt),
+        };
+        #[cfg(not(any(test, feature = "cache")))]
+        let salted_password = salt_password(&self.hash, &self.password, iterations, &salt);
+
+        // The final message is built in place: the proofs are computed from the `c=` value in the
+        // message, and the proof is encoded straight into it.
+        let mut client_final = String::with_capacity(
+            9 + encoded_len(self.gs2header.len() + cbind_data.len())
+                + nonce.len()
+                + encoded_len(self.hash.output_len()),
+        );
+        client_final.push_str("c=");
+        self.lenience
+            .encode_to(&[self.gs2header.as_bytes(), cbind_data], &mut client_final);
+        let channel_binding_end = client_final.len();
+        client_final.push_str(",r=");
+        client_final.push_str(nonce);
+        let (client_proof, server_signature) = find_proofs(
+            &self.hash,
+            &client_final[2..channel_binding_end],
             &self.client_first_bare,
-            &server_first,
+            server_first,
             &salted_password,
             nonce,
         );
-        let client_final = format!(
-            "c={},r={},p={}",
-            STANDARD.encode(self.gs2header.as_bytes()),
-            nonce,
-            STANDARD.encode(&client_proof)
-        );
+        client_final.push_str(",p=");
+        self.lenience.encode_to(&[&client_proof], &mut client_final);
+        let authcid = self.authcid().to_string();
         Ok(ClientFinal {
+            hash: self.hash,
             server_signature,
             client_final,
+            authcid,
+            nonce_length: nonce.len(),
+            iterations,
+            lenience: self.lenience,
+            warnings,
+            deadline: self.deadline,
+            #[cfg(any(test, feature = "pinning"))]
+            pending,
         })
     }
 }
@@ -203,20 +924,53 @@ impl<'a> ServerFirst<'a> {
 /// The third state of the SCRAM mechanism after the first server message was successfully
 /// processed.
 #[derive(Debug)]
-pub struct ClientFinal {
-    server_signature: hmac::Tag,
+pub struct ClientFinal<H = Mechanism> {
+    hash: H,
+    server_signature: Output,
     client_final: String,
+    authcid: String,
+    nonce_length: usize,
+    iterations: NonZeroU32,
+    lenience: Lenience,
+    warnings: Vec<InteropWarning>,
+    deadline: Deadline,
+    #[cfg(any(test, feature = "pinning"))]
+    pending: Option<Pending>,
 }
 
-impl ClientFinal {
+impl<H: ScramHash> ClientFinal<H> {
+    /// Returns the violations of RFC5802 in the `server-first-message` which the client
+    /// tolerated. See the [`interop`] module.
+    pub fn warnings(&self) -> &[InteropWarning] {
+        &self.warnings
+    }
+
+    /// Aborts the handshake and zeroizes the final client message with the proof. See
+    /// [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        zeroize_string(&mut self.client_final);
+        Aborted::new()
+    }
+
     /// Returns the next state and the final client message.
     ///
     /// Call the
     /// [`ServerFinal::handle_server_final`] method to continue the SCRAM handshake.
     #[inline]
-    pub fn client_final(self) -> (ServerFinal, String) {
+    pub fn client_final(self) -> (ServerFinal<H>, String) {
+        let _phase = trace::phase(self.hash.name(), "client_final");
+        trace::sent(self.client_final.len());
         let server_final = ServerFinal {
+            hash: self.hash,
             server_signature: self.server_signature,
+            authcid: self.authcid,
+            nonce_length: self.nonce_length,
+            iterations: self.iterations,
+            lenience: self.lenience,
+            warnings: self.warnings,
+            deadline: self.deadline,
+            #[cfg(any(test, feature = "pinning"))]
+            pending: self.pending,
         };
         (server_final, self.client_final)
     }
@@ -224,27 +978,898 @@ impl ClientFinal {
 
 /// The final state of the SCRAM mechanism after the final client message was computed.
 #[derive(Debug)]
-pub struct ServerFinal {
-    server_signature: hmac::Tag,
+pub struct ServerFinal<H = Mechanism> {
+    hash: H,
+    server_signature: Output,
+    authcid: String,
+    nonce_length: usize,
+    iterations: NonZeroU32,
+    lenience: Lenience,
+    warnings: Vec<InteropWarning>,
+    deadline: Deadline,
+    #[cfg(any(test, feature = "pinning"))]
+    pending: Option<Pending>,
 }
 
-impl ServerFinal {
+impl<H: ScramHash> ServerFinal<H> {
+    /// Returns the violations of RFC5802 in the `server-first-message` which the client
+    /// tolerated. See the [`interop`] module.
+    pub fn warnings(&self) -> &[InteropWarning] {
+        &self.warnings
+    }
+
     /// Processes the final answer from the server and returns the authentication result.
     ///
     /// # Return value
     ///
     /// * A value of `Ok(())` signals a successful authentication attempt.
     /// * A value of `Err(Error::Protocol(_)` or `Err(Error::UnsupportedExtension)` means that the
-    /// authentication request failed.
+    ///   authentication request failed.
     /// * A value of `Err(Error::InvalidServer)` or `Err(Error::Authentication(_))` means that the
-    /// authentication request was rejected.
+    ///   authentication request was rejected.
     ///
     /// Detailed semantics are documented in the [`Error`] type.
+    ///
+    /// Extension attributes the server appends after the verifier are ignored. Use
+    /// [`handle_server_final_with_extensions`](Self::handle_server_final_with_extensions) to
+    /// receive them.
     pub fn handle_server_final(self, server_final: &str) -> Result<(), Error> {
-        if self.server_signature.as_ref() == &*parse_server_final(server_final)? {
+        self.handle_server_final_with_extensions(server_final)
+            .map(|_| ())
+    }
+
+    /// Aborts the handshake. See [`Aborted`].
+    pub fn abort(self) -> Aborted {
+        Aborted::new()
+    }
+
+    /// Like [`handle_server_final`](Self::handle_server_final), but returns the extension
+    /// attributes following the verifier on success, for example `"x=1,y=2"`. The string is empty
+    /// if the server didn't send any.
+    pub fn handle_server_final_with_extensions(self, server_final: &str) -> Result<&str, Error> {
+        self.process_server_final(server_final).0
+    }
+
+    /// Like [`handle_server_final`](Self::handle_server_final), but also returns the violations
+    /// of RFC5802 in both server messages which the client tolerated, whether the authentication
+    /// succeeded or not. See the [`interop`] module.
+    pub fn handle_server_final_with_warnings(
+        self,
+        server_final: &str,
+    ) -> (Result<(), Error>, Vec<InteropWarning>) {
+        let (result, warnings) = self.process_server_final(server_final);
+        (result.map(|_| ()), warnings)
+    }
+
+    fn process_server_final(
+        mut self,
+        server_final: &str,
+    ) -> (Result<&str, Error>, Vec<InteropWarning>) {
+        let _phase = trace::phase(self.hash.name(), "handle_server_final");
+        trace::received(server_final.len());
+        let server_final =
+            self.lenience
+                .trim(server_final, ServerMessage::Final, &mut self.warnings);
+        let result = self.deadline.check().and_then(|()| {
+            let message = ServerFinalMessage::parse(server_final)?;
+            let verifier =
+                decode_verifier(message.verification, &self.lenience, &mut self.warnings)?;
+            if *self.server_signature != *verifier {
+                return Err(Error::InvalidServer);
+            }
+            if !message.extensions.is_empty() {
+                interop::warn(
+                    &mut self.warnings,
+                    InteropWarning::UnknownAttributes(ServerMessage::Final),
+                );
+            }
+            #[cfg(any(test, feature = "pinning"))]
+            if let Some(pending) = self.pending.take() {
+                pending.commit();
+            }
+            Ok(message.extensions)
+        });
+        trace::outcome(&result);
+        (result, self.warnings)
+    }
+}
+
+impl ServerFinal {
+    /// Like [`handle_server_final`](Self::handle_server_final), but returns the helper which
+    /// derives the stored credentials of a new password on success, for password changes over
+    /// the authenticated channel. The new credentials use the mechanism of the handshake and the
+    /// iteration count of the server, see [`PasswordChange`].
+    ///
+    /// This method is only available for the built-in mechanisms.
+    pub fn handle_server_final_for_password_change(
+        self,
+        server_final: &str,
+    ) -> Result<PasswordChange, Error> {
+        let (mechanism, iterations) = (self.hash, self.iterations);
+        self.handle_server_final(server_final)
+            .map(|()| PasswordChange::new(mechanism, iterations))
+    }
+}
+
+/// The terminal state of a handshake aborted with the `abort` method of a state, for example
+/// [`ServerFirst::abort`]. The secrets the state held were overwritten with zeros; a borrowed
+/// password stays untouched.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Aborted(());
+
+impl Aborted {
+    /// Creates the terminal state.
+    pub(crate) fn new() -> Self {
+        Aborted(())
+    }
+
+    /// Returns the line a client sends to cancel the exchange in the SASL profiles of IMAP
+    /// (RFC3501), SMTP (RFC4954) and POP3 (RFC5034), without the line terminator.
+    pub fn cancellation(&self) -> &'static str {
+        "*"
+    }
+}
+
+impl<'a, H: ScramHash> fmt::Display for ScramClient<'a, H> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("client", "client-first")
+            .mechanism(self.hash.name())
+            .user(&self.authcid)
+            .nonce_length(self.nonce.len())
+            .fmt(fmt)
+    }
+}
+
+impl<'a, H: ScramHash> fmt::Display for ServerFirst<'a, H> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("client", "server-first")
+            .mechanism(self.hash.name())
+            .user(self.authcid())
+            .nonce_length(self.client_nonce.len())
+            .fmt(fmt)
+    }
+}
+
+impl<H: ScramHash> fmt::Display for ClientFinal<H> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("client", "client-final")
+            .mechanism(self.hash.name())
+            .user(&self.authcid)
+            .nonce_length(self.nonce_length)
+            .fmt(fmt)
+    }
+}
+
+impl<H: ScramHash> fmt::Display for ServerFinal<H> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("client", "server-final")
+            .mechanism(self.hash.name())
+            .user(&self.authcid)
+            .nonce_length(self.nonce_length)
+            .fmt(fmt)
+    }
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("client", "aborted").fmt(fmt)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, H: ScramHash> defmt::Format for ScramClient<'a, H> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ScramClient {{ mechanism: {=str} }}", self.hash.name())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a> defmt::Format for ClientFirstTemplate<'a> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ClientFirstTemplate {{ mechanism: {} }}", self.mechanism)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, H: ScramHash> defmt::Format for ServerFirst<'a, H> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ServerFirst {{ mechanism: {=str} }}", self.hash.name())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<H: ScramHash> defmt::Format for ClientFinal<H> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ClientFinal {{ mechanism: {=str} }}", self.hash.name())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<H: ScramHash> defmt::Format for ServerFinal<H> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "ServerFinal {{ mechanism: {=str} }}", self.hash.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::time::Duration;
+    use std::time::Instant;
+
+    use proptest::prelude::*;
+
+    use super::super::{ChannelBindingError, Error, Excerpt, Field, Kind};
+    use super::{parse_server_final, ClientFirstTemplate, ScramClient};
+    use crate::cache::CredentialCache;
+    use crate::channel_binding::ChannelBinding;
+    use crate::confidentiality::Confidentiality;
+    use crate::credentials::derive_credentials;
+    use crate::grammar::ServerFirstMessage;
+    use crate::interop::{Base64Variant, InteropWarning, Lenience, ServerMessage};
+    use crate::mechanism::Mechanism;
+    use crate::pinning::{MemoryPinStore, PinStore};
+    use crate::saslprep::Mode;
+    use crate::strategies::{invalid_server_final, invalid_server_first, server_final, server_first};
+
+    #[test]
+    fn test_rfc7677_example() {
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let (scram, client_first) = scram.client_first();
+        assert_eq!(client_first, "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        let (scram, client_final) = scram.client_final();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
             Ok(())
-        } else {
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        let scram = ScramClient::with_nonce("us,er", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        assert_eq!(
+            scram.to_string(),
+            "SCRAM-SHA-256 client, phase client-first, user \"us=2Cer\", nonce length 20"
+        );
+        let (scram, _) = scram.client_first();
+        assert_eq!(
+            scram.to_string(),
+            "SCRAM-SHA-256 client, phase server-first, user \"us=2Cer\", nonce length 20"
+        );
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=c2FsdA==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(
+            scram.to_string(),
+            "SCRAM-SHA-256 client, phase client-final, user \"us=2Cer\", nonce length 50"
+        );
+        let (scram, _) = scram.client_final();
+        assert_eq!(
+            scram.to_string(),
+            "SCRAM-SHA-256 client, phase server-final, user \"us=2Cer\", nonce length 50"
+        );
+        assert_eq!(
+            scram.abort().to_string(),
+            "SCRAM-SHA-256 client, phase aborted"
+        );
+    }
+
+    #[test]
+    fn test_lenient_whitespace() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096\r\n";
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let (scram, _) = scram.client_first();
+        assert!(scram.handle_server_first(server_first).is_err());
+
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+            .lenient(Lenience::new().trim_whitespace());
+        let (scram, _) = scram.client_first();
+        let (scram, client_final) = scram.handle_server_first(server_first).unwrap().client_final();
+        assert!(client_final.ends_with("p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="));
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4= \n"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_interop_warnings() {
+        let lenience = Lenience::new().trim_whitespace().accept_url_safe_base64();
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+            .lenient(lenience);
+        let (scram, _) = scram.client_first();
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096,x=1",
+            )
+            .unwrap();
+        assert_eq!(
+            scram.warnings(),
+            [InteropWarning::UnknownAttributes(ServerMessage::First)]
+        );
+
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+            .lenient(lenience);
+        let (scram, _) = scram.client_first();
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096\r\n",
+            )
+            .unwrap();
+        let (scram, _) = scram.client_final();
+        let (result, warnings) = scram.handle_server_final_with_warnings(
+            "v=6rriTRBi23WpRR_wtup-mMhUZUn_dB5nLTJRsjl95G4=,y=2",
+        );
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            warnings,
+            [
+                InteropWarning::TrailingWhitespace(ServerMessage::First),
+                InteropWarning::UrlSafeBase64(Field::VerifyOrError),
+                InteropWarning::UnknownAttributes(ServerMessage::Final),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lenient_base64() {
+        let lenience = Lenience::new()
+            .accept_unpadded_base64()
+            .accept_url_safe_base64()
+            .emit_base64(Base64Variant::UrlSafeNoPad);
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+            .lenient(lenience);
+        let (scram, _) = scram.client_first();
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        let (scram, client_final) = scram.client_final();
+        assert_eq!(
+            client_final,
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN-Ute9ytag9zjfMHgsqmmiz7AndVQ"
+        );
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR_wtup-mMhUZUn_dB5nLTJRsjl95G4"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_channel_binding_data() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let nonce = "rOprNGfwEbeRWgbNEkqO";
+        let scram = ScramClient::with_nonce("user", "pencil", Some("admin"), nonce.into())
+            .channel_binding("tls-server-end-point");
+        let (scram, client_first) = scram.client_first();
+        assert_eq!(
+            client_first,
+            "p=tls-server-end-point,a=admin,n=user,r=rOprNGfwEbeRWgbNEkqO"
+        );
+        assert_eq!(
+            scram.handle_server_first(server_first).err(),
+            Some(Error::ChannelBinding(ChannelBindingError::MissingData))
+        );
+
+        let scram = ScramClient::with_nonce("user", "pencil", None, nonce.into())
+            .channel_binding("tls-exporter");
+        let (scram, _) = scram.client_first();
+        let (_, client_final) = scram
+            .channel_binding_data(b"data")
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        // base64("p=tls-exporter,,data")
+        assert!(client_final.starts_with("c=cD10bHMtZXhwb3J0ZXIsLGRhdGE=,"));
+    }
+
+    #[test]
+    fn test_with_channel_binding() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let nonce = "rOprNGfwEbeRWgbNEkqO";
+        let scram = ScramClient::with_nonce("user", "pencil", None, nonce.into())
+            .with_channel_binding(ChannelBinding::TlsUnique(b"data"));
+        let (scram, client_first) = scram.client_first();
+        assert_eq!(client_first, "p=tls-unique,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let (_, client_final) = scram
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        // base64("p=tls-unique,,data")
+        assert!(client_final.starts_with("c=cD10bHMtdW5pcXVlLCxkYXRh,"));
+
+        // The data of a certificate which can't be parsed is missing.
+        let scram = ScramClient::with_nonce("user", "pencil", None, nonce.into())
+            .with_channel_binding(ChannelBinding::TlsServerEndPoint(b"data"));
+        let (scram, client_first) = scram.client_first();
+        assert!(client_first.starts_with("p=tls-server-end-point,,"));
+        assert_eq!(
+            scram.handle_server_first(server_first).err(),
+            Some(Error::ChannelBinding(ChannelBindingError::MissingData))
+        );
+    }
+
+    #[test]
+    fn test_supports_channel_binding() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let nonce = "rOprNGfwEbeRWgbNEkqO";
+        let scram = ScramClient::with_nonce("user", "pencil", None, nonce.into())
+            .with_channel_binding(ChannelBinding::TlsUnique(b"data"))
+            .supports_channel_binding();
+        assert_eq!(scram.channel_binding_data, None);
+        let (scram, client_first) = scram.client_first();
+        assert_eq!(client_first, "y,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let (_, client_final) = scram
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        // base64("y,,")
+        assert!(client_final.starts_with("c=eSws,"));
+    }
+
+    #[test]
+    fn test_gs2_header() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let nonce = "rOprNGfwEbeRWgbNEkqO";
+        let template = ClientFirstTemplate::try_with_gs2_header("user", "pencil", "n,a=ad=2Cmin,");
+        let scram = template.unwrap().client_with_nonce(nonce.into());
+        let (scram, client_first) = scram.client_first();
+        assert_eq!(client_first, "n,a=ad=2Cmin,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let (_, client_final) = scram
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        // base64("n,a=ad=2Cmin,")
+        assert!(client_final.starts_with("c=bixhPWFkPTJDbWluLA==,"));
+
+        let template =
+            ClientFirstTemplate::try_with_gs2_header("user", "pencil", "F,p=tls-exporter,,");
+        let scram = template.unwrap().client_with_nonce(nonce.into());
+        let (scram, client_first) = scram.client_first();
+        assert!(client_first.starts_with("F,p=tls-exporter,,n=user,"));
+        assert_eq!(
+            scram.handle_server_first(server_first).err(),
+            Some(Error::ChannelBinding(ChannelBindingError::MissingData))
+        );
+
+        assert_eq!(
+            ClientFirstTemplate::try_with_gs2_header("user", "pencil", "n,,n=user").err(),
+            Some(Error::Protocol(Kind::InvalidField(
+                Field::GS2Header,
+                Some(Excerpt::new(None, "n=user"))
+            )))
+        );
+        assert!(ClientFirstTemplate::try_with_gs2_header("user", "pencil", "x,,").is_err());
+        assert!(ClientFirstTemplate::try_with_gs2_header("user", "pencil", "n,").is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid channel-binding type")]
+    fn test_invalid_channel_binding_type() {
+        let _ = ScramClient::new("user", "pencil", None).channel_binding("tls,unique");
+    }
+
+    #[test]
+    fn test_client_first_template() {
+        let template = ClientFirstTemplate::new("us,er", "password", Some("ad=min"));
+        let (first, first_message) = template.client().client_first();
+        let (second, second_message) = template.client().client_first();
+        assert!(first_message.starts_with("n,a=ad=3Dmin,n=us=2Cer,r="));
+        assert!(second_message.starts_with("n,a=ad=3Dmin,n=us=2Cer,r="));
+        assert_ne!(first.client_nonce, second.client_nonce);
+    }
+
+    #[test]
+    fn test_owned_credentials() {
+        fn client(authcid: String, password: String) -> ScramClient<'static> {
+            ScramClient::new(authcid, password, None)
+        }
+
+        let (_, client_first) = client("us,er".to_string(), "pencil".to_string()).client_first();
+        assert!(client_first.starts_with("n,,n=us=2Cer,r="));
+        let template = ClientFirstTemplate::try_new_owned(
+            "user".to_string(),
+            "pencil".to_string(),
+            Some("ad=min".to_string()),
+        )
+        .unwrap();
+        let (_, client_first) = template.client().client_first();
+        assert!(client_first.starts_with("n,a=ad=3Dmin,n=user,r="));
+        assert_eq!(
+            ClientFirstTemplate::try_new_owned(
+                "user".to_string(),
+                "pencil".to_string(),
+                Some("ad\0min".to_string())
+            )
+            .err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authzid, None)))
+        );
+        let scram = ScramClient::new("user", "pencil".to_string(), None);
+        assert!(!format!("{:?}", scram).contains("pencil"));
+    }
+
+    #[test]
+    fn test_username_with_nul() {
+        assert_eq!(
+            ClientFirstTemplate::try_new("us\0er", "password", None).err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authcid, None)))
+        );
+        assert_eq!(
+            ClientFirstTemplate::try_new("user", "password", Some("ad\0min")).err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authzid, None)))
+        );
+        assert!(ClientFirstTemplate::try_new("us\u{1}er", "password", None).is_ok());
+    }
+
+    #[test]
+    fn test_try_new_nfc() {
+        let template =
+            ClientFirstTemplate::try_new_nfc("Jose\u{301}", "pe\u{301}ncil", None).unwrap();
+        let (_, client_first) = template.client().client_first();
+        assert!(client_first.starts_with("n,,n=Jos\u{e9},r="));
+        assert_eq!(&*template.password, "p\u{e9}ncil");
+        assert_eq!(
+            ClientFirstTemplate::try_new_nfc("user", "pencil", Some("ad\u{7}min")).err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authzid, None)))
+        );
+        assert_eq!(
+            ClientFirstTemplate::try_new_nfc("user", "pen\u{378}cil", None).err(),
+            Some(Error::InvalidPassword)
+        );
+    }
+
+    #[test]
+    fn test_try_new_saslprep() {
+        let template = ClientFirstTemplate::try_new_saslprep(
+            "\u{2168}",
+            "pen\u{AD}cil",
+            Some("ad\u{A0}min"),
+            Mode::Lenient,
+        )
+        .unwrap();
+        let (_, client_first) = template.client().client_first();
+        assert!(client_first.starts_with("n,a=ad min,n=IX,r="));
+        assert_eq!(&*template.password, "pencil");
+        let prepare = |password, mode| {
+            ClientFirstTemplate::try_new_saslprep("user", password, None, mode).map(|_| ())
+        };
+        assert_eq!(prepare("pen\u{378}cil", Mode::Lenient), Ok(()));
+        assert_eq!(
+            prepare("pen\u{378}cil", Mode::Strict),
+            Err(Error::InvalidPassword)
+        );
+        assert_eq!(
+            ClientFirstTemplate::try_new_saslprep("us\u{7}er", "pencil", None, Mode::Lenient).err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authcid, None)))
+        );
+    }
+
+    #[cfg(feature = "saslprep")]
+    #[test]
+    fn test_new_saslprep() {
+        let password = "pen\u{AD}cil".to_string();
+        let template = ClientFirstTemplate::new("\u{2168}", password, Some("ad\u{A0}min"));
+        let (_, client_first) = template.client().client_first();
+        assert!(client_first.starts_with("n,a=ad min,n=IX,r="));
+        assert_eq!(&*template.password, "pencil");
+        // Text which can't be prepared is sent unchanged.
+        let template = ClientFirstTemplate::new("user", "pass\u{7}word", None);
+        assert_eq!(&*template.password, "pass\u{7}word");
+
+        let templates = [
+            ClientFirstTemplate::try_new("\u{2168}", "pen\u{AD}cil", Some("ad\u{A0}min")).unwrap(),
+            ClientFirstTemplate::try_new_owned(
+                "\u{2168}".to_string(),
+                "pen\u{AD}cil".to_string(),
+                Some("ad\u{A0}min".to_string()),
+            )
+            .unwrap(),
+        ];
+        for template in &templates {
+            let (_, client_first) = template.client().client_first();
+            assert!(client_first.starts_with("n,a=ad min,n=IX,r="));
+            assert_eq!(&*template.password, "pencil");
+        }
+        let template =
+            ClientFirstTemplate::try_with_gs2_header("\u{2168}", "pen\u{AD}cil", "n,,").unwrap();
+        let (_, client_first) = template.client().client_first();
+        assert!(client_first.starts_with("n,,n=IX,r="));
+        assert_eq!(&*template.password, "pencil");
+    }
+
+    #[test]
+    fn test_username_with_nul_client_first() {
+        let scram = ScramClient::new("us\0er", "password", None);
+        assert_eq!(
+            scram.try_client_first().err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authcid, None)))
+        );
+        let scram = ScramClient::new("user", "password", Some("ad\0min"));
+        assert_eq!(
+            scram.try_client_first().err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authzid, None)))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "can't send client-first-message")]
+    fn test_username_with_nul_panics() {
+        let _ = ScramClient::new("us\0er", "password", None).client_first();
+    }
+
+    #[test]
+    fn test_server_final_extensions() {
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let (scram, _) = scram.client_first();
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        let (scram, _) = scram.client_final();
+        assert_eq!(
+            scram.handle_server_final_with_extensions(
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=,x=1,y=2"
+            ),
+            Ok("x=1,y=2")
+        );
+        assert_eq!(
+            parse_server_final("e=invalid-proof,x=1", &Lenience::default(), &mut Vec::new()),
+            Err(Error::Authentication("invalid-proof".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_deadline() {
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let (scram, _) = scram.with_deadline(Instant::now()).client_first();
+        assert_eq!(
+            scram.handle_server_first(server_first).unwrap_err(),
+            Error::Timeout
+        );
+
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let deadline = Instant::now() + Duration::from_secs(3600);
+        let (scram, _) = scram.with_deadline(deadline).client_first();
+        let (scram, _) = scram
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = CredentialCache::new(&[7; 32], Duration::from_secs(3600));
+        for _ in 0..2 {
+            let scram =
+                ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+            let (scram, _) = scram.with_cache(&cache).client_first();
+            let (scram, _) = scram
+                .handle_server_first(
+                    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                )
+                .unwrap()
+                .client_final();
+            assert_eq!(
+                scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
+                Ok(())
+            );
+            assert_eq!(cache.len(), 1);
+        }
+
+        // The salted passwords of the other mechanisms are cached separately.
+        for _ in 0..2 {
+            let scram =
+                ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+                    .with_mechanism(Mechanism::ScramSha512);
+            let (scram, _) = scram.with_cache(&cache).client_first();
+            scram
+                .handle_server_first(
+                    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                )
+                .unwrap();
+            assert_eq!(cache.len(), 2);
+        }
+        assert_eq!(cache.stats().hits, 2);
+    }
+
+    #[test]
+    fn test_pin_store() {
+        let store = Arc::new(MemoryPinStore::new());
+        let client = || {
+            ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into())
+                .with_pin_store(store.clone(), "example.org")
+                .client_first()
+                .0
+        };
+        let server_first = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                            s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+        let (scram, _) = client()
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        // A server which isn't verified doesn't get pinned.
+        assert_eq!(
+            scram.handle_server_final("v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
             Err(Error::InvalidServer)
+        );
+        assert!(store.is_empty());
+
+        let (scram, _) = client()
+            .handle_server_first(server_first)
+            .unwrap()
+            .client_final();
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
+            Ok(())
+        );
+        let pin = store.get("example.org", "user").unwrap();
+        assert_eq!(pin.iterations.get(), 4096);
+        assert!(client().handle_server_first(server_first).is_ok());
+        assert_eq!(
+            client()
+                .handle_server_first(
+                    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=8192",
+                )
+                .unwrap_err(),
+            Error::VerifierChanged
+        );
+    }
+
+    #[test]
+    fn test_password_change() {
+        let server_final = || {
+            let scram =
+                ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+            let (scram, _) = scram.client_first();
+            scram
+                .handle_server_first(
+                    "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+                )
+                .unwrap()
+                .client_final()
+                .0
+        };
+        assert_eq!(
+            server_final().handle_server_final_for_password_change("v=AAAA"),
+            Err(Error::InvalidServer)
+        );
+        let change = server_final()
+            .handle_server_final_for_password_change(
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=",
+            )
+            .unwrap();
+        assert_eq!(change.mechanism, Mechanism::ScramSha256);
+        assert_eq!(change.iterations.get(), 4096);
+        let credentials = change.credentials("new pencil");
+        let expected = derive_credentials(
+            "new pencil",
+            change.mechanism,
+            change.iterations,
+            credentials.salt.clone(),
+        );
+        assert_eq!(credentials, expected);
+    }
+
+    #[test]
+    fn test_abort() {
+        let scram = ScramClient::with_nonce("user", "pencil", None, "rOprNGfwEbeRWgbNEkqO".into());
+        let (scram, _) = scram.client_first();
+        let scram = scram
+            .handle_server_first(
+                "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                 s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096",
+            )
+            .unwrap();
+        assert_eq!(scram.abort().cancellation(), "*");
+    }
+
+    #[test]
+    fn test_require_confidentiality() {
+        let scram = ScramClient::new("user", "pencil", None).require_confidentiality();
+        assert_eq!(
+            scram.try_client_first().unwrap_err(),
+            Error::ConfidentialityNotAsserted
+        );
+        for confidentiality in [Confidentiality::Encrypted, Confidentiality::Unencrypted] {
+            let scram = ScramClient::new("user", "pencil", None)
+                .require_confidentiality()
+                .confidentiality(confidentiality);
+            assert!(scram.try_client_first().is_ok());
+        }
+        assert!(ScramClient::new("user", "pencil", None)
+            .try_client_first()
+            .is_ok());
+    }
+
+    proptest! {
+        #[test]
+        fn test_handle_server_first_valid(
+            server_first in server_first("clientnonce".to_string()),
+        ) {
+            let scram = ScramClient::new("user", "password", None).insecure_allow_low_iterations();
+            let (mut scram, _) = scram.client_first();
+            scram.client_nonce = "clientnonce".to_string();
+            prop_assert!(scram.handle_server_first(&server_first).is_ok());
+        }
+
+        #[test]
+        fn test_handle_server_first_invalid(
+            server_first in invalid_server_first("clientnonce".to_string()),
+        ) {
+            let scram = ScramClient::new("user", "password", None).insecure_allow_low_iterations();
+            let (mut scram, _) = scram.client_first();
+            scram.client_nonce = "clientnonce".to_string();
+            prop_assert!(scram.handle_server_first(&server_first).is_err());
+        }
+
+        #[test]
+        fn test_handle_server_first_low_iterations(
+            server_first in server_first("clientnonce".to_string()),
+        ) {
+            let (mut scram, _) = ScramClient::new("user", "password", None).client_first();
+            scram.client_nonce = "clientnonce".to_string();
+            let iterations = ServerFirstMessage::parse(&server_first).unwrap().iterations;
+            let excerpt = Excerpt::new(Some('i'), &iterations.to_string());
+            prop_assert_eq!(
+                scram.handle_server_first(&server_first).unwrap_err(),
+                Error::Protocol(Kind::InvalidField(Field::Iterations, Some(excerpt)))
+            );
+        }
+
+        #[test]
+        fn test_parse_server_final_valid(server_final in server_final()) {
+            match parse_server_final(&server_final, &Lenience::default(), &mut Vec::new()) {
+                Ok(verifier) => prop_assert_eq!(verifier.len(), 32),
+                Err(Error::Authentication(_)) => prop_assert!(server_final.starts_with("e=")),
+                Err(err) => prop_assert!(false, "unexpected error {:?}", err),
+            }
+        }
+
+        #[test]
+        fn test_parse_server_final_invalid(server_final in invalid_server_final()) {
+            match parse_server_final(&server_final, &Lenience::default(), &mut Vec::new()) {
+                Err(Error::Protocol(Kind::ExpectedField(_, _)))
+                | Err(Error::Protocol(Kind::InvalidField(_, _)))
+                | Err(Error::Protocol(Kind::InvalidEncoding(..))) => {}
+                other => prop_assert!(false, "unexpected result {:?}", other),
+            }
         }
     }
 }
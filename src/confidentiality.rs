@@ -0,0 +1,37 @@
+//! The caller's assertion about the encryption of the transport.
+use crate::error::Error;
+
+/// Whether the transport a handshake runs on is encrypted, as asserted by the caller.
+///
+/// SCRAM authenticates without sending the password, but an eavesdropper who records a handshake
+/// can still run a dictionary attack against the proof. Deployments which must never run SCRAM
+/// over plaintext connections enable the guardrail with
+/// [`ScramClient::require_confidentiality`](crate::ScramClient::require_confidentiality) or
+/// [`ScramServer::require_confidentiality`](crate::ScramServer::require_confidentiality). Then a
+/// handshake only starts after the caller asserted `Encrypted` or explicitly opted out with
+/// `Unencrypted`, and fails with `Error::ConfidentialityNotAsserted` otherwise. This catches code
+/// paths which forgot to set up TLS or to pass its state along.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Confidentiality {
+    /// The caller didn't make an assertion.
+    #[default]
+    Unasserted,
+    /// The transport is encrypted, for example with TLS.
+    Encrypted,
+    /// The transport isn't encrypted, and the caller accepts that, for example on a loopback
+    /// connection.
+    Unencrypted,
+}
+
+impl Confidentiality {
+    /// Fails with `Error::ConfidentialityNotAsserted` if confidentiality is `required` but wasn't
+    /// asserted.
+    pub(crate) fn check(self, required: bool) -> Result<(), Error> {
+        if required && self == Confidentiality::Unasserted {
+            return Err(Error::ConfidentialityNotAsserted);
+        }
+        Ok(())
+    }
+}
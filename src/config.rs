@@ -0,0 +1,349 @@
+//! Settings of the client and the server which deployments keep in configuration files.
+//!
+//! A [`ScramConfig`] collects the settings which operators tune without code changes. With the
+//! `serde` feature it's deserialized from the configuration file of the application, for example
+//! from this TOML table, where every field is optional:
+//!
+//! ```toml
+//! [scram]
+//! mechanisms = ["SCRAM-SHA-256"]
+//! min_iterations = 10000
+//! nonce_length = 32
+//! channel_binding = { required = "tls-exporter" }
+//! cache = { ttl_secs = 3600, capacity = 100, lru = true }
+//! ```
+//!
+//! The configuration is passed to [`ScramClient::with_config`](crate::ScramClient::with_config),
+//! [`ClientFirstTemplate::with_config`](crate::client::ClientFirstTemplate::with_config) and
+//! [`ScramServer::with_config`](crate::ScramServer::with_config). Each side uses the settings which
+//! apply to it: the server takes its iteration counts from the stored credentials and the
+//! channel-binding data from the [`ConnectionContext`](crate::ConnectionContext) of each
+//! connection, and only clients cache salted passwords.
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::grammar;
+use crate::mechanism::Mechanism;
+use crate::{MIN_ITERATIONS, NONCE_LENGTH};
+
+/// The shortest nonce a [`ScramConfig`] allows, in characters.
+pub const MIN_NONCE_LENGTH: usize = 16;
+
+/// The longest nonce a [`ScramConfig`] allows, in characters.
+pub const MAX_NONCE_LENGTH: usize = 256;
+
+/// The settings of SCRAM handshakes, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct ScramConfig {
+    /// The mechanisms the server advertises, by their SASL names, strongest first. The default is
+    /// [`Mechanism::DEFAULT`].
+    #[cfg_attr(feature = "serde", serde(with = "mechanism_names"))]
+    pub mechanisms: Vec<Mechanism>,
+    /// The lowest iteration count the client accepts from a server. The default and the lowest
+    /// allowed value is 4096, as recommended by RFC7677 section 4.
+    pub min_iterations: u32,
+    /// The length of the nonces the client and the server generate, in characters. The default
+    /// is 24.
+    pub nonce_length: usize,
+    /// Whether the client binds the authentication to the TLS channel.
+    pub channel_binding: ChannelBindingPolicy,
+    /// The settings of the client's `cache::CredentialCache`.
+    pub cache: CacheConfig,
+}
+
+/// Whether a client uses channel binding.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ChannelBindingPolicy {
+    /// The client doesn't use channel binding.
+    #[default]
+    Disabled,
+    /// The client requires channel binding of the contained type, for example `tls-exporter`.
+    /// See [`ScramClient::channel_binding`](crate::ScramClient::channel_binding).
+    Required(String),
+}
+
+/// The settings of a `cache::CredentialCache`.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(default, deny_unknown_fields))]
+pub struct CacheConfig {
+    /// The time to live of the entries in seconds. The default is one day.
+    pub ttl_secs: u64,
+    /// The maximum number of entries, or `None` to only remove entries after the time to live.
+    /// The default is `None`.
+    pub capacity: Option<usize>,
+    /// Whether the least recently used entry is evicted when the capacity is reached, instead of
+    /// the one stored first. The default is `true`.
+    pub lru: bool,
+    /// Whether the salted passwords are kept encrypted in memory. The default is `false`.
+    pub encrypt_in_memory: bool,
+}
+
+/// The reasons a [`ScramConfig`] is invalid.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// The list of mechanisms is empty.
+    NoMechanisms,
+    /// The minimum iteration count is below 4096.
+    MinIterations(u32),
+    /// The nonce length is outside of `MIN_NONCE_LENGTH..=MAX_NONCE_LENGTH`.
+    NonceLength(usize),
+    /// The channel-binding type isn't a valid name.
+    ChannelBindingType(String),
+    /// The capacity of the cache is zero.
+    CacheCapacity,
+}
+
+impl Default for ScramConfig {
+    fn default() -> Self {
+        ScramConfig {
+            mechanisms: Mechanism::DEFAULT.to_vec(),
+            min_iterations: MIN_ITERATIONS,
+            nonce_length: NONCE_LENGTH,
+            channel_binding: ChannelBindingPolicy::Disabled,
+            cache: CacheConfig::default(),
+        }
+    }
+}
+
+impl ScramConfig {
+    /// Checks the settings, for example after reading them from a file. The builders which
+    /// accept a configuration panic if it isn't valid.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.mechanisms.is_empty() {
+            return Err(ConfigError::NoMechanisms);
+        }
+        if self.min_iterations < MIN_ITERATIONS {
+            return Err(ConfigError::MinIterations(self.min_iterations));
+        }
+        if !(MIN_NONCE_LENGTH..=MAX_NONCE_LENGTH).contains(&self.nonce_length) {
+            return Err(ConfigError::NonceLength(self.nonce_length));
+        }
+        if let ChannelBindingPolicy::Required(ref cb_type) = self.channel_binding {
+            if !grammar::is_cb_name(cb_type) {
+                return Err(ConfigError::ChannelBindingType(cb_type.clone()));
+            }
+        }
+        if self.cache.capacity == Some(0) {
+            return Err(ConfigError::CacheCapacity);
+        }
+        Ok(())
+    }
+
+    /// Returns the channel-binding type the client requires, if any.
+    pub(crate) fn cb_type(&self) -> Option<&str> {
+        match self.channel_binding {
+            ChannelBindingPolicy::Disabled => None,
+            ChannelBindingPolicy::Required(ref cb_type) => Some(cb_type),
+        }
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            ttl_secs: 86400,
+            capacity: None,
+            lru: true,
+            encrypt_in_memory: false,
+        }
+    }
+}
+
+impl CacheConfig {
+    /// Returns the time to live of the entries.
+    pub fn ttl(&self) -> Duration {
+        Duration::from_secs(self.ttl_secs)
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::NoMechanisms => write!(fmt, "No mechanisms configured"),
+            ConfigError::MinIterations(iterations) => write!(
+                fmt,
+                "Minimum iteration count {} below {}",
+                iterations, MIN_ITERATIONS
+            ),
+            ConfigError::NonceLength(length) => write!(
+                fmt,
+                "Nonce length {} outside of {}..={}",
+                length, MIN_NONCE_LENGTH, MAX_NONCE_LENGTH
+            ),
+            ConfigError::ChannelBindingType(ref cb_type) => {
+                write!(fmt, "Invalid channel-binding type {:?}", cb_type)
+            }
+            ConfigError::CacheCapacity => write!(fmt, "Cache capacity of zero"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ConfigError {}
+
+/// Serializes the mechanisms by their IANA registered names, like `SCRAM-SHA-256`, instead of
+/// the names of the variants.
+#[cfg(feature = "serde")]
+mod mechanism_names {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::mechanism::Mechanism;
+
+    pub(super) fn serialize<S: Serializer>(
+        mechanisms: &[Mechanism],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(mechanisms.iter().map(|mechanism| mechanism.name()))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Mechanism>, D::Error> {
+        Vec::<String>::deserialize(deserializer)?
+            .iter()
+            .map(|name| {
+                Mechanism::from_name(name)
+                    .ok_or_else(|| D::Error::custom(format!("unsupported mechanism {:?}", name)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheConfig, ChannelBindingPolicy, ConfigError, ScramConfig};
+    use crate::client::ClientFirstTemplate;
+    #[cfg(feature = "serde")]
+    use crate::mechanism::Mechanism;
+    use crate::server::{AuthenticationProvider, PasswordInfo, ScramServer};
+
+    struct AnyUser;
+
+    impl AuthenticationProvider for AnyUser {
+        fn get_password_for(&self, _username: &str) -> Option<PasswordInfo> {
+            Some(PasswordInfo::new(vec![0; 32], 4096, b"salt".to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(ScramConfig::default().validate(), Ok(()));
+        let invalid = [
+            (
+                ScramConfig {
+                    mechanisms: vec![],
+                    ..ScramConfig::default()
+                },
+                ConfigError::NoMechanisms,
+            ),
+            (
+                ScramConfig {
+                    min_iterations: 4095,
+                    ..ScramConfig::default()
+                },
+                ConfigError::MinIterations(4095),
+            ),
+            (
+                ScramConfig {
+                    nonce_length: 8,
+                    ..ScramConfig::default()
+                },
+                ConfigError::NonceLength(8),
+            ),
+            (
+                ScramConfig {
+                    channel_binding: ChannelBindingPolicy::Required("tls,unique".to_string()),
+                    ..ScramConfig::default()
+                },
+                ConfigError::ChannelBindingType("tls,unique".to_string()),
+            ),
+            (
+                ScramConfig {
+                    cache: CacheConfig {
+                        capacity: Some(0),
+                        ..CacheConfig::default()
+                    },
+                    ..ScramConfig::default()
+                },
+                ConfigError::CacheCapacity,
+            ),
+        ];
+        for (config, error) in invalid.iter() {
+            assert_eq!(config.validate().as_ref(), Err(error));
+        }
+    }
+
+    #[test]
+    fn test_builders() {
+        let config = ScramConfig {
+            nonce_length: 32,
+            channel_binding: ChannelBindingPolicy::Required("tls-exporter".to_string()),
+            ..ScramConfig::default()
+        };
+        let template = ClientFirstTemplate::new("user", "pencil", None).with_config(&config);
+        let (_, client_first) = template.client().client_first();
+        let nonce = client_first
+            .strip_prefix("p=tls-exporter,,n=user,r=")
+            .unwrap();
+        assert_eq!(nonce.len(), 32);
+
+        let server = ScramServer::new(AnyUser).with_config(&config);
+        assert_eq!(server.advertised_mechanisms(false), ["SCRAM-SHA-256"]);
+        let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+        let (_, server_first) = server_first.server_first();
+        let nonce = server_first.split(',').next().unwrap();
+        assert_eq!(nonce.len(), "r=abc".len() + 32);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid configuration")]
+    fn test_invalid_config_panics() {
+        let config = ScramConfig {
+            nonce_length: 0,
+            ..ScramConfig::default()
+        };
+        let _ = ScramServer::new(AnyUser).with_config(&config);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let config: ScramConfig = serde_json::from_str(
+            r#"{
+                "mechanisms": ["SCRAM-SHA-256"],
+                "nonce_length": 32,
+                "channel_binding": {"required": "tls-exporter"},
+                "cache": {"capacity": 10}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.mechanisms, [Mechanism::ScramSha256]);
+        assert_eq!(config.min_iterations, 4096);
+        assert_eq!(config.cache.capacity, Some(10));
+        assert!(config.cache.lru);
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains(r#""mechanisms":["SCRAM-SHA-256"]"#));
+        assert_eq!(serde_json::from_str::<ScramConfig>(&json).unwrap(), config);
+
+        let config: ScramConfig =
+            serde_json::from_str(r#"{"channel_binding": "disabled"}"#).unwrap();
+        assert_eq!(config, ScramConfig::default());
+        let err =
+            serde_json::from_str::<ScramConfig>(r#"{"mechanisms": ["SCRAM-MD5"]}"#).unwrap_err();
+        assert!(err.to_string().contains("unsupported mechanism"));
+        assert!(serde_json::from_str::<ScramConfig>(r#"{"nonce": 32}"#).is_err());
+    }
+}
@@ -0,0 +1,672 @@
+//! A self-test running the client of every supported mechanism against the server of this crate.
+//!
+//! This module is available with the `conformance` feature. Embedders can call [`self_test`] from
+//! their test suite to verify that the combination of features they selected still performs
+//! complete handshakes.
+//!
+//! ``` rust,ignore
+//! let report = scram::conformance::self_test(4);
+//! assert!(report.passed(), "{:?}", report.failures().collect::<Vec<_>>());
+//! ```
+//!
+//! Every [`Scenario`] runs in both directions as well: the client of this crate against a
+//! reference server of this module, and a reference client against the server of this crate, see
+//! [`Peers`]. The reference peers implement RFC5802 on their own, so a mistake the client and the
+//! server of this crate share doesn't go unnoticed.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
+
+use crate::channel_binding::ChannelBinding;
+use crate::client::{parse_server_final, parse_server_first, ScramClient};
+use crate::entropy::{below, EntropySource};
+use crate::error::{ChannelBindingError, Error, Field, Kind};
+use crate::grammar::{self, ClientFinalMessage, ClientFirstMessage};
+use crate::gs2::ChannelBindingFlag;
+use crate::interop::Lenience;
+use crate::mechanism::Mechanism;
+use crate::server::{
+    AuthenticationProvider, AuthenticationStatus, ConnectionContext, PasswordInfo, ScramServer,
+};
+use crate::utils::{
+    escape_username, find_proofs, generate_nonce, hash_password_for, unescape_username, Output,
+};
+use crate::MIN_ITERATIONS;
+
+/// The channel-binding type of the scenarios with channel binding.
+const CB_TYPE: &str = "tls-exporter";
+
+/// The situations exercised for every mechanism.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Scenario {
+    /// The client authenticates as itself with the correct password.
+    Authenticate,
+    /// The client authenticates with the correct password and requests to act as another user,
+    /// which the server permits.
+    Authorize,
+    /// The client supplies a wrong password and the server has to reject it.
+    WrongPassword,
+    /// The client announces with `y` that it supports channel binding to a server without
+    /// channel binding, which accepts it.
+    SupportsChannelBinding,
+    /// The client binds the handshake with `p=tls-exporter` to the channel of the server.
+    ChannelBinding,
+    /// The client binds the handshake to another channel than the server's, and the server has
+    /// to reject it.
+    ChannelBindingMismatch,
+    /// The client announces with `y` that it supports channel binding to a server with channel
+    /// binding, which has to reject the downgrade.
+    Downgrade,
+}
+
+impl Scenario {
+    const ALL: &'static [Scenario] = &[
+        Scenario::Authenticate,
+        Scenario::Authorize,
+        Scenario::WrongPassword,
+        Scenario::SupportsChannelBinding,
+        Scenario::ChannelBinding,
+        Scenario::ChannelBindingMismatch,
+        Scenario::Downgrade,
+    ];
+
+    /// Returns whether the server has channel-binding data of the connection.
+    fn server_binds(self) -> bool {
+        matches!(
+            self,
+            Scenario::ChannelBinding | Scenario::ChannelBindingMismatch | Scenario::Downgrade
+        )
+    }
+
+    /// Returns the outcome the handshake must have.
+    fn expected(self) -> Outcome {
+        match self {
+            Scenario::WrongPassword => Outcome::NotAuthenticated,
+            Scenario::ChannelBindingMismatch => {
+                Outcome::Rejected(Error::ChannelBinding(ChannelBindingError::Mismatch))
+            }
+            Scenario::Downgrade => {
+                Outcome::Rejected(Error::ChannelBinding(ChannelBindingError::Downgrade))
+            }
+            _ => Outcome::Authenticated,
+        }
+    }
+}
+
+/// The implementations performing the handshakes of the self-test.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Peers {
+    /// The client of this crate against the server of this crate.
+    ClientAndServer,
+    /// The client of this crate against the reference server of this module.
+    Client,
+    /// The reference client of this module against the server of this crate.
+    Server,
+}
+
+impl Peers {
+    const ALL: &'static [Peers] = &[Peers::ClientAndServer, Peers::Client, Peers::Server];
+}
+
+/// How a handshake ended.
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    /// The server authenticated the client and the client verified the server.
+    Authenticated,
+    /// The server rejected the proof and the client didn't accept the final message.
+    NotAuthenticated,
+    /// The server failed the handshake with the error.
+    Rejected(Error),
+}
+
+/// The result of a single handshake of the self-test.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Case {
+    /// The mechanism used by client and server.
+    pub mechanism: Mechanism,
+    /// The situation that was tested.
+    pub scenario: Scenario,
+    /// The implementations which performed the handshake.
+    pub peers: Peers,
+    /// The randomly generated username.
+    pub username: String,
+    /// A description of what went wrong, or `None` if the handshake behaved as expected.
+    pub failure: Option<String>,
+}
+
+/// The structured result of [`self_test`].
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Report {
+    /// Every handshake which was performed.
+    pub cases: Vec<Case>,
+}
+
+impl Report {
+    /// Returns `true` if every handshake behaved as expected.
+    pub fn passed(&self) -> bool {
+        self.cases.iter().all(|case| case.failure.is_none())
+    }
+
+    /// Returns the handshakes which didn't behave as expected.
+    pub fn failures(&self) -> impl Iterator<Item = &Case> {
+        self.cases.iter().filter(|case| case.failure.is_some())
+    }
+}
+
+/// Runs `rounds` rounds of handshakes using [`OsRng`] to generate credentials. This function is
+/// only available with the `std` feature.
+#[cfg(feature = "std")]
+pub fn self_test(rounds: usize) -> Report {
+    self_test_with_rng(rounds, &mut OsRng)
+}
+
+/// Runs `rounds` rounds of handshakes using the given random number generator or
+/// [`EntropySource`] to generate credentials. Every round exercises every [`Scenario`] with all
+/// [`Peers`] for every supported [`Mechanism`].
+pub fn self_test_with_rng<R: EntropySource + ?Sized>(rounds: usize, rng: &mut R) -> Report {
+    let mut report = Report::default();
+    for _ in 0..rounds {
+        for &mechanism in Mechanism::ALL {
+            for &scenario in Scenario::ALL {
+                for &peers in Peers::ALL {
+                    let username = random_string(rng, ",=abcdefghijklmnopqrstuvwxyz");
+                    let password = random_string(rng, ",=-!abcdefghijklmnopqrstuvwxyzABCXYZ0123");
+                    let mut salt = vec![0; 16];
+                    rng.fill_bytes(&mut salt);
+                    let mut cbind_data = vec![0; 32];
+                    rng.fill_bytes(&mut cbind_data);
+                    let setup = Setup {
+                        mechanism,
+                        scenario,
+                        username: &username,
+                        password: &password,
+                        salt,
+                        cbind_data,
+                    };
+                    let failure = run_case(&setup, peers, rng).err();
+                    report.cases.push(Case {
+                        mechanism,
+                        scenario,
+                        peers,
+                        username,
+                        failure,
+                    });
+                }
+            }
+        }
+    }
+    report
+}
+
+/// A user can act as itself and as the user `"other"`.
+struct SelfTestProvider {
+    username: String,
+    password_info: (Vec<u8>, Vec<u8>),
+}
+
+impl SelfTestProvider {
+    fn new(setup: &Setup) -> Self {
+        SelfTestProvider {
+            username: setup.username.to_string(),
+            password_info: (setup.salted_password(), setup.salt.clone()),
+        }
+    }
+}
+
+impl AuthenticationProvider for SelfTestProvider {
+    fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+        if username == self.username {
+            let (ref hashed_password, ref salt) = self.password_info;
+            Some(PasswordInfo::new(
+                hashed_password.clone(),
+                MIN_ITERATIONS as u16,
+                salt.clone(),
+            ))
+        } else {
+            None
+        }
+    }
+
+    fn authorize(&self, authcid: &str, authzid: &str) -> bool {
+        authcid == authzid || authzid == "other"
+    }
+}
+
+fn random_string<R: EntropySource + ?Sized>(rng: &mut R, alphabet: &str) -> String {
+    let alphabet = alphabet.as_bytes();
+    (0..12)
+        .map(|_| alphabet[usize::from(below(rng, alphabet.len() as u8))] as char)
+        .collect()
+}
+
+/// The credentials and the channel of a handshake.
+struct Setup<'a> {
+    mechanism: Mechanism,
+    scenario: Scenario,
+    username: &'a str,
+    password: &'a str,
+    salt: Vec<u8>,
+    /// The `tls-exporter` data of the connection as seen by the server.
+    cbind_data: Vec<u8>,
+}
+
+impl<'a> Setup<'a> {
+    fn iterations(&self) -> NonZeroU32 {
+        NonZeroU32::new(MIN_ITERATIONS).unwrap()
+    }
+
+    fn salted_password(&self) -> Vec<u8> {
+        hash_password_for(self.mechanism, self.password, self.iterations(), &self.salt)
+    }
+
+    /// Returns the password the client authenticates with.
+    fn client_password(&self) -> String {
+        match self.scenario {
+            Scenario::WrongPassword => format!("{}!", self.password),
+            _ => self.password.to_string(),
+        }
+    }
+
+    /// Returns the user the client requests to act as.
+    fn client_authzid(&self) -> Option<&'static str> {
+        match self.scenario {
+            Scenario::Authorize => Some("other"),
+            _ => None,
+        }
+    }
+
+    /// Returns the channel-binding data the client binds to, if it requires channel binding.
+    fn client_cbind_data(&self) -> Option<Vec<u8>> {
+        let mut data = self.cbind_data.clone();
+        match self.scenario {
+            Scenario::ChannelBinding => Some(data),
+            Scenario::ChannelBindingMismatch => {
+                data[0] ^= 1;
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns whether the client announces that it supports channel binding with `y`.
+    fn client_supports_channel_binding(&self) -> bool {
+        matches!(
+            self.scenario,
+            Scenario::SupportsChannelBinding | Scenario::Downgrade
+        )
+    }
+
+    /// Returns the client of this crate authenticating with `password` and binding to
+    /// `cbind_data`.
+    fn client<'b, R: EntropySource + ?Sized>(
+        &'b self,
+        password: &'b str,
+        cbind_data: Option<&'b [u8]>,
+        rng: &mut R,
+    ) -> ScramClient<'b> {
+        let client = ScramClient::with_rng(self.username, password, self.client_authzid(), rng)
+            .with_mechanism(self.mechanism);
+        match cbind_data {
+            Some(data) => client.with_channel_binding(ChannelBinding::TlsExporter(data)),
+            None if self.client_supports_channel_binding() => client.supports_channel_binding(),
+            None => client,
+        }
+    }
+
+    /// Returns the connection context of the server of this crate, with the channel-binding data
+    /// `values` if the server binds.
+    fn context<'c>(&self, values: &'c [&'c [u8]]) -> ConnectionContext<'c> {
+        let context = ConnectionContext::new().with_mechanism(self.mechanism);
+        if self.scenario.server_binds() {
+            context.with_channel_binding_data(CB_TYPE, values)
+        } else {
+            context
+        }
+    }
+}
+
+fn run_case<R: EntropySource + ?Sized>(
+    setup: &Setup,
+    peers: Peers,
+    rng: &mut R,
+) -> Result<(), String> {
+    let outcome = match peers {
+        Peers::ClientAndServer => run_client_and_server(setup, rng)?,
+        Peers::Client => run_client(setup, rng)?,
+        Peers::Server => run_server(setup, rng)?,
+    };
+    let expected = setup.scenario.expected();
+    if outcome == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "expected {:?}, the handshake ended with {:?}",
+            expected, outcome
+        ))
+    }
+}
+
+/// Runs the client of this crate against the server of this crate.
+fn run_client_and_server<R: EntropySource + ?Sized>(
+    setup: &Setup,
+    rng: &mut R,
+) -> Result<Outcome, String> {
+    let scram_server = ScramServer::new(SelfTestProvider::new(setup));
+    let values: &[&[u8]] = &[&setup.cbind_data];
+    let context = setup.context(values);
+    let password = setup.client_password();
+    let cbind_data = setup.client_cbind_data();
+    let scram_client = setup.client(&password, cbind_data.as_deref(), rng);
+
+    let (scram_client, client_first) = scram_client.client_first();
+    let scram_server = match scram_server.handle_client_first_in_context(&client_first, &context) {
+        Ok(scram_server) => scram_server,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    let (scram_server, server_first) = scram_server.server_first_with_rng(rng);
+    let scram_client = scram_client
+        .handle_server_first(&server_first)
+        .map_err(|err| format!("client rejected server-first-message: {}", err))?;
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = match scram_server.handle_client_final(&client_final) {
+        Ok(scram_server) => scram_server,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    let (status, server_final) = scram_server.server_final();
+    outcome(
+        status,
+        scram_client.handle_server_final(&server_final).is_ok(),
+    )
+}
+
+/// Runs the client of this crate against the reference server.
+fn run_client<R: EntropySource + ?Sized>(setup: &Setup, rng: &mut R) -> Result<Outcome, String> {
+    let mut reference_server = ReferenceServer {
+        setup,
+        handshake: None,
+    };
+    let password = setup.client_password();
+    let cbind_data = setup.client_cbind_data();
+    let scram_client = setup.client(&password, cbind_data.as_deref(), rng);
+
+    let (scram_client, client_first) = scram_client.client_first();
+    let server_first = match reference_server.server_first(&client_first, rng) {
+        Ok(server_first) => server_first,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    let scram_client = scram_client
+        .handle_server_first(&server_first)
+        .map_err(|err| format!("client rejected server-first-message: {}", err))?;
+    let (scram_client, client_final) = scram_client.client_final();
+    let (status, server_final) = match reference_server.server_final(&client_final) {
+        Ok(server_final) => server_final,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    outcome(
+        status,
+        scram_client.handle_server_final(&server_final).is_ok(),
+    )
+}
+
+/// Runs the reference client against the server of this crate.
+fn run_server<R: EntropySource + ?Sized>(setup: &Setup, rng: &mut R) -> Result<Outcome, String> {
+    let scram_server = ScramServer::new(SelfTestProvider::new(setup));
+    let values: &[&[u8]] = &[&setup.cbind_data];
+    let context = setup.context(values);
+    let mut reference_client = ReferenceClient::new(setup, rng);
+
+    let client_first = reference_client.client_first();
+    let scram_server = match scram_server.handle_client_first_in_context(&client_first, &context) {
+        Ok(scram_server) => scram_server,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    let (scram_server, server_first) = scram_server.server_first_with_rng(rng);
+    let client_final = reference_client.client_final(&server_first)?;
+    let scram_server = match scram_server.handle_client_final(&client_final) {
+        Ok(scram_server) => scram_server,
+        Err(err) => return Ok(Outcome::Rejected(err)),
+    };
+    let (status, server_final) = scram_server.server_final();
+    outcome(status, reference_client.verify(&server_final))
+}
+
+/// Combines the verdicts of the server and the client.
+fn outcome(status: AuthenticationStatus, verified: bool) -> Result<Outcome, String> {
+    match (status, verified) {
+        (AuthenticationStatus::Authenticated, true) => Ok(Outcome::Authenticated),
+        (AuthenticationStatus::NotAuthenticated, false) => Ok(Outcome::NotAuthenticated),
+        (AuthenticationStatus::Authenticated, false) => {
+            Err("client rejected server-final-message".to_string())
+        }
+        (status, _) => Err(format!("server returned {:?}", status)),
+    }
+}
+
+/// The parts of the first two messages the reference server verifies the proof with.
+struct Handshake {
+    cbind_input: Vec<u8>,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+    authorized: bool,
+}
+
+/// A server following RFC5802 which knows the user of a [`Setup`].
+struct ReferenceServer<'a> {
+    setup: &'a Setup<'a>,
+    handshake: Option<Handshake>,
+}
+
+impl<'a> ReferenceServer<'a> {
+    fn server_first<R: EntropySource + ?Sized>(
+        &mut self,
+        client_first: &str,
+        rng: &mut R,
+    ) -> Result<String, Error> {
+        let message = ClientFirstMessage::parse(client_first)?;
+        let (gs2header, client_first_bare) = grammar::split_client_first(client_first);
+        let invalid = |field| Error::Protocol(Kind::InvalidField(field, None));
+        let authcid = unescape_username(message.authcid).ok_or_else(|| invalid(Field::Authcid))?;
+        if authcid != self.setup.username {
+            return Err(Error::InvalidUser(authcid.into_owned()));
+        }
+        let authorized = match message.authzid {
+            Some(authzid) => {
+                unescape_username(authzid).ok_or_else(|| invalid(Field::Authzid))? == "other"
+            }
+            None => true,
+        };
+        let server_binds = self.setup.scenario.server_binds();
+        let mut cbind_input = gs2header.as_bytes().to_vec();
+        let error = match message.channel_binding {
+            ChannelBindingFlag::Required(CB_TYPE) if server_binds => {
+                cbind_input.extend_from_slice(&self.setup.cbind_data);
+                None
+            }
+            ChannelBindingFlag::Required(name) if server_binds => {
+                Some(ChannelBindingError::UnsupportedType(name.to_string()))
+            }
+            ChannelBindingFlag::Required(_) => Some(ChannelBindingError::NotSupported),
+            ChannelBindingFlag::NotUsed if server_binds => Some(ChannelBindingError::Downgrade),
+            ChannelBindingFlag::NotUsed | ChannelBindingFlag::NotSupported => None,
+        };
+        if let Some(error) = error {
+            return Err(Error::ChannelBinding(error));
+        }
+        let nonce = format!("{}{}", message.nonce, generate_nonce(rng));
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            STANDARD.encode(&self.setup.salt),
+            MIN_ITERATIONS
+        );
+        self.handshake = Some(Handshake {
+            cbind_input,
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+            authorized,
+        });
+        Ok(server_first)
+    }
+
+    fn server_final(
+        &mut self,
+        client_final: &str,
+    ) -> Result<(AuthenticationStatus, String), Error> {
+        let handshake = self
+            .handshake
+            .take()
+            .ok_or(Error::Protocol(Kind::ExpectedField(Field::Nonce, None)))?;
+        let message = ClientFinalMessage::parse(client_final)?;
+        if message.channel_binding != STANDARD.encode(&handshake.cbind_input) {
+            return Err(if self.setup.scenario.server_binds() {
+                Error::ChannelBinding(ChannelBindingError::Mismatch)
+            } else {
+                Error::Protocol(Kind::InvalidField(Field::GS2Header, None))
+            });
+        }
+        if message.nonce != handshake.nonce {
+            return Err(Error::Protocol(Kind::InvalidField(Field::Nonce, None)));
+        }
+        let proof = STANDARD
+            .decode(message.proof)
+            .map_err(|err| Error::base64(Field::Proof, err))?;
+        let (client_proof, server_signature) = find_proofs(
+            &self.setup.mechanism,
+            message.channel_binding,
+            &handshake.client_first_bare,
+            &handshake.server_first,
+            &self.setup.salted_password(),
+            message.nonce,
+        );
+        Ok(if proof != *client_proof {
+            (
+                AuthenticationStatus::NotAuthenticated,
+                "e=invalid-proof".to_string(),
+            )
+        } else if !handshake.authorized {
+            (
+                AuthenticationStatus::NotAuthorized,
+                "e=other-error".to_string(),
+            )
+        } else {
+            (
+                AuthenticationStatus::Authenticated,
+                format!("v={}", STANDARD.encode(server_signature.as_ref())),
+            )
+        })
+    }
+}
+
+/// A client following RFC5802 which authenticates with the credentials of a [`Setup`].
+struct ReferenceClient<'a> {
+    setup: &'a Setup<'a>,
+    gs2header: String,
+    cbind_input: Vec<u8>,
+    nonce: String,
+    server_signature: Option<Output>,
+}
+
+impl<'a> ReferenceClient<'a> {
+    fn new<R: EntropySource + ?Sized>(setup: &'a Setup<'a>, rng: &mut R) -> Self {
+        let cbind_data = setup.client_cbind_data();
+        let flag = match cbind_data {
+            Some(_) => format!("p={}", CB_TYPE),
+            None if setup.client_supports_channel_binding() => "y".to_string(),
+            None => "n".to_string(),
+        };
+        let authzid = match setup.client_authzid() {
+            Some(authzid) => format!("a={}", escape_username(authzid)),
+            None => String::new(),
+        };
+        let gs2header = format!("{},{},", flag, authzid);
+        let mut cbind_input = gs2header.as_bytes().to_vec();
+        cbind_input.extend_from_slice(cbind_data.as_deref().unwrap_or_default());
+        ReferenceClient {
+            setup,
+            gs2header,
+            cbind_input,
+            nonce: generate_nonce(rng),
+            server_signature: None,
+        }
+    }
+
+    fn client_first_bare(&self) -> String {
+        format!(
+            "n={},r={}",
+            escape_username(self.setup.username),
+            self.nonce
+        )
+    }
+
+    fn client_first(&self) -> String {
+        format!("{}{}", self.gs2header, self.client_first_bare())
+    }
+
+    fn client_final(&mut self, server_first: &str) -> Result<String, String> {
+        let (nonce, salt, iterations) = parse_server_first(server_first)
+            .map_err(|err| format!("reference client rejected server-first-message: {}", err))?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err("server-first-message doesn't continue the client nonce".to_string());
+        }
+        let password = self.setup.client_password();
+        let salted_password = hash_password_for(self.setup.mechanism, &password, iterations, &salt);
+        let channel_binding = STANDARD.encode(&self.cbind_input);
+        let (client_proof, server_signature) = find_proofs(
+            &self.setup.mechanism,
+            &channel_binding,
+            &self.client_first_bare(),
+            server_first,
+            &salted_password,
+            nonce,
+        );
+        self.server_signature = Some(server_signature);
+        Ok(format!(
+            "c={},r={},p={}",
+            channel_binding,
+            nonce,
+            STANDARD.encode(&*client_proof)
+        ))
+    }
+
+    /// Returns whether the server-final-message contains the expected server signature.
+    fn verify(&self, server_final: &str) -> bool {
+        match parse_server_final(server_final, &Lenience::default(), &mut Vec::new()) {
+            Ok(verifier) => matches!(
+                self.server_signature,
+                Some(ref signature) if signature.as_ref() == &verifier[..]
+            ),
+            Err(_) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{self_test, Peers, Scenario};
+    use crate::mechanism::Mechanism;
+
+    #[test]
+    fn test_self_test() {
+        let report = self_test(1);
+        assert!(
+            report.passed(),
+            "{:?}",
+            report.failures().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            report.cases.len(),
+            Mechanism::ALL.len() * Scenario::ALL.len() * Peers::ALL.len()
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! Transcripts of handshakes with widely deployed servers, replayed against the client.
+//!
+//! Every server implementation has its own nonce alphabet and length, salt size and iteration
+//! count, and clients have broken on some of them before. The corpus holds a transcript in the
+//! message format of each of the following servers, with test credentials instead of real ones:
+//!
+//! * PostgreSQL with the empty username sent by libpq
+//! * MongoDB with a salt of 28 bytes and 15000 iterations
+//! * Apache Kafka with base36 nonces and salt
+//! * Dovecot with a server nonce of arbitrary printable characters
+//!
+//! [`replay_all`] runs the client against all of them, so the test suite catches interop
+//! regressions. The transcripts are text files in the `corpus` directory of the crate, with the
+//! username and the password of the client followed by the messages, `C:` for the client and `S:`
+//! for the server. This module is available with the `interop-corpus` feature.
+//!
+//! ```
+//! let failures = scram::corpus::replay_all();
+//! assert!(failures.is_empty(), "{:?}", failures);
+//! ```
+use alloc::vec::Vec;
+
+use crate::transcript::{ReplayError, Sender, Transcript};
+
+/// A transcript of the corpus.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capture {
+    /// The server implementation the transcript was made with, for example `"PostgreSQL"`.
+    pub server: &'static str,
+    source: &'static str,
+}
+
+/// All transcripts of the corpus.
+pub const CAPTURES: &[Capture] = &[
+    Capture {
+        server: "PostgreSQL",
+        source: include_str!("../corpus/postgresql.txt"),
+    },
+    Capture {
+        server: "MongoDB",
+        source: include_str!("../corpus/mongodb.txt"),
+    },
+    Capture {
+        server: "Kafka",
+        source: include_str!("../corpus/kafka.txt"),
+    },
+    Capture {
+        server: "Dovecot",
+        source: include_str!("../corpus/dovecot.txt"),
+    },
+];
+
+impl Capture {
+    /// Returns the username the client authenticated with.
+    pub fn username(&self) -> &'static str {
+        self.field("user:")
+    }
+
+    /// Returns the password the client authenticated with.
+    pub fn password(&self) -> &'static str {
+        self.field("password:")
+    }
+
+    /// Returns the recorded messages.
+    pub fn transcript(&self) -> Transcript {
+        let mut transcript = Transcript::new();
+        for line in self.source.lines() {
+            if let Some(message) = line.strip_prefix("C: ") {
+                transcript.record(Sender::Client, message);
+            } else if let Some(message) = line.strip_prefix("S: ") {
+                transcript.record(Sender::Server, message);
+            }
+        }
+        transcript
+    }
+
+    /// Replays the client side of the transcript, see [`Transcript::replay_client`].
+    pub fn replay(&self) -> Result<(), ReplayError> {
+        self.transcript()
+            .replay_client(self.username(), self.password(), None)
+    }
+
+    /// Returns the value of the line starting with `name`.
+    fn field(&self, name: &str) -> &'static str {
+        self.source
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .map_or("", str::trim)
+    }
+}
+
+/// Replays the client against every transcript of the corpus and returns the servers whose
+/// transcripts failed, with the errors.
+pub fn replay_all() -> Vec<(&'static str, ReplayError)> {
+    CAPTURES
+        .iter()
+        .filter_map(|capture| capture.replay().err().map(|error| (capture.server, error)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{replay_all, CAPTURES};
+    use crate::transcript::ReplayError;
+
+    #[test]
+    fn test_corpus() {
+        for capture in CAPTURES {
+            assert_eq!(
+                capture.transcript().messages().len(),
+                4,
+                "{}",
+                capture.server
+            );
+        }
+        assert_eq!(CAPTURES[0].username(), "");
+        assert_eq!(CAPTURES[3].username(), "user@example.org");
+        assert_eq!(replay_all(), []);
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let transcript = CAPTURES[1].transcript();
+        match transcript.replay_client(CAPTURES[1].username(), "wrong", None) {
+            Err(ReplayError::Diverged { index: 2, .. }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
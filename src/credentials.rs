@@ -0,0 +1,272 @@
+//! The derivation of stored credentials for user provisioning.
+//!
+//! Servers store the salt, the iteration count and keys derived from the password instead of the
+//! password. [`generate_credentials`] derives all of them as described in RFC5802 section 3, for
+//! provisioning tools and user stores which would otherwise reimplement the key derivation:
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use scram::credentials::generate_credentials;
+//! use scram::Mechanism;
+//!
+//! let iterations = NonZeroU32::new(4096).unwrap();
+//! let credentials = generate_credentials("pencil", Mechanism::ScramSha256, iterations);
+//! let password_info = credentials.password_info().unwrap();
+//! ```
+//!
+//! For password changes, a client derives the credentials of the new password with the
+//! [`PasswordChange`] returned by
+//! [`handle_server_final_for_password_change`](crate::client::ServerFinal::handle_server_final_for_password_change)
+//! after it authenticated, and sends them to the server over the authenticated channel. The server
+//! never sees the new password.
+#[cfg(feature = "saslprep")]
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::num::NonZeroU32;
+
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
+use ring::digest::digest;
+use ring::hmac::{self, Key};
+
+use crate::entropy::EntropySource;
+use crate::mechanism::Mechanism;
+#[cfg(feature = "saslprep")]
+use crate::saslprep;
+use crate::server::PasswordInfo;
+#[cfg(feature = "saslprep")]
+use crate::utils::zeroize_string;
+use crate::utils::{hash_password_for, zeroize};
+
+/// The length of the generated salts in bytes.
+pub const SALT_LENGTH: usize = 16;
+
+/// The credentials derived from a password. The salted password and the client key are secrets
+/// which allow authenticating as the user, they are zeroized when the credentials are dropped.
+///
+/// The keys have the [`output_len`](Mechanism::output_len) of the mechanism.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    /// The mechanism the credentials were derived for.
+    pub mechanism: Mechanism,
+    /// The random salt.
+    pub salt: Vec<u8>,
+    /// The iteration count of the key derivation.
+    pub iterations: NonZeroU32,
+    /// `SaltedPassword := Hi(Normalize(password), salt, i)`
+    pub salted_password: Vec<u8>,
+    /// `ClientKey := HMAC(SaltedPassword, "Client Key")`
+    pub client_key: Vec<u8>,
+    /// `StoredKey := H(ClientKey)`
+    pub stored_key: Vec<u8>,
+    /// `ServerKey := HMAC(SaltedPassword, "Server Key")`
+    pub server_key: Vec<u8>,
+}
+
+/// Derives the credentials for `password` with a random salt of [`SALT_LENGTH`] bytes from the
+/// operating system's random number generator.
+///
+/// This function is only available with the `std` feature. Without it, use
+/// [`generate_credentials_with_rng`].
+#[cfg(feature = "std")]
+pub fn generate_credentials(
+    password: &str,
+    mechanism: Mechanism,
+    iterations: NonZeroU32,
+) -> Credentials {
+    generate_credentials_with_rng(password, mechanism, iterations, &mut OsRng)
+}
+
+/// Derives the credentials for `password` with a random salt generated from `rng`.
+pub fn generate_credentials_with_rng<R: EntropySource + ?Sized>(
+    password: &str,
+    mechanism: Mechanism,
+    iterations: NonZeroU32,
+    rng: &mut R,
+) -> Credentials {
+    let mut salt = vec![0; SALT_LENGTH];
+    rng.fill_bytes(&mut salt);
+    derive_credentials(password, mechanism, iterations, salt)
+}
+
+/// Derives the credentials for `password` with the given salt, for example to check stored keys.
+///
+/// With the `saslprep` feature the password is prepared with SASLprep like the clients of this
+/// crate prepare it, see the [`saslprep` module](crate::saslprep).
+pub fn derive_credentials(
+    password: &str,
+    mechanism: Mechanism,
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+) -> Credentials {
+    let salted_password = salt_password(mechanism, password, iterations, &salt);
+    let key = Key::new(mechanism.hmac(), &salted_password);
+    let client_key = hmac::sign(&key, b"Client Key").as_ref().to_vec();
+    let stored_key = digest(mechanism.digest(), &client_key).as_ref().to_vec();
+    let server_key = hmac::sign(&key, b"Server Key").as_ref().to_vec();
+    Credentials {
+        mechanism,
+        salt,
+        iterations,
+        salted_password,
+        client_key,
+        stored_key,
+        server_key,
+    }
+}
+
+/// Prepares `password` with SASLprep like the clients of this crate and salts it.
+#[cfg(feature = "saslprep")]
+fn salt_password(
+    mechanism: Mechanism,
+    password: &str,
+    iterations: NonZeroU32,
+    salt: &[u8],
+) -> Vec<u8> {
+    let mut password = saslprep::prepare_or_keep(password);
+    let salted_password = hash_password_for(mechanism, &password, iterations, salt);
+    if let Cow::Owned(ref mut password) = password {
+        zeroize_string(password);
+    }
+    salted_password
+}
+
+#[cfg(not(feature = "saslprep"))]
+fn salt_password(
+    mechanism: Mechanism,
+    password: &str,
+    iterations: NonZeroU32,
+    salt: &[u8],
+) -> Vec<u8> {
+    hash_password_for(mechanism, password, iterations, salt)
+}
+
+/// The parameters of the credentials of a new password, returned after a successful handshake by
+/// [`handle_server_final_for_password_change`](crate::client::ServerFinal::handle_server_final_for_password_change).
+///
+/// The mechanism and the iteration count are those of the handshake, so the server can verify the
+/// new credentials like the current ones. A client may raise the iteration count before deriving
+/// them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PasswordChange {
+    /// The mechanism the server authenticated the client with.
+    pub mechanism: Mechanism,
+    /// The iteration count the server sent for the current password.
+    pub iterations: NonZeroU32,
+}
+
+impl PasswordChange {
+    pub(crate) fn new(mechanism: Mechanism, iterations: NonZeroU32) -> Self {
+        PasswordChange {
+            mechanism,
+            iterations,
+        }
+    }
+
+    /// Derives the credentials of `new_password` with a random salt from the operating system's
+    /// random number generator.
+    ///
+    /// This method is only available with the `std` feature. Without it, use
+    /// [`credentials_with_rng`](Self::credentials_with_rng).
+    #[cfg(feature = "std")]
+    pub fn credentials(&self, new_password: &str) -> Credentials {
+        generate_credentials(new_password, self.mechanism, self.iterations)
+    }
+
+    /// Derives the credentials of `new_password` with a random salt generated from `rng`.
+    pub fn credentials_with_rng<R: EntropySource + ?Sized>(
+        &self,
+        new_password: &str,
+        rng: &mut R,
+    ) -> Credentials {
+        generate_credentials_with_rng(new_password, self.mechanism, self.iterations, rng)
+    }
+}
+
+impl Credentials {
+    /// Returns the [`PasswordInfo`] of the credentials for an
+    /// [`AuthenticationProvider`](crate::server::AuthenticationProvider), or `None` if the
+    /// iteration count is above 65535, the maximum of the server.
+    pub fn password_info(&self) -> Option<PasswordInfo> {
+        let iterations = u16::try_from(self.iterations.get()).ok()?;
+        Some(PasswordInfo::new(
+            self.salted_password.clone(),
+            iterations,
+            self.salt.clone(),
+        ))
+    }
+}
+
+impl Drop for Credentials {
+    fn drop(&mut self) {
+        zeroize(&mut self.salted_password);
+        zeroize(&mut self.client_key);
+    }
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Credentials")
+            .field("mechanism", &self.mechanism)
+            .field("salt", &self.salt)
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::{derive_credentials, generate_credentials, SALT_LENGTH};
+    use crate::mechanism::Mechanism;
+
+    #[test]
+    fn test_derive_credentials() {
+        // The example of RFC7677 section 3.
+        let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let credentials = derive_credentials("pencil", Mechanism::ScramSha256, iterations, salt);
+        assert_eq!(
+            STANDARD.encode(&credentials.stored_key),
+            "WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY="
+        );
+        assert_eq!(
+            STANDARD.encode(&credentials.server_key),
+            "wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU="
+        );
+        assert!(credentials.password_info().is_some());
+        assert!(!format!("{:?}", credentials).contains("salted_password"));
+
+        let iterations = NonZeroU32::new(70000).unwrap();
+        let credentials = derive_credentials("pencil", Mechanism::ScramSha256, iterations, vec![]);
+        assert!(credentials.password_info().is_none());
+    }
+
+    #[test]
+    fn test_generate_credentials() {
+        let iterations = NonZeroU32::new(1).unwrap();
+        let first = generate_credentials("pencil", Mechanism::ScramSha256, iterations);
+        let second = generate_credentials("pencil", Mechanism::ScramSha256, iterations);
+        assert_eq!(first.salt.len(), SALT_LENGTH);
+        assert_ne!(first.salt, second.salt);
+        assert_ne!(first.stored_key, second.stored_key);
+    }
+
+    #[cfg(feature = "saslprep")]
+    #[test]
+    fn test_derive_credentials_saslprep() {
+        let iterations = NonZeroU32::new(1).unwrap();
+        let derive = |password| {
+            derive_credentials(password, Mechanism::ScramSha256, iterations, vec![1; 16])
+        };
+        assert_eq!(derive("pen\u{AD}cil"), derive("pencil"));
+        // Passwords which can't be prepared are used unchanged, like clients send them.
+        assert_ne!(derive("pen\u{7}cil"), derive("pencil"));
+    }
+}
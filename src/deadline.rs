@@ -0,0 +1,33 @@
+//! Deadlines of handshakes which are kept across slow round-trips.
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+use crate::error::Error;
+
+/// The point in time after which the messages of a handshake are rejected with `Error::Timeout`.
+///
+/// A deadline can only be set with the `std` feature, which provides the clock. Without one the
+/// handshake never times out.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Deadline(#[cfg(feature = "std")] Option<Instant>);
+
+impl Deadline {
+    /// Creates the deadline `instant`.
+    #[cfg(feature = "std")]
+    pub(crate) fn at(instant: Instant) -> Self {
+        Deadline(Some(instant))
+    }
+
+    /// Fails with `Error::Timeout` if the deadline passed.
+    pub(crate) fn check(&self) -> Result<(), Error> {
+        #[cfg(feature = "std")]
+        {
+            if let Some(instant) = self.0 {
+                if Instant::now() >= instant {
+                    return Err(Error::Timeout);
+                }
+            }
+        }
+        Ok(())
+    }
+}
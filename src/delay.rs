@@ -0,0 +1,137 @@
+//! Delaying the responses to failed authentication attempts.
+//!
+//! A [`FailureDelay`] counts consecutive failures per username or per peer and computes how long
+//! the server should wait before answering the next failed attempt. This slows down online
+//! password guessing. The drivers in the `local` module apply the delay automatically. Embedders
+//! driving the state machine themselves call [`FailureDelay::record_failure`] and
+//! [`FailureDelay::record_success`] and sleep in their own runtime.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use core::time::Duration;
+
+use crate::server::ConnectionContext;
+
+/// How the delay grows with the number of consecutive failures.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backoff {
+    /// Every failure is delayed by the same amount.
+    Fixed(Duration),
+    /// The first failure is delayed by `initial`, every following failure twice as long as the
+    /// previous one, up to `max`.
+    Exponential {
+        /// The delay of the first failure.
+        initial: Duration,
+        /// The upper limit of the delay.
+        max: Duration,
+    },
+}
+
+/// Which failures are counted together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    /// Failures are counted per username.
+    Username,
+    /// Failures are counted per peer address. Connections without a peer address in their
+    /// [`ConnectionContext`] are counted per username.
+    Peer,
+}
+
+/// Tracks consecutive authentication failures and computes the delay of the next response.
+///
+/// A counter is kept for every username or peer with a failure since its last success. Call
+/// [`clear`](Self::clear) periodically to bound the memory used by attackers cycling through keys.
+#[derive(Clone, Debug)]
+pub struct FailureDelay {
+    backoff: Backoff,
+    scope: Scope,
+    failures: BTreeMap<String, u32>,
+}
+
+impl FailureDelay {
+    /// Creates a tracker without any recorded failures.
+    pub fn new(backoff: Backoff, scope: Scope) -> Self {
+        FailureDelay {
+            backoff,
+            scope,
+            failures: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the key under which failures of `username` on the connection described by
+    /// `context` are counted.
+    pub fn key<'k>(&self, username: &'k str, context: &ConnectionContext<'k>) -> &'k str {
+        match (self.scope, context.peer_address()) {
+            (Scope::Peer, Some(peer_address)) => peer_address,
+            _ => username,
+        }
+    }
+
+    /// Records a failure for `key` and returns how long to wait before responding.
+    pub fn record_failure(&mut self, key: &str) -> Duration {
+        let failures = self.failures.entry(key.to_string()).or_insert(0);
+        *failures = failures.saturating_add(1);
+        let failures = *failures;
+        self.delay(failures)
+    }
+
+    /// Resets the failures of `key` after a successful authentication.
+    pub fn record_success(&mut self, key: &str) {
+        self.failures.remove(key);
+    }
+
+    /// Forgets all recorded failures.
+    pub fn clear(&mut self) {
+        self.failures.clear();
+    }
+
+    /// Returns the delay after `failures` consecutive failures.
+    fn delay(&self, failures: u32) -> Duration {
+        match self.backoff {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => 1u32
+                .checked_shl(failures - 1)
+                .and_then(|factor| initial.checked_mul(factor))
+                .map_or(max, |delay| delay.min(max)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use super::{Backoff, FailureDelay, Scope};
+    use crate::ConnectionContext;
+
+    #[test]
+    fn test_exponential_backoff() {
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let mut delay = FailureDelay::new(backoff, Scope::Username);
+        assert_eq!(delay.record_failure("user"), Duration::from_millis(100));
+        assert_eq!(delay.record_failure("user"), Duration::from_millis(200));
+        assert_eq!(delay.record_failure("other"), Duration::from_millis(100));
+        assert_eq!(delay.record_failure("user"), Duration::from_millis(400));
+        assert_eq!(delay.record_failure("user"), Duration::from_millis(800));
+        assert_eq!(delay.record_failure("user"), Duration::from_secs(1));
+        for _ in 0..64 {
+            delay.record_failure("user");
+        }
+        assert_eq!(delay.record_failure("user"), Duration::from_secs(1));
+        delay.record_success("user");
+        assert_eq!(delay.record_failure("user"), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_scope() {
+        let backoff = Backoff::Fixed(Duration::from_secs(1));
+        let context = ConnectionContext::new().with_peer_address("192.0.2.1:5432");
+        let delay = FailureDelay::new(backoff, Scope::Peer);
+        assert_eq!(delay.key("user", &context), "192.0.2.1:5432");
+        assert_eq!(delay.key("user", &ConnectionContext::new()), "user");
+        let delay = FailureDelay::new(backoff, Scope::Username);
+        assert_eq!(delay.key("user", &context), "user");
+    }
+}
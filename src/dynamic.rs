@@ -0,0 +1,630 @@
+//! Object-safe handshakes for plugin systems and mechanism registries.
+//!
+//! The client and the server in the [`client`] and [`server`] modules are typestates: every
+//! message consumes the state and returns the next one. Registries holding handshakes of many SASL
+//! mechanisms need a single type instead. [`SteppedClient`] and [`SteppedServer`] keep the current
+//! state and advance it with every call to `step`, through the object-safe [`DynScramClient`] and
+//! [`DynScramServer`] traits.
+//!
+//! ``` rust,no_run
+//! use scram::dynamic::{DynScramClient, SteppedClient, StepOutput};
+//! use scram::ScramClient;
+//!
+//! // This function represents your I/O implementation.
+//! # #[allow(unused_variables)]
+//! fn send_and_receive(message: &str) -> String {
+//!     unimplemented!()
+//! }
+//!
+//! let mut client: Box<dyn DynScramClient> =
+//!     Box::new(SteppedClient::new(ScramClient::new("user", "password", None)));
+//! let mut input = None;
+//! loop {
+//!     match client.step(input.as_deref()).unwrap() {
+//!         StepOutput::Continue(message) => input = Some(send_and_receive(&message)),
+//!         StepOutput::Done { .. } => break,
+//!     }
+//! }
+//! ```
+//!
+//! Channel binding requires the typestates, since the client has to supply the channel-binding
+//! data between the messages.
+//!
+//! Applications which offer SCRAM next to simple mechanisms like PLAIN or LOGIN, without adopting
+//! a SASL framework, implement [`AuthMechanism`] for those and use it for all of them. It's
+//! implemented by [`SteppedClient`], and its server counterpart [`ServerMechanism`] by
+//! [`SteppedServer`]. The [`registry`](crate::registry) module creates both by mechanism name.
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::mem;
+#[cfg(feature = "std")]
+use std::time::Instant;
+
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
+
+use crate::client::{self, ScramClient};
+use crate::deadline::Deadline;
+use crate::entropy::EntropySource;
+use crate::error::Error;
+use crate::mechanism::Mechanism;
+use crate::server::{
+    self, AuthenticationProvider, AuthenticationStatus, ConnectionContext, ScramServer,
+};
+
+/// The result of a step of a handshake.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StepOutput {
+    /// Send the message to the peer and pass its answer to the next `step`.
+    Continue(String),
+    /// The handshake is complete. The server sends the `message` to the client; the client has
+    /// nothing left to send. The client only completes successfully, with `Authenticated`.
+    Done {
+        /// The last message to send to the peer.
+        message: Option<String>,
+        /// The outcome of the handshake.
+        status: AuthenticationStatus,
+    },
+}
+
+/// The phases of a client handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Phase {
+    /// The `client-first-message` wasn't created yet.
+    Initial,
+    /// The `client-first-message` was created and the client waits for the
+    /// `server-first-message`.
+    AwaitingServerFirst,
+    /// The `client-final-message` was created and the client waits for the
+    /// `server-final-message`.
+    AwaitingServerFinal,
+    /// The server was verified and the handshake completed successfully.
+    Completed,
+    /// A step failed and aborted the handshake.
+    Failed,
+    /// The handshake was aborted with [`abort`](DynScramClient::abort).
+    Aborted,
+}
+
+/// An object-safe SCRAM client handshake.
+pub trait DynScramClient {
+    /// Returns the mechanism of the handshake.
+    fn mechanism(&self) -> Mechanism;
+
+    /// Advances the handshake with the message received from the server. The client sends the
+    /// first message, so the `input` of the first step is ignored; afterwards a missing `input`
+    /// is treated as an empty message.
+    ///
+    /// An error aborts the handshake.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after the handshake completed or failed.
+    fn step(&mut self, input: Option<&str>) -> Result<StepOutput, Error>;
+
+    /// Returns the phase of the handshake, for example to report where a stuck handshake waits.
+    fn phase(&self) -> Phase;
+
+    /// Aborts the handshake and zeroizes its secrets, like [`client::ServerFirst::abort`]. Returns
+    /// the [`cancellation`](client::Aborted::cancellation) line if the handshake was in progress,
+    /// or `None` if it didn't start or already ended.
+    fn abort(&mut self) -> Option<&'static str>;
+}
+
+/// The client side of a SASL mechanism, independent of SCRAM.
+///
+/// The application sends the [`initial_response`](Self::initial_response) with its
+/// authentication command, passes every challenge of the server to [`respond`](Self::respond) and
+/// sends back the response, and checks [`is_complete`](Self::is_complete) when the server reports
+/// success. Protocols without initial responses instead pass the empty first challenge to
+/// `respond`.
+///
+/// ```
+/// use scram::dynamic::{AuthMechanism, SteppedClient};
+/// use scram::{Error, ScramClient};
+///
+/// struct Plain<'a>(&'a str, &'a str);
+///
+/// impl<'a> AuthMechanism for Plain<'a> {
+///     type Error = Error;
+///
+///     fn name(&self) -> &str {
+///         "PLAIN"
+///     }
+///
+///     fn initial_response(&mut self) -> Result<Option<String>, Error> {
+///         Ok(Some(format!("\0{}\0{}", self.0, self.1)))
+///     }
+///
+///     fn respond(&mut self, _challenge: &str) -> Result<String, Error> {
+///         self.initial_response().map(Option::unwrap_or_default)
+///     }
+///
+///     fn is_complete(&self) -> bool {
+///         true
+///     }
+/// }
+///
+/// let mechanisms: Vec<Box<dyn AuthMechanism<Error = Error>>> = vec![
+///     Box::new(SteppedClient::new(ScramClient::new("user", "password", None))),
+///     Box::new(Plain("user", "password")),
+/// ];
+/// let names: Vec<&str> = mechanisms.iter().map(|mechanism| mechanism.name()).collect();
+/// assert_eq!(names, ["SCRAM-SHA-256", "PLAIN"]);
+/// ```
+pub trait AuthMechanism {
+    /// The error of a failed step.
+    type Error;
+
+    /// Returns the IANA registered SASL mechanism name, for example `SCRAM-SHA-256` or `PLAIN`.
+    fn name(&self) -> &str;
+
+    /// Returns the response sent with the authentication command, or `None` if the mechanism
+    /// waits for the first challenge of the server. It's called at most once, before any
+    /// challenge.
+    fn initial_response(&mut self) -> Result<Option<String>, Self::Error>;
+
+    /// Processes a challenge of the server, or the additional data sent with its success
+    /// outcome, and returns the response. An error aborts the exchange.
+    fn respond(&mut self, challenge: &str) -> Result<String, Self::Error>;
+
+    /// Returns whether the exchange completed successfully on the client side. Mechanisms which
+    /// authenticate the server, like SCRAM, only complete after verifying it.
+    fn is_complete(&self) -> bool;
+}
+
+/// The server side of a SASL mechanism, independent of SCRAM, as dispatched by a
+/// [`MechanismRegistry`](crate::registry::MechanismRegistry).
+///
+/// The application passes the initial response of the client, or an empty response if there is
+/// none, and every following response to [`respond`](Self::respond), and sends back the
+/// challenges until the exchange is done. It's implemented by [`SteppedServer`].
+pub trait ServerMechanism {
+    /// The error of a failed step.
+    type Error;
+
+    /// Returns the IANA registered SASL mechanism name, for example `SCRAM-SHA-256` or `PLAIN`.
+    fn name(&self) -> &str;
+
+    /// Processes a response of the client and returns the next challenge, or the outcome with
+    /// the additional data to send with it. An error aborts the exchange.
+    fn respond(&mut self, response: &str) -> Result<StepOutput, Self::Error>;
+}
+
+/// An object-safe SCRAM server handshake.
+pub trait DynScramServer {
+    /// Returns the mechanism of the handshake.
+    fn mechanism(&self) -> Mechanism;
+
+    /// Advances the handshake with the message received from the client. A missing `input` is
+    /// treated as an empty message.
+    ///
+    /// An error aborts the handshake. Failed authentication isn't an error, but completes the
+    /// handshake with an `e=` message and the status.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called after the handshake completed or failed.
+    fn step(&mut self, input: Option<&str>) -> Result<StepOutput, Error>;
+
+    /// Aborts the handshake and zeroizes its secrets, like [`server::ClientFinal::abort`].
+    /// Returns the [`server_final`](server::Aborted::server_final) message with the error if the
+    /// server already answered the client, or `None` if it didn't or the handshake already ended.
+    fn abort(&mut self) -> Option<&'static str>;
+}
+
+enum ClientState<'a> {
+    Initial(ScramClient<'a>),
+    ServerFirst(client::ServerFirst<'a>),
+    ServerFinal(client::ServerFinal),
+    Completed,
+    Failed,
+    Aborted,
+}
+
+/// A client handshake driven through [`DynScramClient`].
+pub struct SteppedClient<'a> {
+    mechanism: Mechanism,
+    state: ClientState<'a>,
+}
+
+impl<'a> SteppedClient<'a> {
+    /// Creates the handshake of `client`.
+    pub fn new(client: ScramClient<'a>) -> Self {
+        SteppedClient {
+            mechanism: client.mechanism(),
+            state: ClientState::Initial(client),
+        }
+    }
+}
+
+impl<'a> From<ScramClient<'a>> for SteppedClient<'a> {
+    fn from(client: ScramClient<'a>) -> Self {
+        SteppedClient::new(client)
+    }
+}
+
+impl<'a> DynScramClient for SteppedClient<'a> {
+    fn mechanism(&self) -> Mechanism {
+        self.mechanism
+    }
+
+    fn step(&mut self, input: Option<&str>) -> Result<StepOutput, Error> {
+        let input = input.unwrap_or("");
+        match mem::replace(&mut self.state, ClientState::Failed) {
+            ClientState::Initial(client) => {
+                let (client, client_first) = client.try_client_first()?;
+                self.state = ClientState::ServerFirst(client);
+                Ok(StepOutput::Continue(client_first))
+            }
+            ClientState::ServerFirst(client) => {
+                let (client, client_final) = client.handle_server_first(input)?.client_final();
+                self.state = ClientState::ServerFinal(client);
+                Ok(StepOutput::Continue(client_final))
+            }
+            ClientState::ServerFinal(client) => {
+                client.handle_server_final(input)?;
+                self.state = ClientState::Completed;
+                Ok(StepOutput::Done {
+                    message: None,
+                    status: AuthenticationStatus::Authenticated,
+                })
+            }
+            ClientState::Completed | ClientState::Failed | ClientState::Aborted => {
+                panic!("step after the end of the handshake")
+            }
+        }
+    }
+
+    fn phase(&self) -> Phase {
+        match self.state {
+            ClientState::Initial(_) => Phase::Initial,
+            ClientState::ServerFirst(_) => Phase::AwaitingServerFirst,
+            ClientState::ServerFinal(_) => Phase::AwaitingServerFinal,
+            ClientState::Completed => Phase::Completed,
+            ClientState::Failed => Phase::Failed,
+            ClientState::Aborted => Phase::Aborted,
+        }
+    }
+
+    fn abort(&mut self) -> Option<&'static str> {
+        let aborted = match mem::replace(&mut self.state, ClientState::Aborted) {
+            ClientState::Initial(client) => {
+                client.abort();
+                return None;
+            }
+            ClientState::ServerFirst(client) => client.abort(),
+            ClientState::ServerFinal(client) => client.abort(),
+            state @ ClientState::Completed | state @ ClientState::Failed => {
+                self.state = state;
+                return None;
+            }
+            ClientState::Aborted => return None,
+        };
+        Some(aborted.cancellation())
+    }
+}
+
+/// SCRAM sends the `client-first-message` as the initial response, and an empty response after
+/// verifying the `server-final-message`.
+impl<'a> AuthMechanism for SteppedClient<'a> {
+    type Error = Error;
+
+    fn name(&self) -> &str {
+        self.mechanism().name()
+    }
+
+    fn initial_response(&mut self) -> Result<Option<String>, Error> {
+        self.respond("").map(Some)
+    }
+
+    fn respond(&mut self, challenge: &str) -> Result<String, Error> {
+        match self.step(Some(challenge))? {
+            StepOutput::Continue(message) => Ok(message),
+            StepOutput::Done { message, .. } => Ok(message.unwrap_or_default()),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.phase() == Phase::Completed
+    }
+}
+
+enum ServerState<'a, P: AuthenticationProvider> {
+    ClientFirst,
+    ClientFinal(Box<server::ClientFinal<'a, P>>),
+    Finished,
+}
+
+/// A server handshake driven through [`DynScramServer`].
+pub struct SteppedServer<'a, P: AuthenticationProvider> {
+    server: &'a ScramServer<P>,
+    context: ConnectionContext<'a>,
+    rng: Box<dyn EntropySource + 'a>,
+    deadline: Deadline,
+    state: ServerState<'a, P>,
+}
+
+impl<'a, P: AuthenticationProvider> SteppedServer<'a, P> {
+    /// Creates a handshake with `server`, which uses [`OsRng`] for the server nonce.
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn new(server: &'a ScramServer<P>) -> Self {
+        SteppedServer::with_rng(server, OsRng)
+    }
+
+    /// Creates a handshake with `server`, which uses `rng` for the server nonce.
+    pub fn with_rng<R: EntropySource + 'a>(server: &'a ScramServer<P>, rng: R) -> Self {
+        SteppedServer {
+            server,
+            context: ConnectionContext::new(),
+            rng: Box::new(rng),
+            deadline: Deadline::default(),
+            state: ServerState::ClientFirst,
+        }
+    }
+
+    /// Passes `context` to the provider, like
+    /// [`handle_client_first_in_context`](ScramServer::handle_client_first_in_context).
+    pub fn with_context(mut self, context: ConnectionContext<'a>) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Fails the steps with `Error::Timeout` after `deadline`, like
+    /// [`ServerFirst::with_deadline`](server::ServerFirst::with_deadline).
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Deadline::at(deadline);
+        self
+    }
+}
+
+impl<'a, P: AuthenticationProvider> DynScramServer for SteppedServer<'a, P> {
+    fn mechanism(&self) -> Mechanism {
+        self.context.mechanism().unwrap_or(Mechanism::ScramSha256)
+    }
+
+    fn step(&mut self, input: Option<&str>) -> Result<StepOutput, Error> {
+        let input = input.unwrap_or("");
+        match mem::replace(&mut self.state, ServerState::Finished) {
+            ServerState::ClientFirst => {
+                self.deadline.check()?;
+                let (server, server_first) = self
+                    .server
+                    .handle_client_first_in_context(input, &self.context)?
+                    .deadline(self.deadline)
+                    .server_first_with_rng(&mut *self.rng);
+                self.state = ServerState::ClientFinal(Box::new(server.detach(self.server)));
+                Ok(StepOutput::Continue(server_first))
+            }
+            ServerState::ClientFinal(server) => {
+                let (status, server_final) = server.handle_client_final(input)?.server_final();
+                Ok(StepOutput::Done {
+                    message: Some(server_final),
+                    status,
+                })
+            }
+            ServerState::Finished => panic!("step after the end of the handshake"),
+        }
+    }
+
+    fn abort(&mut self) -> Option<&'static str> {
+        match mem::replace(&mut self.state, ServerState::Finished) {
+            ServerState::ClientFinal(server) => Some(server.abort().server_final()),
+            ServerState::ClientFirst | ServerState::Finished => None,
+        }
+    }
+}
+
+impl<'a, P: AuthenticationProvider> ServerMechanism for SteppedServer<'a, P> {
+    type Error = Error;
+
+    fn name(&self) -> &str {
+        self.mechanism().name()
+    }
+
+    fn respond(&mut self, response: &str) -> Result<StepOutput, Error> {
+        self.step(Some(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::string::String;
+    use core::num::NonZeroU32;
+    use std::time::Instant;
+
+    use rand::rngs::OsRng;
+
+    use super::{
+        AuthMechanism, DynScramClient, DynScramServer, Phase, StepOutput, SteppedClient,
+        SteppedServer,
+    };
+    use crate::client::ScramClient;
+    use crate::error::Error;
+    use crate::mechanism::Mechanism;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username != "user" {
+                return None;
+            }
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("password", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    /// Runs the handshake and returns the statuses of the client and the server.
+    fn handshake(
+        client: &mut dyn DynScramClient,
+        server: &mut dyn DynScramServer,
+    ) -> Result<(AuthenticationStatus, AuthenticationStatus), Error> {
+        let mut input: Option<String> = None;
+        loop {
+            match client.step(input.as_deref())? {
+                StepOutput::Continue(message) => match server.step(Some(&message))? {
+                    StepOutput::Continue(message) => input = Some(message),
+                    StepOutput::Done { message, status } => {
+                        return match client.step(message.as_deref())? {
+                            StepOutput::Done {
+                                status: client_status,
+                                ..
+                            } => Ok((client_status, status)),
+                            StepOutput::Continue(_) => panic!("client continued"),
+                        };
+                    }
+                },
+                StepOutput::Done { .. } => panic!("client done before the server"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let server = ScramServer::new(TestProvider);
+        let mut client: Box<dyn DynScramClient> = Box::new(SteppedClient::new(ScramClient::new(
+            "user", "password", None,
+        )));
+        let mut stepped: Box<dyn DynScramServer> = Box::new(SteppedServer::new(&server));
+        assert_eq!(client.mechanism(), Mechanism::ScramSha256);
+        assert_eq!(stepped.mechanism(), Mechanism::ScramSha256);
+        assert_eq!(client.phase(), Phase::Initial);
+        assert_eq!(
+            handshake(&mut *client, &mut *stepped),
+            Ok((
+                AuthenticationStatus::Authenticated,
+                AuthenticationStatus::Authenticated
+            ))
+        );
+        assert_eq!(client.phase(), Phase::Completed);
+    }
+
+    #[test]
+    fn test_auth_mechanism() {
+        let server = ScramServer::new(TestProvider);
+        let mut stepped = SteppedServer::new(&server);
+        let mut client = SteppedClient::new(ScramClient::new("user", "password", None));
+        let mechanism: &mut dyn AuthMechanism<Error = Error> = &mut client;
+        assert_eq!(mechanism.name(), "SCRAM-SHA-256");
+        let mut response = mechanism.initial_response().unwrap().unwrap();
+        loop {
+            match stepped.step(Some(&response)).unwrap() {
+                StepOutput::Continue(challenge) => {
+                    assert!(!mechanism.is_complete());
+                    response = mechanism.respond(&challenge).unwrap();
+                }
+                StepOutput::Done { message, status } => {
+                    assert_eq!(status, AuthenticationStatus::Authenticated);
+                    assert_eq!(mechanism.respond(&message.unwrap()), Ok(String::new()));
+                    break;
+                }
+            }
+        }
+        assert!(mechanism.is_complete());
+
+        // Without an initial response, the first challenge is empty.
+        let mut client = SteppedClient::new(ScramClient::new("user", "password", None));
+        let client_first = client.respond("").unwrap();
+        assert!(client_first.starts_with("n,,n=user,r="));
+        assert!(!client.is_complete());
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let server = ScramServer::new(TestProvider);
+        let mut client = SteppedClient::from(ScramClient::new("user", "wrong", None));
+        let mut stepped = SteppedServer::with_rng(&server, OsRng);
+        let client_first = match client.step(None) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(client.phase(), Phase::AwaitingServerFirst);
+        let server_first = match stepped.step(Some(&client_first)) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        let client_final = match client.step(Some(&server_first)) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(client.phase(), Phase::AwaitingServerFinal);
+        let server_final = match stepped.step(Some(&client_final)) {
+            Ok(StepOutput::Done {
+                message: Some(message),
+                status: AuthenticationStatus::NotAuthenticated,
+            }) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            client.step(Some(&server_final)),
+            Err(Error::Authentication("Invalid Password".to_string()))
+        );
+        assert_eq!(client.phase(), Phase::Failed);
+    }
+
+    #[test]
+    fn test_unknown_user() {
+        let server = ScramServer::new(TestProvider);
+        let mut client = SteppedClient::new(ScramClient::new("other", "password", None));
+        let mut stepped = SteppedServer::new(&server);
+        let client_first = match client.step(None) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(
+            stepped.step(Some(&client_first)),
+            Err(Error::InvalidUser("other".to_string()))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "step after the end of the handshake")]
+    fn test_step_after_failure() {
+        let server = ScramServer::new(TestProvider);
+        let mut stepped = SteppedServer::new(&server);
+        assert!(stepped.step(None).is_err());
+        let _ = stepped.step(None);
+    }
+
+    #[test]
+    fn test_abort() {
+        let server = ScramServer::new(TestProvider);
+        let mut client = SteppedClient::new(ScramClient::new("user", "password", None));
+        let mut stepped = SteppedServer::new(&server);
+        assert_eq!(stepped.abort(), None);
+
+        let mut stepped = SteppedServer::new(&server);
+        let client_first = match client.step(None) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert!(stepped.step(Some(&client_first)).is_ok());
+        assert_eq!(client.abort(), Some("*"));
+        assert_eq!(client.phase(), Phase::Aborted);
+        assert_eq!(client.abort(), None);
+        assert_eq!(stepped.abort(), Some("e=other-error"));
+    }
+
+    #[test]
+    fn test_deadline() {
+        let server = ScramServer::new(TestProvider);
+        let mut client = SteppedClient::new(ScramClient::new("user", "password", None));
+        let mut stepped = SteppedServer::new(&server).with_deadline(Instant::now());
+        let client_first = match client.step(None) {
+            Ok(StepOutput::Continue(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        assert_eq!(stepped.step(Some(&client_first)), Err(Error::Timeout));
+    }
+}
@@ -0,0 +1,141 @@
+//! The source of randomness for nonces and generated credentials.
+use core::slice;
+
+#[cfg(feature = "rand")]
+use rand::{CryptoRng, RngCore};
+
+/// A source of random bytes, used for every nonce the crate generates.
+///
+/// With the `rand` feature every `rand::RngCore` which is marked as a `rand::CryptoRng`
+/// implements this trait, so any cryptographically secure random number generator of the `rand`
+/// ecosystem can be passed to the `*_with_rng` methods. Platforms without an operating system
+/// random number generator, like bare metal devices with a hardware TRNG, can implement it
+/// directly and build without `rand`.
+///
+/// The source must be cryptographically secure. Predictable nonces allow replay attacks. Tests
+/// which need reproducible nonces use the `InsecureDeterministicRng` of the `test-util`
+/// feature instead of a general purpose generator.
+pub trait EntropySource {
+    /// Fills `dest` with random bytes.
+    fn fill_bytes(&mut self, dest: &mut [u8]);
+}
+
+#[cfg(feature = "rand")]
+impl<R: RngCore + CryptoRng + ?Sized> EntropySource for R {
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RngCore::fill_bytes(self, dest)
+    }
+}
+
+/// A seedable random number generator which produces the same bytes for the same seed on every
+/// platform, for tests with reproducible nonces and credentials.
+///
+/// The bytes are predictable by anyone who knows or guesses the seed, so the generator must never
+/// be used outside of tests. It's only available with the `test-util` feature and deliberately
+/// doesn't implement the traits of `rand`, so it can't end up in code which expects a secure
+/// generator.
+///
+/// ```
+/// use scram::{InsecureDeterministicRng, ScramClient};
+///
+/// let mut rng = InsecureDeterministicRng::new(42);
+/// let (_, first) = ScramClient::with_rng("user", "password", None, &mut rng).client_first();
+/// let mut rng = InsecureDeterministicRng::new(42);
+/// let (_, again) = ScramClient::with_rng("user", "password", None, &mut rng).client_first();
+/// assert_eq!(first, again);
+/// ```
+#[cfg(any(test, feature = "test-util"))]
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsecureDeterministicRng {
+    state: u64,
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl InsecureDeterministicRng {
+    /// Creates a generator whose output is determined by `seed`.
+    pub fn new(seed: u64) -> Self {
+        InsecureDeterministicRng { state: seed }
+    }
+
+    /// Returns the next number of the SplitMix64 sequence.
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(any(test, feature = "test-util"))]
+impl EntropySource for InsecureDeterministicRng {
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+/// Returns a uniformly distributed number below `bound`, which must not be zero.
+#[cfg(any(test, feature = "conformance"))]
+pub fn below<E: EntropySource + ?Sized>(source: &mut E, bound: u8) -> u8 {
+    let mut byte = 0;
+    source.fill_bytes(slice::from_mut(&mut byte));
+    reject(source, &mut byte, bound);
+    byte % bound
+}
+
+/// Redraws `byte` until it doesn't fall into the incomplete last multiple of `bound`, so that
+/// `byte % bound` is uniformly distributed.
+pub fn reject<E: EntropySource + ?Sized>(source: &mut E, byte: &mut u8, bound: u8) {
+    let zone = 256 - 256 % u16::from(bound);
+    while u16::from(*byte) >= zone {
+        source.fill_bytes(slice::from_mut(byte));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{below, EntropySource, InsecureDeterministicRng};
+
+    /// Returns the bytes 0, 1, 2, ... in order.
+    struct Counter(u8);
+
+    impl EntropySource for Counter {
+        fn fill_bytes(&mut self, dest: &mut [u8]) {
+            for byte in dest {
+                *byte = self.0;
+                self.0 = self.0.wrapping_add(1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_below_rejects_biased_values() {
+        let mut source = Counter(250);
+        // 250..=255 are outside of the largest multiple of 10, the first accepted value is 0.
+        assert_eq!(below(&mut source, 10), 0);
+        assert_eq!(source.0, 1);
+        assert_eq!(below(&mut source, 10), 1);
+    }
+
+    #[test]
+    fn test_insecure_deterministic_rng() {
+        let mut rng = InsecureDeterministicRng::new(0);
+        let mut bytes = [0; 12];
+        rng.fill_bytes(&mut bytes);
+        // The first output of SplitMix64 for the seed 0, in little endian.
+        assert_eq!(bytes[..8], 0xe220_a839_7b1d_cdaf_u64.to_le_bytes());
+        let mut other = InsecureDeterministicRng::new(0);
+        let mut again = [0; 12];
+        other.fill_bytes(&mut again[..5]);
+        other.fill_bytes(&mut again[5..]);
+        assert_ne!(bytes, again);
+        assert_eq!(bytes[..5], again[..5]);
+        assert_ne!(
+            InsecureDeterministicRng::new(1),
+            InsecureDeterministicRng::new(0)
+        );
+    }
+}
@@ -1,7 +1,15 @@
-use std::{error, fmt};
+use alloc::string::String;
+use core::fmt;
+use core::num::ParseIntError;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::grammar;
 
 /// The SCRAM mechanism error cases.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Error {
     /// A message wasn't formatted as required. `Kind` contains further information.
     ///
@@ -12,25 +20,224 @@ pub enum Error {
     /// The server couldn't be validated. This usually means that the server didn't posess a stored
     /// key to verify the credentials.
     InvalidServer,
-    /// The server rejected the authentication request. `String` contains a message from the server.
+    /// The server rejected the authentication request. `String` contains a message from the server,
+    /// sanitized for error reports and logs: control characters are removed, invisible characters
+    /// are escaped, and long messages are cut.
     Authentication(String),
     /// The username supplied was not valid
     InvalidUser(String),
+    /// A message doesn't fit into the fixed-size buffer of a [`heapless`](crate::heapless) type.
+    BufferTooSmall,
+    /// The channel-binding flag of the client doesn't match the channel-binding capability of the
+    /// server.
+    ChannelBinding(ChannelBindingError),
+    /// The deadline of the handshake passed before a message was handled.
+    Timeout,
+    /// Confidentiality is required, but the caller didn't assert whether the transport is
+    /// encrypted. See [`Confidentiality`](crate::Confidentiality).
+    ConfidentialityNotAsserted,
+    /// A message was received when the handshake didn't expect one, for example after it ended.
+    /// Only the [`session`](crate::session) types report it, the typestates can't receive such a
+    /// message.
+    UnexpectedMessage,
+    /// The server sent another salt or iteration count than the one pinned for the user in the
+    /// `PinStore` of the client, see the `pinning` module. The stored credentials of the user were
+    /// replaced, by a password change or by an attacker.
+    VerifierChanged,
+    /// The password contains a character which can't be normalized, see the `nfc` module.
+    InvalidPassword,
+    /// The nonce of the client doesn't meet the `server::ClientNoncePolicy` of the server.
+    ClientNonce(ClientNonceError),
+}
+
+/// The channel-binding failures.
+#[derive(Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ChannelBindingError {
+    /// The client requires channel binding, but it isn't available on this connection.
+    NotSupported,
+    /// The client supports channel binding but assumes the server doesn't, although it does on
+    /// this connection. An attacker may have removed the `-PLUS` mechanisms from the mechanisms
+    /// advertised by the server.
+    Downgrade,
+    /// The client requested a channel-binding type the server doesn't support.
+    UnsupportedType(String),
+    /// The channel-binding data sent by the client doesn't match any of the values of the server.
+    /// The client and the server don't see the same TLS channel.
+    Mismatch,
+    /// The client requested channel binding, but the channel-binding data of the connection wasn't
+    /// supplied before it was needed, on the client with
+    /// [`channel_binding_data`](crate::client::ServerFirst::channel_binding_data) and on the server
+    /// with [`with_channel_binding_data`](crate::ConnectionContext::with_channel_binding_data).
+    MissingData,
+}
+
+/// The reasons a server rejects the nonce of a client, see `server::ClientNoncePolicy`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ClientNonceError {
+    /// The nonce is shorter than the minimum length. Contains the length in characters.
+    TooShort(usize),
+    /// The nonce contains a character outside of the allowed range.
+    InvalidCharacter(char),
+}
+
+impl ClientNonceError {
+    fn code(&self) -> u16 {
+        match *self {
+            ClientNonceError::TooShort(_) => 1,
+            ClientNonceError::InvalidCharacter(_) => 2,
+        }
+    }
+}
+
+/// An SMTP reply code with the enhanced status code of RFC3463, like `535 5.7.8`, as returned by
+/// [`Error::smtp_status`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct SmtpStatus {
+    /// The three-digit reply code.
+    pub reply_code: u16,
+    /// The class of the enhanced status code, 4 for transient and 5 for permanent failures.
+    pub class: u8,
+    /// The subject of the enhanced status code.
+    pub subject: u16,
+    /// The detail of the enhanced status code.
+    pub detail: u16,
+}
+
+impl SmtpStatus {
+    const fn new(reply_code: u16, class: u8, subject: u16, detail: u16) -> Self {
+        SmtpStatus {
+            reply_code,
+            class,
+            subject,
+            detail,
+        }
+    }
+
+    /// Returns whether the failure is permanent, so the client shouldn't retry the authentication
+    /// unchanged.
+    pub fn is_permanent(self) -> bool {
+        self.class == 5
+    }
+}
+
+impl ChannelBindingError {
+    /// Returns the `server-error-value` of RFC5802 section 7 for this failure, to be sent to the
+    /// client in an `e=` attribute.
+    pub fn server_error_value(&self) -> &'static str {
+        match *self {
+            ChannelBindingError::NotSupported => "channel-binding-not-supported",
+            ChannelBindingError::Downgrade => "server-does-support-channel-binding",
+            ChannelBindingError::UnsupportedType(_) => "unsupported-channel-binding-type",
+            ChannelBindingError::Mismatch => "channel-bindings-dont-match",
+            ChannelBindingError::MissingData => "other-error",
+        }
+    }
+
+    fn code(&self) -> u16 {
+        match *self {
+            ChannelBindingError::NotSupported => 1,
+            ChannelBindingError::Downgrade => 2,
+            ChannelBindingError::UnsupportedType(_) => 3,
+            ChannelBindingError::Mismatch => 4,
+            ChannelBindingError::MissingData => 5,
+        }
+    }
 }
 
 /// The kinds of protocol errors.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Kind {
     /// The server responded with a nonce that doesn't start with our nonce.
     InvalidNonce,
-    /// The content of the field `Field` is invalid.
-    InvalidField(Field),
-    /// The field `Field` was expected but not found.
-    ExpectedField(Field),
+    /// The content of the field `Field` is invalid. The `Excerpt` shows the offending attribute, if
+    /// it's known.
+    InvalidField(Field, Option<Excerpt>),
+    /// The field `Field` was expected but not found. The `Excerpt` shows the attribute found in
+    /// its place, if any.
+    ExpectedField(Field, Option<Excerpt>),
+    /// The field `Field` couldn't be decoded. The `DecodeError` is the
+    /// [`source`](std::error::Error::source) of the error and has the cause.
+    InvalidEncoding(Field, DecodeError),
+}
+
+/// A redacted excerpt of the attribute a protocol error refers to, for error reports and logs.
+///
+/// The value is cut after a few characters, control characters are replaced with `?`, and the
+/// values of proofs and verifiers are left out.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Excerpt {
+    /// The letter of the attribute, or `None` for a part of a message which isn't an attribute.
+    pub attribute: Option<char>,
+    /// The start of the value.
+    pub value: String,
+    /// The length of the whole value in bytes.
+    pub length: usize,
+}
+
+/// The number of characters of a value kept in an `Excerpt`.
+const EXCERPT_LENGTH: usize = 8;
+
+/// The number of characters of a server's message kept in `Error::Authentication`, including the
+/// `...` which marks a cut message.
+const SERVER_MESSAGE_LENGTH: usize = 64;
+
+impl Excerpt {
+    /// Creates the excerpt of the value of `attribute`.
+    pub(crate) fn new(attribute: Option<char>, value: &str) -> Self {
+        let excerpt = match attribute {
+            Some('p') | Some('v') => String::new(),
+            _ => value
+                .chars()
+                .take(EXCERPT_LENGTH)
+                .map(|c| if c.is_control() { '?' } else { c })
+                .collect(),
+        };
+        Excerpt {
+            attribute,
+            value: excerpt,
+            length: value.len(),
+        }
+    }
+}
+
+/// The failure of the decoder of a field, like the base64 decoder of the salt or the integer
+/// parser of the iteration count.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DecodeError(Cause);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Cause {
+    Base64(base64::DecodeError),
+    Integer(ParseIntError),
+    /// A deserialized failure, of which only the message is known.
+    #[cfg(feature = "serde")]
+    Message(String),
+}
+
+impl DecodeError {
+    fn base64(err: base64::DecodeError) -> Self {
+        DecodeError(Cause::Base64(err))
+    }
+
+    pub(crate) fn integer(err: ParseIntError) -> Self {
+        DecodeError(Cause::Integer(err))
+    }
 }
 
 /// The fields used in the exchanged messages.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Field {
     /// Nonce
     Nonce,
@@ -50,6 +257,307 @@ pub enum Field {
     GS2Header,
     /// Client Proof
     Proof,
+    /// Extension attributes
+    Extensions,
+}
+
+impl Error {
+    /// Returns a stable numeric code for the error case. The codes never change between releases
+    /// and can be used as metrics labels, in log pipelines or across FFI boundaries.
+    ///
+    /// | Code        | Error                                       |
+    /// |-------------|---------------------------------------------|
+    /// | 1           | `UnsupportedExtension`                      |
+    /// | 2           | `InvalidServer`                             |
+    /// | 3           | `Authentication(_)`                         |
+    /// | 4           | `InvalidUser(_)`                            |
+    /// | 5           | `BufferTooSmall`                            |
+    /// | 6           | `Timeout`                                   |
+    /// | 7           | `ConfidentialityNotAsserted`                |
+    /// | 8           | `UnexpectedMessage`                         |
+    /// | 9           | `VerifierChanged`                           |
+    /// | 10          | `InvalidPassword`                           |
+    /// | 100         | `Protocol(Kind::InvalidNonce)`              |
+    /// | 200 + field | `Protocol(Kind::InvalidField(field, _))`    |
+    /// | 200 + field | `Protocol(Kind::InvalidEncoding(field, _))` |
+    /// | 300 + field | `Protocol(Kind::ExpectedField(field, _))`   |
+    /// | 401         | `ChannelBinding(NotSupported)`              |
+    /// | 402         | `ChannelBinding(Downgrade)`                 |
+    /// | 403         | `ChannelBinding(UnsupportedType(_))`        |
+    /// | 404         | `ChannelBinding(Mismatch)`                  |
+    /// | 405         | `ChannelBinding(MissingData)`               |
+    /// | 501         | `ClientNonce(TooShort(_))`                  |
+    /// | 502         | `ClientNonce(InvalidCharacter(_))`          |
+    ///
+    /// The field is counted from 1 in the order of the [`Field`] variants: `Nonce` is 1, `Salt` is
+    /// 2, and so on up to `Extensions`, which is 10.
+    pub fn code(&self) -> u16 {
+        use self::Error::*;
+        use self::Kind::*;
+        match *self {
+            UnsupportedExtension => 1,
+            InvalidServer => 2,
+            Authentication(_) => 3,
+            InvalidUser(_) => 4,
+            BufferTooSmall => 5,
+            Timeout => 6,
+            ConfidentialityNotAsserted => 7,
+            UnexpectedMessage => 8,
+            VerifierChanged => 9,
+            InvalidPassword => 10,
+            ChannelBinding(ref error) => 400 + error.code(),
+            ClientNonce(ref error) => 500 + error.code(),
+            Protocol(InvalidNonce) => 100,
+            Protocol(InvalidField(ref field, _)) | Protocol(InvalidEncoding(ref field, _)) => {
+                200 + field.code()
+            }
+            Protocol(ExpectedField(ref field, _)) => 300 + field.code(),
+        }
+    }
+}
+
+impl Error {
+    /// Returns the `server-error-value` of RFC5802 section 7 to report this error to a client in
+    /// an `e=` attribute, for example when a proxy translates a failed handshake with an upstream
+    /// server into a `server-final-message` for its own client.
+    ///
+    /// | Error                                        | `server-error-value`          |
+    /// |----------------------------------------------|-------------------------------|
+    /// | `UnsupportedExtension`                       | `extensions-not-supported`    |
+    /// | `InvalidUser(_)`                             | `unknown-user`                |
+    /// | `ChannelBinding(error)`                      | `error.server_error_value()`  |
+    /// | `Protocol(_)` of the `Authcid` or `Authzid`  | `invalid-username-encoding`   |
+    /// | `Protocol(_)` of another field               | `invalid-encoding`            |
+    /// | `ClientNonce(InvalidCharacter(_))`           | `invalid-encoding`            |
+    /// | `Authentication(value)`                      | `value`, if it's a valid one  |
+    /// | everything else                              | `other-error`                 |
+    ///
+    /// The value of an `Authentication` error was received from a server and is passed on as
+    /// sanitized, as RFC5802 allows values outside of its list.
+    pub fn server_error_value(&self) -> &str {
+        use self::Error::*;
+        use self::Kind::*;
+        match *self {
+            UnsupportedExtension => "extensions-not-supported",
+            InvalidUser(_) => "unknown-user",
+            ChannelBinding(ref error) => error.server_error_value(),
+            Protocol(InvalidField(Field::Authcid, _))
+            | Protocol(InvalidField(Field::Authzid, _))
+            | Protocol(ExpectedField(Field::Authcid, _))
+            | Protocol(ExpectedField(Field::Authzid, _))
+            | Protocol(InvalidEncoding(Field::Authcid, _))
+            | Protocol(InvalidEncoding(Field::Authzid, _)) => "invalid-username-encoding",
+            Protocol(InvalidField(..))
+            | Protocol(ExpectedField(..))
+            | Protocol(InvalidEncoding(..))
+            | ClientNonce(ClientNonceError::InvalidCharacter(_)) => "invalid-encoding",
+            Authentication(ref value) if grammar::is_value(value) => value,
+            Protocol(InvalidNonce)
+            | InvalidServer
+            | Authentication(_)
+            | BufferTooSmall
+            | Timeout
+            | ConfidentialityNotAsserted
+            | UnexpectedMessage
+            | VerifierChanged
+            | InvalidPassword
+            | ClientNonce(ClientNonceError::TooShort(_)) => "other-error",
+        }
+    }
+
+    /// Returns the LDAP `resultCode` of RFC4511 for a bind which failed with this error, for LDAP
+    /// servers and proxies which authenticate with SCRAM.
+    ///
+    /// | Error                                                 | `resultCode`                 |
+    /// |-------------------------------------------------------|------------------------------|
+    /// | `Protocol(_)`, `UnexpectedMessage`, `ClientNonce(_)`  | 2 `protocolError`            |
+    /// | `Timeout`                                             | 3 `timeLimitExceeded`        |
+    /// | `UnsupportedExtension`                                | 7 `authMethodNotSupported`   |
+    /// | `ChannelBinding(NotSupported \| UnsupportedType(_))`  | 7 `authMethodNotSupported`   |
+    /// | `ConfidentialityNotAsserted`                          | 13 `confidentialityRequired` |
+    /// | `InvalidUser(_)`, `InvalidPassword`                   | 49 `invalidCredentials`      |
+    /// | `ChannelBinding(Downgrade \| Mismatch)`               | 49 `invalidCredentials`      |
+    /// | `Authentication(value)`                               | see below                    |
+    /// | everything else                                       | 80 `other`                   |
+    ///
+    /// An `Authentication` error is mapped like the error its value stands for: `invalid-encoding`
+    /// and `invalid-username-encoding` like a protocol error, `no-resources` to 51 `busy`,
+    /// `other-error` to 80 `other`, the channel-binding failures and `extensions-not-supported`
+    /// like the errors of [`from_server_error_value`](Self::from_server_error_value), and all
+    /// other values, like `invalid-proof` and `unknown-user`, to 49 `invalidCredentials`.
+    pub fn ldap_result_code(&self) -> u16 {
+        use self::ChannelBindingError::*;
+        use self::Error::*;
+        match *self {
+            Protocol(_) | UnexpectedMessage | ClientNonce(_) => 2,
+            Timeout => 3,
+            UnsupportedExtension
+            | ChannelBinding(NotSupported)
+            | ChannelBinding(UnsupportedType(_)) => 7,
+            ConfidentialityNotAsserted => 13,
+            InvalidUser(_)
+            | InvalidPassword
+            | ChannelBinding(Downgrade)
+            | ChannelBinding(Mismatch) => 49,
+            Authentication(ref value) => match value.as_str() {
+                "invalid-encoding" | "invalid-username-encoding" => 2,
+                "no-resources" => 51,
+                "other-error" => 80,
+                _ => match Error::from_server_error_value(value) {
+                    Authentication(_) => 49,
+                    error => error.ldap_result_code(),
+                },
+            },
+            InvalidServer | BufferTooSmall | VerifierChanged | ChannelBinding(MissingData) => 80,
+        }
+    }
+
+    /// Returns the SMTP reply of RFC4954 for an `AUTH` command which failed with this error, for
+    /// SMTP servers and proxies which authenticate with SCRAM.
+    ///
+    /// | Error                                                 | Reply        |
+    /// |-------------------------------------------------------|--------------|
+    /// | `BufferTooSmall`                                      | `500 5.5.6`  |
+    /// | `Protocol(_)`, `ClientNonce(_)`                       | `501 5.5.2`  |
+    /// | `UnexpectedMessage`                                   | `503 5.5.1`  |
+    /// | `UnsupportedExtension`                                | `504 5.5.4`  |
+    /// | `ChannelBinding(NotSupported \| UnsupportedType(_))`  | `504 5.5.4`  |
+    /// | `InvalidUser(_)`, `InvalidPassword`                   | `535 5.7.8`  |
+    /// | `ChannelBinding(Downgrade \| Mismatch)`               | `535 5.7.8`  |
+    /// | `ConfidentialityNotAsserted`                          | `538 5.7.11` |
+    /// | `Authentication(value)`                               | see below    |
+    /// | everything else                                       | `454 4.7.0`  |
+    ///
+    /// An `Authentication` error is mapped like the error its value stands for: `invalid-encoding`
+    /// and `invalid-username-encoding` like a protocol error, `no-resources` and `other-error` to
+    /// the transient `454 4.7.0`, the channel-binding failures and `extensions-not-supported`
+    /// like the errors of [`from_server_error_value`](Self::from_server_error_value), and all
+    /// other values, like `invalid-proof` and `unknown-user`, to `535 5.7.8`.
+    pub fn smtp_status(&self) -> SmtpStatus {
+        use self::ChannelBindingError::*;
+        use self::Error::*;
+        const TEMPORARY_FAILURE: SmtpStatus = SmtpStatus::new(454, 4, 7, 0);
+        const INVALID_CREDENTIALS: SmtpStatus = SmtpStatus::new(535, 5, 7, 8);
+        const SYNTAX_ERROR: SmtpStatus = SmtpStatus::new(501, 5, 5, 2);
+        match *self {
+            BufferTooSmall => SmtpStatus::new(500, 5, 5, 6),
+            Protocol(_) | ClientNonce(_) => SYNTAX_ERROR,
+            UnexpectedMessage => SmtpStatus::new(503, 5, 5, 1),
+            UnsupportedExtension
+            | ChannelBinding(NotSupported)
+            | ChannelBinding(UnsupportedType(_)) => SmtpStatus::new(504, 5, 5, 4),
+            InvalidUser(_)
+            | InvalidPassword
+            | ChannelBinding(Downgrade)
+            | ChannelBinding(Mismatch) => INVALID_CREDENTIALS,
+            ConfidentialityNotAsserted => SmtpStatus::new(538, 5, 7, 11),
+            Authentication(ref value) => match value.as_str() {
+                "invalid-encoding" | "invalid-username-encoding" => SYNTAX_ERROR,
+                "no-resources" | "other-error" => TEMPORARY_FAILURE,
+                _ => match Error::from_server_error_value(value) {
+                    Authentication(_) => INVALID_CREDENTIALS,
+                    error => error.smtp_status(),
+                },
+            },
+            InvalidServer | Timeout | VerifierChanged | ChannelBinding(MissingData) => {
+                TEMPORARY_FAILURE
+            }
+        }
+    }
+
+    /// Returns the error for a `server-error-value` received in an `e=` attribute, the reverse of
+    /// [`server_error_value`](Self::server_error_value).
+    ///
+    /// The values for unsupported extensions, unknown users and the channel-binding failures of
+    /// [`ChannelBindingError`] have their own error cases. Because the value doesn't name the
+    /// user or the channel-binding type, `InvalidUser` and `UnsupportedType` contain an empty
+    /// string. All other values are returned as `Authentication(value)`, like the client does.
+    pub fn from_server_error_value(value: &str) -> Self {
+        match value {
+            "extensions-not-supported" => Error::UnsupportedExtension,
+            "unknown-user" => Error::InvalidUser(String::new()),
+            "channel-binding-not-supported" => {
+                Error::ChannelBinding(ChannelBindingError::NotSupported)
+            }
+            "server-does-support-channel-binding" => {
+                Error::ChannelBinding(ChannelBindingError::Downgrade)
+            }
+            "unsupported-channel-binding-type" => {
+                Error::ChannelBinding(ChannelBindingError::UnsupportedType(String::new()))
+            }
+            "channel-bindings-dont-match" => Error::ChannelBinding(ChannelBindingError::Mismatch),
+            _ => Error::authentication(value),
+        }
+    }
+}
+
+impl Error {
+    /// Creates the error of a base64 encoded `field` which failed to decode.
+    pub(crate) fn base64(field: Field, err: base64::DecodeError) -> Self {
+        Error::Protocol(Kind::InvalidEncoding(field, DecodeError::base64(err)))
+    }
+
+    /// Creates the error of the `server-error-value` sent by a server.
+    pub(crate) fn authentication(value: &str) -> Self {
+        Error::Authentication(sanitize(value))
+    }
+}
+
+/// Prepares a message of a possibly hostile server for error reports and logs, so it can't forge
+/// log lines or hide text. Control characters, including line breaks, are removed, invisible
+/// formatting characters like bidirectional overrides are escaped as `\u{..}`, and the message is
+/// cut after `SERVER_MESSAGE_LENGTH` characters. Sanitizing a sanitized message doesn't change it.
+fn sanitize(value: &str) -> String {
+    let mut sanitized = String::with_capacity(value.len().min(SERVER_MESSAGE_LENGTH));
+    for c in value.chars().filter(|c| !c.is_control()) {
+        if is_invisible(c) {
+            sanitized.extend(c.escape_unicode());
+        } else {
+            sanitized.push(c);
+        }
+    }
+    if sanitized.chars().count() > SERVER_MESSAGE_LENGTH {
+        let (end, _) = sanitized
+            .char_indices()
+            .nth(SERVER_MESSAGE_LENGTH - 3)
+            .expect("longer than the limit");
+        sanitized.truncate(end);
+        sanitized.push_str("...");
+    }
+    sanitized
+}
+
+/// Returns whether `c` is a formatting character which isn't displayed, or a line or paragraph
+/// separator.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{ad}'
+            | '\u{61c}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{2028}'..='\u{202e}'
+            | '\u{2060}'..='\u{206f}'
+            | '\u{feff}'
+            | '\u{fff9}'..='\u{fffb}'
+    )
+}
+
+impl Field {
+    fn code(&self) -> u16 {
+        match *self {
+            Field::Nonce => 1,
+            Field::Salt => 2,
+            Field::Iterations => 3,
+            Field::VerifyOrError => 4,
+            Field::ChannelBinding => 5,
+            Field::Authzid => 6,
+            Field::Authcid => 7,
+            Field::GS2Header => 8,
+            Field::Proof => 9,
+            Field::Extensions => 10,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -58,28 +566,340 @@ impl fmt::Display for Error {
         use self::Kind::*;
         match *self {
             Protocol(InvalidNonce) => write!(fmt, "Invalid nonce"),
-            Protocol(InvalidField(ref field)) => write!(fmt, "Invalid field {:?}", field),
-            Protocol(ExpectedField(ref field)) => write!(fmt, "Expected field {:?}", field),
+            Protocol(InvalidField(ref field, None)) => write!(fmt, "Invalid field {:?}", field),
+            Protocol(InvalidField(ref field, Some(ref excerpt))) => {
+                write!(fmt, "Invalid field {:?} at {}", field, excerpt)
+            }
+            Protocol(ExpectedField(ref field, None)) => write!(fmt, "Expected field {:?}", field),
+            Protocol(ExpectedField(ref field, Some(ref excerpt))) => {
+                write!(fmt, "Expected field {:?}, found {}", field, excerpt)
+            }
+            Protocol(InvalidEncoding(ref field, ref err)) => {
+                write!(fmt, "Invalid encoding of field {:?}: {}", field, err)
+            }
             UnsupportedExtension => write!(fmt, "Unsupported extension"),
             InvalidServer => write!(fmt, "Server failed validation"),
             InvalidUser(ref username) => write!(fmt, "Invalid user: '{}'", username),
-            Authentication(ref msg) => write!(fmt, "authentication error {}", msg),
+            Authentication(ref msg) => write!(fmt, "authentication error {}", sanitize(msg)),
+            BufferTooSmall => write!(fmt, "Buffer too small"),
+            Timeout => write!(fmt, "Handshake deadline passed"),
+            ConfidentialityNotAsserted => write!(fmt, "Transport confidentiality not asserted"),
+            UnexpectedMessage => write!(fmt, "Unexpected message"),
+            VerifierChanged => write!(fmt, "Server verifier changed"),
+            InvalidPassword => write!(fmt, "Invalid password"),
+            ChannelBinding(ref error) => write!(fmt, "Channel binding: {}", error),
+            ClientNonce(ref error) => write!(fmt, "Client nonce: {}", error),
+        }
+    }
+}
+
+impl fmt::Display for ChannelBindingError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelBindingError::UnsupportedType(ref name) => {
+                write!(fmt, "{} '{}'", self.server_error_value(), name)
+            }
+            ChannelBindingError::MissingData => fmt.write_str("channel-binding data missing"),
+            _ => fmt.write_str(self.server_error_value()),
         }
     }
 }
 
+impl fmt::Display for ClientNonceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ClientNonceError::TooShort(length) => write!(fmt, "too short ({} characters)", length),
+            ClientNonceError::InvalidCharacter(c) => write!(fmt, "invalid character {:?}", c),
+        }
+    }
+}
+
+impl fmt::Display for SmtpStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} {}.{}.{}",
+            self.reply_code, self.class, self.subject, self.detail
+        )
+    }
+}
+
+impl fmt::Display for Excerpt {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(attribute) = self.attribute {
+            write!(fmt, "{}=", attribute)?;
+        }
+        fmt.write_str(&self.value)?;
+        if self.value.len() < self.length {
+            write!(fmt, "... ({} bytes)", self.length)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Cause::Base64(ref err) => write!(fmt, "base64: {}", err),
+            Cause::Integer(ref err) => write!(fmt, "integer: {}", err),
+            #[cfg(feature = "serde")]
+            Cause::Message(ref message) => fmt.write_str(message),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for DecodeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self.0 {
+            Cause::Base64(ref err) => Some(err),
+            Cause::Integer(ref err) => Some(err),
+            #[cfg(feature = "serde")]
+            Cause::Message(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DecodeError {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(fmt, "{}", defmt::Display2Format(self))
+    }
+}
+
+// The decoder errors don't implement serde, so a `DecodeError` is serialized as its message.
+#[cfg(feature = "serde")]
+impl serde::Serialize for DecodeError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for DecodeError {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(|message| DecodeError(Cause::Message(message)))
+    }
+}
+
+#[cfg(feature = "std")]
 impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Protocol(Kind::InvalidEncoding(_, ref err)) => Some(err),
+            _ => None,
+        }
+    }
+
     fn description(&self) -> &str {
         use self::Error::*;
         use self::Kind::*;
         match *self {
             Protocol(InvalidNonce) => "Invalid nonce",
-            Protocol(InvalidField(_)) => "Invalid field",
-            Protocol(ExpectedField(_)) => "Expected field",
+            Protocol(InvalidField(_, _)) => "Invalid field",
+            Protocol(ExpectedField(_, _)) => "Expected field",
+            Protocol(InvalidEncoding(..)) => "Invalid encoding",
             UnsupportedExtension => "Unsupported extension",
             InvalidServer => "Server failed validation",
             InvalidUser(_) => "Invalid user",
             Authentication(_) => "Unspecified error",
+            BufferTooSmall => "Buffer too small",
+            Timeout => "Handshake deadline passed",
+            ConfidentialityNotAsserted => "Transport confidentiality not asserted",
+            UnexpectedMessage => "Unexpected message",
+            VerifierChanged => "Server verifier changed",
+            InvalidPassword => "Invalid password",
+            ChannelBinding(_) => "Channel binding",
+            ClientNonce(_) => "Client nonce",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sanitize, ChannelBindingError, ClientNonceError, Error, Field, Kind};
+
+    #[test]
+    fn test_error_codes() {
+        assert_eq!(Error::UnsupportedExtension.code(), 1);
+        assert_eq!(Error::InvalidServer.code(), 2);
+        assert_eq!(Error::Authentication("e".to_string()).code(), 3);
+        assert_eq!(Error::InvalidUser("user".to_string()).code(), 4);
+        assert_eq!(Error::BufferTooSmall.code(), 5);
+        assert_eq!(Error::Timeout.code(), 6);
+        assert_eq!(Error::ConfidentialityNotAsserted.code(), 7);
+        assert_eq!(Error::UnexpectedMessage.code(), 8);
+        assert_eq!(Error::VerifierChanged.code(), 9);
+        assert_eq!(Error::InvalidPassword.code(), 10);
+        let downgrade = Error::ChannelBinding(ChannelBindingError::Downgrade);
+        assert_eq!(downgrade.code(), 402);
+        let missing = Error::ChannelBinding(ChannelBindingError::MissingData);
+        assert_eq!(missing.code(), 405);
+        let short = Error::ClientNonce(ClientNonceError::TooShort(4));
+        assert_eq!(short.code(), 501);
+        let character = Error::ClientNonce(ClientNonceError::InvalidCharacter('~'));
+        assert_eq!(character.code(), 502);
+        assert_eq!(Error::Protocol(Kind::InvalidNonce).code(), 100);
+        assert_eq!(
+            Error::Protocol(Kind::InvalidField(Field::Salt, None)).code(),
+            202
+        );
+        assert_eq!(
+            Error::Protocol(Kind::ExpectedField(Field::Nonce, None)).code(),
+            301
+        );
+        assert_eq!(
+            Error::Protocol(Kind::ExpectedField(Field::Proof, None)).code(),
+            309
+        );
+    }
+
+    #[test]
+    fn test_server_error_values() {
+        let values = [
+            "invalid-encoding",
+            "extensions-not-supported",
+            "invalid-proof",
+            "channel-bindings-dont-match",
+            "server-does-support-channel-binding",
+            "channel-binding-not-supported",
+            "unsupported-channel-binding-type",
+            "unknown-user",
+            "invalid-username-encoding",
+            "no-resources",
+            "other-error",
+            "User 'user' not authorized",
+        ];
+        for value in values.iter() {
+            let error = Error::from_server_error_value(value);
+            assert_eq!(error.server_error_value(), *value);
+        }
+        assert_eq!(
+            Error::from_server_error_value("channel-bindings-dont-match"),
+            Error::ChannelBinding(ChannelBindingError::Mismatch)
+        );
+        assert_eq!(
+            Error::from_server_error_value("invalid-proof"),
+            Error::Authentication("invalid-proof".to_string())
+        );
+
+        let authcid = Error::Protocol(Kind::ExpectedField(Field::Authcid, None));
+        assert_eq!(authcid.server_error_value(), "invalid-username-encoding");
+        let salt = Error::base64(Field::Salt, base64::DecodeError::InvalidPadding);
+        assert_eq!(salt.server_error_value(), "invalid-encoding");
+        let missing = Error::ChannelBinding(ChannelBindingError::MissingData);
+        assert_eq!(missing.server_error_value(), "other-error");
+        assert_eq!(Error::InvalidServer.server_error_value(), "other-error");
+        let invalid = Error::Authentication("a,b".to_string());
+        assert_eq!(invalid.server_error_value(), "other-error");
+    }
+
+    #[test]
+    fn test_ldap_result_codes() {
+        let invalid_proof = Error::Authentication("invalid-proof".to_string());
+        assert_eq!(invalid_proof.ldap_result_code(), 49);
+        assert_eq!(
+            Error::InvalidUser("user".to_string()).ldap_result_code(),
+            49
+        );
+        let mismatch = Error::ChannelBinding(ChannelBindingError::Mismatch);
+        assert_eq!(mismatch.ldap_result_code(), 49);
+        let salt = Error::Protocol(Kind::InvalidField(Field::Salt, None));
+        assert_eq!(salt.ldap_result_code(), 2);
+        assert_eq!(Error::Timeout.ldap_result_code(), 3);
+        assert_eq!(Error::UnsupportedExtension.ldap_result_code(), 7);
+        assert_eq!(Error::ConfidentialityNotAsserted.ldap_result_code(), 13);
+        assert_eq!(Error::InvalidServer.ldap_result_code(), 80);
+
+        let codes = [
+            ("invalid-encoding", 2),
+            ("extensions-not-supported", 7),
+            ("channel-binding-not-supported", 7),
+            ("channel-bindings-dont-match", 49),
+            ("unknown-user", 49),
+            ("no-resources", 51),
+            ("other-error", 80),
+            ("User 'user' not authorized", 49),
+        ];
+        for &(value, code) in codes.iter() {
+            let error = Error::Authentication(value.to_string());
+            assert_eq!(error.ldap_result_code(), code, "{}", value);
         }
     }
+
+    #[test]
+    fn test_smtp_statuses() {
+        let invalid_proof = Error::Authentication("invalid-proof".to_string());
+        assert_eq!(invalid_proof.smtp_status().to_string(), "535 5.7.8");
+        assert!(invalid_proof.smtp_status().is_permanent());
+        let nonce = Error::Protocol(Kind::InvalidNonce);
+        assert_eq!(nonce.smtp_status().to_string(), "501 5.5.2");
+        assert_eq!(
+            Error::UnexpectedMessage.smtp_status().to_string(),
+            "503 5.5.1"
+        );
+        let unsupported = Error::ChannelBinding(ChannelBindingError::NotSupported);
+        assert_eq!(unsupported.smtp_status().to_string(), "504 5.5.4");
+        assert_eq!(
+            Error::ConfidentialityNotAsserted.smtp_status().to_string(),
+            "538 5.7.11"
+        );
+        let timeout = Error::Timeout.smtp_status();
+        assert_eq!(timeout.to_string(), "454 4.7.0");
+        assert!(!timeout.is_permanent());
+
+        let statuses = [
+            ("invalid-username-encoding", "501 5.5.2"),
+            ("unsupported-channel-binding-type", "504 5.5.4"),
+            ("server-does-support-channel-binding", "535 5.7.8"),
+            ("no-resources", "454 4.7.0"),
+            ("other-error", "454 4.7.0"),
+        ];
+        for &(value, status) in statuses.iter() {
+            let error = Error::Authentication(value.to_string());
+            assert_eq!(error.smtp_status().to_string(), status, "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!(sanitize("invalid-proof"), "invalid-proof");
+        assert_eq!(
+            sanitize("denied\r\n[INFO] login ok\x7f"),
+            "denied[INFO] login ok"
+        );
+        assert_eq!(sanitize("a\u{202e}b\u{85}c"), "a\\u{202e}bc");
+        let long = "x".repeat(100);
+        let sanitized = sanitize(&long);
+        assert_eq!(sanitized, format!("{}...", "x".repeat(61)));
+        assert_eq!(sanitize(&sanitized), sanitized);
+        assert_eq!(sanitize(&"\u{e9}".repeat(70)).chars().count(), 64);
+
+        let error = Error::from_server_error_value("bad\u{2028}user");
+        assert_eq!(error, Error::Authentication("bad\\u{2028}user".to_string()));
+        assert_eq!(error.server_error_value(), "bad\\u{2028}user");
+        let error = Error::Authentication("line\nbreak".to_string());
+        assert_eq!(error.to_string(), "authentication error linebreak");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_source() {
+        use std::error::Error as _;
+
+        let err = Error::base64(Field::Salt, base64::DecodeError::InvalidPadding);
+        assert_eq!(err.code(), 202);
+        assert_eq!(
+            err.to_string(),
+            "Invalid encoding of field Salt: base64: Invalid padding"
+        );
+        let source = err.source().unwrap();
+        assert_eq!(
+            source.source().unwrap().downcast_ref(),
+            Some(&base64::DecodeError::InvalidPadding)
+        );
+        assert!(Error::Protocol(Kind::InvalidField(Field::Salt, None))
+            .source()
+            .is_none());
+    }
 }
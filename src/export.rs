@@ -0,0 +1,506 @@
+//! Export and import of sets of server credentials, for provisioning pipelines moving credentials
+//! between systems built on this crate.
+//!
+//! A [`CredentialExport`] holds the credentials of many users for every [`Mechanism`] with the
+//! binary values base64 encoded. With the `serde` feature it implements `Serialize` and
+//! `Deserialize`, so it can be written as JSON, TOML or any other format supported by serde:
+//!
+//! ``` json
+//! {
+//!   "version": 1,
+//!   "credentials": [
+//!     {
+//!       "username": "user",
+//!       "mechanism": "SCRAM-SHA-256",
+//!       "iterations": 4096,
+//!       "salt": "W22ZaJ0SNY7soEsUEjb6gQ==",
+//!       "salted_password": "xKSVEDI6tPlSysH6mUQZOeeOp01r6B3fcJbodRPcYV0=",
+//!       "stored_key": null,
+//!       "server_key": null,
+//!       "identity": null
+//!     }
+//!   ],
+//!   "digest": "sha256:..."
+//! }
+//! ```
+//!
+//! The digest covers the version and all credentials. Without a key it's a SHA-256 digest, which
+//! detects truncated or corrupted documents. With a key shared by the exporting and the importing
+//! system it's an HMAC-SHA-256, which also detects modifications. [`CredentialExport::import`]
+//! checks the digest and every credential before returning any of them.
+//!
+//! Credentials created with [`PasswordInfo::from_keys`] are exported with the `StoredKey` and the
+//! `ServerKey` instead of the salted password.
+//!
+//! The salted passwords allow impersonating the users to other servers, so exports must be
+//! protected like the credential database itself.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::digest::{self, SHA256};
+use ring::hmac::{self, HMAC_SHA256};
+
+use crate::mechanism::Mechanism;
+use crate::server::PasswordInfo;
+
+/// The version of the export format written by this crate.
+pub const VERSION: u32 = 1;
+
+/// The prefix of a digest without a key.
+const SHA256_PREFIX: &str = "sha256:";
+/// The prefix of a digest with a key.
+const HMAC_PREFIX: &str = "hmac-sha256:";
+
+/// The credentials of a user for one mechanism, as stored in a [`CredentialExport`].
+///
+/// Credentials are ordered by the username first and the mechanism second.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExportedCredential {
+    /// The username the client sends.
+    pub username: String,
+    /// The IANA registered name of the mechanism.
+    pub mechanism: String,
+    /// The iteration count of the salted password.
+    pub iterations: u16,
+    /// The base64 encoded salt.
+    pub salt: String,
+    /// The base64 encoded salted password, or `None` if the credential holds the keys.
+    pub salted_password: Option<String>,
+    /// The base64 encoded `StoredKey`, or `None` if the credential holds the salted password.
+    pub stored_key: Option<String>,
+    /// The base64 encoded `ServerKey`, or `None` if the credential holds the salted password.
+    pub server_key: Option<String>,
+    /// The canonical identity of the user, see [`PasswordInfo::with_identity`].
+    pub identity: Option<String>,
+}
+
+/// A set of credentials with a digest of their contents.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CredentialExport {
+    /// The version of the format, [`VERSION`].
+    pub version: u32,
+    /// The exported credentials.
+    pub credentials: Vec<ExportedCredential>,
+    /// The digest of the version and the credentials, `sha256:` or `hmac-sha256:` followed by the
+    /// base64 encoded digest.
+    pub digest: String,
+}
+
+/// A credential returned by [`CredentialExport::import`].
+pub struct ImportedCredential {
+    /// The username the client sends.
+    pub username: String,
+    /// The mechanism the credential is used with.
+    pub mechanism: Mechanism,
+    /// The credential, ready to be returned by an
+    /// [`AuthenticationProvider`](crate::AuthenticationProvider).
+    pub password_info: PasswordInfo,
+}
+
+impl fmt::Debug for ImportedCredential {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ImportedCredential")
+            .field("username", &self.username)
+            .field("mechanism", &self.mechanism)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The reasons an import can fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImportError {
+    /// The document has a version this crate can't read.
+    UnsupportedVersion(u32),
+    /// The digest is malformed, doesn't match the contents or wasn't computed with the key passed
+    /// to the import.
+    Digest,
+    /// The credential at `index` is invalid.
+    InvalidCredential {
+        /// The index of the credential in the document.
+        index: usize,
+        /// What's wrong with the credential.
+        reason: &'static str,
+    },
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ImportError::UnsupportedVersion(version) => {
+                write!(fmt, "Unsupported export version {}", version)
+            }
+            ImportError::Digest => write!(fmt, "The digest of the export doesn't match"),
+            ImportError::InvalidCredential { index, reason } => {
+                write!(fmt, "Invalid credential {}: {}", index, reason)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ImportError {}
+
+/// Returns the input of the digest of `version` and `credentials`.
+fn digest_input(version: u32, credentials: &[ExportedCredential]) -> Vec<u8> {
+    // Every value is prefixed with its length, so the boundaries between the values are covered.
+    // Optional values are prefixed with whether they are present.
+    fn push(input: &mut Vec<u8>, value: &[u8]) {
+        input.extend_from_slice(&(value.len() as u64).to_be_bytes());
+        input.extend_from_slice(value);
+    }
+    fn push_optional(input: &mut Vec<u8>, value: Option<&String>) {
+        match value {
+            Some(value) => {
+                input.push(1);
+                push(input, value.as_bytes());
+            }
+            None => input.push(0),
+        }
+    }
+
+    let mut input = Vec::new();
+    input.extend_from_slice(&version.to_be_bytes());
+    for credential in credentials {
+        push(&mut input, credential.username.as_bytes());
+        push(&mut input, credential.mechanism.as_bytes());
+        push(&mut input, &credential.iterations.to_be_bytes());
+        push(&mut input, credential.salt.as_bytes());
+        push_optional(&mut input, credential.salted_password.as_ref());
+        push_optional(&mut input, credential.stored_key.as_ref());
+        push_optional(&mut input, credential.server_key.as_ref());
+        push_optional(&mut input, credential.identity.as_ref());
+    }
+    input
+}
+
+/// Computes the digest of `version` and `credentials` with or without `key`.
+fn compute_digest(version: u32, credentials: &[ExportedCredential], key: Option<&[u8]>) -> String {
+    let input = digest_input(version, credentials);
+    match key {
+        Some(key) => {
+            let tag = hmac::sign(&hmac::Key::new(HMAC_SHA256, key), &input);
+            format!("{}{}", HMAC_PREFIX, STANDARD.encode(tag.as_ref()))
+        }
+        None => {
+            let digest = digest::digest(&SHA256, &input);
+            format!("{}{}", SHA256_PREFIX, STANDARD.encode(digest.as_ref()))
+        }
+    }
+}
+
+/// Checks `credential` and converts it.
+fn import_credential(credential: &ExportedCredential) -> Result<ImportedCredential, &'static str> {
+    if credential.username.is_empty() || credential.username.contains('\0') {
+        return Err("invalid username");
+    }
+    let mechanism = Mechanism::from_name(&credential.mechanism).ok_or("unsupported mechanism")?;
+    if credential.iterations == 0 {
+        return Err("zero iterations");
+    }
+    let salt = STANDARD
+        .decode(&credential.salt)
+        .map_err(|_| "invalid salt encoding")?;
+    if salt.is_empty() {
+        return Err("empty salt");
+    }
+    let decode = |value: &str, encoding, length| {
+        let value = STANDARD.decode(value).map_err(|_| encoding)?;
+        if value.len() == mechanism.output_len() {
+            Ok(value)
+        } else {
+            Err(length)
+        }
+    };
+    let keys = (&credential.stored_key, &credential.server_key);
+    let mut password_info = match (&credential.salted_password, keys) {
+        (Some(salted_password), (None, None)) => {
+            let salted_password = decode(
+                salted_password,
+                "invalid salted password encoding",
+                "salted password of the wrong length",
+            )?;
+            PasswordInfo::new(salted_password, credential.iterations, salt)
+        }
+        (None, (Some(stored_key), Some(server_key))) => {
+            let invalid = ("invalid key encoding", "key of the wrong length");
+            let stored_key = decode(stored_key, invalid.0, invalid.1)?;
+            let server_key = decode(server_key, invalid.0, invalid.1)?;
+            PasswordInfo::from_keys(stored_key, server_key, credential.iterations, salt)
+        }
+        _ => return Err("not either a salted password or both keys"),
+    };
+    if let Some(ref identity) = credential.identity {
+        password_info = password_info.with_identity(identity.as_str());
+    }
+    Ok(ImportedCredential {
+        username: credential.username.clone(),
+        mechanism,
+        password_info,
+    })
+}
+
+impl CredentialExport {
+    /// Exports `credentials`, the username, mechanism and credential of every user, and computes
+    /// the digest with `key` if it's given. Credentials created with [`PasswordInfo::from_keys`]
+    /// are exported with the keys.
+    pub fn new<'a, I>(credentials: I, key: Option<&[u8]>) -> Self
+    where
+        I: IntoIterator<Item = (&'a str, Mechanism, &'a PasswordInfo)>,
+    {
+        let credentials: Vec<_> = credentials
+            .into_iter()
+            .map(|(username, mechanism, password_info)| {
+                let keys = password_info.keys();
+                ExportedCredential {
+                    username: username.to_string(),
+                    mechanism: mechanism.name().to_string(),
+                    iterations: password_info.iterations(),
+                    salt: STANDARD.encode(password_info.salt()),
+                    salted_password: password_info
+                        .hashed_password()
+                        .map(|salted_password| STANDARD.encode(salted_password)),
+                    stored_key: keys.map(|(stored_key, _)| STANDARD.encode(stored_key)),
+                    server_key: keys.map(|(_, server_key)| STANDARD.encode(server_key)),
+                    identity: password_info.identity().map(ToString::to_string),
+                }
+            })
+            .collect();
+        let digest = compute_digest(VERSION, &credentials, key);
+        CredentialExport {
+            version: VERSION,
+            credentials,
+            digest,
+        }
+    }
+
+    /// Checks the digest with `key`, which must be the key passed to [`CredentialExport::new`],
+    /// and every credential, and returns the credentials.
+    ///
+    /// Fails with `ImportError::InvalidCredential` if a username is empty or contains a NUL, the
+    /// mechanism isn't supported by this build of the crate, the iteration count is zero, the salt
+    /// is empty, a credential doesn't hold either a salted password or both keys, the salted
+    /// password or a key has the wrong length or a user has two credentials for the same
+    /// mechanism.
+    pub fn import(&self, key: Option<&[u8]>) -> Result<Vec<ImportedCredential>, ImportError> {
+        if self.version != VERSION {
+            return Err(ImportError::UnsupportedVersion(self.version));
+        }
+        let matches = match key {
+            // `hmac::verify` compares the tags in constant time.
+            Some(key) => self
+                .digest
+                .strip_prefix(HMAC_PREFIX)
+                .and_then(|tag| STANDARD.decode(tag).ok())
+                .is_some_and(|tag| {
+                    let input = digest_input(self.version, &self.credentials);
+                    hmac::verify(&hmac::Key::new(HMAC_SHA256, key), &input, &tag).is_ok()
+                }),
+            None => self.digest == compute_digest(self.version, &self.credentials, None),
+        };
+        if !matches {
+            return Err(ImportError::Digest);
+        }
+
+        let mut imported: Vec<ImportedCredential> = Vec::with_capacity(self.credentials.len());
+        for (index, credential) in self.credentials.iter().enumerate() {
+            let credential = import_credential(credential)
+                .map_err(|reason| ImportError::InvalidCredential { index, reason })?;
+            if imported.iter().any(|other| {
+                other.username == credential.username && other.mechanism == credential.mechanism
+            }) {
+                return Err(ImportError::InvalidCredential {
+                    index,
+                    reason: "duplicate user and mechanism",
+                });
+            }
+            imported.push(credential);
+        }
+        Ok(imported)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::{compute_digest, CredentialExport, ImportError, VERSION};
+    use crate::credentials::derive_credentials;
+    use crate::mechanism::Mechanism;
+    use crate::server::PasswordInfo;
+    use crate::utils::hash_password;
+
+    fn password_info(password: &str) -> PasswordInfo {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let hashed_password = hash_password(password, iterations, b"salt").to_vec();
+        PasswordInfo::new(hashed_password, 4096, b"salt".to_vec())
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let user = password_info("pencil");
+        let admin = password_info("secret").with_identity("root");
+        let credentials = [
+            ("user", Mechanism::ScramSha256, &user),
+            ("admin", Mechanism::ScramSha256, &admin),
+        ];
+        for key in [None, Some(&b"key"[..])] {
+            let export = CredentialExport::new(credentials.iter().copied(), key);
+            assert_eq!(export.credentials[0].mechanism, "SCRAM-SHA-256");
+            assert_eq!(export.credentials[0].salt, "c2FsdA==");
+            let imported = export.import(key).unwrap();
+            assert_eq!(imported.len(), 2);
+            assert_eq!(imported[1].username, "admin");
+            assert_eq!(imported[1].mechanism, Mechanism::ScramSha256);
+            assert_eq!(imported[1].password_info.identity(), Some("root"));
+            assert_eq!(
+                imported[0].password_info.hashed_password(),
+                user.hashed_password()
+            );
+        }
+    }
+
+    #[test]
+    fn test_integrity() {
+        let user = password_info("pencil");
+        let export = CredentialExport::new(vec![("user", Mechanism::ScramSha256, &user)], None);
+        assert_eq!(
+            export.import(Some(b"key")).unwrap_err(),
+            ImportError::Digest
+        );
+        let signed =
+            CredentialExport::new(vec![("user", Mechanism::ScramSha256, &user)], Some(b"key"));
+        assert!(signed.digest.starts_with("hmac-sha256:"));
+        assert_eq!(signed.import(None).unwrap_err(), ImportError::Digest);
+        assert_eq!(
+            signed.import(Some(b"other")).unwrap_err(),
+            ImportError::Digest
+        );
+
+        let mut modified = signed.clone();
+        modified.credentials[0].iterations = 1;
+        assert_eq!(
+            modified.import(Some(b"key")).unwrap_err(),
+            ImportError::Digest
+        );
+        let mut modified = signed.clone();
+        modified.version = 3;
+        assert_eq!(
+            modified.import(Some(b"key")).unwrap_err(),
+            ImportError::UnsupportedVersion(3)
+        );
+    }
+
+    #[test]
+    fn test_invalid_credentials() {
+        let user = password_info("pencil");
+        let credentials = vec![
+            ("user", Mechanism::ScramSha256, &user),
+            ("user", Mechanism::ScramSha256, &user),
+        ];
+        let export = CredentialExport::new(credentials, None);
+        assert_eq!(
+            export.import(None).unwrap_err(),
+            ImportError::InvalidCredential {
+                index: 1,
+                reason: "duplicate user and mechanism"
+            }
+        );
+
+        let reasons = [
+            "invalid username",
+            "unsupported mechanism",
+            "zero iterations",
+            "empty salt",
+            "salted password of the wrong length",
+        ];
+        for reason in reasons {
+            let mut export =
+                CredentialExport::new(vec![("user", Mechanism::ScramSha256, &user)], None);
+            let credential = &mut export.credentials[0];
+            match reason {
+                "invalid username" => credential.username.clear(),
+                "unsupported mechanism" => credential.mechanism = "SCRAM-SHA-1".into(),
+                "zero iterations" => credential.iterations = 0,
+                "empty salt" => credential.salt.clear(),
+                _ => credential.salted_password = Some("c2FsdA==".into()),
+            }
+            // Re-sealed, so only the checks of the credentials fail.
+            export.digest = super::compute_digest(export.version, &export.credentials, None);
+            assert_eq!(
+                export.import(None).unwrap_err(),
+                ImportError::InvalidCredential { index: 0, reason }
+            );
+        }
+    }
+
+    #[test]
+    fn test_keys() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let credentials = derive_credentials(
+            "pencil",
+            Mechanism::ScramSha256,
+            iterations,
+            b"salt".to_vec(),
+        );
+        let (stored_key, server_key) = (&credentials.stored_key, &credentials.server_key);
+        let user = PasswordInfo::from_keys(
+            stored_key.clone(),
+            server_key.clone(),
+            4096,
+            b"salt".to_vec(),
+        );
+        let export = CredentialExport::new(vec![("user", Mechanism::ScramSha256, &user)], None);
+        assert_eq!(export.version, VERSION);
+        assert_eq!(export.credentials[0].salted_password, None);
+        let imported = export.import(None).unwrap();
+        assert_eq!(
+            imported[0].password_info.keys(),
+            Some((&stored_key[..], &server_key[..]))
+        );
+        assert_eq!(imported[0].password_info.hashed_password(), None);
+
+        let mut invalid = export.clone();
+        invalid.credentials[0].salted_password =
+            Some(STANDARD.encode(&credentials.salted_password));
+        invalid.digest = compute_digest(invalid.version, &invalid.credentials, None);
+        assert_eq!(
+            invalid.import(None).unwrap_err(),
+            ImportError::InvalidCredential {
+                index: 0,
+                reason: "not either a salted password or both keys"
+            }
+        );
+        let mut invalid = export;
+        invalid.credentials[0].server_key = Some("c2FsdA==".into());
+        invalid.digest = compute_digest(invalid.version, &invalid.credentials, None);
+        assert_eq!(
+            invalid.import(None).unwrap_err(),
+            ImportError::InvalidCredential {
+                index: 0,
+                reason: "key of the wrong length"
+            }
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let user = password_info("pencil");
+        let export = CredentialExport::new(vec![("user", Mechanism::ScramSha256, &user)], None);
+        let json = serde_json::to_string(&export).unwrap();
+        assert!(json.contains(r#""mechanism":"SCRAM-SHA-256""#));
+        let parsed: CredentialExport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, export);
+        assert_eq!(parsed.import(None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,347 @@
+//! Negotiating the mechanism of a handshake with fallback to weaker mechanisms.
+//!
+//! Servers advertise the mechanisms they support, for example in an IMAP `CAPABILITY` response or a
+//! PostgreSQL `AuthenticationSASL` message. [`Fallback::run`] tries the advertised mechanisms in a
+//! configurable order of preference and moves on to the next one when the server rejects a
+//! mechanism for a reason another mechanism may not run into, like missing channel-binding
+//! support. Failures which would repeat with every mechanism, like a wrong password, and failures
+//! hinting at an attack, like mismatching channel bindings, end the negotiation.
+//!
+//! The handshake is passed the mechanism and the [`Binding`] of the attempt, which tells the
+//! client which channel-binding flag RFC5802 section 6 requires. A server which rejects the
+//! channel binding of a `-PLUS` variant as unsupported is retried with the mechanism without
+//! channel binding and the `n` flag, since a server which advertised the `-PLUS` variant fails a
+//! handshake with the `y` flag as a downgrade.
+//!
+//! ```rust,no_run
+//! use scram::channel_binding::ChannelBinding;
+//! use scram::fallback::{Binding, Fallback};
+//! use scram::{Error, Mechanism, ScramClient};
+//!
+//! // This function represents your I/O implementation of one handshake.
+//! # #[allow(unused_variables)]
+//! fn handshake(mechanism: Mechanism, client: ScramClient) -> Result<(), Error> {
+//!     unimplemented!()
+//! }
+//! # let exporter = [0; 32];
+//!
+//! let advertised = ["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"];
+//! let (mechanism, ()) = Fallback::new()
+//!     .run(&advertised, |mechanism, binding| {
+//!         let client = ScramClient::new("user", "password", None).with_mechanism(mechanism);
+//!         let client = match binding {
+//!             Binding::Required => {
+//!                 client.with_channel_binding(ChannelBinding::TlsExporter(&exporter))
+//!             }
+//!             Binding::Unbound => client,
+//!             Binding::NotAdvertised => client.supports_channel_binding(),
+//!         };
+//!         handshake(mechanism, client)
+//!     })
+//!     .unwrap();
+//! ```
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::error::{ChannelBindingError, Error};
+use crate::mechanism::Mechanism;
+
+/// The default order of preference, strongest first.
+const DEFAULT_ORDER: &[&str] = &[
+    "SCRAM-SHA-512-PLUS",
+    "SCRAM-SHA-512",
+    "SCRAM-SHA-384-PLUS",
+    "SCRAM-SHA-384",
+    "SCRAM-SHA-256-PLUS",
+    "SCRAM-SHA-256",
+];
+
+/// The suffix of the mechanism names which bind the handshake to the TLS channel.
+const PLUS_SUFFIX: &str = "-PLUS";
+
+/// How the client of an attempt uses channel binding, as required by RFC5802 section 6.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Binding {
+    /// The attempt is the `-PLUS` variant, so the client binds to the channel with `p=`. See
+    /// [`ScramClient::with_channel_binding`](crate::ScramClient::with_channel_binding).
+    Required,
+    /// The server advertised the `-PLUS` variant of the mechanism, but the attempt doesn't bind,
+    /// for example after the server rejected the channel-binding type of the client. The client
+    /// sends `n`, the default of [`ScramClient`](crate::ScramClient).
+    Unbound,
+    /// The server didn't advertise the `-PLUS` variant of the mechanism. A client which supports
+    /// channel binding sends `y`, see
+    /// [`ScramClient::supports_channel_binding`](crate::ScramClient::supports_channel_binding).
+    NotAdvertised,
+}
+
+/// Returns the mechanism with the SASL mechanism `name` and whether it's the `-PLUS` variant.
+fn parse_name(name: &str) -> Option<(Mechanism, bool)> {
+    match name.strip_suffix(PLUS_SUFFIX) {
+        Some(name) => Mechanism::from_name(name).map(|mechanism| (mechanism, true)),
+        None => Mechanism::from_name(name).map(|mechanism| (mechanism, false)),
+    }
+}
+
+/// An error of a handshake attempt which may stem from the SCRAM state machine.
+///
+/// It's implemented for [`Error`] and the driver errors of this crate, so [`Fallback::run`] can
+/// decide whether to try the next mechanism.
+pub trait HandshakeError {
+    /// Returns the SCRAM error, or `None` if the attempt failed for another reason, like an I/O
+    /// error.
+    fn scram_error(&self) -> Option<&Error>;
+}
+
+impl HandshakeError for Error {
+    fn scram_error(&self) -> Option<&Error> {
+        Some(self)
+    }
+}
+
+/// Returns whether a handshake which failed with `error` should be retried with the next
+/// mechanism.
+///
+/// This is the case if the server rejected the channel binding of the client as unsupported or
+/// the server doesn't support an extension. Wrong credentials, mismatching channel bindings and
+/// downgrade reports end the negotiation.
+pub fn should_fall_back(error: &Error) -> bool {
+    match *error {
+        Error::UnsupportedExtension => true,
+        Error::ChannelBinding(ChannelBindingError::NotSupported)
+        | Error::ChannelBinding(ChannelBindingError::UnsupportedType(_)) => true,
+        Error::Authentication(ref value) => matches!(
+            value.as_str(),
+            "channel-binding-not-supported"
+                | "unsupported-channel-binding-type"
+                | "extensions-not-supported"
+        ),
+        _ => false,
+    }
+}
+
+/// The reasons a negotiation can fail.
+#[derive(Debug, PartialEq)]
+pub enum FallbackError<E> {
+    /// None of the advertised mechanisms is in the order of preference and supported by this
+    /// build of the crate.
+    NoMechanism,
+    /// The attempt with the contained mechanism failed with an error which doesn't warrant a
+    /// fallback, or the last mechanism failed.
+    Failed(Mechanism, E),
+}
+
+impl<E: fmt::Display> fmt::Display for FallbackError<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FallbackError::NoMechanism => write!(fmt, "No common mechanism"),
+            FallbackError::Failed(mechanism, ref err) => {
+                write!(fmt, "{} failed: {}", mechanism, err)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug + fmt::Display> error::Error for FallbackError<E> {}
+
+/// Tries the mechanisms advertised by a server in an order of preference.
+#[derive(Clone, Debug)]
+pub struct Fallback {
+    order: Vec<&'static str>,
+}
+
+impl Default for Fallback {
+    fn default() -> Self {
+        Fallback::new()
+    }
+}
+
+impl Fallback {
+    /// Creates a negotiation which prefers the stronger hash functions, `SCRAM-SHA-512` over
+    /// `SCRAM-SHA-384` over `SCRAM-SHA-256`, and the `-PLUS` variant of each.
+    pub fn new() -> Self {
+        Fallback::with_order(DEFAULT_ORDER)
+    }
+
+    /// Creates a negotiation which tries the mechanism names in `order`, most preferred first.
+    /// Mechanisms missing in `order` are never tried.
+    pub fn with_order(order: &[&'static str]) -> Self {
+        Fallback {
+            order: order.to_vec(),
+        }
+    }
+
+    /// Returns the mechanisms which would be tried for the `advertised` mechanism names, in
+    /// order, with the channel binding of each attempt. Mechanisms which this build of the crate
+    /// doesn't implement are skipped.
+    pub fn candidates<S: AsRef<str>>(&self, advertised: &[S]) -> Vec<(Mechanism, Binding)> {
+        let is_advertised = |name: &str| advertised.iter().any(|offer| offer.as_ref() == name);
+        self.order
+            .iter()
+            .filter(|name| is_advertised(name))
+            .filter_map(|name| parse_name(name))
+            .map(|(mechanism, plus)| {
+                let binding = if plus {
+                    Binding::Required
+                } else if is_advertised(mechanism.plus_name()) {
+                    Binding::Unbound
+                } else {
+                    Binding::NotAdvertised
+                };
+                (mechanism, binding)
+            })
+            .collect()
+    }
+
+    /// Runs `handshake` with the candidate mechanisms for the `advertised` mechanism names until
+    /// it succeeds or fails with an error for which [`should_fall_back`] returns `false`.
+    /// `handshake` is passed the mechanism and the channel binding of the attempt. Returns the
+    /// mechanism which succeeded and the output of the handshake.
+    pub fn run<S, T, E, F>(
+        &self,
+        advertised: &[S],
+        mut handshake: F,
+    ) -> Result<(Mechanism, T), FallbackError<E>>
+    where
+        S: AsRef<str>,
+        E: HandshakeError,
+        F: FnMut(Mechanism, Binding) -> Result<T, E>,
+    {
+        let mut candidates = self.candidates(advertised).into_iter().peekable();
+        while let Some((mechanism, binding)) = candidates.next() {
+            match handshake(mechanism, binding) {
+                Ok(output) => return Ok((mechanism, output)),
+                Err(err) => {
+                    let fall_back = err.scram_error().is_some_and(should_fall_back);
+                    if !fall_back || candidates.peek().is_none() {
+                        return Err(FallbackError::Failed(mechanism, err));
+                    }
+                }
+            }
+        }
+        Err(FallbackError::NoMechanism)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    use super::{should_fall_back, Binding, Fallback, FallbackError};
+    use crate::{ChannelBindingError, Error, Mechanism};
+
+    #[test]
+    fn test_candidates() {
+        let fallback = Fallback::new();
+        assert_eq!(
+            fallback.candidates(&["SCRAM-SHA-1", "SCRAM-SHA-256"]),
+            [(Mechanism::ScramSha256, Binding::NotAdvertised)]
+        );
+        assert!(fallback.candidates(&["SCRAM-SHA-1", "PLAIN"]).is_empty());
+        assert_eq!(
+            fallback.candidates(&["SCRAM-SHA-256", "SCRAM-SHA-384", "SCRAM-SHA-512"]),
+            [
+                (Mechanism::ScramSha512, Binding::NotAdvertised),
+                (Mechanism::ScramSha384, Binding::NotAdvertised),
+                (Mechanism::ScramSha256, Binding::NotAdvertised)
+            ]
+        );
+        assert_eq!(
+            fallback.candidates(&["SCRAM-SHA-256", "SCRAM-SHA-256-PLUS", "SCRAM-SHA-1-PLUS"]),
+            [
+                (Mechanism::ScramSha256, Binding::Required),
+                (Mechanism::ScramSha256, Binding::Unbound)
+            ]
+        );
+        // The client sends `n` even if the `-PLUS` variant isn't tried.
+        let fallback = Fallback::with_order(&["SCRAM-SHA-256"]);
+        assert_eq!(
+            fallback.candidates(&["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"]),
+            [(Mechanism::ScramSha256, Binding::Unbound)]
+        );
+        let fallback = Fallback::with_order(&["SCRAM-SHA-1"]);
+        assert!(fallback.candidates(&["SCRAM-SHA-256"]).is_empty());
+    }
+
+    #[test]
+    fn test_should_fall_back() {
+        assert!(should_fall_back(&Error::Authentication(
+            "channel-binding-not-supported".to_string()
+        )));
+        assert!(should_fall_back(&Error::ChannelBinding(
+            ChannelBindingError::NotSupported
+        )));
+        assert!(!should_fall_back(&Error::Authentication(
+            "invalid-proof".to_string()
+        )));
+        assert!(!should_fall_back(&Error::ChannelBinding(
+            ChannelBindingError::Mismatch
+        )));
+        assert!(!should_fall_back(&Error::InvalidServer));
+    }
+
+    #[test]
+    fn test_run() {
+        let mut attempts = 0;
+        let result = Fallback::new().run(&["SCRAM-SHA-256"], |mechanism, binding| {
+            attempts += 1;
+            assert_eq!(binding, Binding::NotAdvertised);
+            assert_eq!(mechanism, Mechanism::ScramSha256);
+            Err::<(), _>(Error::UnsupportedExtension)
+        });
+        assert_eq!(
+            result,
+            Err(FallbackError::Failed(
+                Mechanism::ScramSha256,
+                Error::UnsupportedExtension
+            ))
+        );
+        assert_eq!(attempts, 1);
+        // Listing a mechanism twice retries it, the only way to fall back with one mechanism.
+        let fallback = Fallback::with_order(&["SCRAM-SHA-256", "SCRAM-SHA-256"]);
+        let mut errors = vec![Error::UnsupportedExtension];
+        let result = fallback.run(&["SCRAM-SHA-256"], |_, _| errors.pop().map_or(Ok(()), Err));
+        assert_eq!(result, Ok((Mechanism::ScramSha256, ())));
+        let mut errors = vec![Error::InvalidServer];
+        let result = fallback.run(&["SCRAM-SHA-256"], |_, _| errors.pop().map_or(Ok(()), Err));
+        assert_eq!(
+            result,
+            Err(FallbackError::Failed(
+                Mechanism::ScramSha256,
+                Error::InvalidServer
+            ))
+        );
+        let result = Fallback::new().run(&["PLAIN"], |_, _| Ok::<_, Error>(()));
+        assert_eq!(result, Err(FallbackError::NoMechanism));
+        let result = Fallback::new().run(&["SCRAM-SHA-256"], |_, _| Ok::<_, Error>(42));
+        assert_eq!(result, Ok((Mechanism::ScramSha256, 42)));
+    }
+
+    #[test]
+    fn test_run_plus() {
+        let advertised = ["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"];
+        let mut attempts = Vec::new();
+        let result = Fallback::new().run(&advertised, |mechanism, binding| {
+            attempts.push((mechanism, binding));
+            if binding == Binding::Required {
+                Err(Error::Authentication(
+                    "channel-binding-not-supported".to_string(),
+                ))
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result, Ok((Mechanism::ScramSha256, ())));
+        assert_eq!(
+            attempts,
+            [
+                (Mechanism::ScramSha256, Binding::Required),
+                (Mechanism::ScramSha256, Binding::Unbound)
+            ]
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Durable, atomic replacement of the files which the stores of this crate persist secrets in.
+use core::sync::atomic::{AtomicUsize, Ordering};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// The number of saves of this process, which names their temporary files.
+static SAVES: AtomicUsize = AtomicUsize::new(0);
+
+/// Writes `data` to a new temporary file next to `path`, readable only by the owner on Unix, and
+/// renames it over `path` once it's on disk, so a crash leaves either the old or the new file.
+pub(crate) fn write_atomically(path: &Path, data: &[u8]) -> io::Result<()> {
+    // Unique within the process and across processes, so concurrent saves don't share the file.
+    let count = SAVES.fetch_add(1, Ordering::Relaxed);
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(format!(".{}.{}.tmp", process::id(), count));
+    let temporary = PathBuf::from(temporary);
+    let result = write_new(&temporary, data).and_then(|()| fs::rename(&temporary, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary);
+    }
+    result?;
+    sync_directory(path)
+}
+
+/// Creates the file at `path`, which must not exist, and writes `data` to the disk.
+fn write_new(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Writes the directory entry of the renamed file at `path` to the disk. Only Unix can open a
+/// directory for this.
+#[cfg(unix)]
+fn sync_directory(path: &Path) -> io::Result<()> {
+    let directory = match path.parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => directory,
+        _ => Path::new("."),
+    };
+    fs::File::open(directory)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_directory(_: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! Length-prefixed framing of SASL messages for binary protocols.
+//!
+//! Protocols like the memcached binary protocol carry every SASL message as a blob after its
+//! length in network byte order. A [`FrameDecoder`] collects the bytes of partial reads until a
+//! message is complete, and a [`PendingFrame`] tracks how much of an encoded message a partial
+//! write has sent, so non-blocking connections only have to move bytes. With the `std` feature a
+//! [`FramedStream`] does both over any blocking `Read + Write` connection:
+//!
+//! ``` rust,no_run
+//! use std::net::TcpStream;
+//! use scram::framing::{FramedStream, Prefix};
+//! use scram::ScramClient;
+//!
+//! let mut stream = FramedStream::new(TcpStream::connect("db:5000").unwrap(), Prefix::U32);
+//! let (client, client_first) = ScramClient::new("user", "password", None).client_first();
+//! stream.send(&client_first).unwrap();
+//! let client = client.handle_server_first(&stream.receive().unwrap()).unwrap();
+//! let (client, client_final) = client.client_final();
+//! stream.send(&client_final).unwrap();
+//! client.handle_server_final(&stream.receive().unwrap()).unwrap();
+//! ```
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+/// The default maximum length of a message in bytes. SCRAM messages are far shorter.
+pub const DEFAULT_MAX_LEN: usize = 64 * 1024;
+
+/// The width of the length before every message, in network byte order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Prefix {
+    /// A 16 bit length.
+    U16,
+    /// A 32 bit length.
+    U32,
+}
+
+impl Prefix {
+    /// Returns the width of the length in bytes.
+    pub fn width(self) -> usize {
+        match self {
+            Prefix::U16 => 2,
+            Prefix::U32 => 4,
+        }
+    }
+
+    /// Returns the length of the message starting with `header`, which holds at least
+    /// [`Prefix::width`] bytes.
+    fn read(self, header: &[u8]) -> usize {
+        match self {
+            Prefix::U16 => usize::from(u16::from_be_bytes([header[0], header[1]])),
+            Prefix::U32 => {
+                let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+                usize::try_from(len).unwrap_or(usize::MAX)
+            }
+        }
+    }
+}
+
+/// The reasons framing can fail.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FramingError {
+    /// The message is longer than the maximum length or can't be expressed by the prefix.
+    TooLong(usize),
+    /// The received message isn't valid UTF-8.
+    InvalidUtf8,
+}
+
+impl fmt::Display for FramingError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FramingError::TooLong(len) => write!(fmt, "Message of {} bytes is too long", len),
+            FramingError::InvalidUtf8 => write!(fmt, "Message isn't valid UTF-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for FramingError {}
+
+#[cfg(feature = "std")]
+impl From<FramingError> for io::Error {
+    fn from(err: FramingError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Encodes `message` with its length. Fails with `FramingError::TooLong` if the length doesn't fit
+/// into `prefix` or exceeds `max_len`.
+pub fn encode(prefix: Prefix, max_len: usize, message: &[u8]) -> Result<Vec<u8>, FramingError> {
+    let too_long = FramingError::TooLong(message.len());
+    if message.len() > max_len {
+        return Err(too_long);
+    }
+    let mut frame = Vec::with_capacity(prefix.width() + message.len());
+    match prefix {
+        Prefix::U16 => {
+            let len = u16::try_from(message.len()).map_err(|_| too_long)?;
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+        Prefix::U32 => {
+            let len = u32::try_from(message.len()).map_err(|_| too_long)?;
+            frame.extend_from_slice(&len.to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(message);
+    Ok(frame)
+}
+
+/// Collects received bytes and splits them into messages.
+#[derive(Clone, Debug)]
+pub struct FrameDecoder {
+    prefix: Prefix,
+    max_len: usize,
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder of messages up to [`DEFAULT_MAX_LEN`] bytes.
+    pub fn new(prefix: Prefix) -> Self {
+        FrameDecoder {
+            prefix,
+            max_len: DEFAULT_MAX_LEN,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Sets the maximum length of a message. Longer messages fail with `FramingError::TooLong`
+    /// as soon as their length is received, before their contents are buffered.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    /// Appends the bytes of a read.
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns the number of bytes missing from the next message, or the length if it hasn't been
+    /// received yet. Zero means a message is complete.
+    pub fn missing(&self) -> usize {
+        let header = self.prefix.width();
+        if self.buffer.len() < header {
+            return header - self.buffer.len();
+        }
+        let len = self.prefix.read(&self.buffer);
+        (header.saturating_add(len)).saturating_sub(self.buffer.len())
+    }
+
+    /// Returns the next complete message, or `None` if more bytes are needed.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FramingError> {
+        let header = self.prefix.width();
+        if self.buffer.len() < header {
+            return Ok(None);
+        }
+        let len = self.prefix.read(&self.buffer);
+        if len > self.max_len {
+            return Err(FramingError::TooLong(len));
+        }
+        if self.buffer.len() < header + len {
+            return Ok(None);
+        }
+        let frame = self.buffer[header..header + len].to_vec();
+        self.buffer.drain(..header + len);
+        Ok(Some(frame))
+    }
+
+    /// Returns the next complete message as a string, or `None` if more bytes are needed.
+    pub fn next_message(&mut self) -> Result<Option<String>, FramingError> {
+        match self.next_frame()? {
+            Some(frame) => String::from_utf8(frame)
+                .map(Some)
+                .map_err(|_| FramingError::InvalidUtf8),
+            None => Ok(None),
+        }
+    }
+}
+
+/// An encoded message of which a part may already have been written.
+#[derive(Clone, Debug)]
+pub struct PendingFrame {
+    frame: Vec<u8>,
+    written: usize,
+}
+
+impl PendingFrame {
+    /// Encodes `message` like [`encode`].
+    pub fn new(prefix: Prefix, max_len: usize, message: &[u8]) -> Result<Self, FramingError> {
+        Ok(PendingFrame {
+            frame: encode(prefix, max_len, message)?,
+            written: 0,
+        })
+    }
+
+    /// Returns the bytes which haven't been written yet.
+    pub fn remaining(&self) -> &[u8] {
+        &self.frame[self.written..]
+    }
+
+    /// Marks `written` more bytes as written.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `written` is larger than the number of remaining bytes.
+    pub fn advance(&mut self, written: usize) {
+        assert!(written <= self.remaining().len(), "advanced past the frame");
+        self.written += written;
+    }
+
+    /// Returns whether the whole frame has been written.
+    pub fn is_done(&self) -> bool {
+        self.written == self.frame.len()
+    }
+}
+
+/// Sends and receives length-prefixed messages over a blocking connection.
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct FramedStream<S> {
+    stream: S,
+    decoder: FrameDecoder,
+}
+
+#[cfg(feature = "std")]
+impl<S: Read + Write> FramedStream<S> {
+    /// Wraps `stream`, accepting messages up to [`DEFAULT_MAX_LEN`] bytes.
+    pub fn new(stream: S, prefix: Prefix) -> Self {
+        FramedStream {
+            stream,
+            decoder: FrameDecoder::new(prefix),
+        }
+    }
+
+    /// Sets the maximum length of a message in both directions.
+    pub fn max_len(mut self, max_len: usize) -> Self {
+        self.decoder = self.decoder.max_len(max_len);
+        self
+    }
+
+    /// Sends `message` and flushes the connection. Fails with `InvalidData` if it's too long.
+    pub fn send(&mut self, message: &str) -> io::Result<()> {
+        let frame = encode(
+            self.decoder.prefix,
+            self.decoder.max_len,
+            message.as_bytes(),
+        )?;
+        self.stream.write_all(&frame)?;
+        self.stream.flush()
+    }
+
+    /// Receives the next message. Fails with `InvalidData` if it's too long or not valid UTF-8,
+    /// and with `UnexpectedEof` if the connection is closed before the message is complete.
+    pub fn receive(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(message) = self.decoder.next_message()? {
+                return Ok(message);
+            }
+            let mut buffer = vec![0; self.decoder.missing().min(self.decoder.max_len)];
+            let read = self.stream.read(&mut buffer)?;
+            if read == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.decoder.push(&buffer[..read]);
+        }
+    }
+
+    /// Returns the wrapped connection. Bytes received after the last message are lost.
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use super::{encode, FrameDecoder, FramedStream, FramingError, PendingFrame, Prefix};
+
+    #[test]
+    fn test_decoder() {
+        let mut data = encode(Prefix::U16, 100, b"n,,n=user,r=abc").unwrap();
+        assert_eq!(&data[..2], &[0, 15]);
+        data.extend(encode(Prefix::U16, 100, b"").unwrap());
+        data.extend(encode(Prefix::U16, 100, b"r=abcdef").unwrap());
+
+        let mut decoder = FrameDecoder::new(Prefix::U16);
+        let mut messages = Vec::new();
+        // Byte by byte, like the shortest partial reads.
+        for byte in data {
+            decoder.push(&[byte]);
+            while let Some(message) = decoder.next_message().unwrap() {
+                messages.push(message);
+            }
+        }
+        assert_eq!(messages, ["n,,n=user,r=abc", "", "r=abcdef"]);
+        assert_eq!(decoder.missing(), 2);
+
+        let mut decoder = FrameDecoder::new(Prefix::U32).max_len(4);
+        decoder.push(&[0, 0, 0, 5]);
+        assert_eq!(decoder.next_frame(), Err(FramingError::TooLong(5)));
+        let mut decoder = FrameDecoder::new(Prefix::U32);
+        decoder.push(&[0, 0, 0, 1, 0xff]);
+        assert_eq!(decoder.next_message(), Err(FramingError::InvalidUtf8));
+        assert_eq!(
+            encode(Prefix::U16, usize::MAX, &[0; 65536]),
+            Err(FramingError::TooLong(65536))
+        );
+    }
+
+    #[test]
+    fn test_pending_frame() {
+        let mut frame = PendingFrame::new(Prefix::U32, 100, b"abc").unwrap();
+        assert_eq!(frame.remaining(), b"\0\0\0\x03abc");
+        frame.advance(5);
+        assert_eq!(frame.remaining(), b"bc");
+        assert!(!frame.is_done());
+        frame.advance(2);
+        assert!(frame.is_done());
+    }
+
+    /// A connection which reads and writes at most two bytes at a time.
+    struct Trickle {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(2);
+            self.input.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = buf.len().min(2);
+            self.output.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_framed_stream() {
+        let mut input = encode(Prefix::U32, 100, b"r=abc,s=c2FsdA==,i=4096").unwrap();
+        input.extend_from_slice(&[0, 0, 0, 9, b'v']);
+        let stream = Trickle {
+            input: Cursor::new(input),
+            output: Vec::new(),
+        };
+        let mut stream = FramedStream::new(stream, Prefix::U32);
+        stream.send("n,,n=user,r=abc").unwrap();
+        assert_eq!(stream.receive().unwrap(), "r=abc,s=c2FsdA==,i=4096");
+        let err = stream.receive().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+        let err = stream.send(&"a".repeat(70000)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            stream.into_inner().output,
+            encode(Prefix::U32, 100, b"n,,n=user,r=abc").unwrap()
+        );
+    }
+}
@@ -0,0 +1,903 @@
+//! Parsers for the messages of RFC5802 section 7.
+//!
+//! Every message is a comma separated list of attributes, a letter followed by `=` and a value.
+//! The parsers check the order of the attributes and the characters of their values against the
+//! ABNF of the RFC, but leave base64 values to the decoder, so the client can accept the base64
+//! variants enabled in its [`Lenience`](crate::interop::Lenience). Unescaping usernames is left
+//! to the caller as well.
+//!
+//! Two deviations from the ABNF are deliberate. An empty username is accepted, so a server can
+//! decide whether to allow it with
+//! [`ScramServer::allow_empty_authcid`](crate::ScramServer::allow_empty_authcid). Extension values
+//! and error values with C0 control characters are rejected, not only those with NUL, so a hostile
+//! peer can't smuggle line breaks or terminal escape sequences into logs and downstream parsers.
+//!
+//! [`parse_attributes`] walks the attributes of any message, for debuggers and protocol analyzers
+//! which look at SCRAM messages without running a handshake:
+//!
+//! ```
+//! use scram::grammar::parse_attributes;
+//!
+//! let server_first = "r=abcdef,s=c2FsdA==,i=4096";
+//! for attribute in parse_attributes(server_first) {
+//!     let (name, value) = attribute.unwrap();
+//!     println!("{}: {}", name, value);
+//! }
+//! ```
+//!
+//! The message types parse and serialize whole messages, for proxies which rewrite them. The
+//! serializers emit the attributes in the order of the RFC and refuse values with characters which
+//! aren't allowed in their field, so a serialized message is always well-formed:
+//!
+//! ```
+//! use scram::grammar::ServerFirstMessage;
+//!
+//! let mut message = ServerFirstMessage::parse("r=abcdef,s=c2FsdA==,i=4096").unwrap();
+//! message.extensions = "x=proxied";
+//! assert_eq!(message.serialize().unwrap(), "r=abcdef,s=c2FsdA==,i=4096,x=proxied");
+//! message.nonce = "abc,def";
+//! assert!(message.serialize().is_err());
+//! ```
+use alloc::string::String;
+use core::fmt;
+use core::num::NonZeroU32;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::error::{DecodeError, Error, Excerpt, Field, Kind};
+use crate::gs2;
+pub use crate::gs2::ChannelBindingFlag;
+
+/// A `client-first-message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientFirstMessage<'a> {
+    pub channel_binding: ChannelBindingFlag<'a>,
+    /// The escaped `authzid` of the gs2 header.
+    pub authzid: Option<&'a str>,
+    /// The escaped username.
+    pub authcid: &'a str,
+    pub nonce: &'a str,
+    /// The extension attributes after the nonce, without the separating comma.
+    pub extensions: &'a str,
+}
+
+/// A `server-first-message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerFirstMessage<'a> {
+    pub nonce: &'a str,
+    /// The base64 encoded salt.
+    pub salt: &'a str,
+    pub iterations: NonZeroU32,
+    /// The extension attributes after the iteration count, without the separating comma.
+    pub extensions: &'a str,
+}
+
+/// A `client-final-message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientFinalMessage<'a> {
+    /// The base64 encoded channel-binding input.
+    pub channel_binding: &'a str,
+    pub nonce: &'a str,
+    /// The extension attributes between the nonce and the proof, without the separating commas.
+    pub extensions: &'a str,
+    /// The base64 encoded proof.
+    pub proof: &'a str,
+}
+
+/// The outcome reported in a `server-final-message`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verification<'a> {
+    /// The base64 encoded server signature of a `v=` attribute.
+    Verifier(&'a str),
+    /// The `server-error-value` of an `e=` attribute.
+    Error(&'a str),
+}
+
+/// A `server-final-message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ServerFinalMessage<'a> {
+    pub verification: Verification<'a>,
+    /// The extension attributes after the verifier or error, without the separating comma.
+    pub extensions: &'a str,
+}
+
+/// A part of a message which isn't an attribute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MalformedAttribute {
+    /// The byte offset of the part in the message.
+    pub offset: usize,
+}
+
+impl fmt::Display for MalformedAttribute {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "Malformed attribute at offset {}", self.offset)
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for MalformedAttribute {}
+
+/// Iterates over the comma separated attributes of a message and returns the letter and the value
+/// of each. A part which isn't a letter followed by `=` is returned as [`MalformedAttribute`],
+/// and the iteration continues after it. An empty message has no attributes.
+///
+/// The gs2 header at the start of a `client-first-message` doesn't consist of attributes, except
+/// for the `p=` and `a=` parts. Skip to the `client-first-message-bare` to walk it without errors.
+pub fn parse_attributes(
+    message: &str,
+) -> impl Iterator<Item = Result<(char, &str), MalformedAttribute>> {
+    let parts = if message.is_empty() {
+        None
+    } else {
+        Some(message.split(','))
+    };
+    let mut offset = 0;
+    parts.into_iter().flatten().map(move |part| {
+        let start = offset;
+        offset += part.len() + 1;
+        attribute(part).ok_or(MalformedAttribute { offset: start })
+    })
+}
+
+/// Walks the comma separated parts of a message.
+struct Parser<'a> {
+    message: &'a str,
+    /// The offset of the next part, or `None` after the last part.
+    offset: Option<usize>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(message: &'a str) -> Self {
+        Parser {
+            message,
+            offset: Some(0),
+        }
+    }
+
+    /// Returns the offset of the next part in the message, or its length after the last part.
+    fn offset(&self) -> usize {
+        self.offset.unwrap_or(self.message.len())
+    }
+
+    /// Returns the next part without consuming it.
+    fn peek(&self) -> Option<&'a str> {
+        self.message[self.offset?..].split(',').next()
+    }
+
+    /// Consumes and returns the next part.
+    fn next_part(&mut self) -> Option<&'a str> {
+        let part = self.peek()?;
+        let end = self.offset() + part.len();
+        self.offset = if end < self.message.len() {
+            Some(end + 1)
+        } else {
+            None
+        };
+        Some(part)
+    }
+
+    /// Consumes the attribute `name` and returns its value. Fails with `ExpectedField(field)` if
+    /// the next part is another attribute or missing.
+    fn attribute(&mut self, name: char, field: Field) -> Result<&'a str, Error> {
+        match self.peek().and_then(attribute) {
+            Some((found, value)) if found == name => {
+                self.next_part();
+                Ok(value)
+            }
+            _ => Err(expected(field, self.peek())),
+        }
+    }
+
+    /// Fails with `UnsupportedExtension` if the next part is the mandatory extension `m=`.
+    fn reject_mandatory_extension(&self) -> Result<(), Error> {
+        match self.peek().and_then(attribute) {
+            Some(('m', _)) => Err(Error::UnsupportedExtension),
+            _ => Ok(()),
+        }
+    }
+
+    /// Consumes the remaining parts as extension attributes and returns them.
+    fn extensions(&mut self) -> Result<&'a str, Error> {
+        let start = self.offset();
+        while let Some(part) = self.next_part() {
+            extension(part)?;
+        }
+        Ok(&self.message[start..])
+    }
+}
+
+/// Splits an attribute into its name and value. The value may be empty.
+fn attribute(part: &str) -> Option<(char, &str)> {
+    let mut chars = part.chars();
+    let name = chars.next().filter(char::is_ascii_alphabetic)?;
+    match chars.next() {
+        Some('=') => Some((name, chars.as_str())),
+        _ => None,
+    }
+}
+
+/// Returns the `ExpectedField(field)` error with an excerpt of the part `found` in its place.
+pub(crate) fn expected(field: Field, found: Option<&str>) -> Error {
+    let excerpt = found
+        .filter(|part| !part.is_empty())
+        .map(|part| match attribute(part) {
+            Some((name, value)) => Excerpt::new(Some(name), value),
+            None => Excerpt::new(None, part),
+        });
+    Error::Protocol(Kind::ExpectedField(field, excerpt))
+}
+
+/// Returns the `InvalidField(field)` error with an excerpt of the `value` of `attribute`.
+pub(crate) fn invalid(field: Field, attribute: Option<char>, value: &str) -> Error {
+    let excerpt = if attribute.is_none() && value.is_empty() {
+        None
+    } else {
+        Some(Excerpt::new(attribute, value))
+    };
+    Error::Protocol(Kind::InvalidField(field, excerpt))
+}
+
+/// Checks an extension attribute, which must have a non-empty value.
+fn extension(part: &str) -> Result<(), Error> {
+    match attribute(part) {
+        Some((_, value)) if is_value(value) => Ok(()),
+        Some((name, value)) => Err(invalid(Field::Extensions, Some(name), value)),
+        None => Err(invalid(Field::Extensions, None, part)),
+    }
+}
+
+/// Checks `printable`: a non-empty string of ASCII characters from `!` to `~` except `,`.
+fn is_printable(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| (0x21..=0x7e).contains(&byte) && byte != b',')
+}
+
+/// Checks `saslname`: a `=` has to start one of the escape sequences `=2C` and `=3D`, and NUL and
+/// `,` aren't allowed. The value may be empty, see the module documentation.
+pub(crate) fn is_saslname(value: &str) -> bool {
+    !value.contains([',', '\0'])
+        && value
+            .split('=')
+            .skip(1)
+            .all(|part| part.starts_with("2C") || part.starts_with("3D"))
+}
+
+/// Checks `value`, of extensions and the `server-error-value`: a non-empty string without `,`
+/// and C0 control characters, see the module documentation.
+pub(crate) fn is_value(value: &str) -> bool {
+    !value.is_empty() && !value.bytes().any(|byte| byte == b',' || byte < 0x20)
+}
+
+/// Checks `cb-name`: a non-empty string of ASCII letters, digits, `.` and `-`.
+pub(crate) fn is_cb_name(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'.' || byte == b'-')
+}
+
+/// Parses the `posit-number` of the attribute `i`: decimal digits without a leading zero. A
+/// number too large for a `u32` fails with `InvalidEncoding`.
+fn posit_number(value: &str) -> Result<NonZeroU32, Error> {
+    let field = Field::Iterations;
+    if value.is_empty()
+        || value.starts_with('0')
+        || !value.bytes().all(|byte| byte.is_ascii_digit())
+    {
+        return Err(invalid(field, Some('i'), value));
+    }
+    value
+        .parse()
+        .map_err(|err| Error::Protocol(Kind::InvalidEncoding(field, DecodeError::integer(err))))
+}
+
+/// Checks `base64` as emitted: the standard alphabet, padded to a multiple of four characters.
+fn is_base64(value: &str) -> bool {
+    let data = value.trim_end_matches('=');
+    value.len().is_multiple_of(4)
+        && value.len() - data.len() <= 2
+        && data
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || byte == b'+' || byte == b'/')
+}
+
+/// Fails with `InvalidField(field)` unless `valid`.
+pub(crate) fn check(valid: bool, field: Field) -> Result<(), Error> {
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::Protocol(Kind::InvalidField(field, None)))
+    }
+}
+
+/// Checks the extension attributes and appends them to `message`, preceded by a comma.
+fn push_extensions(message: &mut String, extensions: &str) -> Result<(), Error> {
+    if extensions.is_empty() {
+        return Ok(());
+    }
+    for part in extensions.split(',') {
+        extension(part)?;
+    }
+    message.push(',');
+    message.push_str(extensions);
+    Ok(())
+}
+
+/// Checks a username before it's escaped and sent. Escaping takes care of `,` and `=`, but a NUL
+/// can't be sent. Fails with `InvalidField(field)`.
+pub(crate) fn check_username(username: &str, field: Field) -> Result<(), Error> {
+    check(!username.contains('\0'), field)
+}
+
+/// Returns the `gs2-header` and the `client-first-message-bare` of a `client-first-message` which
+/// was parsed successfully.
+pub(crate) fn split_client_first(message: &str) -> (&str, &str) {
+    let end = message
+        .match_indices(',')
+        .nth(1)
+        .map_or(message.len(), |(index, _)| index + 1);
+    message.split_at(end)
+}
+
+impl<'a> ClientFirstMessage<'a> {
+    /// Parses a `client-first-message`.
+    pub fn parse(message: &'a str) -> Result<Self, Error> {
+        let mut parser = Parser::new(message);
+        let channel_binding = gs2::parse_flag(parser.next_part())?;
+        let authzid = gs2::parse_authzid(parser.next_part())?;
+        if parser.offset.is_none() {
+            return Err(expected(Field::Authcid, None));
+        }
+        parser.reject_mandatory_extension()?;
+        let authcid = parser.attribute('n', Field::Authcid)?;
+        if !is_saslname(authcid) {
+            return Err(invalid(Field::Authcid, Some('n'), authcid));
+        }
+        let nonce = parser.attribute('r', Field::Nonce)?;
+        if !is_printable(nonce) {
+            return Err(invalid(Field::Nonce, Some('r'), nonce));
+        }
+        let extensions = parser.extensions()?;
+        Ok(ClientFirstMessage {
+            channel_binding,
+            authzid,
+            authcid,
+            nonce,
+            extensions,
+        })
+    }
+
+    /// Serializes the message with the attributes in the order of the RFC. Fails with
+    /// `InvalidField` if a field contains characters which aren't allowed in it.
+    pub fn serialize(&self) -> Result<String, Error> {
+        let mut message = String::new();
+        self.channel_binding.push_to(&mut message)?;
+        message.push(',');
+        if let Some(authzid) = self.authzid {
+            check(!authzid.is_empty() && is_saslname(authzid), Field::Authzid)?;
+            message.push_str("a=");
+            message.push_str(authzid);
+        }
+        check(is_saslname(self.authcid), Field::Authcid)?;
+        check(is_printable(self.nonce), Field::Nonce)?;
+        message.push_str(",n=");
+        message.push_str(self.authcid);
+        message.push_str(",r=");
+        message.push_str(self.nonce);
+        push_extensions(&mut message, self.extensions)?;
+        Ok(message)
+    }
+}
+
+impl<'a> ServerFirstMessage<'a> {
+    /// Parses a `server-first-message`.
+    pub fn parse(message: &'a str) -> Result<Self, Error> {
+        let mut parser = Parser::new(message);
+        parser.reject_mandatory_extension()?;
+        let nonce = parser.attribute('r', Field::Nonce)?;
+        if !is_printable(nonce) {
+            return Err(invalid(Field::Nonce, Some('r'), nonce));
+        }
+        let salt = parser.attribute('s', Field::Salt)?;
+        let iterations = posit_number(parser.attribute('i', Field::Iterations)?)?;
+        let extensions = parser.extensions()?;
+        Ok(ServerFirstMessage {
+            nonce,
+            salt,
+            iterations,
+            extensions,
+        })
+    }
+
+    /// Serializes the message with the attributes in the order of the RFC. Fails with
+    /// `InvalidField` if a field contains characters which aren't allowed in it.
+    pub fn serialize(&self) -> Result<String, Error> {
+        check(is_printable(self.nonce), Field::Nonce)?;
+        check(is_base64(self.salt), Field::Salt)?;
+        let mut message = format!("r={},s={},i={}", self.nonce, self.salt, self.iterations);
+        push_extensions(&mut message, self.extensions)?;
+        Ok(message)
+    }
+}
+
+impl<'a> ClientFinalMessage<'a> {
+    /// Parses a `client-final-message`.
+    pub fn parse(message: &'a str) -> Result<Self, Error> {
+        let mut parser = Parser::new(message);
+        let channel_binding = parser.attribute('c', Field::GS2Header)?;
+        let nonce = parser.attribute('r', Field::Nonce)?;
+        if !is_printable(nonce) {
+            return Err(invalid(Field::Nonce, Some('r'), nonce));
+        }
+        // The proof is the last attribute, the extensions come before it.
+        let rest = &message[parser.offset()..];
+        let (extensions, proof) = match (parser.offset, rest.rfind(',')) {
+            (None, _) => return Err(expected(Field::Proof, None)),
+            (Some(_), Some(index)) => {
+                let extensions = &rest[..index];
+                for part in extensions.split(',') {
+                    extension(part)?;
+                }
+                (extensions, &rest[index + 1..])
+            }
+            (Some(_), None) => ("", rest),
+        };
+        let proof = match attribute(proof) {
+            Some(('p', proof)) => proof,
+            _ => return Err(expected(Field::Proof, Some(proof))),
+        };
+        Ok(ClientFinalMessage {
+            channel_binding,
+            nonce,
+            extensions,
+            proof,
+        })
+    }
+
+    /// Serializes the message with the attributes in the order of the RFC, the extensions before
+    /// the proof. Fails with `InvalidField` if a field contains characters which aren't allowed
+    /// in it.
+    pub fn serialize(&self) -> Result<String, Error> {
+        check(is_base64(self.channel_binding), Field::GS2Header)?;
+        check(is_printable(self.nonce), Field::Nonce)?;
+        check(is_base64(self.proof), Field::Proof)?;
+        let mut message = format!("c={},r={}", self.channel_binding, self.nonce);
+        push_extensions(&mut message, self.extensions)?;
+        message.push_str(",p=");
+        message.push_str(self.proof);
+        Ok(message)
+    }
+}
+
+impl<'a> ServerFinalMessage<'a> {
+    /// Parses a `server-final-message`.
+    pub fn parse(message: &'a str) -> Result<Self, Error> {
+        let mut parser = Parser::new(message);
+        let verification = match parser.peek().and_then(attribute) {
+            Some(('v', verifier)) => Verification::Verifier(verifier),
+            Some(('e', error)) if !is_value(error) => {
+                return Err(invalid(Field::VerifyOrError, Some('e'), error));
+            }
+            Some(('e', error)) => Verification::Error(error),
+            _ => return Err(expected(Field::VerifyOrError, parser.peek())),
+        };
+        parser.next_part();
+        let extensions = parser.extensions()?;
+        Ok(ServerFinalMessage {
+            verification,
+            extensions,
+        })
+    }
+
+    /// Serializes the message with the attributes in the order of the RFC. Fails with
+    /// `InvalidField` if a field contains characters which aren't allowed in it.
+    pub fn serialize(&self) -> Result<String, Error> {
+        let mut message = match self.verification {
+            Verification::Verifier(verifier) => {
+                check(is_base64(verifier), Field::VerifyOrError)?;
+                format!("v={}", verifier)
+            }
+            Verification::Error(error) => {
+                check(is_value(error), Field::VerifyOrError)?;
+                format!("e={}", error)
+            }
+        };
+        push_extensions(&mut message, self.extensions)?;
+        Ok(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        parse_attributes, split_client_first, ChannelBindingFlag, ClientFinalMessage,
+        ClientFirstMessage, MalformedAttribute, ServerFinalMessage, ServerFirstMessage,
+        Verification,
+    };
+    use core::num::NonZeroU32;
+
+    use crate::error::{Error, Excerpt, Field, Kind};
+
+    fn expected(field: Field) -> Error {
+        Error::Protocol(Kind::ExpectedField(field, None))
+    }
+
+    fn invalid(field: Field) -> Error {
+        Error::Protocol(Kind::InvalidField(field, None))
+    }
+
+    /// Drops the excerpt, which the table tests don't check.
+    fn without_excerpt(error: Error) -> Error {
+        match error {
+            Error::Protocol(Kind::ExpectedField(field, _)) => expected(field),
+            Error::Protocol(Kind::InvalidField(field, _)) => invalid(field),
+            error => error,
+        }
+    }
+
+    #[test]
+    fn test_client_first() {
+        assert_eq!(
+            ClientFirstMessage::parse("p=tls-unique,a=ad=3Dmin,n=us=2Cer,r=abc,x=1"),
+            Ok(ClientFirstMessage {
+                channel_binding: ChannelBindingFlag::Required("tls-unique"),
+                authzid: Some("ad=3Dmin"),
+                authcid: "us=2Cer",
+                nonce: "abc",
+                extensions: "x=1",
+            })
+        );
+        let message = ClientFirstMessage::parse("y,,n=,r=abc").unwrap();
+        assert_eq!(message.channel_binding, ChannelBindingFlag::NotUsed);
+        assert_eq!(message.authcid, "");
+        assert_eq!(
+            split_client_first("p=tls-unique,a=ad=3Dmin,n=us=2Cer,r=abc,x=1"),
+            ("p=tls-unique,a=ad=3Dmin,", "n=us=2Cer,r=abc,x=1")
+        );
+    }
+
+    #[test]
+    fn test_client_first_malformed() {
+        let cases = [
+            ("", expected(Field::ChannelBinding)),
+            (",,n=user,r=abc", expected(Field::ChannelBinding)),
+            ("x,,n=user,r=abc", invalid(Field::ChannelBinding)),
+            ("p=,,n=user,r=abc", invalid(Field::ChannelBinding)),
+            ("p=tls_unique,,n=user,r=abc", invalid(Field::ChannelBinding)),
+            ("n", expected(Field::Authzid)),
+            ("n,n=user,r=abc", expected(Field::Authzid)),
+            ("n,a=,n=user,r=abc", invalid(Field::Authzid)),
+            ("n,a=ad=min,n=user,r=abc", invalid(Field::Authzid)),
+            ("n,,", expected(Field::Authcid)),
+            ("n,,r=abc", expected(Field::Authcid)),
+            ("n,,N=user,r=abc", expected(Field::Authcid)),
+            ("n,,n=us=er,r=abc", invalid(Field::Authcid)),
+            ("n,,n=us\0er,r=abc", invalid(Field::Authcid)),
+            ("n,,m=ext,n=user,r=abc", Error::UnsupportedExtension),
+            ("n,,n=user", expected(Field::Nonce)),
+            ("n,,n=user,", expected(Field::Nonce)),
+            ("n,,n=user,r=", invalid(Field::Nonce)),
+            ("n,,n=user,r=a b", invalid(Field::Nonce)),
+            ("n,,n=user,r=abc,", invalid(Field::Extensions)),
+            ("n,,n=user,r=abc,x", invalid(Field::Extensions)),
+            ("n,,n=user,r=abc,x=", invalid(Field::Extensions)),
+            ("n,,n=user,r=abc,1=x", invalid(Field::Extensions)),
+            ("n,,n=user,r=abc,x=a\0b", invalid(Field::Extensions)),
+            ("n,,n=user,r=abc,x=a\nb", invalid(Field::Extensions)),
+        ];
+        for (message, error) in cases.iter() {
+            assert_eq!(
+                ClientFirstMessage::parse(message)
+                    .map_err(without_excerpt)
+                    .as_ref(),
+                Err(error),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_server_first() {
+        assert_eq!(
+            ServerFirstMessage::parse("r=abc,s=c2FsdA==,i=4096,x=1,y=2"),
+            Ok(ServerFirstMessage {
+                nonce: "abc",
+                salt: "c2FsdA==",
+                iterations: NonZeroU32::new(4096).unwrap(),
+                extensions: "x=1,y=2",
+            })
+        );
+        assert_eq!(ServerFirstMessage::parse("r=abc,s=,i=1").unwrap().salt, "");
+    }
+
+    #[test]
+    fn test_server_first_malformed() {
+        let cases = [
+            ("", expected(Field::Nonce)),
+            ("m=ext,r=abc,s=c2FsdA==,i=4096", Error::UnsupportedExtension),
+            ("s=c2FsdA==,i=4096", expected(Field::Nonce)),
+            ("r=,s=c2FsdA==,i=4096", invalid(Field::Nonce)),
+            ("r=a\u{e4}c,s=c2FsdA==,i=4096", invalid(Field::Nonce)),
+            ("r=abc", expected(Field::Salt)),
+            ("r=abc,i=4096,s=c2FsdA==", expected(Field::Salt)),
+            ("r=abc,s=c2FsdA==", expected(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=", invalid(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=0", invalid(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=04096", invalid(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=+4096", invalid(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=4096\r\n", invalid(Field::Iterations)),
+            ("r=abc,s=c2FsdA==,i=4096,", invalid(Field::Extensions)),
+            ("r=abc,s=c2FsdA==,i=4096,x", invalid(Field::Extensions)),
+            ("r=abc,s=c2FsdA==,i=1,x=\x1b[2J", invalid(Field::Extensions)),
+            ("r=a\tbc,s=c2FsdA==,i=4096", invalid(Field::Nonce)),
+            ("r=a\0bc,s=c2FsdA==,i=4096", invalid(Field::Nonce)),
+        ];
+        for (message, error) in cases.iter() {
+            assert_eq!(
+                ServerFirstMessage::parse(message)
+                    .map_err(without_excerpt)
+                    .as_ref(),
+                Err(error),
+                "{}",
+                message
+            );
+        }
+        assert!(matches!(
+            ServerFirstMessage::parse("r=abc,s=c2FsdA==,i=4294967296"),
+            Err(Error::Protocol(Kind::InvalidEncoding(Field::Iterations, _)))
+        ));
+    }
+
+    #[test]
+    fn test_client_final() {
+        assert_eq!(
+            ClientFinalMessage::parse("c=biws,r=abc,p=cHJvb2Y="),
+            Ok(ClientFinalMessage {
+                channel_binding: "biws",
+                nonce: "abc",
+                extensions: "",
+                proof: "cHJvb2Y=",
+            })
+        );
+        assert_eq!(
+            ClientFinalMessage::parse("c=biws,r=abc,x=1,y=2,p=cHJvb2Y=")
+                .unwrap()
+                .extensions,
+            "x=1,y=2"
+        );
+    }
+
+    #[test]
+    fn test_client_final_malformed() {
+        let cases = [
+            ("", expected(Field::GS2Header)),
+            ("r=abc,p=cHJvb2Y=", expected(Field::GS2Header)),
+            ("c=biws", expected(Field::Nonce)),
+            ("c=biws,p=cHJvb2Y=", expected(Field::Nonce)),
+            ("c=biws,r=,p=cHJvb2Y=", invalid(Field::Nonce)),
+            ("c=biws,r=abc", expected(Field::Proof)),
+            ("c=biws,r=abc,", expected(Field::Proof)),
+            ("c=biws,r=abc,x=1", expected(Field::Proof)),
+            ("c=biws,r=abc,p=cHJvb2Y=,x=1", expected(Field::Proof)),
+            ("c=biws,r=abc,x,p=cHJvb2Y=", invalid(Field::Extensions)),
+            ("c=biws,r=abc,,p=cHJvb2Y=", invalid(Field::Extensions)),
+            ("c=biws,r=abc,x=\t,p=cHJvb2Y=", invalid(Field::Extensions)),
+        ];
+        for (message, error) in cases.iter() {
+            assert_eq!(
+                ClientFinalMessage::parse(message)
+                    .map_err(without_excerpt)
+                    .as_ref(),
+                Err(error),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_server_final() {
+        assert_eq!(
+            ServerFinalMessage::parse("v=dmVyaWZpZXI=,x=1"),
+            Ok(ServerFinalMessage {
+                verification: Verification::Verifier("dmVyaWZpZXI="),
+                extensions: "x=1",
+            })
+        );
+        assert_eq!(
+            ServerFinalMessage::parse("e=invalid-proof"),
+            Ok(ServerFinalMessage {
+                verification: Verification::Error("invalid-proof"),
+                extensions: "",
+            })
+        );
+    }
+
+    #[test]
+    fn test_server_final_malformed() {
+        let cases = [
+            ("", expected(Field::VerifyOrError)),
+            ("v", expected(Field::VerifyOrError)),
+            ("x=1,v=dmVyaWZpZXI=", expected(Field::VerifyOrError)),
+            ("e=", invalid(Field::VerifyOrError)),
+            ("e=other\0error", invalid(Field::VerifyOrError)),
+            ("e=other-error\r\n", invalid(Field::VerifyOrError)),
+            ("v=dmVyaWZpZXI=,", invalid(Field::Extensions)),
+            ("e=other-error,=1", invalid(Field::Extensions)),
+        ];
+        for (message, error) in cases.iter() {
+            assert_eq!(
+                ServerFinalMessage::parse(message)
+                    .map_err(without_excerpt)
+                    .as_ref(),
+                Err(error),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let client_first = "p=tls-unique,a=ad=3Dmin,n=us=2Cer,r=abc,x=1";
+        let message = ClientFirstMessage::parse(client_first).unwrap();
+        assert_eq!(message.serialize().unwrap(), client_first);
+        let server_first = "r=abc,s=c2FsdA==,i=4096,x=1,y=2";
+        let message = ServerFirstMessage::parse(server_first).unwrap();
+        assert_eq!(message.serialize().unwrap(), server_first);
+        let client_final = "c=biws,r=abc,x=1,p=cHJvb2Y=";
+        let message = ClientFinalMessage::parse(client_final).unwrap();
+        assert_eq!(message.serialize().unwrap(), client_final);
+        for server_final in ["v=dmVyaWZpZXI=,x=1", "e=invalid-proof"].iter() {
+            let message = ServerFinalMessage::parse(server_final).unwrap();
+            assert_eq!(message.serialize().unwrap(), *server_final);
+        }
+    }
+
+    #[test]
+    fn test_serialize_invalid() {
+        let client_first = ClientFirstMessage {
+            channel_binding: ChannelBindingFlag::NotSupported,
+            authzid: None,
+            authcid: "user",
+            nonce: "abc",
+            extensions: "",
+        };
+        assert_eq!(client_first.serialize().unwrap(), "n,,n=user,r=abc");
+        let cases = [
+            (
+                ClientFirstMessage {
+                    channel_binding: ChannelBindingFlag::Required("tls unique"),
+                    ..client_first.clone()
+                },
+                invalid(Field::ChannelBinding),
+            ),
+            (
+                ClientFirstMessage {
+                    authzid: Some(""),
+                    ..client_first.clone()
+                },
+                invalid(Field::Authzid),
+            ),
+            (
+                ClientFirstMessage {
+                    authcid: "us,er",
+                    ..client_first.clone()
+                },
+                invalid(Field::Authcid),
+            ),
+            (
+                ClientFirstMessage {
+                    authcid: "us=er",
+                    ..client_first.clone()
+                },
+                invalid(Field::Authcid),
+            ),
+            (
+                ClientFirstMessage {
+                    nonce: "a c",
+                    ..client_first.clone()
+                },
+                invalid(Field::Nonce),
+            ),
+            (
+                ClientFirstMessage {
+                    extensions: "x=1,",
+                    ..client_first.clone()
+                },
+                invalid(Field::Extensions),
+            ),
+        ];
+        for (message, error) in cases.iter() {
+            assert_eq!(
+                message.serialize().map_err(without_excerpt).as_ref(),
+                Err(error),
+                "{:?}",
+                message
+            );
+        }
+
+        let server_first = ServerFirstMessage {
+            nonce: "abc",
+            salt: "c2FsdA",
+            iterations: NonZeroU32::new(4096).unwrap(),
+            extensions: "",
+        };
+        assert_eq!(server_first.serialize(), Err(invalid(Field::Salt)));
+        let client_final = ClientFinalMessage {
+            channel_binding: "biws",
+            nonce: "abc",
+            extensions: "",
+            proof: "cHJvb2Y=,x=1",
+        };
+        assert_eq!(client_final.serialize(), Err(invalid(Field::Proof)));
+        let server_final = ServerFinalMessage {
+            verification: Verification::Error("invalid-proof,x=1"),
+            extensions: "",
+        };
+        assert_eq!(server_final.serialize(), Err(invalid(Field::VerifyOrError)));
+    }
+
+    #[test]
+    fn test_excerpts() {
+        let error = ServerFirstMessage::parse("r=abcdefghijkl,s=c2FsdA==,i=04096").unwrap_err();
+        assert_eq!(
+            error,
+            Error::Protocol(Kind::InvalidField(
+                Field::Iterations,
+                Some(Excerpt {
+                    attribute: Some('i'),
+                    value: "04096".into(),
+                    length: 5,
+                })
+            ))
+        );
+        let error = ServerFirstMessage::parse("s=c2FsdA==,i=4096").unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Expected field Nonce, found s=c2FsdA=="
+        );
+        let error = ClientFinalMessage::parse("c=biws,r=a b c d e f g h i,p=cHJvb2Y=").unwrap_err();
+        assert_eq!(
+            format!("{}", error),
+            "Invalid field Nonce at r=a b c d ... (17 bytes)"
+        );
+        let error = ClientFinalMessage::parse("c=biws,p=cHJvb2Y=").unwrap_err();
+        assert_eq!(
+            error,
+            Error::Protocol(Kind::ExpectedField(
+                Field::Nonce,
+                Some(Excerpt {
+                    attribute: Some('p'),
+                    value: "".into(),
+                    length: 8,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_attributes() {
+        let attributes: Vec<_> = parse_attributes("r=abc,s=,x,i=4096,=1").collect();
+        assert_eq!(
+            attributes,
+            [
+                Ok(('r', "abc")),
+                Ok(('s', "")),
+                Err(MalformedAttribute { offset: 9 }),
+                Ok(('i', "4096")),
+                Err(MalformedAttribute { offset: 18 }),
+            ]
+        );
+        assert_eq!(parse_attributes("").count(), 0);
+        assert_eq!(
+            parse_attributes("v=abc,").last(),
+            Some(Err(MalformedAttribute { offset: 6 }))
+        );
+    }
+}
@@ -0,0 +1,228 @@
+//! The GS2 header of RFC5801 section 4, at the start of the first client message of SCRAM and
+//! the other mechanisms of the GS2 family.
+//!
+//! The header announces whether the client binds the authentication to the channel and whom it
+//! wants to act as. [`Gs2Header`] builds and parses it, so other GS2 mechanisms and proxies which
+//! rewrite the header can reuse it:
+//!
+//! ```
+//! use scram::gs2::{ChannelBindingFlag, Gs2Header};
+//!
+//! let header = Gs2Header::new(ChannelBindingFlag::Required("tls-exporter"), Some("ops,admin"));
+//! assert_eq!(header.serialize().unwrap(), "p=tls-exporter,a=ops=2Cadmin,");
+//!
+//! let (header, client_first_bare) = Gs2Header::parse("n,a=ops=2Cadmin,n=user,r=abc").unwrap();
+//! assert_eq!(header.channel_binding, ChannelBindingFlag::NotSupported);
+//! assert_eq!(header.authzid.as_deref(), Some("ops,admin"));
+//! assert_eq!(client_first_bare, "n=user,r=abc");
+//! ```
+use alloc::borrow::Cow;
+use alloc::string::String;
+
+use crate::error::{Error, Field};
+use crate::grammar::{check, check_username, expected, invalid, is_cb_name, is_saslname};
+use crate::utils::{escape_username, unescape_username};
+
+/// The `gs2-cbind-flag` at the start of a `client-first-message`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChannelBindingFlag<'a> {
+    /// `n`: the client doesn't support channel binding.
+    NotSupported,
+    /// `y`: the client supports channel binding, but thinks the server doesn't.
+    NotUsed,
+    /// `p=`: the client requires channel binding of the contained type.
+    Required(&'a str),
+}
+
+impl<'a> ChannelBindingFlag<'a> {
+    /// Returns the channel-binding type the client requires, if any.
+    pub fn cb_name(&self) -> Option<&'a str> {
+        match *self {
+            ChannelBindingFlag::Required(name) => Some(name),
+            ChannelBindingFlag::NotSupported | ChannelBindingFlag::NotUsed => None,
+        }
+    }
+
+    /// Appends the flag to `header`. Fails with `InvalidField(ChannelBinding)` if the
+    /// channel-binding type contains characters which aren't allowed in it.
+    pub(crate) fn push_to(&self, header: &mut String) -> Result<(), Error> {
+        match *self {
+            ChannelBindingFlag::NotSupported => header.push('n'),
+            ChannelBindingFlag::NotUsed => header.push('y'),
+            ChannelBindingFlag::Required(name) => {
+                check(is_cb_name(name), Field::ChannelBinding)?;
+                header.push_str("p=");
+                header.push_str(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses the part with the channel-binding flag.
+pub(crate) fn parse_flag(part: Option<&str>) -> Result<ChannelBindingFlag<'_>, Error> {
+    match part {
+        Some("n") => Ok(ChannelBindingFlag::NotSupported),
+        Some("y") => Ok(ChannelBindingFlag::NotUsed),
+        Some("") | None => Err(expected(Field::ChannelBinding, None)),
+        Some(flag) => match flag.strip_prefix("p=") {
+            Some(name) if is_cb_name(name) => Ok(ChannelBindingFlag::Required(name)),
+            // The whole flag, since the `p` of the channel-binding name isn't redacted.
+            _ => Err(invalid(Field::ChannelBinding, None, flag)),
+        },
+    }
+}
+
+/// Parses the part with the `authzid` and returns it escaped, or `None` if the part is empty.
+pub(crate) fn parse_authzid(part: Option<&str>) -> Result<Option<&str>, Error> {
+    match part {
+        Some("") => Ok(None),
+        Some(part) => match part.strip_prefix("a=") {
+            Some(authzid) if !authzid.is_empty() && is_saslname(authzid) => Ok(Some(authzid)),
+            Some(authzid) => Err(invalid(Field::Authzid, Some('a'), authzid)),
+            None => Err(expected(Field::Authzid, Some(part))),
+        },
+        None => Err(expected(Field::Authzid, None)),
+    }
+}
+
+/// A `gs2-header`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gs2Header<'a> {
+    /// Whether the header starts with the `F` flag, which marks a GS2 mechanism whose Kerberos
+    /// context token isn't standard. SCRAM never sets it.
+    pub nonstandard: bool,
+    /// The channel-binding flag.
+    pub channel_binding: ChannelBindingFlag<'a>,
+    /// The unescaped identity the client wants to act as, if it's not the authenticated one.
+    pub authzid: Option<Cow<'a, str>>,
+}
+
+impl<'a> Gs2Header<'a> {
+    /// Creates a header without the `F` flag.
+    pub fn new(channel_binding: ChannelBindingFlag<'a>, authzid: Option<&'a str>) -> Self {
+        Gs2Header {
+            nonstandard: false,
+            channel_binding,
+            authzid: authzid.map(Cow::Borrowed),
+        }
+    }
+
+    /// Parses the header at the start of `message` and returns it with the rest of the message,
+    /// the `client-first-message-bare` of SCRAM.
+    ///
+    /// Fails with `Error::Protocol` naming `Field::ChannelBinding` or `Field::Authzid`, also if
+    /// the `authzid` contains a `=` which doesn't start an escape sequence.
+    pub fn parse(message: &'a str) -> Result<(Self, &'a str), Error> {
+        let (nonstandard, header) = match message.strip_prefix("F,") {
+            Some(header) => (true, header),
+            None => (false, message),
+        };
+        let mut parts = header.splitn(3, ',');
+        let channel_binding = parse_flag(parts.next())?;
+        let authzid = match parse_authzid(parts.next())? {
+            Some(authzid) => Some(
+                unescape_username(authzid)
+                    .ok_or_else(|| invalid(Field::Authzid, Some('a'), authzid))?,
+            ),
+            None => None,
+        };
+        // The header ends with the comma after the `authzid`.
+        let rest = parts.next().ok_or_else(|| expected(Field::Authzid, None))?;
+        let header = Gs2Header {
+            nonstandard,
+            channel_binding,
+            authzid,
+        };
+        Ok((header, rest))
+    }
+
+    /// Serializes the header, escaping the `authzid`. Fails with `InvalidField` naming
+    /// `Field::ChannelBinding` or `Field::Authzid` if the channel-binding type contains
+    /// characters which aren't allowed in it, or the `authzid` is empty or contains a NUL.
+    pub fn serialize(&self) -> Result<String, Error> {
+        let mut header = String::new();
+        if self.nonstandard {
+            header.push_str("F,");
+        }
+        self.channel_binding.push_to(&mut header)?;
+        header.push(',');
+        if let Some(ref authzid) = self.authzid {
+            check(!authzid.is_empty(), Field::Authzid)?;
+            check_username(authzid, Field::Authzid)?;
+            header.push_str("a=");
+            header.push_str(&escape_username(authzid));
+        }
+        header.push(',');
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::borrow::Cow;
+
+    use super::{ChannelBindingFlag, Gs2Header};
+    use crate::error::{Error, Excerpt, Field, Kind};
+
+    #[test]
+    fn test_parse() {
+        let (header, rest) = Gs2Header::parse("n,,n=user,r=abc").unwrap();
+        assert_eq!(
+            header,
+            Gs2Header::new(ChannelBindingFlag::NotSupported, None)
+        );
+        assert_eq!(rest, "n=user,r=abc");
+        let (header, rest) = Gs2Header::parse("F,p=tls-unique,a=admin,").unwrap();
+        assert!(header.nonstandard);
+        assert_eq!(header.channel_binding.cb_name(), Some("tls-unique"));
+        assert_eq!(header.authzid, Some(Cow::Borrowed("admin")));
+        assert_eq!(rest, "");
+        let (header, _) = Gs2Header::parse("y,a=a=3Db,").unwrap();
+        assert_eq!(header.channel_binding, ChannelBindingFlag::NotUsed);
+        assert_eq!(header.authzid.as_deref(), Some("a=b"));
+
+        assert_eq!(
+            Gs2Header::parse("x,,").unwrap_err(),
+            Error::Protocol(Kind::InvalidField(
+                Field::ChannelBinding,
+                Some(Excerpt::new(None, "x"))
+            ))
+        );
+        assert_eq!(
+            Gs2Header::parse("n,a=a=b,").unwrap_err(),
+            Error::Protocol(Kind::InvalidField(
+                Field::Authzid,
+                Some(Excerpt::new(Some('a'), "a=b"))
+            ))
+        );
+        assert_eq!(
+            Gs2Header::parse("n,").unwrap_err(),
+            Error::Protocol(Kind::ExpectedField(Field::Authzid, None))
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        let header = Gs2Header::new(ChannelBindingFlag::NotSupported, None);
+        assert_eq!(header.serialize().unwrap(), "n,,");
+        let mut header = Gs2Header::new(ChannelBindingFlag::Required("tls-exporter"), Some("a,b"));
+        header.nonstandard = true;
+        let serialized = header.serialize().unwrap();
+        assert_eq!(serialized, "F,p=tls-exporter,a=a=2Cb,");
+        assert_eq!(Gs2Header::parse(&serialized).unwrap().0, header);
+
+        let header = Gs2Header::new(ChannelBindingFlag::Required("tls unique"), None);
+        assert_eq!(
+            header.serialize().unwrap_err(),
+            Error::Protocol(Kind::InvalidField(Field::ChannelBinding, None))
+        );
+        for authzid in ["", "a\0b"] {
+            let header = Gs2Header::new(ChannelBindingFlag::NotUsed, Some(authzid));
+            assert_eq!(
+                header.serialize().unwrap_err(),
+                Error::Protocol(Kind::InvalidField(Field::Authzid, None))
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+//! The hash functions a client handshake is computed with.
+//!
+//! RFC5802 defines SCRAM over any hash function `H` with its `HMAC`. The built-in [`Mechanism`]s
+//! implement [`ScramHash`] with `ring`. Other hash functions, or other implementations of the
+//! built-in ones like a hardware accelerator, implement the trait and are passed to
+//! [`ScramClient::with_hash`](crate::ScramClient::with_hash):
+//!
+//! ```
+//! use scram::hash::ScramHash;
+//! use scram::{Mechanism, ScramClient};
+//!
+//! /// SCRAM-SHA-256 through the implementation of the platform.
+//! #[derive(Debug)]
+//! struct PlatformSha256;
+//!
+//! impl ScramHash for PlatformSha256 {
+//!     fn name(&self) -> &str {
+//!         "SCRAM-SHA-256"
+//!     }
+//!
+//!     fn output_len(&self) -> usize {
+//!         32
+//!     }
+//!
+//!     fn hash(&self, data: &[u8], output: &mut [u8]) {
+//!         // Call the platform instead.
+//!         Mechanism::ScramSha256.hash(data, output)
+//!     }
+//!
+//!     fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+//!         Mechanism::ScramSha256.hmac(key, message, output)
+//!     }
+//! }
+//!
+//! let client = ScramClient::new("user", "password", None).with_hash(PlatformSha256);
+//! ```
+//!
+//! Only the client is generic over the hash function. The server implements the built-in
+//! mechanisms.
+use core::num::NonZeroU32;
+
+use ring::{digest, hmac, pbkdf2};
+
+use crate::mechanism::Mechanism;
+
+/// The longest output of a hash function the client supports, in bytes.
+pub const MAX_OUTPUT_LEN: usize = digest::SHA512_OUTPUT_LEN;
+
+/// A hash function `H` and its `HMAC` as used by SCRAM, see the [module documentation](self).
+///
+/// The output buffers passed to the methods have the length of
+/// [`output_len`](Self::output_len).
+pub trait ScramHash {
+    /// Returns the SASL mechanism name, for example `"SCRAM-SHA-256"`. A client with a hash of
+    /// the name of a built-in mechanism shares the salted passwords of that mechanism in a
+    /// `cache::CredentialCache`.
+    fn name(&self) -> &str;
+
+    /// Returns the output length of `H` and `HMAC` in bytes, at most [`MAX_OUTPUT_LEN`].
+    fn output_len(&self) -> usize;
+
+    /// Writes `H(data)` to `output`.
+    fn hash(&self, data: &[u8], output: &mut [u8]);
+
+    /// Writes `HMAC(key, message)` to `output`. The message is passed in pieces, which are
+    /// concatenated.
+    fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]);
+
+    /// Writes `Hi(password, salt, iterations)`, PBKDF2 with the `HMAC` as pseudorandom function
+    /// and the output length as key length, to `output`. The default implementation computes it
+    /// with [`hmac`](Self::hmac).
+    fn hi(&self, password: &[u8], salt: &[u8], iterations: NonZeroU32, output: &mut [u8]) {
+        let mut block = [0; MAX_OUTPUT_LEN];
+        let block = &mut block[..output.len()];
+        self.hmac(password, &[salt, &1u32.to_be_bytes()], block);
+        output.copy_from_slice(block);
+        let mut next = [0; MAX_OUTPUT_LEN];
+        let next = &mut next[..output.len()];
+        for _ in 1..iterations.get() {
+            self.hmac(password, &[block], next);
+            block.copy_from_slice(next);
+            for (o, b) in output.iter_mut().zip(block.iter()) {
+                *o ^= b;
+            }
+        }
+        crate::utils::zeroize(block);
+        crate::utils::zeroize(next);
+    }
+}
+
+impl ScramHash for Mechanism {
+    fn name(&self) -> &str {
+        Mechanism::name(*self)
+    }
+
+    fn output_len(&self) -> usize {
+        Mechanism::output_len(*self)
+    }
+
+    fn hash(&self, data: &[u8], output: &mut [u8]) {
+        output.copy_from_slice(digest::digest(self.digest(), data).as_ref());
+    }
+
+    fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+        let mut context = hmac::Context::with_key(&hmac::Key::new(Mechanism::hmac(*self), key));
+        for piece in message {
+            context.update(piece);
+        }
+        output.copy_from_slice(context.sign().as_ref());
+    }
+
+    fn hi(&self, password: &[u8], salt: &[u8], iterations: NonZeroU32, output: &mut [u8]) {
+        pbkdf2::derive(self.pbkdf2(), iterations, salt, password, output);
+    }
+}
+
+impl<H: ScramHash + ?Sized> ScramHash for &H {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn output_len(&self) -> usize {
+        (**self).output_len()
+    }
+
+    fn hash(&self, data: &[u8], output: &mut [u8]) {
+        (**self).hash(data, output)
+    }
+
+    fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+        (**self).hmac(key, message, output)
+    }
+
+    fn hi(&self, password: &[u8], salt: &[u8], iterations: NonZeroU32, output: &mut [u8]) {
+        (**self).hi(password, salt, iterations, output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{ScramHash, MAX_OUTPUT_LEN};
+    use crate::mechanism::Mechanism;
+
+    /// A mechanism which derives `Hi` with the default implementation.
+    struct DefaultHi(Mechanism);
+
+    impl ScramHash for DefaultHi {
+        fn name(&self) -> &str {
+            self.0.name()
+        }
+
+        fn output_len(&self) -> usize {
+            self.0.output_len()
+        }
+
+        fn hash(&self, data: &[u8], output: &mut [u8]) {
+            ScramHash::hash(&self.0, data, output)
+        }
+
+        fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+            ScramHash::hmac(&self.0, key, message, output)
+        }
+    }
+
+    #[test]
+    fn test_default_hi() {
+        for &mechanism in Mechanism::ALL {
+            let len = mechanism.output_len();
+            for &iterations in &[1, 2, 4096] {
+                let iterations = NonZeroU32::new(iterations).unwrap();
+                let mut expected = [0; MAX_OUTPUT_LEN];
+                mechanism.hi(b"pencil", b"salt", iterations, &mut expected[..len]);
+                let mut output = [0; MAX_OUTPUT_LEN];
+                DefaultHi(mechanism).hi(b"pencil", b"salt", iterations, &mut output[..len]);
+                assert_eq!(output[..len], expected[..len]);
+            }
+        }
+    }
+}
@@ -0,0 +1,542 @@
+//! An allocation-free SCRAM-SHA-256 client for devices without a heap.
+//!
+//! The types mirror those of the [`client`](crate::client) module, but the nonce, the salt and all
+//! messages are kept in fixed-size buffers on the stack. The const parameter `N` is the maximum
+//! length of a message in bytes and also bounds the length of the decoded salt. A message which
+//! doesn't fit into `N` bytes fails with [`Error::BufferTooSmall`]. 256 bytes are enough for
+//! usernames and salts of common length.
+//!
+//! The crate still links `alloc`, because some [`Error`] variants carry strings. The handshake
+//! only allocates when the server rejects the authentication with an `e=` attribute.
+//!
+//! ``` rust,no_run
+//! use scram::heapless::ScramClient;
+//!
+//! # fn send_and_receive(message: &str) -> &'static str { unimplemented!() }
+//! # let mut rng = rand::rngs::OsRng;
+//! let scram = ScramClient::<256>::with_rng("user", "password", None, &mut rng);
+//! let (scram, client_first) = scram.client_first().unwrap();
+//! let server_first = send_and_receive(client_first.as_str());
+//! let scram = scram.handle_server_first(server_first).unwrap();
+//! let (scram, client_final) = scram.client_final();
+//! let server_final = send_and_receive(client_final.as_str());
+//! scram.handle_server_final(server_final).unwrap();
+//! ```
+use alloc::string::ToString;
+use core::fmt;
+use core::str;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::{DecodeSliceError, Engine};
+use ring::digest::SHA256_OUTPUT_LEN;
+
+use crate::client::Aborted;
+use crate::entropy::EntropySource;
+use crate::error::{Error, Excerpt, Field, Kind};
+use crate::grammar::{self, ServerFinalMessage, ServerFirstMessage, Verification};
+use crate::mechanism::Mechanism;
+use crate::trace;
+use crate::utils::{encode_chunked, fill_nonce, find_proofs, hash_password, zeroize, Output};
+use crate::{MIN_ITERATIONS, NONCE_LENGTH};
+
+/// A fixed-size buffer holding a message of at most `N` bytes.
+#[derive(Clone)]
+pub struct Buffer<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> Buffer<N> {
+    /// Creates an empty buffer.
+    pub const fn new() -> Self {
+        Buffer {
+            data: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Returns the content of the buffer.
+    pub fn as_str(&self) -> &str {
+        str::from_utf8(self.as_bytes()).expect("buffers only contain UTF-8")
+    }
+
+    /// Returns the content of the buffer as bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    /// Returns the length of the content in bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the buffer is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push_str(&mut self, data: &str) -> Result<(), Error> {
+        self.push(data.as_bytes())
+    }
+
+    /// Overwrites the whole buffer with zeros and empties it.
+    fn zeroize(&mut self) {
+        zeroize(&mut self.data);
+        self.len = 0;
+    }
+
+    fn push(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.len + data.len();
+        if end > N {
+            return Err(Error::BufferTooSmall);
+        }
+        self.data[self.len..end].copy_from_slice(data);
+        self.len = end;
+        Ok(())
+    }
+
+    /// Appends a username with `,` and `=` escaped as required by RFC5802 section 5.1.
+    fn push_username(&mut self, username: &str) -> Result<(), Error> {
+        for &byte in username.as_bytes() {
+            match byte {
+                b',' => self.push(b"=2C")?,
+                b'=' => self.push(b"=3D")?,
+                _ => self.push(&[byte])?,
+            }
+        }
+        Ok(())
+    }
+
+    fn push_base64(&mut self, data: &[u8]) -> Result<(), Error> {
+        let mut result = Ok(());
+        encode_chunked(data, |chunk| {
+            if result.is_ok() {
+                result = self.push(chunk);
+            }
+        });
+        result
+    }
+}
+
+impl<const N: usize> Default for Buffer<N> {
+    fn default() -> Self {
+        Buffer::new()
+    }
+}
+
+impl<const N: usize> AsRef<str> for Buffer<N> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Debug for Buffer<N> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), fmt)
+    }
+}
+
+impl<const N: usize> fmt::Display for Buffer<N> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.as_str())
+    }
+}
+
+/// The initial state of the allocation-free SCRAM mechanism, with messages of at most `N` bytes.
+#[derive(Debug)]
+pub struct ScramClient<'a, const N: usize> {
+    authcid: &'a str,
+    password: &'a str,
+    authzid: Option<&'a str>,
+    nonce: Buffer<NONCE_LENGTH>,
+    min_iterations: u32,
+}
+
+impl<'a, const N: usize> ScramClient<'a, N> {
+    /// Constructs an initial state for the SCRAM mechanism using the provided credentials and a
+    /// random number generator. The arguments are the same as those of
+    /// [`client::ScramClient::with_rng`](crate::client::ScramClient::with_rng).
+    pub fn with_rng<R: EntropySource + ?Sized>(
+        authcid: &'a str,
+        password: &'a str,
+        authzid: Option<&'a str>,
+        rng: &mut R,
+    ) -> Self {
+        let mut nonce = Buffer {
+            data: [0; NONCE_LENGTH],
+            len: NONCE_LENGTH,
+        };
+        fill_nonce(rng, &mut nonce.data);
+        Self::with_nonce(authcid, password, authzid, nonce)
+    }
+
+    fn with_nonce(
+        authcid: &'a str,
+        password: &'a str,
+        authzid: Option<&'a str>,
+        nonce: Buffer<NONCE_LENGTH>,
+    ) -> Self {
+        ScramClient {
+            authcid,
+            password,
+            authzid,
+            nonce,
+            min_iterations: MIN_ITERATIONS,
+        }
+    }
+
+    /// Accepts iteration counts below the minimum of 4096 from the server, down to a single
+    /// iteration. This is only available with the `test-util` feature. Never use this outside of
+    /// tests.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn insecure_allow_low_iterations(mut self) -> Self {
+        self.min_iterations = 1;
+        self
+    }
+
+    /// Aborts the handshake and zeroizes the nonce, like
+    /// [`client::ScramClient::abort`](crate::client::ScramClient::abort).
+    pub fn abort(mut self) -> Aborted {
+        self.nonce.zeroize();
+        Aborted::new()
+    }
+
+    /// Returns the next state and the first client message.
+    ///
+    /// Fails with `Error::BufferTooSmall` if the message is longer than `N` bytes, and with
+    /// `Error::Protocol(Kind::InvalidField(_, _))` if the username or `authzid` contains a NUL
+    /// character, which can't be sent.
+    pub fn client_first(self) -> Result<(ServerFirst<'a, N>, Buffer<N>), Error> {
+        let _phase = trace::phase(Mechanism::ScramSha256.name(), "client_first");
+        let mut gs2header = Buffer::new();
+        match self.authzid {
+            Some(authzid) => {
+                grammar::check_username(authzid, Field::Authzid)?;
+                gs2header.push_str("n,a=")?;
+                gs2header.push_username(authzid)?;
+                gs2header.push_str(",")?;
+            }
+            None => gs2header.push_str("n,,")?,
+        }
+        grammar::check_username(self.authcid, Field::Authcid)?;
+        let mut client_first_bare = Buffer::new();
+        client_first_bare.push_str("n=")?;
+        client_first_bare.push_username(self.authcid)?;
+        client_first_bare.push_str(",r=")?;
+        client_first_bare.push(self.nonce.as_bytes())?;
+        let mut client_first = gs2header.clone();
+        client_first.push(client_first_bare.as_bytes())?;
+        trace::sent(client_first.len());
+        let server_first = ServerFirst {
+            gs2header,
+            client_first_bare,
+            password: self.password,
+            client_nonce: self.nonce,
+            min_iterations: self.min_iterations,
+        };
+        Ok((server_first, client_first))
+    }
+}
+
+/// The second state of the allocation-free SCRAM mechanism after the first client message was
+/// computed.
+#[derive(Debug)]
+pub struct ServerFirst<'a, const N: usize> {
+    gs2header: Buffer<N>,
+    client_first_bare: Buffer<N>,
+    password: &'a str,
+    client_nonce: Buffer<NONCE_LENGTH>,
+    min_iterations: u32,
+}
+
+impl<'a, const N: usize> ServerFirst<'a, N> {
+    /// Aborts the handshake and zeroizes the nonce, like
+    /// [`client::ServerFirst::abort`](crate::client::ServerFirst::abort).
+    pub fn abort(mut self) -> Aborted {
+        self.client_nonce.zeroize();
+        self.client_first_bare.zeroize();
+        Aborted::new()
+    }
+
+    /// Processes the first answer from the server and returns the next state or an error.
+    ///
+    /// Returns the same errors as
+    /// [`client::ServerFirst::handle_server_first`](crate::client::ServerFirst::handle_server_first),
+    /// and `Error::BufferTooSmall` if the salt or the final client message is longer than `N`
+    /// bytes.
+    pub fn handle_server_first(self, server_first: &str) -> Result<ClientFinal<N>, Error> {
+        let _phase = trace::phase(Mechanism::ScramSha256.name(), "handle_server_first");
+        trace::received(server_first.len());
+        let result = self.process_server_first(server_first);
+        trace::outcome(&result);
+        result
+    }
+
+    fn process_server_first(self, server_first: &str) -> Result<ClientFinal<N>, Error> {
+        let ServerFirstMessage {
+            nonce,
+            salt,
+            iterations,
+            ..
+        } = ServerFirstMessage::parse(server_first)?;
+        if !nonce.starts_with(self.client_nonce.as_str()) {
+            return Err(Error::Protocol(Kind::InvalidNonce));
+        }
+        if iterations.get() < self.min_iterations {
+            let excerpt = Excerpt::new(Some('i'), &iterations.to_string());
+            return Err(Error::Protocol(Kind::InvalidField(
+                Field::Iterations,
+                Some(excerpt),
+            )));
+        }
+        let mut salt_buffer = [0u8; N];
+        let salt_len = STANDARD
+            .decode_slice(salt, &mut salt_buffer)
+            .map_err(|err| match err {
+                DecodeSliceError::OutputSliceTooSmall => Error::BufferTooSmall,
+                DecodeSliceError::DecodeError(err) => Error::base64(Field::Salt, err),
+            })?;
+        trace::iterations(iterations.get());
+        let salted_password = hash_password(self.password, iterations, &salt_buffer[..salt_len]);
+        let mut client_final = Buffer::new();
+        client_final.push_str("c=")?;
+        client_final.push_base64(self.gs2header.as_bytes())?;
+        let (client_proof, server_signature) = find_proofs(
+            &Mechanism::ScramSha256,
+            &client_final.as_str()[2..],
+            self.client_first_bare.as_str(),
+            server_first,
+            &salted_password,
+            nonce,
+        );
+        client_final.push_str(",r=")?;
+        client_final.push_str(nonce)?;
+        client_final.push_str(",p=")?;
+        client_final.push_base64(&client_proof)?;
+        Ok(ClientFinal {
+            server_signature,
+            client_final,
+        })
+    }
+}
+
+/// The third state of the allocation-free SCRAM mechanism after the first server message was
+/// successfully processed.
+#[derive(Debug)]
+pub struct ClientFinal<const N: usize> {
+    server_signature: Output,
+    client_final: Buffer<N>,
+}
+
+impl<const N: usize> ClientFinal<N> {
+    /// Aborts the handshake and zeroizes the final client message with the proof, like
+    /// [`client::ClientFinal::abort`](crate::client::ClientFinal::abort).
+    pub fn abort(mut self) -> Aborted {
+        self.client_final.zeroize();
+        Aborted::new()
+    }
+
+    /// Returns the next state and the final client message.
+    pub fn client_final(self) -> (ServerFinal, Buffer<N>) {
+        let _phase = trace::phase(Mechanism::ScramSha256.name(), "client_final");
+        trace::sent(self.client_final.len());
+        let server_final = ServerFinal {
+            server_signature: self.server_signature,
+        };
+        (server_final, self.client_final)
+    }
+}
+
+/// The final state of the allocation-free SCRAM mechanism after the final client message was
+/// computed.
+#[derive(Debug)]
+pub struct ServerFinal {
+    server_signature: Output,
+}
+
+impl ServerFinal {
+    /// Processes the final answer from the server and returns the authentication result, with the
+    /// same semantics as
+    /// [`client::ServerFinal::handle_server_final`](crate::client::ServerFinal::handle_server_final).
+    pub fn handle_server_final(self, server_final: &str) -> Result<(), Error> {
+        self.handle_server_final_with_extensions(server_final)
+            .map(|_| ())
+    }
+
+    /// Aborts the handshake, like
+    /// [`client::ServerFinal::abort`](crate::client::ServerFinal::abort).
+    pub fn abort(self) -> Aborted {
+        Aborted::new()
+    }
+
+    /// Like [`handle_server_final`](Self::handle_server_final), but returns the extension
+    /// attributes following the verifier on success. The string is empty if the server didn't
+    /// send any.
+    pub fn handle_server_final_with_extensions(self, server_final: &str) -> Result<&str, Error> {
+        let _phase = trace::phase(Mechanism::ScramSha256.name(), "handle_server_final");
+        trace::received(server_final.len());
+        let result = ServerFinalMessage::parse(server_final).and_then(|message| {
+            let extensions = message.extensions;
+            self.verify(message.verification).map(|()| extensions)
+        });
+        trace::outcome(&result);
+        result
+    }
+
+    fn verify(self, verification: Verification) -> Result<(), Error> {
+        match verification {
+            Verification::Verifier(verifier_base64) => {
+                // Room for the conservative length estimate of the decoder.
+                let mut verifier = [0u8; SHA256_OUTPUT_LEN + 3];
+                let len = STANDARD
+                    .decode_slice(verifier_base64, &mut verifier)
+                    .map_err(|err| match err {
+                        DecodeSliceError::OutputSliceTooSmall => {
+                            let excerpt = Excerpt::new(Some('v'), verifier_base64);
+                            Error::Protocol(Kind::InvalidField(Field::VerifyOrError, Some(excerpt)))
+                        }
+                        DecodeSliceError::DecodeError(err) => {
+                            Error::base64(Field::VerifyOrError, err)
+                        }
+                    })?;
+                if self.server_signature.as_ref() == &verifier[..len] {
+                    Ok(())
+                } else {
+                    Err(Error::InvalidServer)
+                }
+            }
+            Verification::Error(error) => Err(Error::authentication(error)),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<const N: usize> defmt::Format for Buffer<N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        // The content may contain the client proof, so only the length is shown.
+        defmt::write!(
+            fmt,
+            "Buffer {{ len: {=usize}, capacity: {=usize} }}",
+            self.len,
+            N
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, const N: usize> defmt::Format for ScramClient<'a, N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ScramClient {{ mechanism: {}, capacity: {=usize} }}",
+            Mechanism::ScramSha256,
+            N
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<'a, const N: usize> defmt::Format for ServerFirst<'a, N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ServerFirst {{ mechanism: {}, capacity: {=usize} }}",
+            Mechanism::ScramSha256,
+            N
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<const N: usize> defmt::Format for ClientFinal<N> {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ClientFinal {{ mechanism: {}, capacity: {=usize} }}",
+            Mechanism::ScramSha256,
+            N
+        )
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for ServerFinal {
+    fn format(&self, fmt: defmt::Formatter) {
+        defmt::write!(
+            fmt,
+            "ServerFinal {{ mechanism: {} }}",
+            Mechanism::ScramSha256
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Buffer, ScramClient};
+    use crate::error::{Error, Field, Kind};
+
+    const SERVER_FIRST: &str = "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096";
+
+    fn client<const N: usize>() -> ScramClient<'static, N> {
+        let mut nonce = Buffer::new();
+        nonce.push_str("rOprNGfwEbeRWgbNEkqO").unwrap();
+        ScramClient::with_nonce("user", "pencil", None, nonce)
+    }
+
+    #[test]
+    fn test_rfc7677_example() {
+        let (scram, client_first) = client::<128>().client_first().unwrap();
+        assert_eq!(client_first.as_str(), "n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let scram = scram.handle_server_first(SERVER_FIRST).unwrap();
+        let (scram, client_final) = scram.client_final();
+        assert_eq!(
+            client_final.as_str(),
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+             p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+        assert_eq!(
+            scram.handle_server_final("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_server_final_extensions() {
+        let (scram, _) = client::<128>().client_first().unwrap();
+        let (scram, _) = scram.handle_server_first(SERVER_FIRST).unwrap().client_final();
+        assert_eq!(
+            scram.handle_server_final_with_extensions(
+                "v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=,x=1"
+            ),
+            Ok("x=1")
+        );
+    }
+
+    #[test]
+    fn test_buffer_too_small() {
+        assert_eq!(
+            client::<16>().client_first().unwrap_err(),
+            Error::BufferTooSmall
+        );
+        let (scram, _) = client::<64>().client_first().unwrap();
+        assert_eq!(
+            scram.handle_server_first(SERVER_FIRST).unwrap_err(),
+            Error::BufferTooSmall
+        );
+    }
+
+    #[test]
+    fn test_escaped_usernames() {
+        let mut nonce = Buffer::new();
+        nonce.push_str("nonce").unwrap();
+        let scram = ScramClient::<64>::with_nonce("a,b", "password", Some("c=d"), nonce);
+        let (_, client_first) = scram.client_first().unwrap();
+        assert_eq!(client_first.as_str(), "n,a=c=3Dd,n=a=2Cb,r=nonce");
+        let scram = ScramClient::<64>::with_nonce("a\0b", "password", None, Buffer::new());
+        assert_eq!(
+            scram.client_first().err(),
+            Some(Error::Protocol(Kind::InvalidField(Field::Authcid, None)))
+        );
+    }
+}
@@ -0,0 +1,352 @@
+//! Workarounds for peers which don't follow RFC5802 exactly.
+//!
+//! Several SCRAM implementations in the wild, especially on embedded devices, send messages this
+//! crate rejects by default or expect messages in a different form. A [`Lenience`] passed to
+//! [`ScramClient::lenient`](crate::ScramClient::lenient) enables workarounds for them. All of them
+//! are disabled by default, and every message fixed up by one is reported as an
+//! [`InteropWarning`]. The warnings are emitted through the `tracing` feature and collected by the
+//! client, so integrators can report the misbehavior to the vendor of the peer:
+//!
+//! ```rust,no_run
+//! use scram::interop::Lenience;
+//! use scram::ScramClient;
+//!
+//! # let (server_first, server_final) = ("", "");
+//! let client = ScramClient::new("user", "password", None)
+//!     .lenient(Lenience::new().trim_whitespace().accept_unpadded_base64());
+//! let (client, client_first) = client.client_first();
+//! // Send `client_first` and receive `server_first`.
+//! let client = client.handle_server_first(server_first).unwrap();
+//! let mut warnings = client.warnings().to_vec();
+//! let (client, client_final) = client.client_final();
+//! // Send `client_final` and receive `server_final`.
+//! let (result, final_warnings) = client.handle_server_final_with_warnings(server_final);
+//! warnings.extend(final_warnings);
+//! for warning in &warnings {
+//!     eprintln!("non-conformant server: {}", warning);
+//! }
+//! ```
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use base64::alphabet;
+use base64::engine::general_purpose::{
+    GeneralPurpose, GeneralPurposeConfig, STANDARD, STANDARD_NO_PAD, URL_SAFE, URL_SAFE_NO_PAD,
+};
+use base64::engine::DecodePaddingMode;
+use base64::{DecodeError, Engine};
+
+use crate::error::Field;
+use crate::trace;
+
+/// Returns the maximum length of `len` bytes encoded with any of the emitted variants.
+pub(crate) fn encoded_len(len: usize) -> usize {
+    len.div_ceil(3) * 4
+}
+
+/// Decodes the standard alphabet with or without padding.
+const STANDARD_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::STANDARD,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Decodes the URL-safe alphabet with or without padding.
+const URL_SAFE_ANY_PAD: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// The base64 variants of RFC4648.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Base64Variant {
+    /// The standard alphabet with padding, as required by RFC5802.
+    #[default]
+    Standard,
+    /// The standard alphabet without padding.
+    StandardNoPad,
+    /// The URL and filename safe alphabet with padding.
+    UrlSafe,
+    /// The URL and filename safe alphabet without padding.
+    UrlSafeNoPad,
+}
+
+/// The server messages received by the client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ServerMessage {
+    /// The `server-first-message`.
+    First,
+    /// The `server-final-message`.
+    Final,
+}
+
+/// A violation of RFC5802 by the server which the client tolerated.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InteropWarning {
+    /// The message ended with whitespace, which was stripped.
+    TrailingWhitespace(ServerMessage),
+    /// The field was encoded as base64 without padding.
+    UnpaddedBase64(Field),
+    /// The field was encoded with the URL-safe base64 alphabet.
+    UrlSafeBase64(Field),
+    /// The message contained attributes this crate doesn't know, which were ignored.
+    UnknownAttributes(ServerMessage),
+}
+
+impl fmt::Display for ServerMessage {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ServerMessage::First => fmt.write_str("server-first-message"),
+            ServerMessage::Final => fmt.write_str("server-final-message"),
+        }
+    }
+}
+
+impl fmt::Display for InteropWarning {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InteropWarning::TrailingWhitespace(message) => {
+                write!(fmt, "Trailing whitespace in {}", message)
+            }
+            InteropWarning::UnpaddedBase64(ref field) => {
+                write!(fmt, "Unpadded base64 in field {:?}", field)
+            }
+            InteropWarning::UrlSafeBase64(ref field) => {
+                write!(fmt, "URL-safe base64 in field {:?}", field)
+            }
+            InteropWarning::UnknownAttributes(message) => {
+                write!(fmt, "Unknown attributes in {}", message)
+            }
+        }
+    }
+}
+
+/// Records a warning and emits it through the `tracing` feature.
+pub(crate) fn warn(warnings: &mut Vec<InteropWarning>, warning: InteropWarning) {
+    trace::interop_warning(&warning);
+    warnings.push(warning);
+}
+
+/// The enabled interoperability workarounds of a client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lenience {
+    trim_whitespace: bool,
+    unpadded_base64: bool,
+    url_safe_base64: bool,
+    emitted_base64: Base64Variant,
+}
+
+impl Lenience {
+    /// Creates a configuration without any workarounds, which parses messages strictly.
+    pub fn new() -> Self {
+        Lenience::default()
+    }
+
+    /// Strips trailing whitespace, including CR and LF, from server messages before parsing them.
+    pub fn trim_whitespace(mut self) -> Self {
+        self.trim_whitespace = true;
+        self
+    }
+
+    /// Accepts base64 without padding in the salt and the verifier sent by the server.
+    pub fn accept_unpadded_base64(mut self) -> Self {
+        self.unpadded_base64 = true;
+        self
+    }
+
+    /// Accepts base64 with the URL-safe alphabet in the salt and the verifier sent by the server.
+    pub fn accept_url_safe_base64(mut self) -> Self {
+        self.url_safe_base64 = true;
+        self
+    }
+
+    /// Encodes the channel binding and the proof in the `client-final-message` with `variant`
+    /// instead of the standard alphabet with padding, for servers or transports which expect it.
+    /// Only servers which don't follow RFC5802 accept anything but [`Base64Variant::Standard`].
+    pub fn emit_base64(mut self, variant: Base64Variant) -> Self {
+        self.emitted_base64 = variant;
+        self
+    }
+
+    /// Decodes the base64 `field` sent by the server with the accepted variants.
+    pub(crate) fn decode(
+        &self,
+        data: &str,
+        field: Field,
+        warnings: &mut Vec<InteropWarning>,
+    ) -> Result<Vec<u8>, DecodeError> {
+        let err = match STANDARD.decode(data) {
+            Ok(decoded) => return Ok(decoded),
+            Err(err) => err,
+        };
+        if self.unpadded_base64 {
+            if let Ok(decoded) = STANDARD_ANY_PAD.decode(data) {
+                warn(warnings, InteropWarning::UnpaddedBase64(field));
+                return Ok(decoded);
+            }
+        }
+        if self.url_safe_base64 {
+            let engine = if self.unpadded_base64 {
+                &URL_SAFE_ANY_PAD
+            } else {
+                &URL_SAFE
+            };
+            if let Ok(decoded) = engine.decode(data) {
+                warn(warnings, InteropWarning::UrlSafeBase64(field));
+                return Ok(decoded);
+            }
+        }
+        Err(err)
+    }
+
+    /// Appends the concatenation of `parts` to `output`, encoded with the emitted variant. Apart
+    /// from growing `output` this doesn't allocate, so callers pre-size it with [`encoded_len`].
+    pub(crate) fn encode_to(&self, parts: &[&[u8]], output: &mut String) {
+        let engine = match self.emitted_base64 {
+            Base64Variant::Standard => &STANDARD,
+            Base64Variant::StandardNoPad => &STANDARD_NO_PAD,
+            Base64Variant::UrlSafe => &URL_SAFE,
+            Base64Variant::UrlSafeNoPad => &URL_SAFE_NO_PAD,
+        };
+        // Chunks of a multiple of three bytes encode without padding, so the pieces can be joined.
+        let mut chunk = [0u8; 48];
+        let mut len = 0;
+        for &byte in parts.iter().flat_map(|part| part.iter()) {
+            chunk[len] = byte;
+            len += 1;
+            if len == chunk.len() {
+                engine.encode_string(chunk, output);
+                len = 0;
+            }
+        }
+        engine.encode_string(&chunk[..len], output);
+    }
+
+    /// Applies the enabled workarounds for the message framing to a server message.
+    pub(crate) fn trim<'m>(
+        &self,
+        message: &'m str,
+        kind: ServerMessage,
+        warnings: &mut Vec<InteropWarning>,
+    ) -> &'m str {
+        if !self.trim_whitespace {
+            return message;
+        }
+        let trimmed = message.trim_end();
+        if trimmed.len() != message.len() {
+            warn(warnings, InteropWarning::TrailingWhitespace(kind));
+        }
+        trimmed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::{DecodeError, Engine};
+
+    use super::{encoded_len, Base64Variant, InteropWarning, Lenience, ServerMessage};
+    use crate::error::Field;
+
+    #[test]
+    fn test_trim() {
+        let mut warnings = Vec::new();
+        let strict = Lenience::new();
+        assert_eq!(
+            strict.trim("v=abc\r\n", ServerMessage::Final, &mut warnings),
+            "v=abc\r\n"
+        );
+        assert!(warnings.is_empty());
+        let lenient = Lenience::new().trim_whitespace();
+        assert_eq!(
+            lenient.trim("v=abc", ServerMessage::Final, &mut warnings),
+            "v=abc"
+        );
+        assert!(warnings.is_empty());
+        assert_eq!(
+            lenient.trim("v=abc\r\n", ServerMessage::Final, &mut warnings),
+            "v=abc"
+        );
+        assert_eq!(
+            warnings,
+            [InteropWarning::TrailingWhitespace(ServerMessage::Final)]
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        let decode = |lenience: Lenience, data| lenience.decode(data, Field::Salt, &mut Vec::new());
+        let strict = Lenience::new();
+        assert_eq!(decode(strict, "+/8="), Ok(vec![0xfb, 0xff]));
+        assert_eq!(decode(strict, "+/8"), Err(DecodeError::InvalidPadding));
+        assert!(decode(strict, "-_8=").is_err());
+        let unpadded = Lenience::new().accept_unpadded_base64();
+        assert_eq!(decode(unpadded, "+/8"), Ok(vec![0xfb, 0xff]));
+        assert!(decode(unpadded, "-_8=").is_err());
+        let url_safe = Lenience::new().accept_url_safe_base64();
+        assert_eq!(decode(url_safe, "-_8="), Ok(vec![0xfb, 0xff]));
+        assert!(decode(url_safe, "-_8").is_err());
+        let both = url_safe.accept_unpadded_base64();
+        assert_eq!(decode(both, "-_8"), Ok(vec![0xfb, 0xff]));
+    }
+
+    #[test]
+    fn test_decode_warnings() {
+        let lenience = Lenience::new()
+            .accept_unpadded_base64()
+            .accept_url_safe_base64();
+        let mut warnings = Vec::new();
+        lenience.decode("+/8=", Field::Salt, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        lenience.decode("+/8", Field::Salt, &mut warnings).unwrap();
+        lenience
+            .decode("-_8=", Field::VerifyOrError, &mut warnings)
+            .unwrap();
+        assert_eq!(
+            warnings,
+            [
+                InteropWarning::UnpaddedBase64(Field::Salt),
+                InteropWarning::UrlSafeBase64(Field::VerifyOrError),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode() {
+        let encode = |lenience: Lenience, parts: &[&[u8]]| {
+            let mut output = String::from("c=");
+            lenience.encode_to(parts, &mut output);
+            output
+        };
+        let data = [0xfb, 0xff];
+        assert_eq!(encode(Lenience::new(), &[&data]), "c=+/8=");
+        let lenience = Lenience::new().emit_base64(Base64Variant::UrlSafeNoPad);
+        assert_eq!(encode(lenience, &[&data]), "c=-_8");
+
+        // Parts are joined across the chunks, and a multiple of the chunk size isn't padded.
+        let data = [0x5a; 96];
+        let expected = format!("c={}", STANDARD.encode(data));
+        assert_eq!(
+            encode(Lenience::new(), &[&data[..47], &data[47..]]),
+            expected
+        );
+        assert_eq!(
+            encode(Lenience::new(), &[&data[..50], &[], &data[50..]]),
+            expected
+        );
+        assert_eq!(
+            encode(Lenience::new(), &[&data[..70]]),
+            format!("c={}", STANDARD.encode(&data[..70]))
+        );
+        for len in 0..=data.len() {
+            assert_eq!(STANDARD.encode(&data[..len]).len(), encoded_len(len));
+        }
+    }
+}
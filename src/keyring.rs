@@ -0,0 +1,117 @@
+//! Client passwords from the secret store of the platform.
+//!
+//! Instead of keeping passwords in configuration files or environment variables, a client can
+//! fetch them from the macOS Keychain, the Windows Credential Manager or the Secret Service and
+//! keyutils on Linux right before the handshake, through the `keyring` crate. The password is
+//! zeroized when the [`StoredPassword`] is dropped.
+//!
+//! ``` rust,no_run
+//! use scram::keyring::StoredPassword;
+//!
+//! let password = StoredPassword::fetch("imap.example.com", "user").unwrap();
+//! let (client, client_first) = password.client("user", None).client_first();
+//! ```
+//!
+//! This module is available with the `keyring` feature. The `keyring` crate has no credential
+//! store enabled by default, so the application enables the store of each platform with the
+//! features of its own `keyring` dependency, for example `apple-native` or `linux-native`.
+//! Without any, a mock store is used which only keeps passwords in memory.
+use alloc::string::String;
+use core::fmt;
+
+use ::keyring::{Entry, Error};
+
+use crate::client::ScramClient;
+use crate::utils::zeroize_string;
+
+/// A password fetched from the secret store of the platform.
+pub struct StoredPassword {
+    password: String,
+}
+
+impl StoredPassword {
+    /// Fetches the password of `username` for `service` from the default credential store.
+    pub fn fetch(service: &str, username: &str) -> Result<Self, Error> {
+        StoredPassword::from_entry(&Entry::new(service, username)?)
+    }
+
+    /// Fetches the password of `entry`, for entries with a target or a custom credential.
+    pub fn from_entry(entry: &Entry) -> Result<Self, Error> {
+        Ok(StoredPassword {
+            password: entry.get_password()?,
+        })
+    }
+
+    /// Returns the initial state of a handshake with the password. The other arguments have the
+    /// same meaning as those of [`ScramClient::new`].
+    ///
+    /// # Panics
+    ///
+    /// Panics like [`ScramClient::new`].
+    pub fn client<'a>(&'a self, authcid: &'a str, authzid: Option<&'a str>) -> ScramClient<'a> {
+        ScramClient::new(authcid, &self.password, authzid)
+    }
+}
+
+impl Drop for StoredPassword {
+    fn drop(&mut self) {
+        zeroize_string(&mut self.password);
+    }
+}
+
+impl fmt::Debug for StoredPassword {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("StoredPassword(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use ::keyring::mock::MockCredential;
+    use ::keyring::{Entry, Error};
+
+    use super::StoredPassword;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, _: &str) -> Option<PasswordInfo> {
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("pencil", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    #[test]
+    fn test_stored_password() {
+        let entry = Entry::new_with_credential(Box::<MockCredential>::default());
+        assert!(matches!(
+            StoredPassword::from_entry(&entry),
+            Err(Error::NoEntry)
+        ));
+        entry.set_password("pencil").unwrap();
+        let password = StoredPassword::from_entry(&entry).unwrap();
+        assert_eq!(format!("{:?}", password), "StoredPassword(..)");
+
+        let server = ScramServer::new(TestProvider);
+        let (client, client_first) = password.client("user", None).client_first();
+        let (server, server_first) = server
+            .handle_client_first(&client_first)
+            .unwrap()
+            .server_first();
+        let (client, client_final) = client
+            .handle_server_first(&server_first)
+            .unwrap()
+            .client_final();
+        let (status, server_final) = server
+            .handle_client_final(&client_final)
+            .unwrap()
+            .server_final();
+        assert_eq!(status, AuthenticationStatus::Authenticated);
+        assert_eq!(client.handle_server_final(&server_final), Ok(()));
+    }
+}
@@ -2,7 +2,14 @@
 //!
 //! This implementation currently provides a client and a server for the SCRAM-SHA-256 mechanism
 //! according to [RFC5802](https://tools.ietf.org/html/rfc5802) and
-//! [RFC7677](https://tools.ietf.org/html/rfc7677). It doesn't support channel-binding.
+//! [RFC7677](https://tools.ietf.org/html/rfc7677), and for SCRAM-SHA-512 and SCRAM-SHA-384, see
+//! [`Mechanism`], including the channel binding of the `-PLUS` variants, see the
+//! [`channel_binding`] module. Clients can compute the handshake with other hash functions, see
+//! the [`hash`] module.
+//!
+//! The [`prelude`] module imports the commonly used types with a single `use scram::prelude::*;`.
+//! The handshakes below are typestates; the [`session`] module wraps them for connection structs
+//! which send and receive messages through `&mut self`.
 //!
 //! # Usage
 //!
@@ -127,20 +134,223 @@
 //! // Check if the client successfully authenticated
 //! assert_eq!(status, AuthenticationStatus::Authenticated);
 //! ```
+//!
+//! # Features
+//!
+//! The default build only contains the client and server state machines with `ring` as the
+//! cryptographic backend. Everything else is opt-in.
+//!
+//! * `std` (default): Implements `std::error::Error` and provides the constructors using the
+//!   operating system's random number generator, like [`ScramClient::new`]. Without this feature
+//!   the crate is `#![no_std]` and only requires `alloc`. Entropy is then supplied through the
+//!   `*_with_rng` methods, which accept a custom [`EntropySource`], like a hardware TRNG.
+//! * `crypto` (enabled by `std`): Provides everything which needs `ring` or random numbers: the
+//!   client and server state machines and the modules built on them. Without it, only the
+//!   message parsers and serializers of the `grammar`, `gs2` and `framing` modules and the
+//!   error types remain, for fuzzers and protocol analyzers which inspect SCRAM traffic without
+//!   authenticating. `no_std` builds with the state machines enable it explicitly.
+//! * `rand` (enabled by `std`): Implements [`EntropySource`] for every cryptographically secure
+//!   random number generator of the `rand` crate.
+//! * `async`: Provides async handshake drivers in the `local` module. Their futures don't
+//!   require `Send`, for single-threaded executors. Requires Rust 1.75.
+//! * `accounts`: Provides the `CredentialStore` of the `accounts` module, which resolves the
+//!   credentials of clients with many accounts by host, service and username.
+//! * `cache`: Provides the encrypted cache of salted passwords in the `cache` module, which spares
+//!   clients the key derivation after restarts and reconnects.
+//! * `users`: Provides the in-memory `UserDatabase` of the `users` module, a server credential
+//!   provider which derives the verifiers of its users from their passwords, and the
+//!   `FileUserDatabase`, which loads them from a file and saves them back.
+//! * `nfc`: Provides the normalization of usernames and passwords to Unicode Normalization Form
+//!   C in the `nfc` module, a lightweight alternative to SASLprep for servers which store
+//!   normalized credentials.
+//! * `saslprep`: Provides the SASLprep profile of stringprep in the `saslprep` module, which
+//!   prepares the credentials of clients, the usernames servers receive and the derived
+//!   credentials as RFC5802 requires.
+//! * `mongodb`: Provides the pre-hashing of passwords and the SHA-1 hash function for the clients
+//!   of MongoDB's SCRAM-SHA-1 in the `mongodb` module, with `md-5` as dependency.
+//! * `pinning`: Provides the stores of the salts and iteration counts of previous handshakes in
+//!   the `pinning` module, which detect servers whose verifier of a user was replaced.
+//! * `keyring`: Provides the fetching of client passwords from the secret store of the platform
+//!   in the `keyring` module. The platform stores are enabled with the features of `keyring`.
+//! * `rustls`: Provides the extraction of the channel-binding data from `rustls` connections in
+//!   the `rustls` module.
+//! * `openssl`: Provides the same for `openssl` connections in the `openssl` module, including the
+//!   data of `tls-unique`.
+//! * `native-tls`: Provides the same for `native-tls` connections in the `native_tls` module,
+//!   which is limited to `tls-server-end-point`.
+//! * `mlock`: Locks the salted passwords of the `cache` module and the secrets of the `secrets`
+//!   module into memory, so they aren't written to the swap file. Every secret takes at least two
+//!   pages of memory, and locking fails silently beyond the limit of locked memory of the process.
+//! * `serde`: Implements `Serialize` and `Deserialize` for errors, report types, credential
+//!   exports and the [`ScramConfig`].
+//! * `telemetry`: Counts the handshakes of every server by outcome and measures the key derivation
+//!   time. `ScramServer::telemetry` returns a snapshot of the counters.
+//! * `tracing`: Emits spans and events for every handshake phase without secret material.
+//! * `log`: Emits the same diagnostics as `tracing` as records of the `log` crate, for
+//!   applications which don't use `tracing`. Works without `std`.
+//! * `defmt`: Implements `defmt::Format` for errors, `Mechanism`, `AuthenticationStatus` and the
+//!   client states. The states only show the mechanism and the phase, never credentials, nonces
+//!   or proofs.
+//! * `conformance`: Provides the `conformance` self-test of all supported mechanisms.
+//! * `transcript`: Provides the recording and replaying of handshakes in the `transcript` module.
+//! * `interop-corpus`: Provides the `corpus` of transcripts in the formats of PostgreSQL, MongoDB,
+//!   Kafka and Dovecot, and replays the client against them. Enables `transcript`.
+//! * `test-util`: Provides `mock` peers and the `InsecureDeterministicRng`, and allows low
+//!   iteration counts for fast tests.
+//! * `testserver`: Builds the `scram-testserver` binary, a TCP server speaking newline-framed
+//!   SCRAM with configurable credentials, mechanisms and faults, for the CI of database drivers.
+//! * `proptest`: Provides `proptest` strategies for messages and credentials.
+//! * `wasm-js`: Obtains random numbers and runs `ring` through JavaScript on
+//!   `wasm32-unknown-unknown`, for browsers and edge runtimes. The `wasm32-wasip1` target works
+//!   without additional features.
+//!
+//! For microcontrollers the [`heapless`] module provides a client which keeps all messages in
+//! fixed-size buffers and doesn't allocate.
+#![cfg_attr(not(feature = "std"), no_std)]
+// The parser-only build leaves the helpers shared with the state machines unused.
+#![cfg_attr(not(feature = "crypto"), allow(dead_code, unused_imports))]
+
+#[macro_use]
+extern crate alloc;
 extern crate base64;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "defmt")]
+extern crate defmt;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(any(test, feature = "proptest"))]
+extern crate proptest;
+#[cfg(feature = "rand")]
 extern crate rand;
+#[cfg(feature = "crypto")]
 extern crate ring;
+#[cfg(feature = "serde")]
+#[macro_use]
+extern crate serde;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 /// The length of the client nonce in characters/bytes.
 const NONCE_LENGTH: usize = 24;
 
-#[macro_use]
+/// The minimum iteration count accepted from a server, as recommended by RFC7677 section 4.
+const MIN_ITERATIONS: u32 = 4096;
+
 mod utils;
+#[cfg(any(test, feature = "accounts"))]
+pub mod accounts;
+#[cfg(any(test, feature = "cache"))]
+pub mod cache;
+#[cfg(feature = "crypto")]
+pub mod channel_binding;
+#[cfg(feature = "crypto")]
 pub mod client;
+#[cfg(feature = "crypto")]
+mod confidentiality;
+#[cfg(feature = "crypto")]
+pub mod config;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+#[cfg(any(test, feature = "interop-corpus"))]
+pub mod corpus;
+#[cfg(feature = "crypto")]
+pub mod credentials;
+#[cfg(feature = "crypto")]
+mod deadline;
+#[cfg(feature = "crypto")]
+pub mod delay;
+#[cfg(feature = "crypto")]
+pub mod dynamic;
+#[cfg(feature = "crypto")]
+mod entropy;
 mod error;
+#[cfg(feature = "crypto")]
+pub mod export;
+pub mod fallback;
+#[cfg(any(test, feature = "cache", feature = "users"))]
+mod file;
+pub mod framing;
+pub mod grammar;
+pub mod gs2;
+#[cfg(feature = "crypto")]
+pub mod hash;
+#[cfg(feature = "crypto")]
+pub mod heapless;
+pub mod interop;
+#[cfg(feature = "keyring")]
+pub mod keyring;
+#[cfg(any(test, feature = "async"))]
+pub mod local;
+mod mechanism;
+#[cfg(feature = "crypto")]
+pub mod migration;
+#[cfg(feature = "crypto")]
+mod mlock;
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+#[cfg(feature = "mongodb")]
+pub mod mongodb;
+#[cfg(feature = "crypto")]
+pub mod mqtt;
+#[cfg(feature = "native-tls")]
+pub mod native_tls;
+#[cfg(any(test, feature = "nfc"))]
+pub mod nfc;
+#[cfg(feature = "openssl")]
+pub mod openssl;
+#[cfg(any(test, feature = "pinning"))]
+pub mod pinning;
+#[cfg(feature = "crypto")]
+pub mod policy;
+#[cfg(feature = "crypto")]
+pub mod prelude;
+#[cfg(feature = "crypto")]
+pub mod proof;
+#[cfg(feature = "crypto")]
+pub mod registry;
+#[cfg(any(test, feature = "rustls"))]
+pub mod rustls;
+#[cfg(any(test, feature = "saslprep"))]
+pub mod saslprep;
+#[cfg(feature = "crypto")]
+pub mod secrets;
+#[cfg(feature = "crypto")]
 pub mod server;
+#[cfg(feature = "crypto")]
+pub mod session;
+#[cfg(any(test, feature = "proptest"))]
+pub mod strategies;
+#[cfg(feature = "crypto")]
+mod summary;
+#[cfg(feature = "crypto")]
+mod telemetry;
+mod trace;
+#[cfg(any(test, feature = "transcript"))]
+pub mod transcript;
+#[cfg(any(test, feature = "users"))]
+pub mod users;
+#[cfg(feature = "crypto")]
+pub mod verifier;
 
-pub use client::ScramClient;
-pub use error::{Error, Field, Kind};
-pub use server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
-pub use utils::hash_password;
+#[cfg(feature = "crypto")]
+pub use crate::client::ScramClient;
+#[cfg(feature = "crypto")]
+pub use crate::confidentiality::Confidentiality;
+#[cfg(feature = "crypto")]
+pub use crate::config::ScramConfig;
+#[cfg(feature = "crypto")]
+pub use crate::entropy::EntropySource;
+#[cfg(any(test, feature = "test-util"))]
+pub use crate::entropy::InsecureDeterministicRng;
+pub use crate::error::{
+    ChannelBindingError, ClientNonceError, DecodeError, Error, Excerpt, Field, Kind, SmtpStatus,
+};
+pub use crate::mechanism::Mechanism;
+#[cfg(feature = "crypto")]
+pub use crate::server::{
+    AuthenticationProvider, AuthenticationStatus, ClientNoncePolicy, ConnectionContext,
+    PasswordInfo, ScramServer,
+};
+#[cfg(feature = "crypto")]
+pub use crate::utils::{hash_password, hash_password_for};
@@ -0,0 +1,380 @@
+//! Async handshake drivers for single-threaded executors.
+//!
+//! This module is available with the `async` feature. The drivers run a complete handshake over a
+//! [`Transport`]. Neither the transport nor the returned futures are required to be `Send`, so
+//! transports built on `Rc`, `RefCell` or executor-local I/O work on wasm, glommio, embassy and
+//! other executors without a work-stealing scheduler.
+//!
+//! ``` rust,ignore
+//! use scram::local::{authenticate, Transport};
+//! use scram::ScramClient;
+//!
+//! # async fn example<T: Transport>(mut transport: T) {
+//! let client = ScramClient::new("user", "password", None);
+//! authenticate(&mut transport, client).await.unwrap();
+//! # }
+//! ```
+use alloc::string::String;
+use core::fmt;
+use core::future::Future;
+use core::time::Duration;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::client::ScramClient;
+use crate::delay::FailureDelay;
+use crate::entropy::EntropySource;
+use crate::error::Error;
+use crate::fallback::HandshakeError;
+use crate::server::{AuthenticationProvider, AuthenticationStatus, ConnectionContext, ScramServer};
+
+/// A connection to the peer which exchanges whole SCRAM messages.
+///
+/// The methods are `async fn`s without `Send` bounds on purpose: implementations may hold
+/// references to executor-local state across `.await` points.
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// The error returned by the connection.
+    type Error;
+
+    /// Sends a message to the peer.
+    async fn send(&mut self, message: &str) -> Result<(), Self::Error>;
+
+    /// Receives the next message from the peer.
+    async fn receive(&mut self) -> Result<String, Self::Error>;
+}
+
+/// The reasons a driven handshake can fail.
+#[derive(Debug, PartialEq)]
+pub enum DriverError<E> {
+    /// The transport failed to send or receive a message.
+    Transport(E),
+    /// The SCRAM state machine rejected a message of the peer.
+    Scram(Error),
+}
+
+impl<E: fmt::Display> fmt::Display for DriverError<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DriverError::Transport(ref err) => write!(fmt, "Transport error: {}", err),
+            DriverError::Scram(ref err) => write!(fmt, "SCRAM error: {}", err),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug + fmt::Display> error::Error for DriverError<E> {}
+
+impl<E> HandshakeError for DriverError<E> {
+    fn scram_error(&self) -> Option<&Error> {
+        match *self {
+            DriverError::Transport(_) => None,
+            DriverError::Scram(ref err) => Some(err),
+        }
+    }
+}
+
+impl<E> From<Error> for DriverError<E> {
+    fn from(err: Error) -> Self {
+        DriverError::Scram(err)
+    }
+}
+
+/// Authenticates `client` to the server at the other end of `transport`.
+pub async fn authenticate<T: Transport + ?Sized>(
+    transport: &mut T,
+    client: ScramClient<'_>,
+) -> Result<(), DriverError<T::Error>> {
+    let (client, client_first) = client.try_client_first()?;
+    transport
+        .send(&client_first)
+        .await
+        .map_err(DriverError::Transport)?;
+    let server_first = transport.receive().await.map_err(DriverError::Transport)?;
+    let client = client.handle_server_first(&server_first)?;
+    let (client, client_final) = client.client_final();
+    transport
+        .send(&client_final)
+        .await
+        .map_err(DriverError::Transport)?;
+    let server_final = transport.receive().await.map_err(DriverError::Transport)?;
+    client.handle_server_final(&server_final)?;
+    Ok(())
+}
+
+/// Answers the handshake of the client at the other end of `transport` and returns the
+/// authentication status. The server nonce is generated from `rng`.
+pub async fn serve<T, P, R>(
+    transport: &mut T,
+    server: &ScramServer<P>,
+    rng: &mut R,
+) -> Result<AuthenticationStatus, DriverError<T::Error>>
+where
+    T: Transport + ?Sized,
+    P: AuthenticationProvider,
+    R: EntropySource + ?Sized,
+{
+    let client_first = transport.receive().await.map_err(DriverError::Transport)?;
+    let server = server.handle_client_first(&client_first)?;
+    let (server, server_first) = server.server_first_with_rng(rng);
+    transport
+        .send(&server_first)
+        .await
+        .map_err(DriverError::Transport)?;
+    let client_final = transport.receive().await.map_err(DriverError::Transport)?;
+    let server = server.handle_client_final(&client_final)?;
+    let (status, server_final) = server.server_final();
+    transport
+        .send(&server_final)
+        .await
+        .map_err(DriverError::Transport)?;
+    Ok(status)
+}
+
+/// Like [`serve`], but delays the responses to failed attempts as computed by `delay`.
+///
+/// Unknown users and wrong passwords count as failures. `sleep` is called with the delay and
+/// should return a timer future of the executor. The `context` is passed to the provider and
+/// determines the peer for [`Scope::Peer`](crate::delay::Scope::Peer).
+pub async fn serve_with_delay<T, P, R, S, F>(
+    transport: &mut T,
+    server: &ScramServer<P>,
+    context: &ConnectionContext<'_>,
+    rng: &mut R,
+    delay: &mut FailureDelay,
+    mut sleep: S,
+) -> Result<AuthenticationStatus, DriverError<T::Error>>
+where
+    T: Transport + ?Sized,
+    P: AuthenticationProvider,
+    R: EntropySource + ?Sized,
+    S: FnMut(Duration) -> F,
+    F: Future<Output = ()>,
+{
+    let client_first = transport.receive().await.map_err(DriverError::Transport)?;
+    let server = match server.handle_client_first_in_context(&client_first, context) {
+        Ok(server) => server,
+        Err(Error::InvalidUser(username)) => {
+            sleep(delay.record_failure(delay.key(&username, context))).await;
+            return Err(DriverError::Scram(Error::InvalidUser(username)));
+        }
+        Err(err) => return Err(DriverError::Scram(err)),
+    };
+    let (server, server_first) = server.server_first_with_rng(rng);
+    transport
+        .send(&server_first)
+        .await
+        .map_err(DriverError::Transport)?;
+    let client_final = transport.receive().await.map_err(DriverError::Transport)?;
+    let server = server.handle_client_final(&client_final)?;
+    let identity = String::from(server.identity());
+    let key = delay.key(&identity, context);
+    let (status, server_final) = server.server_final();
+    match status {
+        AuthenticationStatus::Authenticated => delay.record_success(key),
+        AuthenticationStatus::NotAuthenticated => sleep(delay.record_failure(key)).await,
+        AuthenticationStatus::NotAuthorized => {}
+    }
+    transport
+        .send(&server_final)
+        .await
+        .map_err(DriverError::Transport)?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use alloc::string::String;
+    use core::cell::RefCell;
+    use core::future::Future;
+    use core::num::NonZeroU32;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+    use core::time::Duration;
+
+    use rand::rngs::OsRng;
+
+    use super::{authenticate, serve, serve_with_delay, DriverError, Transport};
+    use crate::delay::{Backoff, FailureDelay, Scope};
+    use crate::mock::{MockClient, MockServer};
+    use crate::ConnectionContext;
+    use crate::{hash_password, AuthenticationProvider, AuthenticationStatus, Error, PasswordInfo};
+    use crate::{ScramClient, ScramServer};
+
+    /// Polls a future which never waits to completion.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = pin!(future);
+        let mut context = Context::from_waker(Waker::noop());
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+                return output;
+            }
+        }
+    }
+
+    /// A transport to a mock server. The server is shared through an `Rc`, so the futures of
+    /// this transport aren't `Send`.
+    struct ToServer {
+        server: Rc<RefCell<MockServer>>,
+        reply: Option<String>,
+    }
+
+    impl Transport for ToServer {
+        type Error = Error;
+
+        async fn send(&mut self, message: &str) -> Result<(), Error> {
+            let mut server = self.server.borrow_mut();
+            let reply = if message.starts_with("n,") {
+                server.server_first(message)?
+            } else {
+                server.server_final(message)?
+            };
+            self.reply = Some(reply);
+            Ok(())
+        }
+
+        async fn receive(&mut self) -> Result<String, Error> {
+            Ok(self.reply.take().expect("a reply is pending"))
+        }
+    }
+
+    /// A transport to a mock client, which starts the handshake.
+    struct ToClient {
+        client: MockClient,
+        reply: Option<String>,
+        outcome: Option<Result<(), Error>>,
+    }
+
+    impl Transport for ToClient {
+        type Error = Error;
+
+        async fn send(&mut self, message: &str) -> Result<(), Error> {
+            if message.starts_with("r=") {
+                self.reply = Some(self.client.client_final(message)?);
+                Ok(())
+            } else {
+                self.outcome = Some(self.client.handle_server_final(message));
+                Ok(())
+            }
+        }
+
+        async fn receive(&mut self) -> Result<String, Error> {
+            Ok(self.reply.take().expect("a message is pending"))
+        }
+    }
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            let iterations = NonZeroU32::new(4096).unwrap();
+            match username {
+                "user" => Some(PasswordInfo::new(
+                    hash_password("password", iterations, b"salt").to_vec(),
+                    4096,
+                    b"salt".to_vec(),
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    fn to_server() -> ToServer {
+        ToServer {
+            server: Rc::new(RefCell::new(MockServer::new("user", "password"))),
+            reply: None,
+        }
+    }
+
+    fn to_client(password: &str) -> ToClient {
+        let client = MockClient::new("user", password);
+        let reply = Some(client.client_first());
+        ToClient {
+            client,
+            reply,
+            outcome: None,
+        }
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let client = ScramClient::new("user", "password", None);
+        assert_eq!(block_on(authenticate(&mut to_server(), client)), Ok(()));
+        let client = ScramClient::new("user", "wrong", None);
+        assert_eq!(
+            block_on(authenticate(&mut to_server(), client)),
+            Err(DriverError::Scram(Error::Authentication(
+                "invalid-proof".to_string()
+            )))
+        );
+    }
+
+    #[test]
+    fn test_serve() {
+        let server = ScramServer::new(TestProvider);
+        let mut transport = to_client("password");
+        assert_eq!(
+            block_on(serve(&mut transport, &server, &mut OsRng)),
+            Ok(AuthenticationStatus::Authenticated)
+        );
+        assert_eq!(transport.outcome, Some(Ok(())));
+        let mut transport = to_client("wrong");
+        assert_eq!(
+            block_on(serve(&mut transport, &server, &mut OsRng)),
+            Ok(AuthenticationStatus::NotAuthenticated)
+        );
+        assert!(matches!(
+            transport.outcome,
+            Some(Err(Error::Authentication(_)))
+        ));
+    }
+
+    #[test]
+    fn test_serve_with_delay() {
+        let server = ScramServer::new(TestProvider);
+        let context = ConnectionContext::new();
+        let backoff = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let mut delay = FailureDelay::new(backoff, Scope::Username);
+        let mut slept = Vec::new();
+        for password in ["wrong", "wrong", "password", "wrong"] {
+            block_on(serve_with_delay(
+                &mut to_client(password),
+                &server,
+                &context,
+                &mut OsRng,
+                &mut delay,
+                |duration| {
+                    slept.push(duration);
+                    async {}
+                },
+            ))
+            .unwrap();
+        }
+        let mut unknown = ToClient {
+            client: MockClient::new("nobody", "password"),
+            reply: None,
+            outcome: None,
+        };
+        unknown.reply = Some(unknown.client.client_first());
+        assert!(block_on(serve_with_delay(
+            &mut unknown,
+            &server,
+            &context,
+            &mut OsRng,
+            &mut delay,
+            |duration| {
+                slept.push(duration);
+                async {}
+            },
+        ))
+        .is_err());
+        assert_eq!(
+            slept,
+            [100, 200, 100, 100].map(Duration::from_millis).to_vec()
+        );
+    }
+}
@@ -0,0 +1,141 @@
+use core::fmt;
+
+#[cfg(feature = "crypto")]
+use ring::{digest, hmac, pbkdf2};
+
+/// The SCRAM mechanisms implemented by this crate.
+///
+/// SCRAM-SHA-224 isn't implemented, as `ring` doesn't provide SHA-224.
+///
+/// Mechanisms are ordered like [`Mechanism::ALL`], strongest first, so sorting a list of
+/// mechanisms puts the preferred one first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Mechanism {
+    /// SCRAM-SHA-512, as advertised by Couchbase and other servers, with the hash function
+    /// substituted into RFC5802.
+    ScramSha512,
+    /// SCRAM-SHA-384, with the hash function substituted into RFC5802.
+    ScramSha384,
+    /// SCRAM-SHA-256 as defined in RFC7677.
+    ScramSha256,
+}
+
+impl Mechanism {
+    /// All mechanisms supported by this build of the crate, strongest first.
+    pub const ALL: &'static [Mechanism] = &[
+        Mechanism::ScramSha512,
+        Mechanism::ScramSha384,
+        Mechanism::ScramSha256,
+    ];
+
+    /// The mechanisms servers advertise unless configured otherwise. Servers only learn the
+    /// mechanism a client chose from the application, see `ConnectionContext::with_mechanism`,
+    /// so the others have to be enabled explicitly.
+    pub const DEFAULT: &'static [Mechanism] = &[Mechanism::ScramSha256];
+
+    /// Returns the SASL mechanism name. Only SCRAM-SHA-256 is registered with the IANA.
+    pub fn name(self) -> &'static str {
+        match self {
+            Mechanism::ScramSha512 => "SCRAM-SHA-512",
+            Mechanism::ScramSha384 => "SCRAM-SHA-384",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256",
+        }
+    }
+
+    /// Returns the SASL mechanism name of the `-PLUS` variant, which binds the handshake to the
+    /// TLS channel.
+    pub fn plus_name(self) -> &'static str {
+        match self {
+            Mechanism::ScramSha512 => "SCRAM-SHA-512-PLUS",
+            Mechanism::ScramSha384 => "SCRAM-SHA-384-PLUS",
+            Mechanism::ScramSha256 => "SCRAM-SHA-256-PLUS",
+        }
+    }
+
+    /// Returns the output length of the hash function in bytes, which is the length of the salted
+    /// password, the keys, the proof and the signature.
+    pub fn output_len(self) -> usize {
+        match self {
+            Mechanism::ScramSha512 => 64,
+            Mechanism::ScramSha384 => 48,
+            Mechanism::ScramSha256 => 32,
+        }
+    }
+
+    /// Returns the mechanism with the IANA registered SASL mechanism `name`, if it's supported by
+    /// this build of the crate.
+    pub fn from_name(name: &str) -> Option<Mechanism> {
+        Mechanism::ALL
+            .iter()
+            .copied()
+            .find(|mechanism| mechanism.name() == name)
+    }
+
+    /// Returns the hash function `H` of RFC5802.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn digest(self) -> &'static digest::Algorithm {
+        match self {
+            Mechanism::ScramSha512 => &digest::SHA512,
+            Mechanism::ScramSha384 => &digest::SHA384,
+            Mechanism::ScramSha256 => &digest::SHA256,
+        }
+    }
+
+    /// Returns the `HMAC` of RFC5802.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn hmac(self) -> hmac::Algorithm {
+        match self {
+            Mechanism::ScramSha512 => hmac::HMAC_SHA512,
+            Mechanism::ScramSha384 => hmac::HMAC_SHA384,
+            Mechanism::ScramSha256 => hmac::HMAC_SHA256,
+        }
+    }
+
+    /// Returns the PBKDF2 variant computing `Hi` of RFC5802.
+    #[cfg(feature = "crypto")]
+    pub(crate) fn pbkdf2(self) -> pbkdf2::Algorithm {
+        match self {
+            Mechanism::ScramSha512 => pbkdf2::PBKDF2_HMAC_SHA512,
+            Mechanism::ScramSha384 => pbkdf2::PBKDF2_HMAC_SHA384,
+            Mechanism::ScramSha256 => pbkdf2::PBKDF2_HMAC_SHA256,
+        }
+    }
+}
+
+impl fmt::Display for Mechanism {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::Mechanism;
+
+    #[test]
+    fn test_order() {
+        let mut mechanisms = Mechanism::ALL.to_vec();
+        mechanisms.reverse();
+        mechanisms.sort();
+        assert_eq!(mechanisms, Mechanism::ALL);
+        let names: HashSet<_> = Mechanism::ALL.iter().copied().collect();
+        assert_eq!(names.len(), Mechanism::ALL.len());
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_output_len() {
+        for &mechanism in Mechanism::ALL {
+            assert_eq!(mechanism.digest().output_len(), mechanism.output_len());
+            assert_eq!(
+                mechanism.hmac().digest_algorithm().output_len(),
+                mechanism.output_len()
+            );
+            assert_eq!(Mechanism::from_name(mechanism.name()), Some(mechanism));
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! Gradual migration of users from legacy password hashes to SCRAM credentials.
+//!
+//! A server moving to SCRAM often only has bcrypt, argon2 or similar hashes of the existing
+//! passwords. SCRAM credentials can't be derived from them, only from the plaintext password. The
+//! [`Migrator`] creates the credentials the next time the plaintext is available, for example when
+//! the user logs in once more through a companion PLAIN exchange or a provisioning call:
+//!
+//! 1. The [`AuthenticationProvider`](crate::AuthenticationProvider) doesn't find SCRAM credentials
+//!    for the user and the SCRAM handshake fails with [`Error::InvalidUser`](crate::Error).
+//! 2. If [`Migrator::needs_migration`] returns `true` the server offers the legacy login instead.
+//! 3. [`Migrator::migrate`] verifies the received password against the legacy hash, derives the
+//!    SCRAM credentials and hands them to [`LegacyStore::store_scram`].
+//!
+//! From then on the user authenticates with SCRAM. Verifying the legacy hashes is left to the
+//! [`LegacyStore`], so this crate doesn't depend on any legacy hashing algorithm.
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
+
+use crate::entropy::EntropySource;
+use crate::server::PasswordInfo;
+use crate::utils::hash_password;
+
+/// The storage of users which may still have a legacy password hash.
+pub trait LegacyStore {
+    /// The legacy password hash, for example a bcrypt or argon2 hash string.
+    type Hash;
+
+    /// Returns the legacy hash of `username`, or `None` if the user is unknown or was already
+    /// migrated.
+    fn legacy_hash(&self, username: &str) -> Option<Self::Hash>;
+
+    /// Checks `password` against the legacy `hash`.
+    fn verify_legacy(&self, hash: &Self::Hash, password: &str) -> bool;
+
+    /// Stores the new SCRAM credentials of `username`. The implementation should delete the legacy
+    /// hash in the same transaction.
+    fn store_scram(&self, username: &str, password_info: PasswordInfo);
+}
+
+/// The outcome of a migration attempt.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationOutcome {
+    /// The password matched the legacy hash and the SCRAM credentials were stored.
+    Migrated,
+    /// The password didn't match the legacy hash. Nothing was stored.
+    NotAuthenticated,
+    /// The user has no legacy hash, because it's unknown or already migrated.
+    NoLegacyHash,
+}
+
+/// Creates SCRAM credentials for users of a [`LegacyStore`] when they log in with their password.
+#[derive(Debug)]
+pub struct Migrator<S: LegacyStore> {
+    store: S,
+    iterations: u16,
+    salt_length: usize,
+}
+
+impl<S: LegacyStore> Migrator<S> {
+    /// Creates a migrator which derives credentials with 4096 iterations and a 16 byte salt.
+    pub fn new(store: S) -> Self {
+        Migrator {
+            store,
+            iterations: 4096,
+            salt_length: 16,
+        }
+    }
+
+    /// Sets the iteration count of the derived credentials. Zero is treated as one.
+    pub fn iterations(mut self, iterations: u16) -> Self {
+        self.iterations = iterations.max(1);
+        self
+    }
+
+    /// Sets the length of the random salt of the derived credentials in bytes.
+    pub fn salt_length(mut self, salt_length: usize) -> Self {
+        self.salt_length = salt_length;
+        self
+    }
+
+    /// Returns the store.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Returns whether `username` still has a legacy hash and must log in without SCRAM once.
+    pub fn needs_migration(&self, username: &str) -> bool {
+        self.store.legacy_hash(username).is_some()
+    }
+
+    /// Verifies `password` against the legacy hash of `username` and stores SCRAM credentials for
+    /// it if it matches. The salt is generated from [`OsRng`].
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn migrate(&self, username: &str, password: &str) -> MigrationOutcome {
+        self.migrate_with_rng(username, password, &mut OsRng)
+    }
+
+    /// Like [`migrate`](Self::migrate), but generates the salt from `rng`.
+    pub fn migrate_with_rng<R: EntropySource + ?Sized>(
+        &self,
+        username: &str,
+        password: &str,
+        rng: &mut R,
+    ) -> MigrationOutcome {
+        let hash = match self.store.legacy_hash(username) {
+            Some(hash) => hash,
+            None => return MigrationOutcome::NoLegacyHash,
+        };
+        if !self.store.verify_legacy(&hash, password) {
+            return MigrationOutcome::NotAuthenticated;
+        }
+        let mut salt = vec![0; self.salt_length];
+        rng.fill_bytes(&mut salt);
+        let iterations = NonZeroU32::new(u32::from(self.iterations)).expect("at least one");
+        let hashed_password: Vec<u8> = hash_password(password, iterations, &salt).to_vec();
+        let password_info = PasswordInfo::new(hashed_password, self.iterations, salt);
+        self.store.store_scram(username, password_info);
+        MigrationOutcome::Migrated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::collections::BTreeMap;
+    use alloc::string::{String, ToString};
+    use core::cell::RefCell;
+
+    use super::{LegacyStore, MigrationOutcome, Migrator};
+    use crate::server::PasswordInfo;
+    use crate::{AuthenticationProvider, AuthenticationStatus, ScramClient, ScramServer};
+
+    /// Stores the reversed password as the "legacy hash".
+    #[derive(Default)]
+    struct Store {
+        legacy: RefCell<BTreeMap<String, String>>,
+        scram: RefCell<BTreeMap<String, PasswordInfo>>,
+    }
+
+    impl LegacyStore for &Store {
+        type Hash = String;
+
+        fn legacy_hash(&self, username: &str) -> Option<String> {
+            self.legacy.borrow().get(username).cloned()
+        }
+
+        fn verify_legacy(&self, hash: &String, password: &str) -> bool {
+            *hash == password.chars().rev().collect::<String>()
+        }
+
+        fn store_scram(&self, username: &str, password_info: PasswordInfo) {
+            self.legacy.borrow_mut().remove(username);
+            self.scram
+                .borrow_mut()
+                .insert(username.to_string(), password_info);
+        }
+    }
+
+    impl AuthenticationProvider for &Store {
+        // `PasswordInfo` isn't `Clone`, the credentials can be looked up once.
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            self.scram.borrow_mut().remove(username)
+        }
+    }
+
+    #[test]
+    fn test_migrate() {
+        let store = Store::default();
+        store
+            .legacy
+            .borrow_mut()
+            .insert("user".to_string(), "drowssap".to_string());
+        let migrator = Migrator::new(&store);
+        assert!(migrator.needs_migration("user"));
+        assert_eq!(
+            migrator.migrate("user", "wrong"),
+            MigrationOutcome::NotAuthenticated
+        );
+        assert_eq!(
+            migrator.migrate("other", "password"),
+            MigrationOutcome::NoLegacyHash
+        );
+        assert_eq!(
+            migrator.migrate("user", "password"),
+            MigrationOutcome::Migrated
+        );
+        assert!(!migrator.needs_migration("user"));
+
+        let server = ScramServer::new(&store);
+        let (client, client_first) = ScramClient::new("user", "password", None).client_first();
+        let (server, server_first) = server
+            .handle_client_first(&client_first)
+            .unwrap()
+            .server_first();
+        let (client, client_final) = client
+            .handle_server_first(&server_first)
+            .unwrap()
+            .client_final();
+        let (status, server_final) = server
+            .handle_client_final(&client_final)
+            .unwrap()
+            .server_final();
+        assert_eq!(status, AuthenticationStatus::Authenticated);
+        assert!(client.handle_server_final(&server_final).is_ok());
+    }
+}
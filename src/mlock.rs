@@ -0,0 +1,110 @@
+//! Buffers for long-lived secrets, which are locked into memory with the `mlock` feature.
+//!
+//! A locked page can't be written to the swap file, so the secret doesn't outlive the process on
+//! disk. `munlock` isn't reference counted, so a buffer never shares a page with other data: it
+//! allocates one page more than it needs and uses the pages which lie entirely inside of its
+//! allocation. Locking is best effort. If it fails, for example because the limit of locked
+//! memory is reached, the buffer is used without the lock.
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Deref;
+
+use crate::utils::zeroize;
+
+/// A secret which is locked into memory with the `mlock` feature and overwritten with zeros when
+/// it's dropped.
+pub(crate) struct LockedBytes {
+    // Dropped before the buffer, so the pages are unlocked before they are freed.
+    #[cfg(feature = "mlock")]
+    guard: Option<region::LockGuard>,
+    buffer: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl LockedBytes {
+    /// Copies `bytes` into a new buffer and tries to lock it.
+    pub(crate) fn new(bytes: &[u8]) -> Self {
+        #[cfg(feature = "mlock")]
+        {
+            let page = region::page::size();
+            let locked_len = bytes.len().div_ceil(page).max(1) * page;
+            let mut buffer = vec![0; locked_len + page];
+            let offset = buffer.as_ptr().align_offset(page);
+            buffer[offset..offset + bytes.len()].copy_from_slice(bytes);
+            let guard = region::lock(buffer[offset..].as_ptr(), locked_len).ok();
+            LockedBytes {
+                guard,
+                buffer,
+                offset,
+                len: bytes.len(),
+            }
+        }
+        #[cfg(not(feature = "mlock"))]
+        LockedBytes {
+            buffer: bytes.to_vec(),
+            offset: 0,
+            len: bytes.len(),
+        }
+    }
+
+    /// Returns whether the buffer is locked into memory.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub(crate) fn is_locked(&self) -> bool {
+        #[cfg(feature = "mlock")]
+        return self.guard.is_some();
+        #[cfg(not(feature = "mlock"))]
+        false
+    }
+}
+
+impl Deref for LockedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer[self.offset..self.offset + self.len]
+    }
+}
+
+impl Clone for LockedBytes {
+    fn clone(&self) -> Self {
+        LockedBytes::new(self)
+    }
+}
+
+impl PartialEq for LockedBytes {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        zeroize(&mut self.buffer);
+    }
+}
+
+impl fmt::Debug for LockedBytes {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("LockedBytes(..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LockedBytes;
+
+    #[test]
+    fn test_locked_bytes() {
+        let bytes = LockedBytes::new(b"pepper");
+        assert_eq!(&*bytes, b"pepper");
+        assert_eq!(bytes.clone(), bytes);
+        assert_eq!(&*LockedBytes::new(b""), b"");
+        if cfg!(feature = "mlock") {
+            // Small enough for the default limit of locked memory of every platform.
+            assert!(bytes.is_locked());
+        } else {
+            assert!(!bytes.is_locked());
+        }
+    }
+}
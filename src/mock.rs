@@ -0,0 +1,392 @@
+//! Mock peers with switchable faults.
+//!
+//! This module is available with the `test-util` feature. [`MockServer`] plays the server side for
+//! code built on [`ScramClient`](crate::ScramClient), [`MockClient`] the client side for code built
+//! on [`ScramServer`](crate::ScramServer). Both perform correct handshakes unless a [`Fault`] is
+//! injected, which lets downstream code verify that it surfaces every class of failure.
+//!
+//! ``` rust,ignore
+//! use scram::mock::{Fault, MockServer};
+//! use scram::ScramClient;
+//!
+//! let mut server = MockServer::new("user", "password").with_fault(Fault::WrongServerSignature);
+//! let (client, client_first) = ScramClient::new("user", "password", None).client_first();
+//! let client = client.handle_server_first(&server.server_first(&client_first)?)?;
+//! let (client, client_final) = client.client_final();
+//! let server_final = server.server_final(&client_final)?;
+//! assert_eq!(client.handle_server_final(&server_final), Err(scram::Error::InvalidServer));
+//! ```
+use core::num::NonZeroU32;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::client::{parse_server_final, parse_server_first};
+use crate::error::{Error, Field, Kind};
+use crate::grammar::{self, ClientFinalMessage, ClientFirstMessage};
+use crate::interop::Lenience;
+use crate::mechanism::Mechanism;
+use crate::utils::{
+    escape_username, find_proofs, generate_nonce, salt_password, unescape_username, Output,
+};
+use crate::MIN_ITERATIONS;
+
+/// The faults a mock peer can inject into the messages it sends.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// The client proof of the `client-final-message` doesn't match the password. Only injected by
+    /// [`MockClient`].
+    CorruptedProof,
+    /// The server signature of the `server-final-message` doesn't match the password. Only
+    /// injected by [`MockServer`].
+    WrongServerSignature,
+    /// A nonce of an earlier message is sent instead of the combined nonce of this handshake.
+    ReusedNonce,
+    /// Every message is cut off in the middle.
+    TruncatedMessage,
+    /// Base64 encoded attributes contain characters outside of the base64 alphabet.
+    BogusBase64,
+}
+
+/// Cuts a message in half if the fault is [`Fault::TruncatedMessage`].
+fn truncate(fault: Option<Fault>, mut message: String) -> String {
+    if fault == Some(Fault::TruncatedMessage) {
+        let mut end = message.len() / 2;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
+
+/// Inverts every bit of a signature or proof.
+fn corrupt(data: &[u8]) -> String {
+    let corrupted: Vec<u8> = data.iter().map(|byte| !byte).collect();
+    STANDARD.encode(corrupted)
+}
+
+/// A server for a single user which answers the messages of a client under test.
+#[derive(Debug)]
+pub struct MockServer {
+    mechanism: Mechanism,
+    username: String,
+    password: String,
+    salt: Vec<u8>,
+    iterations: NonZeroU32,
+    fault: Option<Fault>,
+    handshake: Option<ServerHandshake>,
+}
+
+/// The parts of the first two messages the server needs to verify the `client-final-message`.
+#[derive(Debug)]
+struct ServerHandshake {
+    gs2header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+}
+
+impl MockServer {
+    /// Creates a mock server knowing the given user. A random salt and 4096 iterations are used.
+    pub fn new(username: &str, password: &str) -> Self {
+        let mut salt = vec![0; 16];
+        OsRng.fill_bytes(&mut salt);
+        Self::with_parameters(
+            username,
+            password,
+            salt,
+            NonZeroU32::new(MIN_ITERATIONS).unwrap(),
+        )
+    }
+
+    /// Creates a mock server knowing the given user with a fixed salt and iteration count.
+    pub fn with_parameters(
+        username: &str,
+        password: &str,
+        salt: Vec<u8>,
+        iterations: NonZeroU32,
+    ) -> Self {
+        MockServer {
+            mechanism: Mechanism::ScramSha256,
+            username: username.to_string(),
+            password: password.to_string(),
+            salt,
+            iterations,
+            fault: None,
+            handshake: None,
+        }
+    }
+
+    /// Runs the handshakes with `mechanism` instead of SCRAM-SHA-256.
+    pub fn with_mechanism(mut self, mechanism: Mechanism) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    /// Injects the given fault into all following messages.
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Answers a `client-first-message`. An error is returned if the message is malformed or
+    /// names an unknown user.
+    pub fn server_first(&mut self, client_first: &str) -> Result<String, Error> {
+        let message = ClientFirstMessage::parse(client_first)?;
+        let (gs2header, client_first_bare) = grammar::split_client_first(client_first);
+        let authcid = unescape_username(message.authcid)
+            .ok_or(Error::Protocol(Kind::InvalidField(Field::Authcid, None)))?;
+        if authcid != self.username {
+            return Err(Error::InvalidUser(authcid.into_owned()));
+        }
+        let nonce = format!("{}{}", message.nonce, generate_nonce(&mut OsRng));
+        let sent_nonce = match (self.fault, self.handshake.take()) {
+            (Some(Fault::ReusedNonce), Some(previous)) => previous.nonce,
+            (Some(Fault::ReusedNonce), None) => generate_nonce(&mut OsRng),
+            _ => nonce.clone(),
+        };
+        let salt = match self.fault {
+            Some(Fault::BogusBase64) => "*bogus*".to_string(),
+            _ => STANDARD.encode(&self.salt),
+        };
+        let server_first = format!("r={},s={},i={}", sent_nonce, salt, self.iterations);
+        self.handshake = Some(ServerHandshake {
+            gs2header: gs2header.to_string(),
+            client_first_bare: client_first_bare.to_string(),
+            server_first: server_first.clone(),
+            nonce,
+        });
+        Ok(truncate(self.fault, server_first))
+    }
+
+    /// Answers a `client-final-message`. A wrong proof is answered with `e=invalid-proof`. An error
+    /// is returned if the message is malformed or doesn't belong to the current handshake.
+    pub fn server_final(&mut self, client_final: &str) -> Result<String, Error> {
+        let handshake = self
+            .handshake
+            .as_ref()
+            .ok_or(Error::Protocol(Kind::ExpectedField(Field::Nonce, None)))?;
+        let message = ClientFinalMessage::parse(client_final)?;
+        if message.channel_binding != STANDARD.encode(&handshake.gs2header) {
+            return Err(Error::Protocol(Kind::InvalidField(Field::GS2Header, None)));
+        }
+        if message.nonce != handshake.nonce {
+            return Err(Error::Protocol(Kind::InvalidField(Field::Nonce, None)));
+        }
+        let proof = STANDARD
+            .decode(message.proof)
+            .map_err(|err| Error::base64(Field::Proof, err))?;
+        let salted_password =
+            salt_password(&self.mechanism, &self.password, self.iterations, &self.salt);
+        let (client_proof, server_signature) = find_proofs(
+            &self.mechanism,
+            message.channel_binding,
+            &handshake.client_first_bare,
+            &handshake.server_first,
+            &salted_password,
+            &handshake.nonce,
+        );
+        let server_final = if proof != *client_proof {
+            "e=invalid-proof".to_string()
+        } else {
+            match self.fault {
+                Some(Fault::WrongServerSignature) => {
+                    format!("v={}", corrupt(server_signature.as_ref()))
+                }
+                Some(Fault::BogusBase64) => "v=*bogus*".to_string(),
+                _ => format!("v={}", STANDARD.encode(server_signature.as_ref())),
+            }
+        };
+        Ok(truncate(self.fault, server_final))
+    }
+}
+
+/// A client which sends messages to a server under test.
+#[derive(Debug)]
+pub struct MockClient {
+    mechanism: Mechanism,
+    username: String,
+    password: String,
+    nonce: String,
+    fault: Option<Fault>,
+    server_signature: Option<Output>,
+}
+
+impl MockClient {
+    /// Creates a mock client authenticating with the given credentials.
+    pub fn new(username: &str, password: &str) -> Self {
+        MockClient {
+            mechanism: Mechanism::ScramSha256,
+            username: username.to_string(),
+            password: password.to_string(),
+            nonce: generate_nonce(&mut OsRng),
+            fault: None,
+            server_signature: None,
+        }
+    }
+
+    /// Runs the handshake with `mechanism` instead of SCRAM-SHA-256.
+    pub fn with_mechanism(mut self, mechanism: Mechanism) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    /// Injects the given fault into all following messages.
+    pub fn with_fault(mut self, fault: Fault) -> Self {
+        self.fault = Some(fault);
+        self
+    }
+
+    /// Returns the `client-first-message`.
+    pub fn client_first(&self) -> String {
+        let client_first = format!("n,,{}", self.client_first_bare());
+        truncate(self.fault, client_first)
+    }
+
+    /// Answers a `server-first-message`. Iteration counts below 4096 are accepted. An error is
+    /// returned if the message is malformed or doesn't continue the client nonce.
+    pub fn client_final(&mut self, server_first: &str) -> Result<String, Error> {
+        let (nonce, salt, iterations) = parse_server_first(server_first)?;
+        if !nonce.starts_with(&self.nonce) {
+            return Err(Error::Protocol(Kind::InvalidNonce));
+        }
+        let salted_password = salt_password(&self.mechanism, &self.password, iterations, &salt);
+        let (client_proof, server_signature) = find_proofs(
+            &self.mechanism,
+            "biws",
+            &self.client_first_bare(),
+            server_first,
+            &salted_password,
+            nonce,
+        );
+        self.server_signature = Some(server_signature);
+        let sent_nonce = match self.fault {
+            Some(Fault::ReusedNonce) => &self.nonce,
+            _ => nonce,
+        };
+        let proof = match self.fault {
+            Some(Fault::CorruptedProof) => corrupt(&client_proof),
+            Some(Fault::BogusBase64) => "*bogus*".to_string(),
+            _ => STANDARD.encode(&*client_proof),
+        };
+        let client_final = format!("c={},r={},p={}", STANDARD.encode("n,,"), sent_nonce, proof);
+        Ok(truncate(self.fault, client_final))
+    }
+
+    /// Verifies the `server-final-message` like
+    /// [`ServerFinal::handle_server_final`](crate::client::ServerFinal::handle_server_final) does.
+    pub fn handle_server_final(&self, server_final: &str) -> Result<(), Error> {
+        let verifier = parse_server_final(server_final, &Lenience::default(), &mut Vec::new())?;
+        match self.server_signature {
+            Some(ref signature) if signature.as_ref() == &*verifier => Ok(()),
+            _ => Err(Error::InvalidServer),
+        }
+    }
+
+    fn client_first_bare(&self) -> String {
+        format!("n={},r={}", escape_username(&self.username), self.nonce)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{Fault, MockClient, MockServer};
+    use crate::{hash_password, ScramClient, ScramServer};
+    use crate::{AuthenticationProvider, AuthenticationStatus, Error, Field, Kind, PasswordInfo};
+
+    fn client_handshake(server: &mut MockServer) -> Result<(), Error> {
+        let scram = ScramClient::new("user", "password", None).insecure_allow_low_iterations();
+        let (scram, client_first) = scram.client_first();
+        let scram = scram.handle_server_first(&server.server_first(&client_first)?)?;
+        let (scram, client_final) = scram.client_final();
+        scram.handle_server_final(&server.server_final(&client_final)?)
+    }
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            let iterations = NonZeroU32::new(1).unwrap();
+            match username {
+                "user" => Some(PasswordInfo::new(
+                    hash_password("password", iterations, b"salt").to_vec(),
+                    1,
+                    b"salt".to_vec(),
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    fn server_handshake(client: &mut MockClient) -> Result<AuthenticationStatus, Error> {
+        let scram = ScramServer::new(TestProvider);
+        let client_first = client.client_first();
+        let scram = scram.handle_client_first(&client_first)?;
+        let (scram, server_first) = scram.server_first();
+        let client_final = client.client_final(&server_first)?;
+        let (status, server_final) = scram.handle_client_final(&client_final)?.server_final();
+        if status == AuthenticationStatus::Authenticated {
+            client.handle_server_final(&server_final)?;
+        }
+        Ok(status)
+    }
+
+    fn mock_server() -> MockServer {
+        MockServer::with_parameters(
+            "user",
+            "password",
+            b"salt".to_vec(),
+            NonZeroU32::new(1).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_mock_server() {
+        assert_eq!(client_handshake(&mut mock_server()), Ok(()));
+        let mut server = mock_server().with_fault(Fault::WrongServerSignature);
+        assert_eq!(client_handshake(&mut server), Err(Error::InvalidServer));
+        let mut server = mock_server().with_fault(Fault::ReusedNonce);
+        assert_eq!(
+            client_handshake(&mut server),
+            Err(Error::Protocol(Kind::InvalidNonce))
+        );
+        let mut server = mock_server().with_fault(Fault::BogusBase64);
+        assert!(matches!(
+            client_handshake(&mut server),
+            Err(Error::Protocol(Kind::InvalidEncoding(Field::Salt, _)))
+        ));
+        let mut server = mock_server().with_fault(Fault::TruncatedMessage);
+        assert!(client_handshake(&mut server).is_err());
+    }
+
+    #[test]
+    fn test_mock_client() {
+        let mut client = MockClient::new("user", "password");
+        assert_eq!(
+            server_handshake(&mut client),
+            Ok(AuthenticationStatus::Authenticated)
+        );
+        let mut client = MockClient::new("user", "password").with_fault(Fault::CorruptedProof);
+        assert_eq!(
+            server_handshake(&mut client),
+            Ok(AuthenticationStatus::NotAuthenticated)
+        );
+        let mut client = MockClient::new("user", "password").with_fault(Fault::ReusedNonce);
+        assert!(matches!(
+            server_handshake(&mut client),
+            Err(Error::Protocol(Kind::InvalidField(Field::Nonce, Some(_))))
+        ));
+        let mut client = MockClient::new("user", "password").with_fault(Fault::BogusBase64);
+        assert!(matches!(
+            server_handshake(&mut client),
+            Err(Error::Protocol(Kind::InvalidEncoding(Field::Proof, _)))
+        ));
+        let mut client = MockClient::new("user", "password").with_fault(Fault::TruncatedMessage);
+        assert!(server_handshake(&mut client).is_err());
+    }
+}
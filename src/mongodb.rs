@@ -0,0 +1,131 @@
+//! The client side of MongoDB's SCRAM-SHA-1.
+//!
+//! MongoDB doesn't run SCRAM-SHA-1 over the password of a user, but over the hex encoded
+//! `MD5(username ":mongo:" password)`, which it stored before it supported SCRAM.
+//! [`hash_password`] computes it, and a client passes it as the password and runs the handshake
+//! with the SHA-1 of [`ScramSha1`]:
+//!
+//! ```
+//! use scram::mongodb::{hash_password, ScramSha1};
+//! use scram::ScramClient;
+//!
+//! let password = hash_password("user", "pencil");
+//! assert_eq!(password, "1c33006ec1ffd90f9cadcbcc0e118200");
+//! let client = ScramClient::new("user", password, None).with_hash(ScramSha1);
+//! ```
+//!
+//! SCRAM-SHA-1 isn't one of the built-in [`Mechanism`](crate::Mechanism)s, so servers of this
+//! crate don't offer it. MongoDB's SCRAM-SHA-256 uses the password itself and needs no helper.
+//!
+//! This module is available with the `mongodb` feature.
+use alloc::string::String;
+use core::num::NonZeroU32;
+
+use md5::{Digest, Md5};
+use ring::{digest, hmac, pbkdf2};
+
+use crate::hash::ScramHash;
+use crate::utils::zeroize;
+
+/// The lowercase hexadecimal digits.
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Returns the password MongoDB's SCRAM-SHA-1 authenticates `username` with, the lowercase hex
+/// encoded `MD5(username ":mongo:" password)`.
+///
+/// The username is the one MongoDB stores, before the escaping of `,` and `=` in the
+/// client-first message. The result is as secret as the password. Passed to
+/// [`ScramClient::new`](crate::ScramClient::new) as an owned `String`, it is overwritten with
+/// zeros when the handshake no longer needs it.
+pub fn hash_password(username: &str, password: &str) -> String {
+    let mut md5 = Md5::new();
+    md5.update(username.as_bytes());
+    md5.update(b":mongo:");
+    md5.update(password.as_bytes());
+    let mut digest = md5.finalize();
+    let mut hex = String::with_capacity(2 * digest.len());
+    for byte in digest.iter() {
+        hex.push(char::from(HEX_DIGITS[usize::from(byte >> 4)]));
+        hex.push(char::from(HEX_DIGITS[usize::from(byte & 0xf)]));
+    }
+    zeroize(&mut digest);
+    hex
+}
+
+/// The SHA-1 hash function of SCRAM-SHA-1 for [`ScramClient::with_hash`], only for MongoDB's
+/// SCRAM-SHA-1, see the [module documentation](self).
+///
+/// [`ScramClient::with_hash`]: crate::ScramClient::with_hash
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ScramSha1;
+
+impl ScramHash for ScramSha1 {
+    fn name(&self) -> &str {
+        "SCRAM-SHA-1"
+    }
+
+    fn output_len(&self) -> usize {
+        digest::SHA1_OUTPUT_LEN
+    }
+
+    fn hash(&self, data: &[u8], output: &mut [u8]) {
+        let digest = digest::digest(&digest::SHA1_FOR_LEGACY_USE_ONLY, data);
+        output.copy_from_slice(digest.as_ref());
+    }
+
+    fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+        let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+        let mut context = hmac::Context::with_key(&key);
+        for piece in message {
+            context.update(piece);
+        }
+        output.copy_from_slice(context.sign().as_ref());
+    }
+
+    fn hi(&self, password: &[u8], salt: &[u8], iterations: NonZeroU32, output: &mut [u8]) {
+        pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA1, iterations, salt, password, output);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{hash_password, ScramSha1};
+    use crate::ScramClient;
+
+    #[test]
+    fn test_hash_password() {
+        // The example of the MongoDB authentication specification.
+        assert_eq!(
+            hash_password("user", "pencil"),
+            "1c33006ec1ffd90f9cadcbcc0e118200"
+        );
+        assert_eq!(hash_password("", ""), "4f75033e39d8b884aa7d88e2fadfe2cb");
+    }
+
+    #[test]
+    fn test_handshake() {
+        // The conversation of the MongoDB authentication specification.
+        let password = hash_password("user", "pencil");
+        let client =
+            ScramClient::with_nonce("user", password, None, "fyko+d2lbbFgONRv9qkxdawL".into())
+                .with_hash(ScramSha1);
+        let (client, client_first) = client.client_first();
+        assert_eq!(client_first, "n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL");
+        let (client, client_final) = client
+            .handle_server_first(
+                "r=fyko+d2lbbFgONRv9qkxdawLHo+Vgk7qvUOKUwuWLIWg4l/9SraGMHEE,\
+                 s=rQ9ZY3MntBeuP3E1TDVC4w==,i=10000",
+            )
+            .unwrap()
+            .client_final();
+        assert_eq!(
+            client_final,
+            "c=biws,r=fyko+d2lbbFgONRv9qkxdawLHo+Vgk7qvUOKUwuWLIWg4l/9SraGMHEE,\
+             p=MC2T8BvbmWRckDw8oWl5IVghwCY="
+        );
+        assert_eq!(
+            client.handle_server_final("v=UMWeI25JD1yNYZRMpZ4VHvhZ9e0="),
+            Ok(())
+        );
+    }
+}
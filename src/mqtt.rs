@@ -0,0 +1,548 @@
+//! SCRAM as MQTT 5 enhanced authentication.
+//!
+//! MQTT 5 carries SASL-style exchanges in the Authentication Method and Authentication Data
+//! properties of the CONNECT, CONNACK and AUTH packets. The SCRAM messages are mapped onto them as
+//! follows, with the mechanism name as the method:
+//!
+//! 1. The client sends CONNECT with the `client-first-message`.
+//! 2. The broker answers with AUTH, reason code Continue authentication (0x18), and the
+//!    `server-first-message`.
+//! 3. The client answers with AUTH 0x18 and the `client-final-message`.
+//! 4. The broker completes with CONNACK, reason code Success (0x00) and the
+//!    `server-final-message`, or Not authorized (0x87) and the `e=` message.
+//!
+//! Re-authentication starts with AUTH, reason code Re-authenticate (0x19), instead of CONNECT and
+//! completes with AUTH Success, or DISCONNECT on failure.
+//!
+//! [`MqttClient`] and [`MqttServer`] run the exchange and return the properties of the packets to
+//! send as an [`AuthPacket`]. Encoding the packets is left to the MQTT library;
+//! [`AuthPacket::properties`] encodes the two properties for libraries which take raw properties.
+//!
+//! ``` rust,no_run
+//! use scram::mqtt::MqttClient;
+//! use scram::ScramClient;
+//!
+//! // These functions represent your MQTT library. They return the reason code and the
+//! // Authentication Data of the received packet.
+//! # #[allow(unused_variables)]
+//! fn send_connect(method: &str, data: &[u8]) -> (u8, Option<Vec<u8>>) {
+//!     unimplemented!()
+//! }
+//! # #[allow(unused_variables)]
+//! fn send_auth(reason_code: u8, method: &str, data: &[u8]) -> (u8, Option<Vec<u8>>) {
+//!     unimplemented!()
+//! }
+//!
+//! let mut client = MqttClient::new(ScramClient::new("user", "password", None));
+//! let connect = client.connect().unwrap();
+//! let (reason_code, data) = send_connect(connect.method, connect.data.as_deref().unwrap());
+//! let auth = client.handle_auth(reason_code, Some(connect.method), data.as_deref()).unwrap();
+//! let code = auth.reason_code.unwrap().code();
+//! let (reason_code, data) = send_auth(code, auth.method, auth.data.as_deref().unwrap());
+//! client.handle_completion(reason_code, data.as_deref()).unwrap();
+//! ```
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::dynamic::{DynScramClient, DynScramServer, StepOutput, SteppedClient, SteppedServer};
+use crate::error::Error;
+use crate::fallback::HandshakeError;
+use crate::mechanism::Mechanism;
+use crate::server::{AuthenticationProvider, AuthenticationStatus};
+use crate::ScramClient;
+
+/// The identifier of the Authentication Method property.
+pub const AUTHENTICATION_METHOD: u8 = 0x15;
+
+/// The identifier of the Authentication Data property.
+pub const AUTHENTICATION_DATA: u8 = 0x16;
+
+/// The reason codes of the packets of the exchange.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReasonCode {
+    /// 0x00, the authentication succeeded.
+    Success,
+    /// 0x18, the peer has to send the next message of the exchange.
+    ContinueAuthentication,
+    /// 0x19, the client starts a re-authentication.
+    ReAuthenticate,
+    /// 0x87, the authentication failed.
+    NotAuthorized,
+    /// 0x8C, the broker doesn't support the Authentication Method.
+    BadAuthenticationMethod,
+}
+
+impl ReasonCode {
+    /// Returns the value of the reason code.
+    pub fn code(self) -> u8 {
+        match self {
+            ReasonCode::Success => 0x00,
+            ReasonCode::ContinueAuthentication => 0x18,
+            ReasonCode::ReAuthenticate => 0x19,
+            ReasonCode::NotAuthorized => 0x87,
+            ReasonCode::BadAuthenticationMethod => 0x8C,
+        }
+    }
+
+    /// Returns the reason code with the value `code`, if it's one of the exchange.
+    pub fn from_code(code: u8) -> Option<ReasonCode> {
+        match code {
+            0x00 => Some(ReasonCode::Success),
+            0x18 => Some(ReasonCode::ContinueAuthentication),
+            0x19 => Some(ReasonCode::ReAuthenticate),
+            0x87 => Some(ReasonCode::NotAuthorized),
+            0x8C => Some(ReasonCode::BadAuthenticationMethod),
+            _ => None,
+        }
+    }
+}
+
+/// The MQTT control packets of the exchange.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PacketType {
+    /// The CONNECT packet of the client.
+    Connect,
+    /// The CONNACK packet of the broker.
+    Connack,
+    /// An AUTH packet of either side.
+    Auth,
+    /// The DISCONNECT packet of the broker after a failed re-authentication.
+    Disconnect,
+}
+
+/// The authentication part of a packet to send.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthPacket {
+    /// The type of the packet.
+    pub packet_type: PacketType,
+    /// The reason code, or `None` for a CONNECT packet.
+    pub reason_code: Option<ReasonCode>,
+    /// The value of the Authentication Method property.
+    pub method: &'static str,
+    /// The value of the Authentication Data property, if the packet has one.
+    pub data: Option<Vec<u8>>,
+}
+
+impl AuthPacket {
+    fn new(packet_type: PacketType, reason_code: Option<ReasonCode>, data: Option<String>) -> Self {
+        AuthPacket {
+            packet_type,
+            reason_code,
+            method: Mechanism::ScramSha256.name(),
+            data: data.map(String::into_bytes),
+        }
+    }
+
+    /// Encodes the Authentication Method and Authentication Data properties, each as the
+    /// identifier followed by the length as two byte big-endian integer and the value.
+    pub fn properties(&self) -> Vec<u8> {
+        let mut properties = Vec::new();
+        properties.push(AUTHENTICATION_METHOD);
+        properties.extend_from_slice(&(self.method.len() as u16).to_be_bytes());
+        properties.extend_from_slice(self.method.as_bytes());
+        if let Some(ref data) = self.data {
+            // SCRAM messages are far shorter than the 65535 bytes a property can hold.
+            properties.push(AUTHENTICATION_DATA);
+            properties.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            properties.extend_from_slice(data);
+        }
+        properties
+    }
+}
+
+/// The reasons an MQTT exchange can fail.
+#[derive(Debug, PartialEq)]
+pub enum MqttError {
+    /// The SCRAM state machine rejected a message of the peer.
+    Scram(Error),
+    /// The peer requested another Authentication Method.
+    Method,
+    /// The packet of the peer has a reason code which doesn't fit the state of the exchange.
+    ReasonCode(u8),
+    /// The Authentication Data of the peer isn't UTF-8.
+    Encoding,
+}
+
+impl MqttError {
+    /// Returns the reason code the broker reports the error with: Bad authentication method for
+    /// [`MqttError::Method`] and Not authorized otherwise.
+    pub fn reason_code(&self) -> ReasonCode {
+        match *self {
+            MqttError::Method => ReasonCode::BadAuthenticationMethod,
+            _ => ReasonCode::NotAuthorized,
+        }
+    }
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MqttError::Scram(ref err) => write!(fmt, "SCRAM error: {}", err),
+            MqttError::Method => write!(fmt, "Unsupported authentication method"),
+            MqttError::ReasonCode(code) => write!(fmt, "Unexpected reason code 0x{:02X}", code),
+            MqttError::Encoding => write!(fmt, "Authentication data isn't UTF-8"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for MqttError {}
+
+impl HandshakeError for MqttError {
+    fn scram_error(&self) -> Option<&Error> {
+        match *self {
+            MqttError::Scram(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for MqttError {
+    fn from(err: Error) -> Self {
+        MqttError::Scram(err)
+    }
+}
+
+/// Checks the Authentication Method of a received packet. A missing method is accepted for
+/// packets after the first, where the method is only repeated optionally.
+fn check_method(method: Option<&str>) -> Result<(), MqttError> {
+    match method {
+        Some(method) if method != Mechanism::ScramSha256.name() => Err(MqttError::Method),
+        _ => Ok(()),
+    }
+}
+
+/// Returns the Authentication Data of a received packet as SCRAM message.
+fn message(data: Option<&[u8]>) -> Result<&str, MqttError> {
+    str::from_utf8(data.unwrap_or_default()).map_err(|_| MqttError::Encoding)
+}
+
+/// The client side of the exchange.
+pub struct MqttClient<'a> {
+    client: SteppedClient<'a>,
+}
+
+impl<'a> MqttClient<'a> {
+    /// Wraps `client` for the exchange.
+    pub fn new(client: ScramClient<'a>) -> Self {
+        MqttClient {
+            client: SteppedClient::new(client),
+        }
+    }
+
+    /// Returns the properties of the CONNECT packet.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the exchange already started.
+    pub fn connect(&mut self) -> Result<AuthPacket, MqttError> {
+        let client_first = self.first()?;
+        Ok(AuthPacket::new(
+            PacketType::Connect,
+            None,
+            Some(client_first),
+        ))
+    }
+
+    /// Returns the AUTH packet starting a re-authentication on an established connection.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the exchange already started.
+    pub fn reauthenticate(&mut self) -> Result<AuthPacket, MqttError> {
+        let client_first = self.first()?;
+        let reason_code = Some(ReasonCode::ReAuthenticate);
+        Ok(AuthPacket::new(
+            PacketType::Auth,
+            reason_code,
+            Some(client_first),
+        ))
+    }
+
+    fn first(&mut self) -> Result<String, MqttError> {
+        match self.client.step(None)? {
+            StepOutput::Continue(client_first) => Ok(client_first),
+            StepOutput::Done { .. } => unreachable!("the client sends the first message"),
+        }
+    }
+
+    /// Processes the AUTH packet of the broker with the `server-first-message` and returns the
+    /// AUTH packet with the `client-final-message`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the exchange didn't start or already ended.
+    pub fn handle_auth(
+        &mut self,
+        reason_code: u8,
+        method: Option<&str>,
+        data: Option<&[u8]>,
+    ) -> Result<AuthPacket, MqttError> {
+        if reason_code != ReasonCode::ContinueAuthentication.code() {
+            self.client.abort();
+            return Err(MqttError::ReasonCode(reason_code));
+        }
+        check_method(method)?;
+        match self.client.step(Some(message(data)?))? {
+            StepOutput::Continue(client_final) => Ok(AuthPacket::new(
+                PacketType::Auth,
+                Some(ReasonCode::ContinueAuthentication),
+                Some(client_final),
+            )),
+            StepOutput::Done { .. } => unreachable!("the client sends the final message"),
+        }
+    }
+
+    /// Processes the CONNACK packet, or the AUTH packet completing a re-authentication, and
+    /// verifies the broker. A failure reason code with an `e=` message fails with the SCRAM error,
+    /// one without fails with `MqttError::ReasonCode`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client didn't send the `client-final-message`.
+    pub fn handle_completion(
+        &mut self,
+        reason_code: u8,
+        data: Option<&[u8]>,
+    ) -> Result<(), MqttError> {
+        if reason_code != ReasonCode::Success.code() && data.is_none() {
+            self.client.abort();
+            return Err(MqttError::ReasonCode(reason_code));
+        }
+        self.client.step(Some(message(data)?))?;
+        Ok(())
+    }
+}
+
+/// The broker side of the exchange.
+pub struct MqttServer<'a, P: AuthenticationProvider> {
+    server: SteppedServer<'a, P>,
+    reauthenticating: bool,
+}
+
+impl<'a, P: AuthenticationProvider> MqttServer<'a, P> {
+    /// Wraps `server` for the exchange.
+    pub fn new(server: SteppedServer<'a, P>) -> Self {
+        MqttServer {
+            server,
+            reauthenticating: false,
+        }
+    }
+
+    /// Processes the Authentication Method and Data of a CONNECT packet and returns the AUTH
+    /// packet with the `server-first-message`.
+    ///
+    /// On error the broker answers with CONNACK and the [`reason_code`](MqttError::reason_code)
+    /// of the error.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the exchange already started.
+    pub fn handle_connect(
+        &mut self,
+        method: &str,
+        data: Option<&[u8]>,
+    ) -> Result<AuthPacket, MqttError> {
+        self.first(method, data)
+    }
+
+    /// Like [`handle_connect`](Self::handle_connect), but for the AUTH packet with the reason code
+    /// Re-authenticate. The broker answers errors with DISCONNECT instead of CONNACK.
+    pub fn handle_reauthenticate(
+        &mut self,
+        method: &str,
+        data: Option<&[u8]>,
+    ) -> Result<AuthPacket, MqttError> {
+        self.reauthenticating = true;
+        self.first(method, data)
+    }
+
+    fn first(&mut self, method: &str, data: Option<&[u8]>) -> Result<AuthPacket, MqttError> {
+        check_method(Some(method))?;
+        match self.server.step(Some(message(data)?))? {
+            StepOutput::Continue(server_first) => Ok(AuthPacket::new(
+                PacketType::Auth,
+                Some(ReasonCode::ContinueAuthentication),
+                Some(server_first),
+            )),
+            StepOutput::Done { .. } => unreachable!("the server answers the first message"),
+        }
+    }
+
+    /// Processes the AUTH packet with the `client-final-message` and returns the packet
+    /// completing the exchange with the authentication status: CONNACK, or AUTH after a
+    /// re-authentication, with the `server-final-message` if the client was authenticated, and
+    /// CONNACK with Not authorized, or DISCONNECT after a re-authentication, otherwise.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the exchange didn't start or already ended.
+    pub fn handle_auth(
+        &mut self,
+        reason_code: u8,
+        method: Option<&str>,
+        data: Option<&[u8]>,
+    ) -> Result<(AuthPacket, AuthenticationStatus), MqttError> {
+        if reason_code != ReasonCode::ContinueAuthentication.code() {
+            self.server.abort();
+            return Err(MqttError::ReasonCode(reason_code));
+        }
+        check_method(method)?;
+        let (server_final, status) = match self.server.step(Some(message(data)?))? {
+            StepOutput::Done { message, status } => (message, status),
+            StepOutput::Continue(_) => unreachable!("the server completes after the final message"),
+        };
+        let (packet_type, reason_code) = match (status, self.reauthenticating) {
+            (AuthenticationStatus::Authenticated, false) => {
+                (PacketType::Connack, ReasonCode::Success)
+            }
+            (AuthenticationStatus::Authenticated, true) => (PacketType::Auth, ReasonCode::Success),
+            (_, false) => (PacketType::Connack, ReasonCode::NotAuthorized),
+            (_, true) => (PacketType::Disconnect, ReasonCode::NotAuthorized),
+        };
+        let packet = AuthPacket::new(packet_type, Some(reason_code), server_final);
+        Ok((packet, status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{AuthPacket, MqttClient, MqttError, MqttServer, PacketType, ReasonCode};
+    use crate::client::ScramClient;
+    use crate::dynamic::SteppedServer;
+    use crate::error::Error;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            match username {
+                "user" => {
+                    let iterations = NonZeroU32::new(4096).unwrap();
+                    let password = hash_password("password", iterations, b"salt");
+                    Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn code(packet: &AuthPacket) -> u8 {
+        packet.reason_code.unwrap().code()
+    }
+
+    #[test]
+    fn test_exchange() {
+        let scram_server = ScramServer::new(TestProvider);
+        for &reauthenticate in &[false, true] {
+            let mut server = MqttServer::new(SteppedServer::new(&scram_server));
+            let mut client = MqttClient::new(ScramClient::new("user", "password", None));
+            let first = if reauthenticate {
+                let first = client.reauthenticate().unwrap();
+                assert_eq!(first.packet_type, PacketType::Auth);
+                assert_eq!(first.reason_code, Some(ReasonCode::ReAuthenticate));
+                server.handle_reauthenticate(first.method, first.data.as_deref())
+            } else {
+                let first = client.connect().unwrap();
+                assert_eq!(first.packet_type, PacketType::Connect);
+                assert_eq!(first.method, "SCRAM-SHA-256");
+                server.handle_connect(first.method, first.data.as_deref())
+            };
+            let auth = first.unwrap();
+            assert_eq!(auth.reason_code, Some(ReasonCode::ContinueAuthentication));
+            let auth = client
+                .handle_auth(code(&auth), Some(auth.method), auth.data.as_deref())
+                .unwrap();
+            let (completion, status) = server
+                .handle_auth(code(&auth), None, auth.data.as_deref())
+                .unwrap();
+            assert_eq!(status, AuthenticationStatus::Authenticated);
+            let packet_type = if reauthenticate {
+                PacketType::Auth
+            } else {
+                PacketType::Connack
+            };
+            assert_eq!(completion.packet_type, packet_type);
+            assert_eq!(completion.reason_code, Some(ReasonCode::Success));
+            client
+                .handle_completion(code(&completion), completion.data.as_deref())
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let scram_server = ScramServer::new(TestProvider);
+        let mut server = MqttServer::new(SteppedServer::new(&scram_server));
+        let mut client = MqttClient::new(ScramClient::new("user", "wrong", None));
+        let connect = client.connect().unwrap();
+        let auth = server
+            .handle_connect(connect.method, connect.data.as_deref())
+            .unwrap();
+        let auth = client
+            .handle_auth(code(&auth), None, auth.data.as_deref())
+            .unwrap();
+        let (connack, status) = server
+            .handle_auth(code(&auth), None, auth.data.as_deref())
+            .unwrap();
+        assert_eq!(status, AuthenticationStatus::NotAuthenticated);
+        assert_eq!(connack.packet_type, PacketType::Connack);
+        assert_eq!(connack.reason_code, Some(ReasonCode::NotAuthorized));
+        assert!(matches!(
+            client.handle_completion(code(&connack), connack.data.as_deref()),
+            Err(MqttError::Scram(Error::Authentication(_)))
+        ));
+    }
+
+    #[test]
+    fn test_rejected() {
+        let scram_server = ScramServer::new(TestProvider);
+        let mut server = MqttServer::new(SteppedServer::new(&scram_server));
+        let err = server.handle_connect("PLAIN", None).unwrap_err();
+        assert_eq!(err, MqttError::Method);
+        assert_eq!(err.reason_code(), ReasonCode::BadAuthenticationMethod);
+        let mut server = MqttServer::new(SteppedServer::new(&scram_server));
+        let err = server
+            .handle_connect("SCRAM-SHA-256", Some(&[0xff]))
+            .unwrap_err();
+        assert_eq!(err, MqttError::Encoding);
+        assert_eq!(err.reason_code(), ReasonCode::NotAuthorized);
+
+        let mut client = MqttClient::new(ScramClient::new("user", "password", None));
+        client.connect().unwrap();
+        assert_eq!(
+            client.handle_auth(0x8C, None, None),
+            Err(MqttError::ReasonCode(0x8C))
+        );
+    }
+
+    #[test]
+    fn test_properties() {
+        let packet = AuthPacket {
+            packet_type: PacketType::Auth,
+            reason_code: Some(ReasonCode::ContinueAuthentication),
+            method: "SCRAM-SHA-256",
+            data: Some(b"r=abc".to_vec()),
+        };
+        let mut expected = vec![0x15, 0, 13];
+        expected.extend_from_slice(b"SCRAM-SHA-256");
+        expected.extend_from_slice(&[0x16, 0, 5]);
+        expected.extend_from_slice(b"r=abc");
+        assert_eq!(packet.properties(), expected);
+        for code in 0..=255 {
+            if let Some(reason_code) = ReasonCode::from_code(code) {
+                assert_eq!(reason_code.code(), code);
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+//! The channel-binding data of `native-tls` connections.
+//!
+//! `native-tls` exposes neither the keying material exporter nor the Finished messages on every
+//! platform, so [`client_channel_binding`] always picks `tls-server-end-point` with the
+//! certificate of the server:
+//!
+//! ``` rust,no_run
+//! use scram::ScramClient;
+//!
+//! # fn connect() -> native_tls::TlsStream<std::net::TcpStream> { unimplemented!() }
+//! let stream = connect();
+//! let binding = scram::native_tls::client_channel_binding(&stream).unwrap();
+//! let client =
+//!     ScramClient::new("user", "password", None).with_channel_binding(binding.binding());
+//! ```
+//!
+//! Servers pass the hash of their certificate from
+//! [`tls_server_end_point`](crate::channel_binding::tls_server_end_point) to
+//! [`ConnectionContext::with_channel_binding_data`](crate::ConnectionContext::with_channel_binding_data).
+//!
+//! This module is available with the `native-tls` feature.
+use std::io::{Read, Write};
+
+use ::native_tls::TlsStream;
+
+use crate::channel_binding::OwnedChannelBinding;
+
+/// Returns the channel binding of the client `stream`, see the [module documentation](self), or
+/// `None` if the server didn't send a certificate.
+pub fn client_channel_binding<S: Read + Write>(
+    stream: &TlsStream<S>,
+) -> Option<OwnedChannelBinding> {
+    let certificate = stream.peer_certificate().ok()??.to_der().ok()?;
+    Some(OwnedChannelBinding::TlsServerEndPoint(certificate))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::string::String;
+    use std::thread;
+
+    use ::native_tls::{Identity, TlsAcceptor, TlsConnector};
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::client_channel_binding;
+    use crate::channel_binding::{tls_server_end_point, OwnedChannelBinding};
+
+    /// A self-signed certificate for `localhost` with an ECDSA P-256 key, signed with SHA-384.
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/data/localhost.der");
+    /// The PKCS #8 private key of [`CERTIFICATE`].
+    const KEY: &[u8] = include_bytes!("../tests/data/localhost.key.der");
+
+    /// Encodes `der` as PEM with the `label`.
+    fn pem(label: &str, der: &[u8]) -> String {
+        let mut pem = format!("-----BEGIN {}-----\n", label);
+        for line in STANDARD.encode(der).as_bytes().chunks(64) {
+            pem.push_str(core::str::from_utf8(line).unwrap());
+            pem.push('\n');
+        }
+        pem.push_str(&format!("-----END {}-----\n", label));
+        pem
+    }
+
+    #[test]
+    fn test_client_channel_binding() {
+        let identity = Identity::from_pkcs8(
+            pem("CERTIFICATE", CERTIFICATE).as_bytes(),
+            pem("PRIVATE KEY", KEY).as_bytes(),
+        )
+        .unwrap();
+        let acceptor = TlsAcceptor::new(identity).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            acceptor.accept(socket).unwrap()
+        });
+
+        let connector = TlsConnector::builder()
+            .danger_accept_invalid_certs(true)
+            .build()
+            .unwrap();
+        let socket = TcpStream::connect(address).unwrap();
+        let client = connector.connect("localhost", socket).unwrap();
+        let _server = server.join().unwrap();
+
+        let binding = client_channel_binding(&client).unwrap();
+        assert_eq!(
+            binding,
+            OwnedChannelBinding::TlsServerEndPoint(CERTIFICATE.to_vec())
+        );
+        let data = binding.binding().data().unwrap().into_owned();
+        assert_eq!(data.len(), 48);
+        assert_eq!(Some(&data), tls_server_end_point(CERTIFICATE).as_ref());
+        assert_eq!(client.tls_server_end_point().unwrap(), Some(data));
+    }
+}
@@ -0,0 +1,148 @@
+//! A lightweight normalization of usernames and passwords to Unicode Normalization Form C.
+//!
+//! RFC5802 requires SASLprep (RFC4013) for usernames and passwords, which most servers only apply
+//! partially if at all. Servers which normalize credentials usually store them in NFC, so a
+//! client sending a password typed in decomposed form, for example on macOS, fails to
+//! authenticate. [`normalize`] converts text to NFC and rejects the characters SASLprep
+//! prohibits and unassigned code points, without the tables of a full SASLprep implementation:
+//!
+//! ```
+//! use scram::nfc::normalize;
+//!
+//! assert_eq!(normalize("Cafe\u{301}").unwrap(), "Caf\u{e9}");
+//! assert!(normalize("pass\u{7}word").is_err());
+//! ```
+//!
+//! Unlike SASLprep, the normalization doesn't map non-ASCII spaces to the space character, doesn't
+//! remove the characters SASLprep maps to nothing and doesn't check bidirectional text. The
+//! assigned code points are those of the Unicode version of the `unicode-normalization` crate, not
+//! Unicode 3.2. [`ClientFirstTemplate::try_new_nfc`](crate::client::ClientFirstTemplate::try_new_nfc)
+//! normalizes the credentials of a client. The `saslprep` module implements SASLprep in full.
+//!
+//! This module is available with the `nfc` feature.
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+use unicode_normalization::char::is_public_assigned;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+/// The reasons text can't be normalized. Both contain the byte offset of the offending character
+/// in the text, not the character itself, which may be part of a password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum NfcError {
+    /// The character is prohibited by SASLprep, for example a control character or a private use
+    /// code point.
+    Prohibited(usize),
+    /// The code point isn't assigned to a character.
+    Unassigned(usize),
+}
+
+impl fmt::Display for NfcError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            NfcError::Prohibited(offset) => write!(fmt, "Prohibited character at {}", offset),
+            NfcError::Unassigned(offset) => write!(fmt, "Unassigned code point at {}", offset),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for NfcError {}
+
+/// Returns `text` in Normalization Form C, borrowed if it already is.
+///
+/// # Return value
+///
+/// Fails if `text` contains a character which SASLprep prohibits or an unassigned code point.
+pub fn normalize(text: &str) -> Result<Cow<'_, str>, NfcError> {
+    for (offset, c) in text.char_indices() {
+        if is_prohibited(c) {
+            return Err(NfcError::Prohibited(offset));
+        }
+        if !is_public_assigned(c) {
+            return Err(NfcError::Unassigned(offset));
+        }
+    }
+    if is_nfc(text) {
+        Ok(Cow::Borrowed(text))
+    } else {
+        Ok(Cow::Owned(text.nfc().collect::<String>()))
+    }
+}
+
+/// Returns whether SASLprep prohibits `c`, see RFC4013 section 2.3. Non-ASCII spaces are mapped
+/// instead, and surrogates can't occur in a `str`.
+fn is_prohibited(c: char) -> bool {
+    match c {
+        // C.2.1 and C.2.2, control characters
+        '\u{0}'..='\u{1F}'
+        | '\u{7F}'..='\u{9F}'
+        | '\u{6DD}'
+        | '\u{70F}'
+        | '\u{180E}'
+        | '\u{200C}'
+        | '\u{200D}'
+        | '\u{2028}'
+        | '\u{2029}'
+        | '\u{2060}'..='\u{2063}'
+        | '\u{FEFF}'
+        | '\u{1D173}'..='\u{1D17A}'
+        // C.3, private use
+        | '\u{E000}'..='\u{F8FF}'
+        | '\u{F0000}'..='\u{FFFFD}'
+        | '\u{100000}'..='\u{10FFFD}'
+        // C.4, non-character code points of the BMP, the others follow below
+        | '\u{FDD0}'..='\u{FDEF}'
+        // C.6, inappropriate for plain text
+        | '\u{FFF9}'..='\u{FFFD}'
+        // C.7, inappropriate for canonical representation
+        | '\u{2FF0}'..='\u{2FFB}'
+        // C.8, change display properties or deprecated
+        | '\u{340}'
+        | '\u{341}'
+        | '\u{200E}'
+        | '\u{200F}'
+        | '\u{202A}'..='\u{202E}'
+        | '\u{206A}'..='\u{206F}'
+        // C.9, tagging characters
+        | '\u{E0001}'
+        | '\u{E0020}'..='\u{E007F}' => true,
+        // C.4, the last two code points of every plane
+        _ => c as u32 & 0xFFFE == 0xFFFE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::borrow::Cow;
+
+    use super::{normalize, NfcError};
+
+    #[test]
+    fn test_normalize() {
+        assert!(matches!(normalize("pencil"), Ok(Cow::Borrowed("pencil"))));
+        assert!(matches!(normalize("Caf\u{e9}"), Ok(Cow::Borrowed(_))));
+        assert_eq!(normalize("Cafe\u{301}").unwrap(), "Caf\u{e9}");
+        assert_eq!(normalize("\u{212B}").unwrap(), "\u{C5}");
+        // NFC doesn't apply compatibility mappings.
+        assert_eq!(normalize("\u{FB01}").unwrap(), "\u{FB01}");
+        assert_eq!(normalize("user name").unwrap(), "user name");
+    }
+
+    #[test]
+    fn test_rejected() {
+        assert_eq!(normalize("a\u{7}"), Err(NfcError::Prohibited(1)));
+        assert_eq!(normalize("\u{e9}\u{200E}"), Err(NfcError::Prohibited(2)));
+        assert_eq!(normalize("\u{E000}"), Err(NfcError::Prohibited(0)));
+        assert_eq!(normalize("\u{FFFF}"), Err(NfcError::Prohibited(0)));
+        assert_eq!(normalize("\u{1FFFE}"), Err(NfcError::Prohibited(0)));
+        assert_eq!(normalize("\u{E0041}"), Err(NfcError::Prohibited(0)));
+        assert_eq!(normalize("a\u{378}"), Err(NfcError::Unassigned(1)));
+        assert_eq!(normalize("\u{50000}"), Err(NfcError::Unassigned(0)));
+    }
+}
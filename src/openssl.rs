@@ -0,0 +1,165 @@
+//! The channel-binding data of `openssl` connections.
+//!
+//! [`client_channel_binding`] picks the binding of a client connection after the TLS handshake,
+//! like the `rustls` module: `tls-exporter` with TLS 1.3, for which `tls-unique` isn't defined,
+//! and `tls-server-end-point` with the certificate of the server with earlier versions. Servers
+//! which only support `tls-unique` get its data from [`tls_unique`]:
+//!
+//! ``` rust,no_run
+//! use scram::ScramClient;
+//!
+//! # fn connect() -> openssl::ssl::SslStream<std::net::TcpStream> { unimplemented!() }
+//! let stream = connect();
+//! let binding = scram::openssl::client_channel_binding(stream.ssl()).unwrap();
+//! let client =
+//!     ScramClient::new("user", "password", None).with_channel_binding(binding.binding());
+//! ```
+//!
+//! Servers pass the data of the type the client chose to
+//! [`ConnectionContext::with_channel_binding_data`](crate::ConnectionContext::with_channel_binding_data):
+//! [`exporter`] for `tls-exporter`, [`tls_unique`] for `tls-unique`, and the hash of their
+//! certificate from [`tls_server_end_point`](crate::channel_binding::tls_server_end_point) for
+//! `tls-server-end-point`.
+//!
+//! This module is available with the `openssl` feature.
+use alloc::vec::Vec;
+
+use ::openssl::error::ErrorStack;
+use ::openssl::ssl::{SslRef, SslVersion};
+
+use crate::channel_binding::{OwnedChannelBinding, TLS_EXPORTER_LABEL, TLS_EXPORTER_LENGTH};
+
+/// The longest Finished message, of SSL 3.0.
+const MAX_FINISHED_LEN: usize = 36;
+
+/// Returns the `tls-exporter` channel-binding data of the connection `ssl`, on the client and on
+/// the server.
+///
+/// # Return value
+///
+/// Fails like `SslRef::export_keying_material`, for example if the TLS handshake isn't complete.
+pub fn exporter(ssl: &SslRef) -> Result<[u8; TLS_EXPORTER_LENGTH], ErrorStack> {
+    let mut exporter = [0; TLS_EXPORTER_LENGTH];
+    ssl.export_keying_material(&mut exporter, TLS_EXPORTER_LABEL, None)?;
+    Ok(exporter)
+}
+
+/// Returns the `tls-unique` channel-binding data of the connection `ssl`, on the client and on the
+/// server: the first Finished message of the latest TLS handshake, which the client sends in a
+/// full handshake and the server in a resumption.
+///
+/// # Return value
+///
+/// Returns `None` with TLS 1.3, for which `tls-unique` isn't defined, or if the TLS handshake
+/// isn't complete.
+pub fn tls_unique(ssl: &SslRef) -> Option<Vec<u8>> {
+    match ssl.version2()? {
+        SslVersion::TLS1_3 => return None,
+        _ if !ssl.is_init_finished() => return None,
+        _ => {}
+    }
+    let mut finished = [0; MAX_FINISHED_LEN];
+    let len = if ssl.is_server() == ssl.session_reused() {
+        ssl.finished(&mut finished)
+    } else {
+        ssl.peer_finished(&mut finished)
+    };
+    Some(finished[..len.min(MAX_FINISHED_LEN)].to_vec())
+}
+
+/// Returns the channel binding of the client connection `ssl`, see the
+/// [module documentation](self), or `None` if the TLS handshake isn't complete.
+pub fn client_channel_binding(ssl: &SslRef) -> Option<OwnedChannelBinding> {
+    if !ssl.is_init_finished() {
+        return None;
+    }
+    match ssl.version2()? {
+        SslVersion::TLS1_3 => exporter(ssl)
+            .ok()
+            .map(|exporter| OwnedChannelBinding::TlsExporter(exporter.to_vec())),
+        _ => {
+            let certificate = ssl.peer_certificate()?.to_der().ok()?;
+            Some(OwnedChannelBinding::TlsServerEndPoint(certificate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use ::openssl::pkey::PKey;
+    use ::openssl::ssl::{
+        HandshakeError, SslAcceptor, SslConnector, SslMethod, SslStream, SslVerifyMode, SslVersion,
+    };
+    use ::openssl::x509::X509;
+
+    use super::{client_channel_binding, exporter, tls_unique};
+    use crate::channel_binding::{tls_server_end_point, OwnedChannelBinding};
+
+    /// A self-signed certificate for `localhost` with an ECDSA P-256 key, signed with SHA-384.
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/data/localhost.der");
+    /// The PKCS #8 private key of [`CERTIFICATE`].
+    const KEY: &[u8] = include_bytes!("../tests/data/localhost.key.der");
+
+    /// Runs a TLS handshake of `version` over the loopback interface and returns the client and
+    /// the server stream.
+    fn handshake(version: SslVersion) -> (SslStream<TcpStream>, SslStream<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_certificate(&X509::from_der(CERTIFICATE).unwrap())
+            .unwrap();
+        acceptor
+            .set_private_key(&PKey::private_key_from_der(KEY).unwrap())
+            .unwrap();
+        acceptor.set_min_proto_version(Some(version)).unwrap();
+        acceptor.set_max_proto_version(Some(version)).unwrap();
+        let acceptor = acceptor.build();
+        let server = thread::spawn(move || {
+            let (socket, _) = listener.accept().unwrap();
+            acceptor.accept(socket).unwrap()
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let ssl = connector.build().configure().unwrap();
+        let ssl = ssl.into_ssl("localhost").unwrap();
+        assert_eq!(client_channel_binding(&ssl), None);
+        let client = match ssl.connect(TcpStream::connect(address).unwrap()) {
+            Ok(client) => client,
+            Err(HandshakeError::SetupFailure(err)) => panic!("{}", err),
+            Err(_) => panic!("handshake failed"),
+        };
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn test_tls13() {
+        let (client, server) = handshake(SslVersion::TLS1_3);
+        let expected = exporter(server.ssl()).unwrap();
+        assert_eq!(
+            client_channel_binding(client.ssl()),
+            Some(OwnedChannelBinding::TlsExporter(expected.to_vec()))
+        );
+        assert_eq!(tls_unique(client.ssl()), None);
+    }
+
+    #[test]
+    fn test_tls12() {
+        let (client, server) = handshake(SslVersion::TLS1_2);
+        let binding = client_channel_binding(client.ssl()).unwrap();
+        assert_eq!(
+            binding,
+            OwnedChannelBinding::TlsServerEndPoint(CERTIFICATE.to_vec())
+        );
+        let data = binding.binding().data().unwrap();
+        assert_eq!(Some(data.into_owned()), tls_server_end_point(CERTIFICATE));
+
+        let finished = tls_unique(client.ssl()).unwrap();
+        assert_eq!(finished.len(), 12);
+        assert_eq!(tls_unique(server.ssl()), Some(finished));
+    }
+}
@@ -0,0 +1,350 @@
+//! The salts and iteration counts servers sent in previous handshakes, which detect replaced
+//! verifiers across restarts of the client.
+//!
+//! A server only changes the salt and the iteration count of a user when the password changes or
+//! the stored credentials are upgraded. An attacker who got hold of the stored credentials of a
+//! user, or who replaced them, also has to send other values, unless they copied the salt. A
+//! [`PinStore`] keeps the values of the last successful handshake with every user at every host,
+//! and a client with [`ScramClient::with_pin_store`](crate::ScramClient::with_pin_store) fails
+//! with `Error::VerifierChanged` if the server sends different ones:
+//!
+//! ``` rust,no_run
+//! use std::sync::Arc;
+//! use scram::pinning::FilePinStore;
+//! use scram::ScramClient;
+//!
+//! let pins = Arc::new(FilePinStore::open("scram.pins").unwrap());
+//! let client =
+//!     ScramClient::new("user", "password", None).with_pin_store(pins.clone(), "db.example.org");
+//! // Run the handshake.
+//! pins.save().unwrap();
+//! ```
+//!
+//! The values are only pinned after the server was verified with its signature, so a server which
+//! doesn't know the credentials can't replace the pin. After a legitimate password change the pin
+//! is removed with [`MemoryPinStore::remove`].
+//!
+//! This module is available with the `pinning` feature.
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::ops::Deref;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::error::Error;
+
+/// The salt and the iteration count a server sent for a user.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Pin {
+    /// The decoded salt.
+    pub salt: Vec<u8>,
+    /// The iteration count.
+    pub iterations: NonZeroU32,
+}
+
+/// A store of the [`Pin`] of every user at every host, see the [module documentation](self).
+///
+/// The store is shared by the states of a handshake and may be shared by the clients of all
+/// threads, so it takes `&self` and has to synchronize its own changes.
+pub trait PinStore: Send + Sync {
+    /// Returns the pin of `username` at `host`, if there is one.
+    fn get(&self, host: &str, username: &str) -> Option<Pin>;
+
+    /// Replaces the pin of `username` at `host` after a successful handshake.
+    fn put(&self, host: &str, username: &str, pin: Pin);
+}
+
+/// A [`PinStore`] which keeps the pins in memory, for the lifetime of the process.
+#[derive(Debug, Default)]
+pub struct MemoryPinStore {
+    pins: Mutex<BTreeMap<(String, String), Pin>>,
+}
+
+impl MemoryPinStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        MemoryPinStore::default()
+    }
+
+    /// Removes the pin of `username` at `host`, for example after the password of the user was
+    /// changed. The next handshake pins the new values.
+    pub fn remove(&self, host: &str, username: &str) {
+        self.pins()
+            .remove(&(host.to_string(), username.to_string()));
+    }
+
+    /// Removes all pins.
+    pub fn clear(&self) {
+        self.pins().clear();
+    }
+
+    /// Returns the number of pins.
+    pub fn len(&self) -> usize {
+        self.pins().len()
+    }
+
+    /// Returns whether the store has no pins.
+    pub fn is_empty(&self) -> bool {
+        self.pins().is_empty()
+    }
+
+    fn pins(&self) -> MutexGuard<'_, BTreeMap<(String, String), Pin>> {
+        // The map is consistent after every operation, so a poisoned lock can be used.
+        self.pins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl PinStore for MemoryPinStore {
+    fn get(&self, host: &str, username: &str) -> Option<Pin> {
+        self.pins()
+            .get(&(host.to_string(), username.to_string()))
+            .cloned()
+    }
+
+    fn put(&self, host: &str, username: &str, pin: Pin) {
+        self.pins()
+            .insert((host.to_string(), username.to_string()), pin);
+    }
+}
+
+/// A [`PinStore`] which is read from a file and written back with [`save`](Self::save).
+///
+/// The file has a line for every pin with the host, the username, the iteration count and the
+/// salt, separated by spaces. The host, the username and the salt are encoded in base64. The
+/// methods of the [`MemoryPinStore`] the pins are kept in are available through `Deref`.
+#[derive(Debug)]
+pub struct FilePinStore {
+    path: PathBuf,
+    pins: MemoryPinStore,
+}
+
+impl FilePinStore {
+    /// Reads the pins from the file at `path`. A missing file results in an empty store, which is
+    /// created by the first [`save`](Self::save).
+    ///
+    /// # Return value
+    ///
+    /// An error of the kind `io::ErrorKind::InvalidData` is returned if a line of the file isn't a
+    /// pin.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let store = FilePinStore {
+            path: path.as_ref().to_path_buf(),
+            pins: MemoryPinStore::new(),
+        };
+        let data = match fs::read_to_string(&store.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(store),
+            Err(err) => return Err(err),
+        };
+        for line in data.lines().filter(|line| !line.is_empty()) {
+            let (host, username, pin) = parse_line(line)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pin"))?;
+            store.pins.put(&host, &username, pin);
+        }
+        Ok(store)
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the pins to the file, replacing it atomically.
+    pub fn save(&self) -> io::Result<()> {
+        let mut data = String::new();
+        for ((host, username), pin) in self.pins.pins().iter() {
+            data.push_str(&STANDARD.encode(host));
+            data.push(' ');
+            data.push_str(&STANDARD.encode(username));
+            data.push(' ');
+            data.push_str(&pin.iterations.to_string());
+            data.push(' ');
+            data.push_str(&STANDARD.encode(&pin.salt));
+            data.push('\n');
+        }
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, data)?;
+        fs::rename(&temporary, &self.path)
+    }
+}
+
+impl Deref for FilePinStore {
+    type Target = MemoryPinStore;
+
+    fn deref(&self) -> &MemoryPinStore {
+        &self.pins
+    }
+}
+
+impl PinStore for FilePinStore {
+    fn get(&self, host: &str, username: &str) -> Option<Pin> {
+        self.pins.get(host, username)
+    }
+
+    fn put(&self, host: &str, username: &str, pin: Pin) {
+        self.pins.put(host, username, pin)
+    }
+}
+
+/// Parses a line of a pin file into the host, the username and the pin.
+fn parse_line(line: &str) -> Option<(String, String, Pin)> {
+    let mut fields = line.split(' ');
+    let mut decode = || STANDARD.decode(fields.next()?).ok();
+    let host = String::from_utf8(decode()?).ok()?;
+    let username = String::from_utf8(decode()?).ok()?;
+    let iterations = fields.next()?.parse().ok()?;
+    let salt = STANDARD.decode(fields.next()?).ok()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    Some((host, username, Pin { salt, iterations }))
+}
+
+/// The pin of the user of a client at a host, checked against the `server-first-message`.
+#[derive(Clone)]
+pub(crate) struct Pinning {
+    store: Arc<dyn PinStore>,
+    host: String,
+    username: String,
+}
+
+impl Pinning {
+    pub(crate) fn new(store: Arc<dyn PinStore>, host: &str, username: &str) -> Self {
+        Pinning {
+            store,
+            host: host.to_string(),
+            username: username.to_string(),
+        }
+    }
+
+    /// Fails with `Error::VerifierChanged` if a pin exists and differs from the values the server
+    /// sent. Otherwise returns the pin to store once the server is verified.
+    pub(crate) fn check(&self, salt: &[u8], iterations: NonZeroU32) -> Result<Pending, Error> {
+        let pin = Pin {
+            salt: salt.to_vec(),
+            iterations,
+        };
+        match self.store.get(&self.host, &self.username) {
+            Some(pinned) if pinned != pin => Err(Error::VerifierChanged),
+            _ => Ok(Pending {
+                pinning: self.clone(),
+                pin,
+            }),
+        }
+    }
+}
+
+impl fmt::Debug for Pinning {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Pinning")
+            .field("host", &self.host)
+            .field("username", &self.username)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The values of a `server-first-message` which are pinned after the server was verified.
+#[derive(Debug)]
+pub(crate) struct Pending {
+    pinning: Pinning,
+    pin: Pin,
+}
+
+impl Pending {
+    /// Stores the pin.
+    pub(crate) fn commit(self) {
+        let Pending { pinning, pin } = self;
+        pinning.store.put(&pinning.host, &pinning.username, pin);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::num::NonZeroU32;
+    use std::env;
+    use std::fs;
+    use std::io;
+    use std::process;
+
+    use super::{FilePinStore, MemoryPinStore, Pin, PinStore, Pinning};
+    use crate::error::Error;
+
+    fn pin(salt: &[u8], iterations: u32) -> Pin {
+        Pin {
+            salt: salt.to_vec(),
+            iterations: NonZeroU32::new(iterations).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryPinStore::new();
+        assert!(store.is_empty());
+        store.put("host", "user", pin(b"salt", 4096));
+        store.put("other", "user", pin(b"other", 4096));
+        assert_eq!(store.get("host", "user"), Some(pin(b"salt", 4096)));
+        assert_eq!(store.get("host", "other"), None);
+        assert_eq!(store.len(), 2);
+        store.remove("host", "user");
+        assert_eq!(store.get("host", "user"), None);
+        store.clear();
+        assert!(store.is_empty());
+    }
+
+    #[test]
+    fn test_check() {
+        let store = Arc::new(MemoryPinStore::new());
+        let pinning = Pinning::new(store.clone(), "host", "user");
+        let iterations = NonZeroU32::new(4096).unwrap();
+        // Nothing is pinned before the pending pin is committed.
+        let pending = pinning.check(b"salt", iterations).unwrap();
+        assert!(store.is_empty());
+        pending.commit();
+        assert_eq!(store.get("host", "user"), Some(pin(b"salt", 4096)));
+        assert!(pinning.check(b"salt", iterations).is_ok());
+        assert_eq!(
+            pinning.check(b"other", iterations).unwrap_err(),
+            Error::VerifierChanged
+        );
+        let more = NonZeroU32::new(8192).unwrap();
+        assert_eq!(
+            pinning.check(b"salt", more).unwrap_err(),
+            Error::VerifierChanged
+        );
+    }
+
+    #[test]
+    fn test_file_store() {
+        let directory = env::temp_dir().join(format!("scram-pins-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("scram.pins");
+        let store = FilePinStore::open(&path).unwrap();
+        assert!(store.is_empty());
+        store.put("host", "user name", pin(b"salt", 4096));
+        store.put("host", "", pin(&[0, 255], 15000));
+        store.save().unwrap();
+
+        let loaded = FilePinStore::open(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get("host", "user name"), Some(pin(b"salt", 4096)));
+        assert_eq!(loaded.get("host", ""), Some(pin(&[0, 255], 15000)));
+
+        fs::write(&path, "aG9zdA== dXNlcg== 0 c2FsdA==\n").unwrap();
+        let err = FilePinStore::open(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
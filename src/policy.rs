@@ -0,0 +1,224 @@
+//! Password policies enforced when credentials are created.
+//!
+//! The plaintext password is only available when a user sets it. Afterwards the server stores the
+//! salted password, which can't be checked against any policy. [`hash_password_checked`] runs a
+//! [`PasswordPolicy`] right before hashing, so operators can reject short, simple or breached
+//! passwords at that moment.
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use scram::policy::{hash_password_checked, CharacterClass, Policy, PolicyViolation};
+//!
+//! let policy = Policy::new()
+//!     .min_length(12)
+//!     .require(CharacterClass::Digit)
+//!     .breach_check(|password| password == "correct horse battery staple 1");
+//! let iterations = NonZeroU32::new(4096).unwrap();
+//! assert_eq!(
+//!     hash_password_checked(&policy, "password", iterations, b"salt"),
+//!     Err(PolicyViolation::TooShort(12))
+//! );
+//! assert!(hash_password_checked(&policy, "tr0ub4dor & three", iterations, b"salt").is_ok());
+//! ```
+use alloc::boxed::Box;
+use core::fmt;
+use core::num::NonZeroU32;
+#[cfg(feature = "std")]
+use std::error;
+
+use ring::digest::SHA256_OUTPUT_LEN;
+
+use crate::utils::hash_password;
+
+/// Decides whether a password may be used for new credentials.
+pub trait PasswordPolicy {
+    /// Checks `password` and returns the first rule it violates.
+    fn check(&self, password: &str) -> Result<(), PolicyViolation>;
+}
+
+/// The kinds of characters a [`Policy`] can require.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CharacterClass {
+    /// A lowercase letter.
+    Lowercase,
+    /// An uppercase letter.
+    Uppercase,
+    /// A decimal digit.
+    Digit,
+    /// Any character which isn't alphanumeric, including whitespace.
+    Symbol,
+}
+
+impl CharacterClass {
+    fn contains(self, character: char) -> bool {
+        match self {
+            CharacterClass::Lowercase => character.is_lowercase(),
+            CharacterClass::Uppercase => character.is_uppercase(),
+            CharacterClass::Digit => character.is_ascii_digit(),
+            CharacterClass::Symbol => !character.is_alphanumeric(),
+        }
+    }
+}
+
+/// The reasons a password is rejected.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PolicyViolation {
+    /// The password has fewer characters than the contained minimum.
+    TooShort(usize),
+    /// The password has more characters than the contained maximum.
+    TooLong(usize),
+    /// The password doesn't contain a character of the class.
+    MissingCharacter(CharacterClass),
+    /// The password appears in a list of breached passwords.
+    Breached,
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PolicyViolation::TooShort(min) => {
+                write!(fmt, "Password shorter than {} characters", min)
+            }
+            PolicyViolation::TooLong(max) => write!(fmt, "Password longer than {} characters", max),
+            PolicyViolation::MissingCharacter(class) => {
+                write!(fmt, "Password without {:?} character", class)
+            }
+            PolicyViolation::Breached => write!(fmt, "Password found in a breach"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for PolicyViolation {}
+
+/// A callback telling whether a password appears in a list of breached passwords.
+type BreachCheck = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// A [`PasswordPolicy`] with length limits, required character classes and an optional breach
+/// list lookup. Lengths are counted in characters.
+///
+/// The default policy accepts every password.
+#[derive(Default)]
+pub struct Policy {
+    min_length: usize,
+    max_length: Option<usize>,
+    required: [bool; 4],
+    breach_check: Option<BreachCheck>,
+}
+
+impl Policy {
+    /// Creates a policy which accepts every password.
+    pub fn new() -> Self {
+        Policy::default()
+    }
+
+    /// Rejects passwords with fewer than `min` characters.
+    pub fn min_length(mut self, min: usize) -> Self {
+        self.min_length = min;
+        self
+    }
+
+    /// Rejects passwords with more than `max` characters.
+    pub fn max_length(mut self, max: usize) -> Self {
+        self.max_length = Some(max);
+        self
+    }
+
+    /// Rejects passwords without a character of `class`.
+    pub fn require(mut self, class: CharacterClass) -> Self {
+        self.required[class as usize] = true;
+        self
+    }
+
+    /// Rejects passwords for which `is_breached` returns `true`, for example because they appear
+    /// in a list of breached passwords. The callback runs after all other rules passed.
+    pub fn breach_check<F>(mut self, is_breached: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.breach_check = Some(Box::new(is_breached));
+        self
+    }
+}
+
+impl PasswordPolicy for Policy {
+    fn check(&self, password: &str) -> Result<(), PolicyViolation> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PolicyViolation::TooShort(self.min_length));
+        }
+        if let Some(max) = self.max_length {
+            if length > max {
+                return Err(PolicyViolation::TooLong(max));
+            }
+        }
+        let classes = [
+            CharacterClass::Lowercase,
+            CharacterClass::Uppercase,
+            CharacterClass::Digit,
+            CharacterClass::Symbol,
+        ];
+        for &class in &classes {
+            let required = self.required[class as usize];
+            if required && !password.chars().any(|character| class.contains(character)) {
+                return Err(PolicyViolation::MissingCharacter(class));
+            }
+        }
+        match self.breach_check {
+            Some(ref is_breached) if is_breached(password) => Err(PolicyViolation::Breached),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Debug for Policy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Policy")
+            .field("min_length", &self.min_length)
+            .field("max_length", &self.max_length)
+            .field("required", &self.required)
+            .field("breach_check", &self.breach_check.as_ref().map(|_| ".."))
+            .finish()
+    }
+}
+
+/// Checks `password` against `policy` and hashes it like [`hash_password`] if it complies.
+pub fn hash_password_checked<P: PasswordPolicy + ?Sized>(
+    policy: &P,
+    password: &str,
+    iterations: NonZeroU32,
+    salt: &[u8],
+) -> Result<[u8; SHA256_OUTPUT_LEN], PolicyViolation> {
+    policy.check(password)?;
+    Ok(hash_password(password, iterations, salt))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CharacterClass, PasswordPolicy, Policy, PolicyViolation};
+
+    #[test]
+    fn test_policy() {
+        let policy = Policy::new()
+            .min_length(4)
+            .max_length(8)
+            .require(CharacterClass::Uppercase)
+            .require(CharacterClass::Symbol)
+            .breach_check(|password| password == "Pass!");
+        assert_eq!(policy.check("Ab!"), Err(PolicyViolation::TooShort(4)));
+        assert_eq!(policy.check("Abcdefg!!"), Err(PolicyViolation::TooLong(8)));
+        // Seven characters, but 13 bytes.
+        assert_eq!(policy.check("ÄÖÜäöü!"), Ok(()));
+        assert_eq!(
+            policy.check("abcd!"),
+            Err(PolicyViolation::MissingCharacter(CharacterClass::Uppercase))
+        );
+        assert_eq!(
+            policy.check("Abcde"),
+            Err(PolicyViolation::MissingCharacter(CharacterClass::Symbol))
+        );
+        assert_eq!(policy.check("Pass!"), Err(PolicyViolation::Breached));
+        assert_eq!(policy.check("Pässe!"), Ok(()));
+        assert_eq!(Policy::new().check(""), Ok(()));
+    }
+}
@@ -0,0 +1,24 @@
+//! The commonly used types of the crate, for a single glob import:
+//!
+//! ```
+//! use scram::prelude::*;
+//!
+//! let scram = ScramClient::new("user", "password", None);
+//! let (scram, client_first): (client::ServerFirst, String) = scram.client_first();
+//! assert_eq!(Mechanism::ScramSha256.name(), "SCRAM-SHA-256");
+//! # let _ = (scram, client_first);
+//! ```
+//!
+//! The client and the server have states of the same names, like `ServerFirst`, so the states
+//! are reached through the [`client`] and [`server`] modules, which are exported as well.
+pub use crate::client::{self, ClientFirstTemplate, ScramClient};
+pub use crate::confidentiality::Confidentiality;
+pub use crate::config::ScramConfig;
+pub use crate::entropy::EntropySource;
+pub use crate::error::{ChannelBindingError, Error};
+pub use crate::gs2::ChannelBindingFlag;
+pub use crate::mechanism::Mechanism;
+pub use crate::server::{
+    self, AuthenticationProvider, AuthenticationStatus, ConnectionContext, PasswordInfo,
+    ScramServer,
+};
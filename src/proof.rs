@@ -0,0 +1,243 @@
+//! The computations of SCRAM for implementations with their own message handling.
+//!
+//! Proxies and protocol stacks which build and parse the messages themselves only need the
+//! cryptography of RFC5802 section 3. [`auth_message`] joins the messages the proofs are
+//! computed over, and [`compute_client_proof`] proves the possession of the `ClientKey`, as
+//! derived by the [`credentials`](crate::credentials) module. Servers check the proof with
+//! [`verify_client_proof`] and prove their own possession of the `ServerKey` with
+//! [`compute_server_signature`]:
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use scram::credentials::derive_credentials;
+//! use scram::proof::{
+//!     auth_message, compute_client_proof, compute_server_signature, verify_client_proof,
+//! };
+//! use scram::Mechanism;
+//!
+//! let iterations = NonZeroU32::new(4096).unwrap();
+//! let salt = b"salt".to_vec();
+//! let credentials = derive_credentials("pencil", Mechanism::ScramSha256, iterations, salt);
+//! let auth_message = auth_message(
+//!     "n=user,r=rOprNGfwEbeRWgbNEkqO",
+//!     "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=c2FsdA==,i=4096",
+//!     "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0",
+//! );
+//! let mechanism = credentials.mechanism;
+//! let client_proof =
+//!     compute_client_proof(mechanism, &credentials.client_key, auth_message.as_bytes());
+//!
+//! let auth_message = auth_message.as_bytes();
+//! assert!(verify_client_proof(mechanism, &credentials.stored_key, auth_message, &client_proof));
+//! let server_key = &credentials.server_key;
+//! let server_signature = compute_server_signature(mechanism, server_key, auth_message);
+//! ```
+//!
+//! The keys, the proof and the signature have the [`output_len`](Mechanism::output_len) of the
+//! mechanism.
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use ring::digest::digest;
+use ring::hmac::{self, Key};
+
+use crate::mechanism::Mechanism;
+use crate::utils::zeroize;
+
+/// Returns the `AuthMessage` of RFC5802 section 3, which both proofs are computed over:
+/// `client-first-message-bare + "," + server-first-message + "," +
+/// client-final-message-without-proof`.
+pub fn auth_message(
+    client_first_bare: &str,
+    server_first: &str,
+    client_final_without_proof: &str,
+) -> String {
+    let mut message = String::with_capacity(
+        client_first_bare.len() + server_first.len() + client_final_without_proof.len() + 2,
+    );
+    message.push_str(client_first_bare);
+    message.push(',');
+    message.push_str(server_first);
+    message.push(',');
+    message.push_str(client_final_without_proof);
+    message
+}
+
+/// Returns the `ClientProof` for the `AuthMessage`, without the base64 encoding of the `p=`
+/// attribute:
+///
+/// ```text
+/// StoredKey       := H(ClientKey)
+/// ClientSignature := HMAC(StoredKey, AuthMessage)
+/// ClientProof     := ClientKey XOR ClientSignature
+/// ```
+pub fn compute_client_proof(
+    mechanism: Mechanism,
+    client_key: &[u8],
+    auth_message: &[u8],
+) -> Vec<u8> {
+    let stored_key = digest(mechanism.digest(), client_key);
+    let client_signature = hmac::sign(
+        &Key::new(mechanism.hmac(), stored_key.as_ref()),
+        auth_message,
+    );
+    client_key
+        .iter()
+        .zip(client_signature.as_ref())
+        .map(|(k, s)| k ^ s)
+        .collect()
+}
+
+/// Returns whether `client_proof` proves the possession of the `ClientKey` whose hash is
+/// `stored_key`, by recovering the `ClientKey` from the proof:
+///
+/// ```text
+/// ClientSignature := HMAC(StoredKey, AuthMessage)
+/// ClientKey       := ClientProof XOR ClientSignature
+/// H(ClientKey) == StoredKey
+/// ```
+///
+/// The comparison takes the same time for every proof of the output length of `mechanism`.
+pub fn verify_client_proof(
+    mechanism: Mechanism,
+    stored_key: &[u8],
+    auth_message: &[u8],
+    client_proof: &[u8],
+) -> bool {
+    if client_proof.len() != mechanism.output_len() || stored_key.len() != client_proof.len() {
+        return false;
+    }
+    let client_signature = hmac::sign(&Key::new(mechanism.hmac(), stored_key), auth_message);
+    let mut client_key: Vec<u8> = client_proof
+        .iter()
+        .zip(client_signature.as_ref())
+        .map(|(p, s)| p ^ s)
+        .collect();
+    let hashed = digest(mechanism.digest(), &client_key);
+    zeroize(&mut client_key);
+    let difference = hashed
+        .as_ref()
+        .iter()
+        .zip(stored_key)
+        .fold(0, |difference, (a, b)| difference | (a ^ b));
+    difference == 0
+}
+
+/// Returns the `ServerSignature` for the `AuthMessage`, without the base64 encoding of the `v=`
+/// attribute:
+///
+/// ```text
+/// ServerSignature := HMAC(ServerKey, AuthMessage)
+/// ```
+pub fn compute_server_signature(
+    mechanism: Mechanism,
+    server_key: &[u8],
+    auth_message: &[u8],
+) -> Vec<u8> {
+    hmac::sign(&Key::new(mechanism.hmac(), server_key), auth_message)
+        .as_ref()
+        .to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::{
+        auth_message, compute_client_proof, compute_server_signature, verify_client_proof,
+    };
+    use crate::credentials::derive_credentials;
+    use crate::mechanism::Mechanism;
+
+    #[test]
+    fn test_rfc7677_example() {
+        let salt = STANDARD.decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let credentials = derive_credentials("pencil", Mechanism::ScramSha256, iterations, salt);
+        let auth_message = auth_message(
+            "n=user,r=rOprNGfwEbeRWgbNEkqO",
+            "r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,\
+             i=4096",
+            "c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0",
+        );
+        let mechanism = Mechanism::ScramSha256;
+        let client_proof =
+            compute_client_proof(mechanism, &credentials.client_key, auth_message.as_bytes());
+        assert_eq!(
+            STANDARD.encode(&client_proof),
+            "dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ="
+        );
+
+        let auth_message = auth_message.as_bytes();
+        assert!(verify_client_proof(
+            mechanism,
+            &credentials.stored_key,
+            auth_message,
+            &client_proof
+        ));
+        let mut wrong = client_proof.clone();
+        wrong[0] ^= 1;
+        assert!(!verify_client_proof(
+            mechanism,
+            &credentials.stored_key,
+            auth_message,
+            &wrong
+        ));
+        assert!(!verify_client_proof(
+            mechanism,
+            &credentials.stored_key,
+            auth_message,
+            &client_proof[1..]
+        ));
+        assert_eq!(
+            STANDARD.encode(compute_server_signature(
+                mechanism,
+                &credentials.server_key,
+                auth_message
+            )),
+            "6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="
+        );
+    }
+    #[test]
+    fn test_mechanisms() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let auth_message = b"n=user,r=abc,r=abcdef,s=c2FsdA==,i=4096,c=biws,r=abcdef";
+        for &mechanism in Mechanism::ALL {
+            let credentials = derive_credentials("pencil", mechanism, iterations, b"salt".to_vec());
+            assert_eq!(credentials.stored_key.len(), mechanism.output_len());
+            let client_proof =
+                compute_client_proof(mechanism, &credentials.client_key, auth_message);
+            assert_eq!(client_proof.len(), mechanism.output_len());
+            assert!(verify_client_proof(
+                mechanism,
+                &credentials.stored_key,
+                auth_message,
+                &client_proof
+            ));
+            let signature =
+                compute_server_signature(mechanism, &credentials.server_key, auth_message);
+            assert_eq!(signature.len(), mechanism.output_len());
+        }
+        // A proof of another mechanism is rejected.
+        let credentials = derive_credentials(
+            "pencil",
+            Mechanism::ScramSha512,
+            iterations,
+            b"salt".to_vec(),
+        );
+        let client_proof = compute_client_proof(
+            Mechanism::ScramSha512,
+            &credentials.client_key,
+            auth_message,
+        );
+        assert!(!verify_client_proof(
+            Mechanism::ScramSha256,
+            &credentials.stored_key,
+            auth_message,
+            &client_proof
+        ));
+    }
+}
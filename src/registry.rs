@@ -0,0 +1,338 @@
+//! A runtime registry of SASL mechanisms, resolved by name.
+//!
+//! Applications which support mechanisms beyond SCRAM, or plug in their own implementations,
+//! register a factory for every mechanism name in a [`MechanismRegistry`]. Clients pick the most
+//! preferred of the mechanisms a server advertises with
+//! [`negotiate`](MechanismRegistry::negotiate), servers list theirs with
+//! [`advertised`](MechanismRegistry::advertised) and start the exchange of the mechanism a client
+//! chose with [`start`](MechanismRegistry::start):
+//!
+//! ```
+//! use scram::dynamic::{AuthMechanism, SteppedClient};
+//! use scram::registry::MechanismRegistry;
+//! use scram::{Error, ScramClient};
+//!
+//! struct Plain<'a>(&'a str, &'a str);
+//!
+//! impl<'a> AuthMechanism for Plain<'a> {
+//!     type Error = Error;
+//!
+//!     fn name(&self) -> &str {
+//!         "PLAIN"
+//!     }
+//!
+//!     fn initial_response(&mut self) -> Result<Option<String>, Error> {
+//!         Ok(Some(format!("\0{}\0{}", self.0, self.1)))
+//!     }
+//!
+//!     fn respond(&mut self, _challenge: &str) -> Result<String, Error> {
+//!         self.initial_response().map(Option::unwrap_or_default)
+//!     }
+//!
+//!     fn is_complete(&self) -> bool {
+//!         true
+//!     }
+//! }
+//!
+//! let mut registry = MechanismRegistry::<Error>::new();
+//! registry
+//!     .register_client("SCRAM-SHA-256", || {
+//!         SteppedClient::new(ScramClient::new("user", "password", None))
+//!     })
+//!     .register_client("PLAIN", || Plain("user", "password"));
+//!
+//! let mechanism = registry.negotiate(&["PLAIN", "SCRAM-SHA-256"]).unwrap();
+//! assert_eq!(mechanism.name(), "SCRAM-SHA-256");
+//! ```
+//!
+//! The order of registration is the order of preference. Registering a name again replaces its
+//! factory and keeps its position.
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::dynamic::{AuthMechanism, ServerMechanism};
+
+type ClientFactory<'a, E> = Box<dyn Fn() -> Box<dyn AuthMechanism<Error = E> + 'a> + 'a>;
+type ServerFactory<'a, E> = Box<dyn Fn() -> Box<dyn ServerMechanism<Error = E> + 'a> + 'a>;
+
+/// Factories of the client and server sides of SASL mechanisms, see the
+/// [module documentation](self).
+///
+/// All mechanisms of a registry share the error type `E`.
+pub struct MechanismRegistry<'a, E> {
+    clients: Vec<(String, ClientFactory<'a, E>)>,
+    servers: Vec<(String, ServerFactory<'a, E>)>,
+}
+
+impl<'a, E> Default for MechanismRegistry<'a, E> {
+    fn default() -> Self {
+        MechanismRegistry::new()
+    }
+}
+
+impl<'a, E> MechanismRegistry<'a, E> {
+    /// Creates a registry without mechanisms.
+    pub fn new() -> Self {
+        MechanismRegistry {
+            clients: Vec::new(),
+            servers: Vec::new(),
+        }
+    }
+
+    /// Registers `factory` as the client side of the mechanism `name`, less preferred than the
+    /// mechanisms registered before.
+    pub fn register_client<M, F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        M: AuthMechanism<Error = E> + 'a,
+        F: Fn() -> M + 'a,
+    {
+        let factory: ClientFactory<'a, E> = Box::new(move || Box::new(factory()));
+        insert(&mut self.clients, name, factory);
+        self
+    }
+
+    /// Registers `factory` as the server side of the mechanism `name`, advertised after the
+    /// mechanisms registered before.
+    pub fn register_server<M, F>(&mut self, name: &str, factory: F) -> &mut Self
+    where
+        M: ServerMechanism<Error = E> + 'a,
+        F: Fn() -> M + 'a,
+    {
+        let factory: ServerFactory<'a, E> = Box::new(move || Box::new(factory()));
+        insert(&mut self.servers, name, factory);
+        self
+    }
+
+    /// Removes both sides of the mechanism `name`.
+    pub fn unregister(&mut self, name: &str) -> &mut Self {
+        self.clients.retain(|(registered, _)| registered != name);
+        self.servers.retain(|(registered, _)| registered != name);
+        self
+    }
+
+    /// Returns the names of the mechanisms with a client side, most preferred first.
+    pub fn client_names(&self) -> Vec<&str> {
+        self.clients.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Returns the names of the mechanisms with a server side, in the order to advertise them to
+    /// clients.
+    pub fn advertised(&self) -> Vec<&str> {
+        self.servers.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Returns the names of the mechanisms with a client side which are among the `advertised`
+    /// mechanism names, most preferred first.
+    pub fn candidates<S: AsRef<str>>(&self, advertised: &[S]) -> Vec<&str> {
+        self.clients
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .filter(|name| advertised.iter().any(|offer| offer.as_ref() == *name))
+            .collect()
+    }
+
+    /// Creates the client side of the mechanism `name`, or returns `None` if it isn't
+    /// registered.
+    pub fn client(&self, name: &str) -> Option<Box<dyn AuthMechanism<Error = E> + 'a>> {
+        find(&self.clients, name).map(|factory| factory())
+    }
+
+    /// Creates the client side of the most preferred of the `advertised` mechanisms, or returns
+    /// `None` if none of them is registered.
+    pub fn negotiate<S: AsRef<str>>(
+        &self,
+        advertised: &[S],
+    ) -> Option<Box<dyn AuthMechanism<Error = E> + 'a>> {
+        self.candidates(advertised)
+            .first()
+            .and_then(|name| self.client(name))
+    }
+
+    /// Creates the server side of the mechanism `name` a client chose, or returns `None` if it
+    /// isn't registered. The server then rejects the authentication attempt.
+    pub fn start(&self, name: &str) -> Option<Box<dyn ServerMechanism<Error = E> + 'a>> {
+        find(&self.servers, name).map(|factory| factory())
+    }
+}
+
+impl<'a, E> fmt::Debug for MechanismRegistry<'a, E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MechanismRegistry")
+            .field("clients", &self.client_names())
+            .field("servers", &self.advertised())
+            .finish()
+    }
+}
+
+/// Replaces the factory of `name`, or appends it.
+fn insert<F>(factories: &mut Vec<(String, F)>, name: &str, factory: F) {
+    match factories
+        .iter_mut()
+        .find(|(registered, _)| registered == name)
+    {
+        Some(entry) => entry.1 = factory,
+        None => factories.push((name.to_string(), factory)),
+    }
+}
+
+/// Returns the factory of `name`.
+fn find<'f, F>(factories: &'f [(String, F)], name: &str) -> Option<&'f F> {
+    factories
+        .iter()
+        .find(|(registered, _)| registered == name)
+        .map(|(_, factory)| factory)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::{String, ToString};
+    use core::num::NonZeroU32;
+
+    use super::MechanismRegistry;
+    use crate::client::ScramClient;
+    use crate::dynamic::{
+        AuthMechanism, ServerMechanism, StepOutput, SteppedClient, SteppedServer,
+    };
+    use crate::error::Error;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username != "user" {
+                return None;
+            }
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("password", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    struct Plain(&'static str);
+
+    impl AuthMechanism for Plain {
+        type Error = Error;
+
+        fn name(&self) -> &str {
+            "PLAIN"
+        }
+
+        fn initial_response(&mut self) -> Result<Option<String>, Error> {
+            Ok(Some(format!("\0user\0{}", self.0)))
+        }
+
+        fn respond(&mut self, _challenge: &str) -> Result<String, Error> {
+            self.initial_response().map(Option::unwrap_or_default)
+        }
+
+        fn is_complete(&self) -> bool {
+            true
+        }
+    }
+
+    struct PlainServer;
+
+    impl ServerMechanism for PlainServer {
+        type Error = Error;
+
+        fn name(&self) -> &str {
+            "PLAIN"
+        }
+
+        fn respond(&mut self, response: &str) -> Result<StepOutput, Error> {
+            let status = if response == "\0user\0password" {
+                AuthenticationStatus::Authenticated
+            } else {
+                AuthenticationStatus::NotAuthenticated
+            };
+            Ok(StepOutput::Done {
+                message: None,
+                status,
+            })
+        }
+    }
+
+    /// Runs the exchange of `client` with `server` and returns the status of the server.
+    fn exchange(
+        client: &mut dyn AuthMechanism<Error = Error>,
+        server: &mut dyn ServerMechanism<Error = Error>,
+    ) -> AuthenticationStatus {
+        let mut response = client.initial_response().unwrap().unwrap_or_default();
+        loop {
+            match server.respond(&response).unwrap() {
+                StepOutput::Continue(challenge) => response = client.respond(&challenge).unwrap(),
+                StepOutput::Done { message, status } => {
+                    if let Some(message) = message {
+                        client.respond(&message).unwrap();
+                    }
+                    assert!(client.is_complete());
+                    return status;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let mut registry = MechanismRegistry::<Error>::new();
+        assert!(registry.negotiate(&["PLAIN"]).is_none());
+        registry
+            .register_client("SCRAM-SHA-256", || {
+                SteppedClient::new(ScramClient::new("user", "password", None))
+            })
+            .register_client("PLAIN", || Plain("password"));
+        assert_eq!(registry.client_names(), ["SCRAM-SHA-256", "PLAIN"]);
+        assert_eq!(
+            registry.candidates(&["PLAIN", "LOGIN", "SCRAM-SHA-256"]),
+            ["SCRAM-SHA-256", "PLAIN"]
+        );
+        let mechanism = registry.negotiate(&["PLAIN", "SCRAM-SHA-256"]).unwrap();
+        assert_eq!(mechanism.name(), "SCRAM-SHA-256");
+        let mechanism = registry.negotiate(&["PLAIN".to_string()]).unwrap();
+        assert_eq!(mechanism.name(), "PLAIN");
+        assert!(registry.negotiate(&["LOGIN"]).is_none());
+        assert!(registry.client("LOGIN").is_none());
+
+        // Registering a name again keeps its position.
+        registry.register_client("SCRAM-SHA-256", || Plain("other"));
+        assert_eq!(registry.client_names(), ["SCRAM-SHA-256", "PLAIN"]);
+        let mut mechanism = registry.client("SCRAM-SHA-256").unwrap();
+        assert_eq!(
+            mechanism.initial_response(),
+            Ok(Some("\0user\0other".to_string()))
+        );
+        registry.unregister("SCRAM-SHA-256");
+        assert_eq!(registry.candidates(&["SCRAM-SHA-256", "PLAIN"]), ["PLAIN"]);
+    }
+
+    #[test]
+    fn test_start() {
+        let server = ScramServer::new(TestProvider);
+        let mut registry = MechanismRegistry::<Error>::new();
+        registry
+            .register_server("SCRAM-SHA-256", || SteppedServer::new(&server))
+            .register_server("PLAIN", || PlainServer)
+            .register_client("SCRAM-SHA-256", || {
+                SteppedClient::new(ScramClient::new("user", "password", None))
+            })
+            .register_client("PLAIN", || Plain("wrong"));
+        assert_eq!(registry.advertised(), ["SCRAM-SHA-256", "PLAIN"]);
+        assert!(registry.start("LOGIN").is_none());
+
+        for name in registry.advertised() {
+            let mut client = registry.client(name).unwrap();
+            let mut server = registry.start(name).unwrap();
+            assert_eq!(server.name(), name);
+            let expected = if name == "PLAIN" {
+                AuthenticationStatus::NotAuthenticated
+            } else {
+                AuthenticationStatus::Authenticated
+            };
+            assert_eq!(exchange(&mut *client, &mut *server), expected);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! The channel-binding data of `rustls` connections.
+//!
+//! [`client_channel_binding`] picks the binding of a client connection after the TLS handshake:
+//! `tls-exporter` with TLS 1.3, for which `tls-unique` isn't defined, and `tls-server-end-point`
+//! with the certificate of the server with TLS 1.2, since `rustls` doesn't tell whether the
+//! extended master secret the exporter needs there was used:
+//!
+//! ``` rust,no_run
+//! use scram::ScramClient;
+//!
+//! # fn connect() -> rustls::ClientConnection { unimplemented!() }
+//! let connection = connect();
+//! let binding = scram::rustls::client_channel_binding(&connection).unwrap();
+//! let client =
+//!     ScramClient::new("user", "password", None).with_channel_binding(binding.binding());
+//! ```
+//!
+//! Servers pass the data of the type the client chose to
+//! [`ConnectionContext::with_channel_binding_data`](crate::ConnectionContext::with_channel_binding_data):
+//! [`exporter`] for `tls-exporter`, and the hash of their certificate from
+//! [`tls_server_end_point`](crate::channel_binding::tls_server_end_point) for
+//! `tls-server-end-point`.
+//!
+//! This module is available with the `rustls` feature.
+use ::rustls::{ClientConnection, ConnectionCommon, Error, ProtocolVersion};
+
+use crate::channel_binding::{OwnedChannelBinding, TLS_EXPORTER_LABEL, TLS_EXPORTER_LENGTH};
+
+/// Returns the `tls-exporter` channel-binding data of `connection`, on the client and on the
+/// server.
+///
+/// # Return value
+///
+/// Fails like `ConnectionCommon::export_keying_material`, for example if the TLS handshake isn't
+/// complete.
+pub fn exporter<Data>(
+    connection: &ConnectionCommon<Data>,
+) -> Result<[u8; TLS_EXPORTER_LENGTH], Error> {
+    connection.export_keying_material(
+        [0; TLS_EXPORTER_LENGTH],
+        TLS_EXPORTER_LABEL.as_bytes(),
+        None,
+    )
+}
+
+/// Returns the channel binding of the client `connection`, see the
+/// [module documentation](self), or `None` if the TLS handshake isn't complete.
+pub fn client_channel_binding(connection: &ClientConnection) -> Option<OwnedChannelBinding> {
+    if connection.is_handshaking() {
+        return None;
+    }
+    match connection.protocol_version()? {
+        ProtocolVersion::TLSv1_3 => exporter(connection)
+            .ok()
+            .map(|exporter| OwnedChannelBinding::TlsExporter(exporter.to_vec())),
+        _ => connection
+            .peer_certificates()?
+            .first()
+            .map(|certificate| OwnedChannelBinding::TlsServerEndPoint(certificate.to_vec())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::sync::Arc;
+    use core::convert::TryFrom;
+
+    use ::rustls::crypto::ring::default_provider;
+    use ::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+    use ::rustls::version::{TLS12, TLS13};
+    use ::rustls::{
+        ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+        SupportedProtocolVersion,
+    };
+
+    use super::{client_channel_binding, exporter};
+    use crate::channel_binding::{tls_server_end_point, OwnedChannelBinding};
+
+    /// A self-signed certificate for `localhost` with an ECDSA P-256 key, signed with SHA-384.
+    const CERTIFICATE: &[u8] = include_bytes!("../tests/data/localhost.der");
+    /// The PKCS #8 private key of [`CERTIFICATE`].
+    const KEY: &[u8] = include_bytes!("../tests/data/localhost.key.der");
+
+    /// Runs a TLS handshake of `version` in memory.
+    fn handshake(
+        version: &'static SupportedProtocolVersion,
+    ) -> (ClientConnection, ServerConnection) {
+        let provider = Arc::new(default_provider());
+        let certificate = CertificateDer::from(CERTIFICATE);
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.clone()).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let server_config = ServerConfig::builder_with_provider(provider)
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate], PrivateKeyDer::try_from(KEY).unwrap())
+            .unwrap();
+        let name = ServerName::try_from("localhost").unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), name).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        assert_eq!(client_channel_binding(&client), None);
+
+        let mut buffer = Vec::new();
+        while client.is_handshaking() || server.is_handshaking() {
+            buffer.clear();
+            client.write_tls(&mut buffer).unwrap();
+            server.read_tls(&mut &buffer[..]).unwrap();
+            server.process_new_packets().unwrap();
+            buffer.clear();
+            server.write_tls(&mut buffer).unwrap();
+            client.read_tls(&mut &buffer[..]).unwrap();
+            client.process_new_packets().unwrap();
+        }
+        (client, server)
+    }
+
+    #[test]
+    fn test_tls13() {
+        let (client, server) = handshake(&TLS13);
+        let expected = exporter(&server).unwrap();
+        assert_eq!(
+            client_channel_binding(&client),
+            Some(OwnedChannelBinding::TlsExporter(expected.to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_tls12() {
+        let (client, _) = handshake(&TLS12);
+        let binding = client_channel_binding(&client).unwrap();
+        assert_eq!(
+            binding,
+            OwnedChannelBinding::TlsServerEndPoint(CERTIFICATE.to_vec())
+        );
+        let data = binding.binding().data().unwrap();
+        assert_eq!(data.len(), 48);
+        assert_eq!(Some(data.into_owned()), tls_server_end_point(CERTIFICATE));
+    }
+}
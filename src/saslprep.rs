@@ -0,0 +1,215 @@
+//! The SASLprep profile of stringprep (RFC4013) for usernames and passwords.
+//!
+//! RFC5802 requires SASLprep for the usernames and the password of a handshake. [`saslprep`]
+//! maps non-ASCII spaces to the space character, removes the characters which are mapped to
+//! nothing, normalizes the text to Unicode Normalization Form KC and rejects prohibited
+//! characters and invalid bidirectional text:
+//!
+//! ```
+//! use scram::saslprep::{saslprep, Mode};
+//!
+//! assert_eq!(saslprep("I\u{AD}X", Mode::Strict).unwrap(), "IX");
+//! assert_eq!(saslprep("\u{2168}", Mode::Strict).unwrap(), "IX");
+//! assert!(saslprep("pass\u{7}word", Mode::Strict).is_err());
+//! ```
+//!
+//! The [`Mode`] decides about unassigned code points. RFC4013 prepares stored strings, like the
+//! passwords a server derives credentials from, in [`Mode::Strict`], which rejects them.
+//! Queries, like the credentials a client sends, are prepared in [`Mode::Lenient`], which keeps
+//! them. Some servers, PostgreSQL among them, use a password which can't be prepared unchanged;
+//! clients talking to them fall back to the password:
+//!
+//! ```
+//! use scram::saslprep::{saslprep, Mode};
+//!
+//! let password = "pass\u{7}word";
+//! let prepared = saslprep(password, Mode::Lenient).unwrap_or(password.into());
+//! ```
+//!
+//! With this module, the crate prepares credentials in [`Mode::Lenient`] by default and keeps text
+//! which can't be prepared unchanged, so its clients and servers agree on non-ASCII credentials: [`ScramClient::new`](crate::ScramClient::new) and the constructors of
+//! [`ClientFirstTemplate`](crate::client::ClientFirstTemplate) prepare the credentials a client
+//! sends, [`ScramServer`](crate::ScramServer) the usernames it receives, and
+//! [`derive_credentials`](crate::credentials::derive_credentials) and the `users` module the
+//! passwords and usernames they store.
+//! [`ClientFirstTemplate::try_new_saslprep`](crate::client::ClientFirstTemplate::try_new_saslprep)
+//! and [`ScramServer::with_saslprep`](crate::ScramServer::with_saslprep) reject text which can't
+//! be prepared instead. Unlike the `nfc` module, this module checks against the tables of Unicode
+//! 3.2, which RFC3454 is defined with.
+//!
+//! This module is available with the `saslprep` feature.
+use alloc::borrow::Cow;
+use alloc::string::String;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+use stringprep::tables;
+use unicode_normalization::UnicodeNormalization;
+
+/// Whether unassigned code points are accepted, see the [module documentation](self).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mode {
+    /// Rejects unassigned code points, for stored strings.
+    Strict,
+    /// Accepts unassigned code points, for queries.
+    Lenient,
+}
+
+/// The reasons text can't be prepared. They don't contain the offending character, which may be
+/// part of a password.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SaslprepError {
+    /// The text contains a character prohibited by SASLprep, for example a control character or
+    /// a private use code point.
+    Prohibited,
+    /// The text contains a right-to-left character and doesn't meet the requirements of RFC3454
+    /// section 6.
+    Bidirectional,
+    /// The text contains a code point which isn't assigned in Unicode 3.2, in [`Mode::Strict`].
+    Unassigned,
+}
+
+impl fmt::Display for SaslprepError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaslprepError::Prohibited => fmt.write_str("Prohibited character"),
+            SaslprepError::Bidirectional => fmt.write_str("Prohibited bidirectional text"),
+            SaslprepError::Unassigned => fmt.write_str("Unassigned code point"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for SaslprepError {}
+
+/// Prepares `text` with SASLprep in `mode`, borrowed if it's printable ASCII, which SASLprep
+/// leaves unchanged.
+///
+/// # Return value
+///
+/// Fails if `text` contains an unassigned code point in [`Mode::Strict`], or if the prepared text
+/// contains a prohibited character or invalid bidirectional text.
+pub fn saslprep(text: &str, mode: Mode) -> Result<Cow<'_, str>, SaslprepError> {
+    if text.bytes().all(|byte| (b' '..=b'~').contains(&byte)) {
+        return Ok(Cow::Borrowed(text));
+    }
+    // 2.5, unassigned code points, which RFC3454 section 7 checks in the input. Normalization
+    // with the tables of a later Unicode version could turn them into assigned ones.
+    if mode == Mode::Strict && text.chars().any(tables::unassigned_code_point) {
+        return Err(SaslprepError::Unassigned);
+    }
+    // 2.1, mapping
+    let prepared = text
+        .chars()
+        .filter(|&c| !tables::commonly_mapped_to_nothing(c))
+        .map(|c| {
+            if tables::non_ascii_space_character(c) {
+                ' '
+            } else {
+                c
+            }
+        })
+        // 2.2, normalization
+        .nfkc()
+        .collect::<String>();
+    // 2.3, prohibited output
+    if prepared.chars().any(is_prohibited) {
+        return Err(SaslprepError::Prohibited);
+    }
+    // 2.4, bidirectional characters
+    if !is_valid_bidirectional(&prepared) {
+        return Err(SaslprepError::Bidirectional);
+    }
+    Ok(Cow::Owned(prepared))
+}
+
+/// Prepares `text` in [`Mode::Lenient`], or returns it unchanged if it can't be prepared. This is
+/// what clients, servers and the credential derivation of this crate do by default.
+pub(crate) fn prepare_or_keep(text: &str) -> Cow<'_, str> {
+    saslprep(text, Mode::Lenient).unwrap_or(Cow::Borrowed(text))
+}
+
+/// Returns whether SASLprep prohibits `c`, see RFC4013 section 2.3.
+fn is_prohibited(c: char) -> bool {
+    tables::non_ascii_space_character(c)
+        || tables::ascii_control_character(c)
+        || tables::non_ascii_control_character(c)
+        || tables::private_use(c)
+        || tables::non_character_code_point(c)
+        || tables::surrogate_code(c)
+        || tables::inappropriate_for_plain_text(c)
+        || tables::inappropriate_for_canonical_representation(c)
+        || tables::change_display_properties_or_deprecated(c)
+        || tables::tagging_character(c)
+}
+
+/// Returns whether `text` meets the requirements of RFC3454 section 6: text with a right-to-left
+/// character contains no left-to-right character, and starts and ends with a right-to-left
+/// character.
+fn is_valid_bidirectional(text: &str) -> bool {
+    !text.chars().any(tables::bidi_r_or_al)
+        || !text.chars().any(tables::bidi_l)
+            && text.starts_with(tables::bidi_r_or_al)
+            && text.ends_with(tables::bidi_r_or_al)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::borrow::Cow;
+
+    use super::{saslprep, Mode, SaslprepError};
+
+    #[test]
+    fn test_saslprep() {
+        // The examples of RFC4013 section 3.
+        assert_eq!(saslprep("I\u{AD}X", Mode::Strict).unwrap(), "IX");
+        assert!(matches!(
+            saslprep("user", Mode::Strict),
+            Ok(Cow::Borrowed("user"))
+        ));
+        assert_eq!(saslprep("USER", Mode::Strict).unwrap(), "USER");
+        assert_eq!(saslprep("\u{AA}", Mode::Strict).unwrap(), "a");
+        assert_eq!(saslprep("\u{2168}", Mode::Strict).unwrap(), "IX");
+        assert_eq!(
+            saslprep("\u{7}", Mode::Strict),
+            Err(SaslprepError::Prohibited)
+        );
+        assert_eq!(
+            saslprep("\u{627}1", Mode::Strict),
+            Err(SaslprepError::Bidirectional)
+        );
+
+        assert_eq!(saslprep("a\u{A0}b", Mode::Strict).unwrap(), "a b");
+        assert_eq!(saslprep("Cafe\u{301}", Mode::Strict).unwrap(), "Caf\u{e9}");
+        assert_eq!(
+            saslprep("\u{627}1\u{628}", Mode::Strict).unwrap(),
+            "\u{627}1\u{628}"
+        );
+        assert_eq!(
+            saslprep("\u{E000}", Mode::Lenient),
+            Err(SaslprepError::Prohibited)
+        );
+    }
+
+    #[test]
+    fn test_unassigned() {
+        assert_eq!(
+            saslprep("a\u{378}", Mode::Strict),
+            Err(SaslprepError::Unassigned)
+        );
+        assert_eq!(saslprep("a\u{378}", Mode::Lenient).unwrap(), "a\u{378}");
+        // Assigned after Unicode 3.2, with a compatibility decomposition into assigned characters.
+        assert_eq!(
+            saslprep("\u{1F100}", Mode::Strict),
+            Err(SaslprepError::Unassigned)
+        );
+        assert_eq!(saslprep("\u{1F100}", Mode::Lenient).unwrap(), "0.");
+        assert_eq!(
+            saslprep("\u{7}\u{378}", Mode::Strict),
+            Err(SaslprepError::Unassigned)
+        );
+    }
+}
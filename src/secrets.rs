@@ -0,0 +1,309 @@
+//! Storage of the long-lived secrets of a server.
+//!
+//! A server keeps secrets which aren't credentials of a user, like the key deriving the mock
+//! credentials of unknown users. They shouldn't be compiled into the server or kept in its
+//! configuration. A [`SecretBackend`] fetches them by name from where the deployment keeps them,
+//! and [`ServerSecrets::load`] fetches all of them when the server starts:
+//!
+//! ``` rust,no_run
+//! use scram::secrets::{EnvBackend, ServerSecrets};
+//!
+//! // Reads `MYAPP_SCRAM_ENUMERATION_KEY`.
+//! let secrets = ServerSecrets::load(&EnvBackend::new("MYAPP_")).unwrap();
+//! ```
+//!
+//! [`EnvBackend`] reads base64 encoded secrets from environment variables and [`FileBackend`]
+//! reads them from files, for example mounted by the container orchestrator. Both require the
+//! `std` feature. Backends with heavy dependencies live in their own crates, like the HashiCorp
+//! Vault backend in `scram-vault`.
+#[cfg(feature = "std")]
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "std")]
+use std::{env, fs, io};
+
+#[cfg(feature = "std")]
+use base64::engine::general_purpose::STANDARD;
+#[cfg(feature = "std")]
+use base64::Engine;
+
+use crate::mlock::LockedBytes;
+use crate::utils::zeroize;
+#[cfg(feature = "std")]
+use crate::utils::zeroize_string;
+
+/// The name of the key deriving the mock credentials of unknown users.
+pub const ENUMERATION_KEY: &str = "scram-enumeration-key";
+
+/// The minimum length of the secrets of a [`ServerSecrets`] in bytes.
+pub const MIN_SECRET_LEN: usize = 32;
+
+/// A secret which is overwritten with zeros when it's dropped. With the `mlock` feature it's
+/// locked into memory, so it isn't written to the swap file.
+#[derive(Clone, PartialEq)]
+pub struct Secret(LockedBytes);
+
+impl Secret {
+    /// Moves `bytes` into a secret and overwrites the vector with zeros.
+    pub fn new(mut bytes: Vec<u8>) -> Self {
+        let secret = Secret(LockedBytes::new(&bytes));
+        zeroize(&mut bytes);
+        secret
+    }
+
+    /// Returns the bytes of the secret.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str("Secret(..)")
+    }
+}
+
+/// A store of named secrets.
+pub trait SecretBackend {
+    /// The error of a failed lookup.
+    type Error: fmt::Debug + fmt::Display;
+
+    /// Fetches the secret `name`. Returns `Ok(None)` if the backend doesn't hold it.
+    fn fetch(&self, name: &str) -> Result<Option<Secret>, Self::Error>;
+}
+
+impl<B: SecretBackend + ?Sized> SecretBackend for &B {
+    type Error = B::Error;
+
+    fn fetch(&self, name: &str) -> Result<Option<Secret>, Self::Error> {
+        (**self).fetch(name)
+    }
+}
+
+/// The error of [`ServerSecrets::load`].
+#[derive(Debug)]
+pub enum SecretError<E> {
+    /// The backend failed to fetch a secret.
+    Backend(E),
+    /// The named secret is shorter than [`MIN_SECRET_LEN`].
+    TooShort(&'static str),
+}
+
+impl<E: fmt::Display> fmt::Display for SecretError<E> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SecretError::Backend(ref err) => write!(fmt, "Secret backend error: {}", err),
+            SecretError::TooShort(name) => write!(
+                fmt,
+                "The secret {} is shorter than {} bytes",
+                name, MIN_SECRET_LEN
+            ),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: fmt::Debug + fmt::Display> error::Error for SecretError<E> {}
+
+/// The secrets of a server. Every secret is optional, since a server only needs the secrets of the
+/// features it uses.
+#[derive(Debug, Default)]
+pub struct ServerSecrets {
+    enumeration_key: Option<Secret>,
+}
+
+impl ServerSecrets {
+    /// Fetches the secret named [`ENUMERATION_KEY`] from `backend`. Fails with
+    /// `SecretError::TooShort` if it's shorter than [`MIN_SECRET_LEN`].
+    pub fn load<B: SecretBackend + ?Sized>(backend: &B) -> Result<Self, SecretError<B::Error>> {
+        let fetch = |name| match backend.fetch(name).map_err(SecretError::Backend)? {
+            Some(secret) if secret.expose().len() < MIN_SECRET_LEN => {
+                Err(SecretError::TooShort(name))
+            }
+            secret => Ok(secret),
+        };
+        Ok(ServerSecrets {
+            enumeration_key: fetch(ENUMERATION_KEY)?,
+        })
+    }
+
+    /// Returns the key deriving the mock credentials of unknown users, see
+    /// [`ScramServer::with_mock_credentials`](crate::ScramServer::with_mock_credentials).
+    pub fn enumeration_key(&self) -> Option<&[u8]> {
+        self.enumeration_key.as_ref().map(Secret::expose)
+    }
+}
+
+/// Reads base64 encoded secrets from environment variables.
+///
+/// The variable of a secret is its name in upper case with `-` replaced by `_`, after a prefix.
+/// With the prefix `MYAPP_` the secret [`ENUMERATION_KEY`] is read from
+/// `MYAPP_SCRAM_ENUMERATION_KEY`.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct EnvBackend {
+    prefix: String,
+}
+
+#[cfg(feature = "std")]
+impl EnvBackend {
+    /// Creates a backend reading the variables starting with `prefix`.
+    pub fn new<S: Into<String>>(prefix: S) -> Self {
+        EnvBackend {
+            prefix: prefix.into(),
+        }
+    }
+
+    /// Returns the name of the variable holding the secret `name`.
+    pub fn variable(&self, name: &str) -> String {
+        let mut variable = self.prefix.clone();
+        variable.extend(name.chars().map(|c| match c {
+            '-' => '_',
+            c => c.to_ascii_uppercase(),
+        }));
+        variable
+    }
+}
+
+#[cfg(feature = "std")]
+impl SecretBackend for EnvBackend {
+    type Error = io::Error;
+
+    /// Fails with `InvalidData` if the variable isn't valid base64.
+    fn fetch(&self, name: &str) -> Result<Option<Secret>, io::Error> {
+        let variable = self.variable(name);
+        let mut value = match env::var_os(&variable) {
+            Some(value) => value.into_string().map_err(|_| invalid_base64(&variable))?,
+            None => return Ok(None),
+        };
+        let decoded = STANDARD.decode(value.trim());
+        zeroize_string(&mut value);
+        decoded
+            .map(|bytes| Some(Secret::new(bytes)))
+            .map_err(|_| invalid_base64(&variable))
+    }
+}
+
+#[cfg(feature = "std")]
+fn invalid_base64(variable: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} isn't valid base64", variable),
+    )
+}
+
+/// Reads secrets from the files of a directory, for example the secrets mounted by Kubernetes or
+/// systemd credentials.
+///
+/// The file of a secret has its name and holds its raw bytes.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug)]
+pub struct FileBackend {
+    directory: PathBuf,
+}
+
+#[cfg(feature = "std")]
+impl FileBackend {
+    /// Creates a backend reading the files in `directory`.
+    pub fn new<P: AsRef<Path>>(directory: P) -> Self {
+        FileBackend {
+            directory: directory.as_ref().to_path_buf(),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl SecretBackend for FileBackend {
+    type Error = io::Error;
+
+    /// Fails with `InvalidInput` if `name` isn't a plain file name.
+    fn fetch(&self, name: &str) -> Result<Option<Secret>, io::Error> {
+        if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{:?} isn't a file name", name),
+            ));
+        }
+        match fs::read(self.directory.join(name)) {
+            Ok(bytes) => Ok(Some(Secret::new(bytes))),
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::convert::Infallible;
+    use std::{env, fs, io, process};
+
+    use super::{
+        EnvBackend, FileBackend, Secret, SecretBackend, SecretError, ServerSecrets, ENUMERATION_KEY,
+    };
+
+    impl SecretBackend for BTreeMap<&str, Vec<u8>> {
+        type Error = Infallible;
+
+        fn fetch(&self, name: &str) -> Result<Option<Secret>, Infallible> {
+            Ok(self.get(name).cloned().map(Secret::new))
+        }
+    }
+
+    #[test]
+    fn test_server_secrets() {
+        let mut backend = BTreeMap::new();
+        let secrets = ServerSecrets::load(&backend).unwrap();
+        assert_eq!(secrets.enumeration_key(), None);
+        backend.insert(ENUMERATION_KEY, vec![7; 32]);
+        let secrets = ServerSecrets::load(&backend).unwrap();
+        assert_eq!(secrets.enumeration_key(), Some(&[7; 32][..]));
+        assert_eq!(format!("{:?}", secrets.enumeration_key), "Some(Secret(..))");
+
+        backend.insert(ENUMERATION_KEY, vec![7; 31]);
+        assert!(matches!(
+            ServerSecrets::load(&backend),
+            Err(SecretError::TooShort(ENUMERATION_KEY))
+        ));
+    }
+
+    #[test]
+    fn test_env_backend() {
+        let backend = EnvBackend::new(format!("SCRAM_TEST_{}_", process::id()));
+        let variable = backend.variable(ENUMERATION_KEY);
+        assert!(variable.ends_with("_SCRAM_ENUMERATION_KEY"));
+        assert_eq!(backend.fetch(ENUMERATION_KEY).unwrap(), None);
+        env::set_var(&variable, "cGVwcGVy\n");
+        assert_eq!(
+            backend.fetch(ENUMERATION_KEY).unwrap(),
+            Some(Secret::new(b"pepper".to_vec()))
+        );
+        env::set_var(&variable, "pepper!");
+        let err = backend.fetch(ENUMERATION_KEY).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        env::remove_var(&variable);
+    }
+
+    #[test]
+    fn test_file_backend() {
+        let directory = env::temp_dir().join(format!("scram-secrets-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let backend = FileBackend::new(&directory);
+        assert_eq!(backend.fetch(ENUMERATION_KEY).unwrap(), None);
+        fs::write(directory.join(ENUMERATION_KEY), b"pepper\n").unwrap();
+        assert_eq!(
+            backend.fetch(ENUMERATION_KEY).unwrap(),
+            Some(Secret::new(b"pepper\n".to_vec()))
+        );
+        for name in ["", "..", "../pepper", "a\\b"] {
+            let err = backend.fetch(name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,33 +1,117 @@
-use std::borrow::Cow;
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::any::Any;
+use core::fmt;
+use core::ops::RangeInclusive;
+#[cfg(feature = "std")]
+use std::time::Instant;
 
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
-use rand::distributions::{Distribution, Uniform};
-use rand::{rngs::OsRng, Rng};
-use ring::digest::SHA256_OUTPUT_LEN;
+#[cfg(feature = "std")]
+use rand::rngs::OsRng;
 use ring::hmac;
 
-use error::{Error, Field, Kind};
-use utils::find_proofs;
-use NONCE_LENGTH;
+use crate::confidentiality::Confidentiality;
+use crate::config::ScramConfig;
+use crate::credentials::SALT_LENGTH;
+use crate::deadline::Deadline;
+use crate::entropy::EntropySource;
+use crate::error::{ChannelBindingError, ClientNonceError, Error, Excerpt, Field, Kind};
+use crate::grammar::{self, ClientFinalMessage, ClientFirstMessage};
+use crate::gs2::ChannelBindingFlag;
+use crate::mechanism::Mechanism;
+use crate::proof;
+#[cfg(any(test, feature = "saslprep"))]
+use crate::saslprep::{self, saslprep};
+use crate::secrets::{Secret, MIN_SECRET_LEN};
+use crate::summary::Summary;
+use crate::telemetry::Counters;
+#[cfg(any(test, feature = "telemetry"))]
+pub use crate::telemetry::TelemetrySnapshot;
+use crate::trace;
+use crate::utils::{
+    escape_username, find_proofs, generate_nonce_of, unescape_username, zeroize, zeroize_string,
+};
+use crate::NONCE_LENGTH;
 
 /// Responds to client authentication challenges. It's the entrypoint for the SCRAM server side
 /// implementation.
 pub struct ScramServer<P: AuthenticationProvider> {
     /// The ['AuthenticationProvider'] that will find passwords and check authorization.
     provider: P,
+    counters: Counters,
+    empty_authcid: bool,
+    require_confidentiality: bool,
+    mechanisms: Vec<Mechanism>,
+    nonce_length: usize,
+    nonce_policy: Option<ClientNoncePolicy>,
+    mock_credentials: Option<MockCredentials>,
+    #[cfg(any(test, feature = "saslprep"))]
+    saslprep: Preparation,
+}
+
+/// How the server prepares the usernames it receives with SASLprep.
+#[cfg(any(test, feature = "saslprep"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Preparation {
+    /// Usernames are prepared in `Mode::Lenient` and used unchanged if they can't be prepared,
+    /// like the clients of this crate send them.
+    Lenient,
+    /// Usernames which can't be prepared in the mode are rejected.
+    Required(saslprep::Mode),
+}
+
+/// The key and the iteration count of the mock credentials of unknown users.
+struct MockCredentials {
+    key: Secret,
+    iterations: u16,
+}
+
+impl MockCredentials {
+    /// Derives the mock credentials of `username` for `mechanism`: a salt and a salted password
+    /// which are the same in every handshake, but can't be told apart from those of a user
+    /// without the key.
+    fn password_info(&self, mechanism: Mechanism, username: &str) -> PasswordInfo {
+        let salt = self.derive(hmac::HMAC_SHA256, b"salt", mechanism, username);
+        let salt = salt.as_ref()[..SALT_LENGTH].to_vec();
+        let salted_password = self.derive(mechanism.hmac(), b"password", mechanism, username);
+        let salted_password = salted_password.as_ref().to_vec();
+        PasswordInfo::new(salted_password, self.iterations, salt)
+    }
+
+    /// Returns `HMAC(key, label "\0" mechanism "\0" username)`.
+    fn derive(
+        &self,
+        algorithm: hmac::Algorithm,
+        label: &[u8],
+        mechanism: Mechanism,
+        username: &str,
+    ) -> hmac::Tag {
+        let mut context = hmac::Context::with_key(&hmac::Key::new(algorithm, self.key.expose()));
+        context.update(label);
+        context.update(b"\0");
+        context.update(mechanism.name().as_bytes());
+        context.update(b"\0");
+        context.update(username.as_bytes());
+        context.sign()
+    }
 }
 
 /// Contains information about stored passwords. In particular, it stores the password that has been
 /// salted and hashed, the salt that was used, and the number of iterations of the hashing algorithm
 pub struct PasswordInfo {
-    hashed_password: Vec<u8>,
+    secret: StoredSecret,
     salt: Vec<u8>,
     iterations: u16,
+    identity: Option<String>,
 }
 
 /// The status of authentication after the final client message has been received by the server.
-#[derive(Clone, Copy, PartialEq, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AuthenticationStatus {
     /// The client has correctly authenticated, and has been authorized.
     Authenticated,
@@ -38,16 +122,277 @@ pub enum AuthenticationStatus {
     NotAuthorized,
 }
 
+/// The secret the proof of a client is verified with.
+enum StoredSecret {
+    /// The salted password, from which the keys are derived in every handshake.
+    SaltedPassword(Vec<u8>),
+    /// The `StoredKey` and the `ServerKey`.
+    Keys {
+        stored_key: Vec<u8>,
+        server_key: Vec<u8>,
+    },
+}
+
+impl StoredSecret {
+    fn zeroize(&mut self) {
+        match *self {
+            StoredSecret::SaltedPassword(ref mut salted_password) => zeroize(salted_password),
+            StoredSecret::Keys {
+                ref mut stored_key,
+                ref mut server_key,
+            } => {
+                zeroize(stored_key);
+                zeroize(server_key);
+            }
+        }
+    }
+}
+
 impl PasswordInfo {
     /// Create a new `PasswordInfo` from the given information. The password is assumed to have
     /// already been hashed using the given salt and iterations.
     pub fn new(hashed_password: Vec<u8>, iterations: u16, salt: Vec<u8>) -> Self {
         PasswordInfo {
-            hashed_password,
+            secret: StoredSecret::SaltedPassword(hashed_password),
+            iterations,
+            salt,
+            identity: None,
+        }
+    }
+
+    /// Creates a `PasswordInfo` from the `StoredKey` and the `ServerKey` derived with the given
+    /// salt and iterations, for stores which don't keep the salted password. Unlike the salted
+    /// password, the keys don't allow authenticating as the user to other servers.
+    pub fn from_keys(
+        stored_key: Vec<u8>,
+        server_key: Vec<u8>,
+        iterations: u16,
+        salt: Vec<u8>,
+    ) -> Self {
+        PasswordInfo {
+            secret: StoredSecret::Keys {
+                stored_key,
+                server_key,
+            },
             iterations,
             salt,
+            identity: None,
+        }
+    }
+
+    /// Sets the canonical identity of the user, for example a case-folded or domain-qualified form
+    /// of the username sent by the client, or the account an alias refers to. It's passed to
+    /// [`AuthenticationProvider::authorize`] and returned by [`ServerFinal::identity`] instead of
+    /// the username sent by the client.
+    pub fn with_identity<S: Into<String>>(mut self, identity: S) -> Self {
+        self.identity = Some(identity.into());
+        self
+    }
+
+    /// Returns the salted password, or `None` if the `PasswordInfo` was created from the keys.
+    pub(crate) fn hashed_password(&self) -> Option<&[u8]> {
+        match self.secret {
+            StoredSecret::SaltedPassword(ref salted_password) => Some(salted_password),
+            StoredSecret::Keys { .. } => None,
+        }
+    }
+
+    /// Returns the `StoredKey` and the `ServerKey`, or `None` if the `PasswordInfo` was created
+    /// from the salted password.
+    pub(crate) fn keys(&self) -> Option<(&[u8], &[u8])> {
+        match self.secret {
+            StoredSecret::SaltedPassword(_) => None,
+            StoredSecret::Keys {
+                ref stored_key,
+                ref server_key,
+            } => Some((stored_key, server_key)),
         }
     }
+
+    pub(crate) fn iterations(&self) -> u16 {
+        self.iterations
+    }
+
+    pub(crate) fn salt(&self) -> &[u8] {
+        &self.salt
+    }
+
+    pub(crate) fn identity(&self) -> Option<&str> {
+        self.identity.as_deref()
+    }
+}
+
+/// The requirements of a server for the nonces of clients, see
+/// [`ScramServer::with_client_nonce_policy`].
+///
+/// RFC5802 only requires the nonce to be printable. A short nonce, or one from a narrow alphabet,
+/// hints at a broken or malicious client which reuses nonces or generates them from a weak source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientNoncePolicy {
+    /// The minimum length of the nonce in characters. The default is 16.
+    pub min_length: usize,
+    /// The characters the nonce may consist of. The default is all printable characters, `!`
+    /// to `~`; the comma is never allowed.
+    pub characters: RangeInclusive<char>,
+}
+
+impl Default for ClientNoncePolicy {
+    fn default() -> Self {
+        ClientNoncePolicy {
+            min_length: 16,
+            characters: '!'..='~',
+        }
+    }
+}
+
+impl ClientNoncePolicy {
+    /// Checks `nonce`, which the grammar already checked to be printable.
+    fn check(&self, nonce: &str) -> Result<(), ClientNonceError> {
+        if let Some(c) = nonce.chars().find(|c| !self.characters.contains(c)) {
+            return Err(ClientNonceError::InvalidCharacter(c));
+        }
+        let length = nonce.chars().count();
+        if length < self.min_length {
+            return Err(ClientNonceError::TooShort(length));
+        }
+        Ok(())
+    }
+}
+
+/// Information about the connection a handshake runs on, passed to
+/// [`AuthenticationProvider::get_password_in_context`].
+///
+/// The application supplies the peer address, the tenant, the mechanism the client chose and
+/// arbitrary other data like TLS session information. The server adds the mechanism in use if the
+/// application didn't.
+#[derive(Clone, Copy, Default)]
+pub struct ConnectionContext<'c> {
+    mechanism: Option<Mechanism>,
+    peer_address: Option<&'c str>,
+    tenant: Option<&'c str>,
+    channel_binding: bool,
+    channel_binding_type: Option<&'c str>,
+    channel_binding_data: &'c [&'c [u8]],
+    confidentiality: Confidentiality,
+    data: Option<&'c dyn Any>,
+}
+
+impl<'c> ConnectionContext<'c> {
+    /// Creates a context without any connection metadata.
+    pub fn new() -> Self {
+        ConnectionContext::default()
+    }
+
+    /// Sets the mechanism the client chose, for servers which advertise other mechanisms than
+    /// SCRAM-SHA-256. The handshake uses SCRAM-SHA-256 if it isn't set.
+    pub fn with_mechanism(mut self, mechanism: Mechanism) -> Self {
+        self.mechanism = Some(mechanism);
+        self
+    }
+
+    /// Sets the address of the peer, for example `"192.0.2.1:5432"`.
+    pub fn with_peer_address(mut self, peer_address: &'c str) -> Self {
+        self.peer_address = Some(peer_address);
+        self
+    }
+
+    /// Sets the tenant the connection belongs to.
+    pub fn with_tenant(mut self, tenant: &'c str) -> Self {
+        self.tenant = Some(tenant);
+        self
+    }
+
+    /// Declares whether the server supports channel binding on this connection, which is the case
+    /// if it advertised the `-PLUS` mechanisms. The channel-binding flag of the client is checked
+    /// against it. Clients which bind are rejected with `ChannelBindingError::MissingData` until
+    /// the data is set with [`with_channel_binding_data`](Self::with_channel_binding_data).
+    pub fn with_channel_binding(mut self, channel_binding: bool) -> Self {
+        self.channel_binding = channel_binding;
+        self
+    }
+
+    /// Enables channel binding of type `cb_type`, for example `"tls-server-end-point"`, and sets
+    /// the channel-binding data the client may send. A client binding to any of `values` is
+    /// accepted. During a certificate rollover pass the hashes of both the old and the new
+    /// certificate, so clients which still see the old one can log in.
+    pub fn with_channel_binding_data(mut self, cb_type: &'c str, values: &'c [&'c [u8]]) -> Self {
+        self.channel_binding = true;
+        self.channel_binding_type = Some(cb_type);
+        self.channel_binding_data = values;
+        self
+    }
+
+    /// Asserts whether the transport of the connection is encrypted, which servers with
+    /// [`ScramServer::require_confidentiality`] require before a handshake.
+    pub fn with_confidentiality(mut self, confidentiality: Confidentiality) -> Self {
+        self.confidentiality = confidentiality;
+        self
+    }
+
+    /// Attaches arbitrary data, like TLS session information, which the provider can retrieve with
+    /// [`data`](Self::data).
+    pub fn with_data(mut self, data: &'c dyn Any) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    /// Returns the mechanism of the handshake. It's set by the server and `None` only in contexts
+    /// which weren't passed to a provider yet and weren't given a mechanism with
+    /// [`with_mechanism`](Self::with_mechanism).
+    pub fn mechanism(&self) -> Option<Mechanism> {
+        self.mechanism
+    }
+
+    /// Returns the address of the peer.
+    pub fn peer_address(&self) -> Option<&'c str> {
+        self.peer_address
+    }
+
+    /// Returns the tenant the connection belongs to.
+    pub fn tenant(&self) -> Option<&'c str> {
+        self.tenant
+    }
+
+    /// Returns whether the server supports channel binding on this connection.
+    pub fn channel_binding(&self) -> bool {
+        self.channel_binding
+    }
+
+    /// Returns the channel-binding type set with
+    /// [`with_channel_binding_data`](Self::with_channel_binding_data).
+    pub fn channel_binding_type(&self) -> Option<&'c str> {
+        self.channel_binding_type
+    }
+
+    /// Returns the acceptable channel-binding data set with
+    /// [`with_channel_binding_data`](Self::with_channel_binding_data).
+    pub fn channel_binding_data(&self) -> &'c [&'c [u8]] {
+        self.channel_binding_data
+    }
+
+    /// Returns the asserted confidentiality of the transport.
+    pub fn confidentiality(&self) -> Confidentiality {
+        self.confidentiality
+    }
+
+    /// Returns the attached data if it is of type `T`.
+    pub fn data<T: Any>(&self) -> Option<&'c T> {
+        self.data.and_then(|data| data.downcast_ref())
+    }
+}
+
+impl<'c> fmt::Debug for ConnectionContext<'c> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ConnectionContext")
+            .field("mechanism", &self.mechanism)
+            .field("peer_address", &self.peer_address)
+            .field("tenant", &self.tenant)
+            .field("channel_binding", &self.channel_binding)
+            .field("channel_binding_type", &self.channel_binding_type)
+            .field("confidentiality", &self.confidentiality)
+            .field("data", &self.data.map(|_| ".."))
+            .finish()
+    }
 }
 
 /// An `AuthenticationProvider` looks up password information for a given user, and also checks if a
@@ -60,75 +405,198 @@ pub trait AuthenticationProvider {
     /// Gets the [`PasswordInfo`] for the given user.
     fn get_password_for(&self, username: &str) -> Option<PasswordInfo>;
 
+    /// Gets the [`PasswordInfo`] for the given user on the connection described by `context`. This
+    /// allows resolving credentials per tenant or applying per-connection policy. The default
+    /// implementation ignores the context and calls
+    /// [`get_password_for`](Self::get_password_for).
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<PasswordInfo> {
+        let _ = context;
+        self.get_password_for(username)
+    }
+
     /// Checks to see if the user given by `authcid` is authorized to act as the user given by
     /// `authzid.` Implementors do not need to implement this method. The default implementation
     /// just checks if the two are equal
+    ///
+    /// If [`PasswordInfo::with_identity`] was used, `authcid` is the canonical identity.
     fn authorize(&self, authcid: &str, authzid: &str) -> bool {
         authcid == authzid
     }
 }
 
-/// Parses a client's first message by splitting it on commas and analyzing each part. Gives an
-/// error if the data was malformed in any way
-fn parse_client_first(data: &str) -> Result<(&str, Option<&str>, &str), Error> {
-    let mut parts = data.split(',');
-
-    // Channel binding
-    if let Some(part) = parts.next() {
-        if let Some(cb) = part.chars().next() {
-            if cb == 'p' {
-                return Err(Error::UnsupportedExtension);
-            }
-            if cb != 'n' && cb != 'y' || part.len() > 1 {
-                return Err(Error::Protocol(Kind::InvalidField(Field::ChannelBinding)));
-            }
-        } else {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::ChannelBinding)));
+/// Checks the channel-binding flag of the gs2 header against the channel-binding capability of the
+/// server on this connection, as required by RFC5802 section 6. Returns the channel-binding data
+/// the client may send in its final message, which is empty if the client doesn't bind.
+///
+/// A server which supports channel binding without data to verify it against fails with
+/// `MissingData` instead of accepting any binding.
+fn check_channel_binding(
+    flag: ChannelBindingFlag,
+    context: &ConnectionContext,
+) -> Result<Vec<Vec<u8>>, Error> {
+    let error = match flag {
+        ChannelBindingFlag::Required(_) if !context.channel_binding() => {
+            ChannelBindingError::NotSupported
         }
-    } else {
-        return Err(Error::Protocol(Kind::ExpectedField(Field::ChannelBinding)));
-    }
-
-    // Authzid
-    let authzid = if let Some(part) = parts.next() {
-        if part.is_empty() {
-            None
-        } else if part.len() < 2 || &part.as_bytes()[..2] != b"a=" {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Authzid)));
-        } else {
-            Some(&part[2..])
+        ChannelBindingFlag::Required(_) if context.channel_binding_data().is_empty() => {
+            ChannelBindingError::MissingData
         }
-    } else {
-        return Err(Error::Protocol(Kind::ExpectedField(Field::Authzid)));
-    };
-
-    // Authcid
-    let authcid = parse_part!(parts, Authcid, b"n=");
-
-    // Nonce
-    let nonce = match parts.next() {
-        Some(part) if &part.as_bytes()[..2] == b"r=" => &part[2..],
-        _ => {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::Nonce)));
+        ChannelBindingFlag::Required(name) if context.channel_binding_type() == Some(name) => {
+            return Ok(context
+                .channel_binding_data()
+                .iter()
+                .map(|value| value.to_vec())
+                .collect());
         }
+        ChannelBindingFlag::Required(name) => {
+            ChannelBindingError::UnsupportedType(name.to_string())
+        }
+        ChannelBindingFlag::NotUsed if context.channel_binding() => ChannelBindingError::Downgrade,
+        ChannelBindingFlag::NotUsed | ChannelBindingFlag::NotSupported => return Ok(Vec::new()),
     };
-    Ok((authcid, authzid, nonce))
-}
-
-/// Parses the client's final message. Gives an error if the data was malformed.
-fn parse_client_final(data: &str) -> Result<(&str, &str, &str), Error> {
-    // 6 is the length of the required parts of the message
-    let mut parts = data.split(',');
-    let gs2header = parse_part!(parts, GS2Header, b"c=");
-    let nonce = parse_part!(parts, Nonce, b"r=");
-    let proof = parse_part!(parts, Proof, b"p=");
-    Ok((gs2header, nonce, proof))
+    Err(Error::ChannelBinding(error))
 }
 
 impl<P: AuthenticationProvider> ScramServer<P> {
     /// Creates a new `ScramServer` using the given authentication provider.
     pub fn new(provider: P) -> Self {
-        ScramServer { provider }
+        ScramServer {
+            provider,
+            counters: Counters::default(),
+            empty_authcid: false,
+            require_confidentiality: false,
+            mechanisms: Mechanism::DEFAULT.to_vec(),
+            nonce_length: NONCE_LENGTH,
+            nonce_policy: None,
+            mock_credentials: None,
+            #[cfg(any(test, feature = "saslprep"))]
+            saslprep: Preparation::Lenient,
+        }
+    }
+
+    /// Applies the server settings of `config`: the advertised mechanisms and the length of the
+    /// server's part of the nonce.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` isn't valid, see [`ScramConfig::validate`].
+    pub fn with_config(mut self, config: &ScramConfig) -> Self {
+        config.validate().expect("invalid configuration");
+        self.mechanisms = config.mechanisms.clone();
+        self.nonce_length = config.nonce_length;
+        self
+    }
+
+    /// Accepts an empty username in the first client message, for deployments which establish
+    /// the identity out of band, for example with a TLS client certificate. The provider is asked
+    /// for the credentials of the empty username and must determine the identity from the
+    /// [`ConnectionContext`] passed to
+    /// [`get_password_in_context`](AuthenticationProvider::get_password_in_context), ideally
+    /// returning it with [`PasswordInfo::with_identity`].
+    ///
+    /// Without this option an empty username is rejected as
+    /// `Error::Protocol(Kind::InvalidField(Field::Authcid, _))`, as RFC5802 requires at least one
+    /// character.
+    pub fn allow_empty_authcid(mut self) -> Self {
+        self.empty_authcid = true;
+        self
+    }
+
+    /// Rejects handshakes with `Error::ConfidentialityNotAsserted` unless the encryption of the
+    /// transport was asserted with [`ConnectionContext::with_confidentiality`]. See
+    /// [`Confidentiality`].
+    pub fn require_confidentiality(mut self) -> Self {
+        self.require_confidentiality = true;
+        self
+    }
+
+    /// Rejects client nonces which don't meet `policy` with `Error::ClientNonce`, before the
+    /// provider is asked for the credentials. Without a policy every nonce the grammar allows is
+    /// accepted.
+    pub fn with_client_nonce_policy(mut self, policy: ClientNoncePolicy) -> Self {
+        self.nonce_policy = Some(policy);
+        self
+    }
+
+    /// Answers the first message of a client with an unknown username with mock credentials
+    /// instead of failing with `Error::InvalidUser`, so that an attacker can't enumerate the
+    /// accounts of the server. The salt and the salted password are derived from `key` and the
+    /// username, so they are the same in every handshake of the username. The handshake then
+    /// fails like one with a wrong password, with [`AuthenticationStatus::NotAuthenticated`].
+    ///
+    /// `iterations` should be the iteration count of the stored credentials, so that the mock
+    /// credentials have the same. The key is a secret of the server, for example
+    /// [`ServerSecrets::enumeration_key`](crate::secrets::ServerSecrets::enumeration_key), of
+    /// at least [`MIN_SECRET_LEN`] bytes. Changing it changes the salts of all unknown usernames.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is shorter than [`MIN_SECRET_LEN`].
+    pub fn with_mock_credentials(mut self, key: &[u8], iterations: u16) -> Self {
+        assert!(
+            key.len() >= MIN_SECRET_LEN,
+            "mock credential key shorter than MIN_SECRET_LEN"
+        );
+        self.mock_credentials = Some(MockCredentials {
+            key: Secret::new(key.to_vec()),
+            iterations,
+        });
+        self
+    }
+
+    /// Prepares the usernames of the first client message with SASLprep in `mode` before the
+    /// provider is asked for the credentials, as RFC5802 requires. Usernames which can't be
+    /// prepared are rejected as `Error::Protocol(Kind::InvalidField(_, _))` naming
+    /// `Field::Authcid` or `Field::Authzid`. See the [`saslprep` module](crate::saslprep).
+    ///
+    /// By default, the server prepares the usernames in
+    /// [`Mode::Lenient`](saslprep::Mode::Lenient) and uses those which can't be prepared
+    /// unchanged, like the clients of this crate send them. The server never sees the password,
+    /// so the stored credentials have to be derived from prepared passwords, as
+    /// [`derive_credentials`](crate::credentials::derive_credentials) does.
+    ///
+    /// This method is only available with the `saslprep` feature.
+    #[cfg(any(test, feature = "saslprep"))]
+    pub fn with_saslprep(mut self, mode: saslprep::Mode) -> Self {
+        self.saslprep = Preparation::Required(mode);
+        self
+    }
+
+    /// Returns the current values of the handshake counters of this server. This method is only
+    /// available with the `telemetry` feature.
+    #[cfg(any(test, feature = "telemetry"))]
+    pub fn telemetry(&self) -> TelemetrySnapshot {
+        self.counters.snapshot()
+    }
+
+    /// Returns the SASL mechanism names to advertise to clients, for example in an IMAP
+    /// `CAPABILITY` response or a PostgreSQL `AuthenticationSASL` message, strongest first. These
+    /// are the [`Mechanism::DEFAULT`] mechanisms, or those of the [`ScramConfig`] passed to
+    /// [`with_config`](Self::with_config). The mechanism a client chooses is passed to
+    /// [`handle_client_first_in_context`](Self::handle_client_first_in_context) with
+    /// [`ConnectionContext::with_mechanism`].
+    ///
+    /// `channel_binding` tells whether channel-binding data is available on the connection. If it
+    /// is, the `-PLUS` variant of each mechanism is advertised before it, and the connection
+    /// context of the handshake has to carry the data, see
+    /// [`ConnectionContext::with_channel_binding_data`]. Clients which chose a `-PLUS` variant
+    /// bind with `p=`, the others have to send `n`. The `y` flag claims that the server didn't
+    /// advertise channel binding, so a handshake with it fails with
+    /// `Error::ChannelBinding(ChannelBindingError::Downgrade)`. Without channel binding, clients
+    /// which support it send `y`.
+    pub fn advertised_mechanisms(&self, channel_binding: bool) -> Vec<&'static str> {
+        let mut names = Vec::with_capacity(2 * self.mechanisms.len());
+        for mechanism in self.mechanisms.iter() {
+            if channel_binding {
+                names.push(mechanism.plus_name());
+            }
+            names.push(mechanism.name());
+        }
+        names
     }
 
     /// Handle a challenge message sent by the client to the server. If the message is well formed,
@@ -138,17 +606,110 @@ impl<P: AuthenticationProvider> ScramServer<P> {
         &'a self,
         client_first: &'a str,
     ) -> Result<ServerFirst<'a, P>, Error> {
-        let (authcid, authzid, client_nonce) = parse_client_first(client_first)?;
-        let password_info = self
-            .provider
-            .get_password_for(authcid)
-            .ok_or_else(|| Error::InvalidUser(authcid.to_string()))?;
+        self.handle_client_first_in_context(client_first, &ConnectionContext::new())
+    }
+
+    /// Like [`handle_client_first`](Self::handle_client_first), but passes the connection metadata
+    /// in `context` to [`AuthenticationProvider::get_password_in_context`].
+    pub fn handle_client_first_in_context<'a>(
+        &'a self,
+        client_first: &'a str,
+        context: &ConnectionContext,
+    ) -> Result<ServerFirst<'a, P>, Error> {
+        let _phase = trace::phase(
+            context.mechanism.unwrap_or(Mechanism::ScramSha256).name(),
+            "handle_client_first",
+        );
+        trace::received(client_first.len());
+        self.counters.started();
+        let result = self.process_client_first(client_first, context);
+        trace::outcome(&result);
+        if let Err(ref err) = result {
+            self.counters.failed(err);
+        }
+        result
+    }
+
+    /// Prepares `username` with SASLprep, or returns `None` if it can't be prepared and the server
+    /// requires it.
+    #[cfg(any(test, feature = "saslprep"))]
+    fn prepare<'a>(&self, username: Cow<'a, str>) -> Option<Cow<'a, str>> {
+        let prepared = match self.saslprep {
+            Preparation::Lenient => saslprep::prepare_or_keep(&username),
+            Preparation::Required(mode) => saslprep(&username, mode).ok()?,
+        };
+        match prepared {
+            Cow::Borrowed(_) => Some(username),
+            Cow::Owned(prepared) => Some(Cow::Owned(prepared)),
+        }
+    }
+
+    /// Returns `username`, as SASLprep isn't available without the `saslprep` feature.
+    #[cfg(not(any(test, feature = "saslprep")))]
+    fn prepare<'a>(&self, username: Cow<'a, str>) -> Option<Cow<'a, str>> {
+        Some(username)
+    }
+
+    fn process_client_first<'a>(
+        &'a self,
+        client_first: &'a str,
+        context: &ConnectionContext,
+    ) -> Result<ServerFirst<'a, P>, Error> {
+        context
+            .confidentiality
+            .check(self.require_confidentiality)?;
+        let message = ClientFirstMessage::parse(client_first)?;
+        if let Some(ref policy) = self.nonce_policy {
+            policy.check(message.nonce).map_err(Error::ClientNonce)?;
+        }
+        let (gs2header, client_first_bare) = grammar::split_client_first(client_first);
+        let invalid_authcid = || {
+            let excerpt = Excerpt::new(Some('n'), message.authcid);
+            Error::Protocol(Kind::InvalidField(Field::Authcid, Some(excerpt)))
+        };
+        let authcid = unescape_username(message.authcid).ok_or_else(invalid_authcid)?;
+        let authcid = self.prepare(authcid).ok_or_else(invalid_authcid)?;
+        if authcid.is_empty() && !self.empty_authcid {
+            return Err(invalid_authcid());
+        }
+        let authzid = match message.authzid {
+            Some(authzid) => Some(
+                unescape_username(authzid)
+                    .and_then(|username| self.prepare(username))
+                    .ok_or_else(|| {
+                        let excerpt = Excerpt::new(Some('a'), authzid);
+                        Error::Protocol(Kind::InvalidField(Field::Authzid, Some(excerpt)))
+                    })?,
+            ),
+            None => None,
+        };
+        let channel_bindings = check_channel_binding(message.channel_binding, context)?;
+        let mechanism = context.mechanism.unwrap_or(Mechanism::ScramSha256);
+        let password_info = self.provider.get_password_in_context(
+            &authcid,
+            &ConnectionContext {
+                mechanism: Some(mechanism),
+                ..*context
+            },
+        );
+        let password_info = match (password_info, &self.mock_credentials) {
+            (Some(password_info), _) => password_info,
+            (None, Some(mock_credentials)) => mock_credentials.password_info(mechanism, &authcid),
+            (None, None) => return Err(Error::InvalidUser(authcid.to_string())),
+        };
         Ok(ServerFirst {
-            client_nonce,
+            mechanism,
+            client_nonce: message.nonce,
+            gs2header,
+            channel_bindings,
+            client_first_bare,
             authcid,
             authzid,
             provider: &self.provider,
+            counters: &self.counters,
             password_info,
+            nonce_length: self.nonce_length,
+            deadline: Deadline::default(),
         })
     }
 }
@@ -156,43 +717,74 @@ impl<P: AuthenticationProvider> ScramServer<P> {
 /// Represents the first stage in the authentication process, after the client has submitted their
 /// first message. This struct is responsible for responding to the message
 pub struct ServerFirst<'a, P: 'a + AuthenticationProvider> {
+    mechanism: Mechanism,
     client_nonce: &'a str,
-    authcid: &'a str,
-    authzid: Option<&'a str>,
+    gs2header: &'a str,
+    channel_bindings: Vec<Vec<u8>>,
+    client_first_bare: &'a str,
+    authcid: Cow<'a, str>,
+    authzid: Option<Cow<'a, str>>,
     provider: &'a P,
+    counters: &'a Counters,
     password_info: PasswordInfo,
+    nonce_length: usize,
+    deadline: Deadline,
 }
 
 impl<'a, P: AuthenticationProvider> ServerFirst<'a, P> {
+    /// Fails the handshake with `Error::Timeout` if the client's final message is handled after
+    /// `deadline`, for drivers which keep the states across slow network round-trips.
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
+    pub fn with_deadline(self, deadline: Instant) -> Self {
+        self.deadline(Deadline::at(deadline))
+    }
+
+    /// Sets the deadline of the handshake.
+    pub(crate) fn deadline(mut self, deadline: Deadline) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Aborts the handshake and zeroizes the hashed password. See [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        self.password_info.secret.zeroize();
+        Aborted(())
+    }
+
     /// Creates the server's first message in response to the client's first message. By default,
     /// this method uses [`OsRng`] as its source of randomness for the nonce. To specify the
     /// randomness source, use [`server_first_with_rng`](Self::server_first_with_rng). This method
     /// will return an error when it cannot initialize the OS's randomness source. See the
     /// documentation on `OsRng` for more information.
+    ///
+    /// This method is only available with the `std` feature.
+    #[cfg(feature = "std")]
     pub fn server_first(self) -> (ClientFinal<'a, P>, String) {
         self.server_first_with_rng(&mut OsRng)
     }
 
     /// Creates the server's first message in response to the client's first message, with the
-    /// given source of randomness used for the server's nonce. The randomness is assigned here
-    /// instead of universally in [`ScramServer`] for increased flexibility, and also to keep
-    /// `ScramServer` immutable.
-    pub fn server_first_with_rng<R: Rng>(self, rng: &mut R) -> (ClientFinal<'a, P>, String) {
-        let mut nonce = String::with_capacity(self.client_nonce.len() + NONCE_LENGTH);
+    /// given random number generator or [`EntropySource`] used for the server's nonce. The
+    /// randomness is assigned here instead of universally in [`ScramServer`] for increased
+    /// flexibility, and also to keep `ScramServer` immutable.
+    pub fn server_first_with_rng<R: EntropySource + ?Sized>(
+        self,
+        rng: &mut R,
+    ) -> (ClientFinal<'a, P>, String) {
+        let server_nonce = generate_nonce_of(rng, self.nonce_length);
+        self.server_first_with_nonce(&server_nonce)
+    }
+
+    /// Creates the server's first message using the given server part of the nonce.
+    pub(crate) fn server_first_with_nonce(self, server_nonce: &str) -> (ClientFinal<'a, P>, String) {
+        let _phase = trace::phase(self.mechanism.name(), "server_first");
+        trace::iterations(u32::from(self.password_info.iterations));
+        let mut nonce = String::with_capacity(self.client_nonce.len() + server_nonce.len());
         nonce.push_str(self.client_nonce);
-        nonce.extend(
-            Uniform::from(33..125)
-                .sample_iter(rng)
-                .map(|x: u8| if x > 43 { (x + 1) as char } else { x as char })
-                .take(NONCE_LENGTH),
-        );
+        nonce.push_str(server_nonce);
 
-        let gs2header: Cow<'static, str> = match self.authzid {
-            Some(authzid) => format!("n,a={},", authzid).into(),
-            None => "n,,".into(),
-        };
-        let client_first_bare: Cow<'static, str> =
-            format!("n={},r={}", self.authcid, self.client_nonce).into();
         let server_first: Cow<'static, str> = format!(
             "r={},s={},i={}",
             nonce,
@@ -200,16 +792,26 @@ impl<'a, P: AuthenticationProvider> ServerFirst<'a, P> {
             self.password_info.iterations
         )
         .into();
+        trace::sent(server_first.len());
+        let identity = match self.password_info.identity {
+            Some(identity) => identity,
+            None => self.authcid.to_string(),
+        };
         (
             ClientFinal {
-                hashed_password: self.password_info.hashed_password,
+                mechanism: self.mechanism,
+                secret: self.password_info.secret,
+                identity,
                 nonce,
-                gs2header,
-                client_first_bare,
+                gs2header: self.gs2header.into(),
+                channel_bindings: self.channel_bindings,
+                client_first_bare: self.client_first_bare.into(),
                 server_first: server_first.clone(),
                 authcid: self.authcid,
                 authzid: self.authzid,
                 provider: self.provider,
+                counters: self.counters,
+                deadline: self.deadline,
             },
             server_first.into_owned(),
         )
@@ -219,14 +821,19 @@ impl<'a, P: AuthenticationProvider> ServerFirst<'a, P> {
 /// Represents the stage after the server has generated its first response to the client. This
 /// struct is responsible for handling the client's final message.
 pub struct ClientFinal<'a, P: 'a + AuthenticationProvider> {
-    hashed_password: Vec<u8>,
+    mechanism: Mechanism,
+    secret: StoredSecret,
+    identity: String,
     nonce: String,
-    gs2header: Cow<'static, str>,
-    client_first_bare: Cow<'static, str>,
+    gs2header: Cow<'a, str>,
+    channel_bindings: Vec<Vec<u8>>,
+    client_first_bare: Cow<'a, str>,
     server_first: Cow<'static, str>,
-    authcid: &'a str,
-    authzid: Option<&'a str>,
+    authcid: Cow<'a, str>,
+    authzid: Option<Cow<'a, str>>,
     provider: &'a P,
+    counters: &'a Counters,
+    deadline: Deadline,
 }
 
 impl<'a, P: AuthenticationProvider> ClientFinal<'a, P> {
@@ -237,47 +844,111 @@ impl<'a, P: AuthenticationProvider> ClientFinal<'a, P> {
     /// contain error information for the client. To check if authentication and authorization have
     /// succeeded, use [`server_final`](ServerFinal::server_final) on the return value.
     pub fn handle_client_final(self, client_final: &str) -> Result<ServerFinal, Error> {
-        let (gs2header_enc, nonce, proof) = parse_client_final(client_final)?;
-        if !self.verify_header(gs2header_enc) {
-            return Err(Error::Protocol(Kind::InvalidField(Field::GS2Header)));
+        let _phase = trace::phase(self.mechanism.name(), "handle_client_final");
+        trace::received(client_final.len());
+        let counters = self.counters;
+        let result = self.process_client_final(client_final);
+        trace::outcome(&result);
+        match result {
+            Ok(ref server_final) => counters.finished(server_final.status),
+            Err(ref err) => counters.failed(err),
+        }
+        result
+    }
+
+    /// Aborts the handshake and zeroizes the hashed password. See [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        self.secret.zeroize();
+        Aborted(())
+    }
+
+    /// Copies the parts of the `client-first-message` this state borrows, so that it outlives the
+    /// message. The provider is borrowed from `server`, which must be the server that created the
+    /// state.
+    pub(crate) fn detach<'s>(self, server: &'s ScramServer<P>) -> ClientFinal<'s, P> {
+        ClientFinal {
+            mechanism: self.mechanism,
+            secret: self.secret,
+            identity: self.identity,
+            nonce: self.nonce,
+            gs2header: self.gs2header.into_owned().into(),
+            channel_bindings: self.channel_bindings,
+            client_first_bare: self.client_first_bare.into_owned().into(),
+            server_first: self.server_first,
+            authcid: self.authcid.into_owned().into(),
+            authzid: self.authzid.map(|authzid| authzid.into_owned().into()),
+            provider: &server.provider,
+            counters: &server.counters,
+            deadline: self.deadline,
         }
-        if !self.verify_nonce(nonce) {
-            return Err(Error::Protocol(Kind::InvalidField(Field::Nonce)));
+    }
+
+    fn process_client_final(self, client_final: &str) -> Result<ServerFinal, Error> {
+        self.deadline.check()?;
+        let message = ClientFinalMessage::parse(client_final)?;
+        self.verify_header(message.channel_binding)?;
+        if !self.verify_nonce(message.nonce) {
+            let excerpt = Excerpt::new(Some('r'), message.nonce);
+            return Err(Error::Protocol(Kind::InvalidField(
+                Field::Nonce,
+                Some(excerpt),
+            )));
         }
-        if let Some(signature) = self.verify_proof(proof)? {
-            if let Some(authzid) = self.authzid {
-                if self.provider.authorize(self.authcid, authzid) {
-                    Ok(ServerFinal {
-                        status: AuthenticationStatus::Authenticated,
-                        signature,
-                    })
+        let (status, signature) = if let Some(signature) = self.verify_proof(&message)? {
+            if let Some(ref authzid) = self.authzid {
+                if self.provider.authorize(&self.identity, authzid) {
+                    (AuthenticationStatus::Authenticated, signature)
                 } else {
-                    Ok(ServerFinal {
-                        status: AuthenticationStatus::NotAuthorized,
-                        signature: format!(
+                    (
+                        AuthenticationStatus::NotAuthorized,
+                        // The usernames are escaped, a `,` would end the error value.
+                        format!(
                             "e=User '{}' not authorized to act as '{}'",
-                            self.authcid, authzid
+                            escape_username(&self.authcid),
+                            escape_username(authzid)
                         ),
-                    })
+                    )
                 }
             } else {
-                Ok(ServerFinal {
-                    status: AuthenticationStatus::Authenticated,
-                    signature,
-                })
+                (AuthenticationStatus::Authenticated, signature)
             }
         } else {
-            Ok(ServerFinal {
-                status: AuthenticationStatus::NotAuthenticated,
-                signature: "e=Invalid Password".to_string(),
-            })
-        }
+            (
+                AuthenticationStatus::NotAuthenticated,
+                "e=Invalid Password".to_string(),
+            )
+        };
+        Ok(ServerFinal {
+            mechanism: self.mechanism,
+            status,
+            signature,
+            identity: self.identity,
+            authzid: self.authzid.map(Cow::into_owned),
+        })
     }
 
-    /// Checks that the gs2header received from the client is the same as the one we've stored
-    fn verify_header(&self, gs2header: &str) -> bool {
-        let server_gs2header = STANDARD.encode(self.gs2header.as_bytes());
-        server_gs2header == gs2header
+    /// Checks that the gs2header received from the client is the same as the one we've stored,
+    /// followed by one of the acceptable channel-binding values if the client binds.
+    fn verify_header(&self, gs2header: &str) -> Result<(), Error> {
+        let invalid = || {
+            let excerpt = Excerpt::new(Some('c'), gs2header);
+            Error::Protocol(Kind::InvalidField(Field::GS2Header, Some(excerpt)))
+        };
+        let cbind_input = STANDARD
+            .decode(gs2header.as_bytes())
+            .map_err(|err| Error::base64(Field::GS2Header, err))?;
+        let cbind_data = cbind_input
+            .strip_prefix(self.gs2header.as_bytes())
+            .ok_or_else(invalid)?;
+        let bindings = &self.channel_bindings;
+        if bindings.is_empty() {
+            if !cbind_data.is_empty() {
+                return Err(invalid());
+            }
+        } else if !bindings.iter().any(|value| value == cbind_data) {
+            return Err(Error::ChannelBinding(ChannelBindingError::Mismatch));
+        }
+        Ok(())
     }
 
     /// Checks that the client has sent the same nonce
@@ -286,47 +957,187 @@ impl<'a, P: AuthenticationProvider> ClientFinal<'a, P> {
     }
 
     /// Checks that the proof from the client matches our saved credentials
-    fn verify_proof(&self, proof: &str) -> Result<Option<String>, Error> {
-        let (client_proof, server_signature): ([u8; SHA256_OUTPUT_LEN], hmac::Tag) = find_proofs(
-            &self.gs2header,
-            &self.client_first_bare,
-            &self.server_first,
-            self.hashed_password.as_slice(),
-            &self.nonce,
-        );
-        let proof = if let Ok(proof) = STANDARD.decode(proof.as_bytes()) {
-            proof
+    fn verify_proof(&self, message: &ClientFinalMessage) -> Result<Option<String>, Error> {
+        // The extensions are part of the `client-final-message-without-proof` in the AuthMessage.
+        let nonce: Cow<str> = if message.extensions.is_empty() {
+            message.nonce.into()
         } else {
-            return Err(Error::Protocol(Kind::InvalidField(Field::Proof)));
+            format!("{},{}", message.nonce, message.extensions).into()
         };
-        if proof != client_proof {
-            return Ok(None);
-        }
-
-        let server_signature_string = format!("v={}", STANDARD.encode(server_signature.as_ref()));
-        Ok(Some(server_signature_string))
+        let proof = STANDARD
+            .decode(message.proof.as_bytes())
+            .map_err(|err| Error::base64(Field::Proof, err))?;
+        let server_signature = match self.secret {
+            StoredSecret::SaltedPassword(ref salted_password) => {
+                let timer = self.counters.start_kdf();
+                let (client_proof, server_signature) = find_proofs(
+                    &self.mechanism,
+                    message.channel_binding,
+                    &self.client_first_bare,
+                    &self.server_first,
+                    salted_password,
+                    &nonce,
+                );
+                self.counters.finish_kdf(timer);
+                if proof != *client_proof {
+                    return Ok(None);
+                }
+                STANDARD.encode(server_signature.as_ref())
+            }
+            StoredSecret::Keys {
+                ref stored_key,
+                ref server_key,
+            } => {
+                let client_final_without_proof =
+                    format!("c={},r={}", message.channel_binding, nonce);
+                let auth_message = proof::auth_message(
+                    &self.client_first_bare,
+                    &self.server_first,
+                    &client_final_without_proof,
+                );
+                let auth_message = auth_message.as_bytes();
+                if !proof::verify_client_proof(self.mechanism, stored_key, auth_message, &proof) {
+                    return Ok(None);
+                }
+                let signature =
+                    proof::compute_server_signature(self.mechanism, server_key, auth_message);
+                STANDARD.encode(signature)
+            }
+        };
+        Ok(Some(format!("v={}", server_signature)))
     }
 }
 
 /// Represents the final stage of authentication, after we have generated the final server message
 /// to send to the client
 pub struct ServerFinal {
+    mechanism: Mechanism,
     status: AuthenticationStatus,
     signature: String,
+    identity: String,
+    authzid: Option<String>,
 }
 
 impl ServerFinal {
+    /// Returns the canonical identity of the authenticating user set by the provider with
+    /// [`PasswordInfo::with_identity`], or the username sent by the client if none was set.
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Returns the username the client requested to act as, if any.
+    pub fn authzid(&self) -> Option<&str> {
+        self.authzid.as_deref()
+    }
+
     /// Get the [`AuthenticationStatus`] of the exchange. This status can be successful, failed
     /// because of invalid authentication or failed because of invalid authorization.
     pub fn server_final(self) -> (AuthenticationStatus, String) {
+        let _phase = trace::phase(self.mechanism.name(), "server_final");
+        trace::status(self.status);
+        trace::sent(self.signature.len());
         (self.status, self.signature)
     }
+
+    /// Aborts the handshake instead of sending the final message and zeroizes the server
+    /// signature. See [`Aborted`].
+    pub fn abort(mut self) -> Aborted {
+        zeroize_string(&mut self.signature);
+        Aborted(())
+    }
+}
+
+/// The terminal state of a handshake aborted with the `abort` method of a state, for example
+/// [`ClientFinal::abort`]. The secrets the state held were overwritten with zeros.
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Aborted(());
+
+impl Aborted {
+    /// Returns a `server-final-message` with the `other-error` value, to tell the client that
+    /// the handshake was aborted.
+    pub fn server_final(&self) -> &'static str {
+        "e=other-error"
+    }
+}
+
+impl<P: AuthenticationProvider> fmt::Display for ScramServer<P> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("server", "client-first").fmt(fmt)
+    }
+}
+
+impl<'a, P: AuthenticationProvider> fmt::Display for ServerFirst<'a, P> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("server", "server-first")
+            .mechanism(self.mechanism.name())
+            .user(&self.authcid)
+            .nonce_length(self.client_nonce.len())
+            .fmt(fmt)
+    }
+}
+
+impl<'a, P: AuthenticationProvider> fmt::Display for ClientFinal<'a, P> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("server", "client-final")
+            .mechanism(self.mechanism.name())
+            .user(&self.authcid)
+            .nonce_length(self.nonce.len())
+            .fmt(fmt)
+    }
+}
+
+impl fmt::Display for ServerFinal {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("server", "server-final")
+            .mechanism(self.mechanism.name())
+            .user(&self.identity)
+            .fmt(fmt)
+    }
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        Summary::new("server", "aborted").fmt(fmt)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::{Error, Field, Kind};
-    use super::{parse_client_final, parse_client_first};
+    use core::num::NonZeroU32;
+    use std::time::Instant;
+
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+
+    use super::super::{ChannelBindingError, ClientNonceError, Error, Excerpt, Field, Kind};
+    use super::check_channel_binding;
+    use super::ScramServer;
+    use super::{
+        AuthenticationProvider, AuthenticationStatus, ClientNoncePolicy, ConnectionContext,
+        PasswordInfo,
+    };
+    use crate::confidentiality::Confidentiality;
+    use crate::credentials::derive_credentials;
+    use crate::grammar::{ClientFinalMessage, ClientFirstMessage};
+    use crate::gs2::Gs2Header;
+    use crate::mechanism::Mechanism;
+    use crate::saslprep::Mode;
+    use crate::utils::{find_proofs, hash_password};
+
+    fn excerpt(attribute: Option<char>, value: &str) -> Option<Excerpt> {
+        Some(Excerpt::new(attribute, value))
+    }
+
+    fn parse_client_first(data: &str) -> Result<(&str, Option<&str>, &str), Error> {
+        ClientFirstMessage::parse(data)
+            .map(|message| (message.authcid, message.authzid, message.nonce))
+    }
+
+    fn parse_client_final(data: &str) -> Result<(&str, &str, &str), Error> {
+        ClientFinalMessage::parse(data)
+            .map(|message| (message.channel_binding, message.nonce, message.proof))
+    }
 
     #[test]
     fn test_parse_client_first_success() {
@@ -341,57 +1152,448 @@ mod tests {
         assert_eq!(authzid, Some("other user"));
         assert_eq!(nonce, "abcdef=hijk");
 
-        let (authcid, authzid, nonce) = parse_client_first("n,,n=,r=").unwrap();
+        let (authcid, authzid, nonce) = parse_client_first("n,,n=,r=a").unwrap();
         assert_eq!(authcid, "");
         assert!(authzid.is_none());
-        assert_eq!(nonce, "");
+        assert_eq!(nonce, "a");
+
+        let (authcid, _, _) = parse_client_first("p=tls-unique,,n=user,r=abc").unwrap();
+        assert_eq!(authcid, "user");
     }
 
     #[test]
     fn test_parse_client_first_missing_fields() {
         assert_eq!(
             parse_client_first("n,,n=user").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Nonce))
+            Error::Protocol(Kind::ExpectedField(Field::Nonce, None))
         );
         assert_eq!(
             parse_client_first("n,,r=user").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Authcid))
+            Error::Protocol(Kind::ExpectedField(
+                Field::Authcid,
+                excerpt(Some('r'), "user")
+            ))
         );
         assert_eq!(
             parse_client_first("n,n=user,r=abc").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Authzid))
+            Error::Protocol(Kind::ExpectedField(
+                Field::Authzid,
+                excerpt(Some('n'), "user")
+            ))
         );
         assert_eq!(
             parse_client_first(",,n=user,r=abc").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding))
+            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding, None))
         );
         assert_eq!(
             parse_client_first("").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding))
+            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding, None))
         );
         assert_eq!(
             parse_client_first(",,,").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding))
+            Error::Protocol(Kind::ExpectedField(Field::ChannelBinding, None))
         );
     }
     #[test]
     fn test_parse_client_first_invalid_data() {
         assert_eq!(
             parse_client_first("a,,n=user,r=abc").unwrap_err(),
-            Error::Protocol(Kind::InvalidField(Field::ChannelBinding))
+            Error::Protocol(Kind::InvalidField(
+                Field::ChannelBinding,
+                excerpt(None, "a")
+            ))
         );
         assert_eq!(
             parse_client_first("p,,n=user,r=abc").unwrap_err(),
-            Error::UnsupportedExtension
+            Error::Protocol(Kind::InvalidField(
+                Field::ChannelBinding,
+                excerpt(None, "p")
+            ))
+        );
+        assert_eq!(
+            parse_client_first("p=,,n=user,r=abc").unwrap_err(),
+            Error::Protocol(Kind::InvalidField(
+                Field::ChannelBinding,
+                excerpt(None, "p=")
+            ))
         );
         assert_eq!(
             parse_client_first("nn,,n=user,r=abc").unwrap_err(),
-            Error::Protocol(Kind::InvalidField(Field::ChannelBinding))
+            Error::Protocol(Kind::InvalidField(
+                Field::ChannelBinding,
+                excerpt(None, "nn")
+            ))
         );
         assert_eq!(
             parse_client_first("n,,n,r=abc").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Authcid))
+            Error::Protocol(Kind::ExpectedField(Field::Authcid, excerpt(None, "n")))
+        );
+    }
+
+    #[test]
+    fn test_check() {
+        let without = ConnectionContext::new();
+        let with = ConnectionContext::new().with_channel_binding(true);
+        let values: &[&[u8]] = &[b"old", b"new"];
+        let with_data = with.with_channel_binding_data("tls-server-end-point", values);
+        let check = |header, context| {
+            let (header, _) = Gs2Header::parse(header).unwrap();
+            check_channel_binding(header.channel_binding, context)
+        };
+        assert_eq!(check("n,,", &without), Ok(Vec::new()));
+        assert_eq!(check("n,,", &with), Ok(Vec::new()));
+        assert_eq!(check("y,,", &without), Ok(Vec::new()));
+        assert_eq!(
+            check("y,,", &with),
+            Err(Error::ChannelBinding(ChannelBindingError::Downgrade))
+        );
+        assert_eq!(
+            check("p=tls-unique,,", &without),
+            Err(Error::ChannelBinding(ChannelBindingError::NotSupported))
+        );
+        assert_eq!(
+            check("p=tls-unique,a=admin,", &with_data),
+            Err(Error::ChannelBinding(ChannelBindingError::UnsupportedType(
+                "tls-unique".to_string()
+            )))
+        );
+        assert_eq!(
+            check("p=tls-server-end-point,,", &with_data),
+            Ok(vec![b"old".to_vec(), b"new".to_vec()])
+        );
+        assert_eq!(
+            check("p=tls-server-end-point,,", &with),
+            Err(Error::ChannelBinding(ChannelBindingError::MissingData))
+        );
+        let without_data = with.with_channel_binding_data("tls-server-end-point", &[]);
+        assert_eq!(
+            check("p=tls-server-end-point,,", &without_data),
+            Err(Error::ChannelBinding(ChannelBindingError::MissingData))
+        );
+    }
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, _: &str) -> Option<PasswordInfo> {
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("password", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    /// Runs a handshake in which the client binds to `cbind_data` against a server which accepts
+    /// the hashes of an old and a new certificate.
+    fn bind(cbind_data: &[u8]) -> Result<AuthenticationStatus, Error> {
+        let server = ScramServer::new(TestProvider);
+        let values: &[&[u8]] = &[b"old certificate hash", b"new certificate hash"];
+        let context =
+            ConnectionContext::new().with_channel_binding_data("tls-server-end-point", values);
+        let gs2header = "p=tls-server-end-point,,";
+        let client_first = format!("{}n=user,r=abc", gs2header);
+        let server_first = server.handle_client_first_in_context(&client_first, &context)?;
+        let (client_final, server_first) = server_first.server_first_with_nonce("def");
+        let mut cbind_input = gs2header.as_bytes().to_vec();
+        cbind_input.extend_from_slice(cbind_data);
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let channel_binding = STANDARD.encode(&cbind_input);
+        let (proof, _) = find_proofs(
+            &Mechanism::ScramSha256,
+            &channel_binding,
+            "n=user,r=abc",
+            &server_first,
+            &hash_password("password", iterations, b"salt"),
+            "abcdef",
+        );
+        let message = format!(
+            "c={},r=abcdef,p={}",
+            channel_binding,
+            STANDARD.encode(proof)
+        );
+        Ok(client_final.handle_client_final(&message)?.server_final().0)
+    }
+
+    #[test]
+    fn test_channel_binding_rotation() {
+        assert_eq!(
+            bind(b"old certificate hash"),
+            Ok(AuthenticationStatus::Authenticated)
         );
+        assert_eq!(
+            bind(b"new certificate hash"),
+            Ok(AuthenticationStatus::Authenticated)
+        );
+        assert_eq!(
+            bind(b"other certificate hash"),
+            Err(Error::ChannelBinding(ChannelBindingError::Mismatch))
+        );
+        assert_eq!(
+            bind(b""),
+            Err(Error::ChannelBinding(ChannelBindingError::Mismatch))
+        );
+    }
+
+    #[test]
+    fn test_client_final_extensions() {
+        let server = ScramServer::new(TestProvider);
+        let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+        let (client_final, server_first) = server_first.server_first_with_nonce("def");
+        let iterations = NonZeroU32::new(4096).unwrap();
+        // The extension is part of the client-final-message-without-proof.
+        let (proof, _) = find_proofs(
+            &Mechanism::ScramSha256,
+            "biws",
+            "n=user,r=abc",
+            &server_first,
+            &hash_password("password", iterations, b"salt"),
+            "abcdef,x=1",
+        );
+        let message = format!("c=biws,r=abcdef,x=1,p={}", STANDARD.encode(proof));
+        let (status, _) = client_final
+            .handle_client_final(&message)
+            .unwrap()
+            .server_final();
+        assert_eq!(status, AuthenticationStatus::Authenticated);
+    }
+
+    #[test]
+    fn test_from_keys() {
+        struct KeysProvider;
+
+        impl AuthenticationProvider for KeysProvider {
+            fn get_password_for(&self, _: &str) -> Option<PasswordInfo> {
+                let iterations = NonZeroU32::new(4096).unwrap();
+                let credentials = derive_credentials(
+                    "password",
+                    Mechanism::ScramSha256,
+                    iterations,
+                    b"salt".to_vec(),
+                );
+                Some(PasswordInfo::from_keys(
+                    credentials.stored_key.clone(),
+                    credentials.server_key.clone(),
+                    4096,
+                    b"salt".to_vec(),
+                ))
+            }
+        }
+
+        let server = ScramServer::new(KeysProvider);
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let salted_password = hash_password("password", iterations, b"salt");
+        for &(password, expected) in &[
+            (&salted_password[..], AuthenticationStatus::Authenticated),
+            (&[0; 32][..], AuthenticationStatus::NotAuthenticated),
+        ] {
+            let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+            let (client_final, server_first) = server_first.server_first_with_nonce("def");
+            let (proof, signature) = find_proofs(
+                &Mechanism::ScramSha256,
+                "biws",
+                "n=user,r=abc",
+                &server_first,
+                password,
+                "abcdef",
+            );
+            let message = format!("c=biws,r=abcdef,p={}", STANDARD.encode(proof));
+            let (status, server_final) = client_final
+                .handle_client_final(&message)
+                .unwrap()
+                .server_final();
+            assert_eq!(status, expected);
+            if expected == AuthenticationStatus::Authenticated {
+                assert_eq!(server_final, format!("v={}", STANDARD.encode(signature)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let server = ScramServer::new(TestProvider);
+        let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+        let (client_final, _) = server_first
+            .with_deadline(Instant::now())
+            .server_first_with_nonce("def");
+        assert_eq!(
+            client_final
+                .handle_client_final("c=biws,r=abcdef,p=cHJvb2Y=")
+                .err(),
+            Some(Error::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_abort() {
+        let server = ScramServer::new(TestProvider);
+        let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+        let (client_final, _) = server_first.server_first_with_nonce("def");
+        assert_eq!(client_final.abort().server_final(), "e=other-error");
+    }
+
+    #[test]
+    fn test_display() {
+        let server = ScramServer::new(TestProvider);
+        assert_eq!(
+            server.to_string(),
+            "SCRAM-SHA-256 server, phase client-first"
+        );
+        let server_first = server.handle_client_first("n,,n=us\ner,r=abc").unwrap();
+        assert_eq!(
+            server_first.to_string(),
+            "SCRAM-SHA-256 server, phase server-first, user \"us\\ner\", nonce length 3"
+        );
+        let (client_final, _) = server_first.server_first_with_nonce("def");
+        assert_eq!(
+            client_final.to_string(),
+            "SCRAM-SHA-256 server, phase client-final, user \"us\\ner\", nonce length 6"
+        );
+        let server_final = client_final
+            .handle_client_final("c=biws,r=abcdef,p=cHJvb2Y=")
+            .unwrap();
+        assert_eq!(
+            server_final.to_string(),
+            "SCRAM-SHA-256 server, phase server-final, user \"us\\ner\""
+        );
+        assert_eq!(
+            server_final.abort().to_string(),
+            "SCRAM-SHA-256 server, phase aborted"
+        );
+    }
+
+    #[test]
+    fn test_require_confidentiality() {
+        let server = ScramServer::new(TestProvider).require_confidentiality();
+        assert_eq!(
+            server.handle_client_first("n,,n=user,r=abc").err(),
+            Some(Error::ConfidentialityNotAsserted)
+        );
+        let context = ConnectionContext::new().with_confidentiality(Confidentiality::Encrypted);
+        assert!(server
+            .handle_client_first_in_context("n,,n=user,r=abc", &context)
+            .is_ok());
+        let context = ConnectionContext::new().with_confidentiality(Confidentiality::Unencrypted);
+        assert!(server
+            .handle_client_first_in_context("n,,n=user,r=abc", &context)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_mock_credentials() {
+        struct NoUsers;
+
+        impl AuthenticationProvider for NoUsers {
+            fn get_password_for(&self, _: &str) -> Option<PasswordInfo> {
+                None
+            }
+        }
+
+        assert_eq!(
+            ScramServer::new(NoUsers)
+                .handle_client_first("n,,n=user,r=abc")
+                .err(),
+            Some(Error::InvalidUser("user".to_string()))
+        );
+        let key = [7; 32];
+        let server_first = |key: &[u8], client_first: &str| {
+            let server = ScramServer::new(NoUsers).with_mock_credentials(key, 4096);
+            let server_first = server.handle_client_first(client_first).unwrap();
+            server_first.server_first_with_nonce("def").1
+        };
+        let first = server_first(&key, "n,,n=user,r=abc");
+        let salt = first.strip_prefix("r=abcdef,s=").unwrap();
+        let salt = salt.strip_suffix(",i=4096").unwrap();
+        assert_eq!(STANDARD.decode(salt).unwrap().len(), 16);
+        assert_eq!(server_first(&key, "n,,n=user,r=abc"), first);
+        assert_ne!(server_first(&key, "n,,n=other,r=abc"), first);
+        assert_ne!(server_first(&[8; 32], "n,,n=user,r=abc"), first);
+
+        let server = ScramServer::new(NoUsers).with_mock_credentials(&key, 4096);
+        let server_first = server.handle_client_first("n,,n=user,r=abc").unwrap();
+        let (client_final, _) = server_first.server_first_with_nonce("def");
+        let (status, message) = client_final
+            .handle_client_final("c=biws,r=abcdef,p=cHJvb2Y=")
+            .unwrap()
+            .server_final();
+        assert_eq!(status, AuthenticationStatus::NotAuthenticated);
+        assert_eq!(message, "e=Invalid Password");
+    }
+
+    #[test]
+    #[should_panic(expected = "mock credential key shorter than MIN_SECRET_LEN")]
+    fn test_mock_credentials_short_key() {
+        let _ = ScramServer::new(TestProvider).with_mock_credentials(&[7; 31], 4096);
+    }
+
+    #[test]
+    fn test_saslprep() {
+        struct Users;
+
+        impl AuthenticationProvider for Users {
+            fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+                match username {
+                    "IX" => TestProvider.get_password_for(username),
+                    _ => None,
+                }
+            }
+        }
+
+        let server = ScramServer::new(Users).with_saslprep(Mode::Lenient);
+        let server_first = server.handle_client_first("n,a=I\u{AD}X,n=\u{2168},r=abc");
+        assert!(server_first.is_ok());
+        assert_eq!(
+            server.handle_client_first("n,,n=I\u{7}X,r=abc").err(),
+            Some(Error::Protocol(Kind::InvalidField(
+                Field::Authcid,
+                excerpt(Some('n'), "I\u{7}X")
+            )))
+        );
+        assert_eq!(
+            server.handle_client_first("n,a=\u{E000},n=IX,r=abc").err(),
+            Some(Error::Protocol(Kind::InvalidField(
+                Field::Authzid,
+                excerpt(Some('a'), "\u{E000}")
+            )))
+        );
+        // By default, usernames which can't be prepared are used unchanged.
+        let server = ScramServer::new(Users);
+        assert!(server.handle_client_first("n,,n=\u{2168},r=abc").is_ok());
+        assert_eq!(
+            server.handle_client_first("n,,n=I\u{7}X,r=abc").err(),
+            Some(Error::InvalidUser("I\u{7}X".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_client_nonce_policy() {
+        let server = ScramServer::new(TestProvider).with_client_nonce_policy(Default::default());
+        assert_eq!(
+            server.handle_client_first("n,,n=user,r=abc").err(),
+            Some(Error::ClientNonce(ClientNonceError::TooShort(3)))
+        );
+        assert!(server
+            .handle_client_first("n,,n=user,r=fyko+d2lbbFgONRv9qkxdawL")
+            .is_ok());
+
+        let policy = ClientNoncePolicy {
+            min_length: 4,
+            characters: '0'..='9',
+        };
+        let server = ScramServer::new(TestProvider).with_client_nonce_policy(policy);
+        assert!(server.handle_client_first("n,,n=user,r=0123").is_ok());
+        assert_eq!(
+            server.handle_client_first("n,,n=user,r=0123a").err(),
+            Some(Error::ClientNonce(ClientNonceError::InvalidCharacter('a')))
+        );
+        // The grammar rejects characters which aren't printable, whatever the policy allows.
+        let policy = ClientNoncePolicy {
+            min_length: 1,
+            characters: '\0'..=char::MAX,
+        };
+        let server = ScramServer::new(TestProvider).with_client_nonce_policy(policy);
+        assert!(matches!(
+            server.handle_client_first("n,,n=user,r=a\u{e9}"),
+            Err(Error::Protocol(Kind::InvalidField(Field::Nonce, _)))
+        ));
     }
 
     #[test]
@@ -401,9 +1603,9 @@ mod tests {
         assert_eq!(nonce, "abcefg");
         assert_eq!(proof, "783232");
 
-        let (gs2head, nonce, proof) = parse_client_final("c=,r=,p=").unwrap();
+        let (gs2head, nonce, proof) = parse_client_final("c=,r=a,p=").unwrap();
         assert_eq!(gs2head, "");
-        assert_eq!(nonce, "");
+        assert_eq!(nonce, "a");
         assert_eq!(proof, "");
     }
 
@@ -411,27 +1613,33 @@ mod tests {
     fn test_parse_client_final_missing_fields() {
         assert_eq!(
             parse_client_final("c=whatever,r=something").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Proof))
+            Error::Protocol(Kind::ExpectedField(Field::Proof, None))
         );
         assert_eq!(
             parse_client_final("c=whatever,p=words").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Nonce))
+            Error::Protocol(Kind::ExpectedField(
+                Field::Nonce,
+                excerpt(Some('p'), "words")
+            ))
         );
         assert_eq!(
             parse_client_final("c=whatever").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Nonce))
+            Error::Protocol(Kind::ExpectedField(Field::Nonce, None))
         );
         assert_eq!(
             parse_client_final("c=").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::Nonce))
+            Error::Protocol(Kind::ExpectedField(Field::Nonce, None))
         );
         assert_eq!(
             parse_client_final("").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::GS2Header))
+            Error::Protocol(Kind::ExpectedField(Field::GS2Header, None))
         );
         assert_eq!(
             parse_client_final("r=anonce").unwrap_err(),
-            Error::Protocol(Kind::ExpectedField(Field::GS2Header))
+            Error::Protocol(Kind::ExpectedField(
+                Field::GS2Header,
+                excerpt(Some('r'), "anonce")
+            ))
         );
     }
 }
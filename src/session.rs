@@ -0,0 +1,290 @@
+//! Handshakes with a send and receive interface, for embedding in connection structs.
+//!
+//! The typestates of the [`client`](crate::client) and [`server`](crate::server) modules consume
+//! the state with every message, which doesn't fit a connection struct that outlives the
+//! handshake. [`ClientSession`] and [`ServerSession`] keep the typestate and take `&mut self`
+//! instead: the connection sends whatever [`message_out`](ClientSession::message_out) returns and
+//! passes every message it receives to [`message_in`](ClientSession::message_in), until the
+//! session [`is_done`](ClientSession::is_done).
+//!
+//! ``` rust,no_run
+//! use scram::session::ClientSession;
+//! use scram::{Error, ScramClient};
+//!
+//! struct Connection {
+//!     session: ClientSession<'static>,
+//! }
+//!
+//! impl Connection {
+//!     // This function represents your I/O implementation.
+//!     # #[allow(unused_variables)]
+//!     fn send(&mut self, message: &str) {
+//!         unimplemented!()
+//!     }
+//!
+//!     fn on_writable(&mut self) {
+//!         if let Some(message) = self.session.message_out() {
+//!             self.send(&message);
+//!         }
+//!     }
+//!
+//!     fn on_message(&mut self, message: &str) -> Result<(), Error> {
+//!         self.session.message_in(message)?;
+//!         if self.session.is_done() {
+//!             // The server was verified.
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let client = ScramClient::new("user".to_string(), "password".to_string(), None);
+//! let connection = Connection {
+//!     session: ClientSession::new(client),
+//! };
+//! ```
+//!
+//! A message the handshake doesn't expect, for example one received after the end of the
+//! handshake, fails with `Error::UnexpectedMessage`. Any error ends the handshake.
+use alloc::string::String;
+
+use crate::client::ScramClient;
+use crate::dynamic::{
+    DynScramClient, DynScramServer, Phase, StepOutput, SteppedClient, SteppedServer,
+};
+use crate::error::Error;
+#[cfg(feature = "std")]
+use crate::server::ScramServer;
+use crate::server::{AuthenticationProvider, AuthenticationStatus};
+
+/// A client handshake with a send and receive interface, see the [module documentation](self).
+pub struct ClientSession<'a> {
+    client: SteppedClient<'a>,
+    outgoing: Option<String>,
+}
+
+impl<'a> ClientSession<'a> {
+    /// Creates the session of `client`.
+    pub fn new(client: ScramClient<'a>) -> Self {
+        ClientSession {
+            client: SteppedClient::new(client),
+            outgoing: None,
+        }
+    }
+
+    /// Returns the next message to send to the server, if there is one. The first call returns the
+    /// `client-first-message`, the call after the `server-first-message` was passed to
+    /// [`message_in`](Self::message_in) returns the `client-final-message`.
+    pub fn message_out(&mut self) -> Option<String> {
+        if self.client.phase() == Phase::Initial {
+            // The first step only creates the `client-first-message`, which can't fail.
+            if let Ok(StepOutput::Continue(message)) = self.client.step(None) {
+                self.outgoing = Some(message);
+            }
+        }
+        self.outgoing.take()
+    }
+
+    /// Handles a message received from the server.
+    ///
+    /// # Return value
+    ///
+    /// Fails with the errors of the client's
+    /// [`handle_server_first`](crate::client::ServerFirst::handle_server_first) and
+    /// [`handle_server_final`](crate::client::ServerFinal::handle_server_final), or with
+    /// `Error::UnexpectedMessage` if the `client-first-message` wasn't sent yet or the handshake
+    /// already ended.
+    pub fn message_in(&mut self, message: &str) -> Result<(), Error> {
+        match self.client.phase() {
+            Phase::AwaitingServerFirst | Phase::AwaitingServerFinal => (),
+            _ => return Err(Error::UnexpectedMessage),
+        }
+        if let StepOutput::Continue(message) = self.client.step(Some(message))? {
+            self.outgoing = Some(message);
+        }
+        Ok(())
+    }
+
+    /// Returns whether the server was verified and the handshake completed successfully.
+    pub fn is_done(&self) -> bool {
+        self.client.phase() == Phase::Completed
+    }
+
+    /// Returns the phase of the handshake.
+    pub fn phase(&self) -> Phase {
+        self.client.phase()
+    }
+}
+
+impl<'a> From<ScramClient<'a>> for ClientSession<'a> {
+    fn from(client: ScramClient<'a>) -> Self {
+        ClientSession::new(client)
+    }
+}
+
+/// A server handshake with a send and receive interface, see the [module documentation](self).
+pub struct ServerSession<'a, P: AuthenticationProvider> {
+    server: SteppedServer<'a, P>,
+    outgoing: Option<String>,
+    status: Option<AuthenticationStatus>,
+    ended: bool,
+}
+
+impl<'a, P: AuthenticationProvider> ServerSession<'a, P> {
+    /// Creates a session with `server`, which uses [`OsRng`](rand::rngs::OsRng) for the server
+    /// nonce. Sessions with another random number generator, a [`ConnectionContext`] or a
+    /// deadline are created from a configured [`SteppedServer`].
+    ///
+    /// This method is only available with the `std` feature.
+    ///
+    /// [`ConnectionContext`]: crate::server::ConnectionContext
+    #[cfg(feature = "std")]
+    pub fn new(server: &'a ScramServer<P>) -> Self {
+        ServerSession::from(SteppedServer::new(server))
+    }
+
+    /// Returns the next message to send to the client, if there is one: the
+    /// `server-first-message` after the `client-first-message`, and the `server-final-message`
+    /// after the `client-final-message`.
+    pub fn message_out(&mut self) -> Option<String> {
+        self.outgoing.take()
+    }
+
+    /// Handles a message received from the client. Failed authentication isn't an error, but
+    /// ends the handshake with a `server-final-message` and the [`status`](Self::status).
+    ///
+    /// # Return value
+    ///
+    /// Fails with the errors of the server's
+    /// [`handle_client_first`](crate::server::ScramServer::handle_client_first) and
+    /// [`handle_client_final`](crate::server::ClientFinal::handle_client_final), or with
+    /// `Error::UnexpectedMessage` if the handshake already ended.
+    pub fn message_in(&mut self, message: &str) -> Result<(), Error> {
+        if self.ended {
+            return Err(Error::UnexpectedMessage);
+        }
+        // The stepped server ends the handshake on errors, too.
+        self.ended = true;
+        match self.server.step(Some(message))? {
+            StepOutput::Continue(message) => {
+                self.ended = false;
+                self.outgoing = Some(message);
+            }
+            StepOutput::Done { message, status } => {
+                self.outgoing = message;
+                self.status = Some(status);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns whether the handshake completed, successfully or not. The `server-final-message`
+    /// may still have to be sent.
+    pub fn is_done(&self) -> bool {
+        self.status.is_some()
+    }
+
+    /// Returns the outcome of the handshake, or `None` while it's in progress or if it failed
+    /// with an error.
+    pub fn status(&self) -> Option<AuthenticationStatus> {
+        self.status
+    }
+}
+
+impl<'a, P: AuthenticationProvider> From<SteppedServer<'a, P>> for ServerSession<'a, P> {
+    fn from(server: SteppedServer<'a, P>) -> Self {
+        ServerSession {
+            server,
+            outgoing: None,
+            status: None,
+            ended: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{ClientSession, ServerSession};
+    use crate::client::ScramClient;
+    use crate::dynamic::Phase;
+    use crate::error::Error;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::utils::hash_password;
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username != "user" {
+                return None;
+            }
+            let iterations = NonZeroU32::new(4096).unwrap();
+            let password = hash_password("password", iterations, b"salt");
+            Some(PasswordInfo::new(password.to_vec(), 4096, b"salt".to_vec()))
+        }
+    }
+
+    /// Exchanges messages until neither side has one to send.
+    fn run(
+        client: &mut ClientSession,
+        server: &mut ServerSession<TestProvider>,
+    ) -> Result<(), Error> {
+        loop {
+            if let Some(message) = client.message_out() {
+                server.message_in(&message)?;
+            } else if let Some(message) = server.message_out() {
+                client.message_in(&message)?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    #[test]
+    fn test_session() {
+        let server = ScramServer::new(TestProvider);
+        let mut session = ServerSession::new(&server);
+        let mut client = ClientSession::new(ScramClient::new("user", "password", None));
+        assert_eq!(client.message_in("r=nonce"), Err(Error::UnexpectedMessage));
+        assert_eq!(session.message_out(), None);
+        assert_eq!(run(&mut client, &mut session), Ok(()));
+        assert!(client.is_done());
+        assert!(session.is_done());
+        assert_eq!(session.status(), Some(AuthenticationStatus::Authenticated));
+        assert_eq!(client.message_out(), None);
+        assert_eq!(client.message_in("v=proof"), Err(Error::UnexpectedMessage));
+        assert_eq!(session.message_in("c=biws"), Err(Error::UnexpectedMessage));
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let server = ScramServer::new(TestProvider);
+        let mut session = ServerSession::new(&server);
+        let mut client = ClientSession::from(ScramClient::new("user", "wrong", None));
+        assert_eq!(
+            run(&mut client, &mut session),
+            Err(Error::Authentication("Invalid Password".to_string()))
+        );
+        assert_eq!(client.phase(), Phase::Failed);
+        assert!(!client.is_done());
+        assert_eq!(
+            session.status(),
+            Some(AuthenticationStatus::NotAuthenticated)
+        );
+        assert_eq!(
+            client.message_in("e=other-error"),
+            Err(Error::UnexpectedMessage)
+        );
+    }
+
+    #[test]
+    fn test_server_error() {
+        let server = ScramServer::new(TestProvider);
+        let mut session = ServerSession::new(&server);
+        assert!(session.message_in("invalid").is_err());
+        assert!(!session.is_done());
+        assert_eq!(session.status(), None);
+        assert_eq!(session.message_in("invalid"), Err(Error::UnexpectedMessage));
+    }
+}
@@ -0,0 +1,187 @@
+//! [proptest](https://docs.rs/proptest) strategies for SCRAM messages and credentials.
+//!
+//! This module is available with the `proptest` feature. The strategies generate well formed
+//! server messages as well as messages which are almost, but not quite, valid. Together with the
+//! credential strategies they can be used to test the parsers and the whole handshake.
+//!
+//! ``` rust,ignore
+//! use proptest::prelude::*;
+//! use scram::strategies;
+//!
+//! proptest! {
+//!     #[test]
+//!     fn rejects_malformed(server_first in strategies::invalid_server_first("nonce".into())) {
+//!         let scram = scram::ScramClient::new("user", "password", None);
+//!         let (scram, _) = scram.insecure_allow_low_iterations().client_first();
+//!         prop_assert!(scram.handle_server_first(&server_first).is_err());
+//!     }
+//! }
+//! ```
+use core::num::NonZeroU32;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use proptest::collection::vec;
+use proptest::prelude::*;
+use ring::digest::SHA256_OUTPUT_LEN;
+
+/// Generates usernames which frequently contain the characters `,` and `=`. These have to be
+/// escaped as `=2C` and `=3D` in the exchanged messages.
+pub fn username() -> impl Strategy<Value = String> {
+    "[a-z0-9,=@.]{1,16}"
+}
+
+/// Generates passwords of arbitrary printable unicode characters, including the empty password.
+pub fn password() -> impl Strategy<Value = String> {
+    "\\PC{0,16}"
+}
+
+/// Generates salts with lengths between zero and 64 bytes.
+pub fn salt() -> impl Strategy<Value = Vec<u8>> {
+    vec(any::<u8>(), 0..=64)
+}
+
+/// Generates small iteration counts, which keeps handshakes in property tests fast. Clients have to
+/// opt into accepting them with
+/// [`ScramClient::insecure_allow_low_iterations`](crate::ScramClient::insecure_allow_low_iterations).
+pub fn iterations() -> impl Strategy<Value = NonZeroU32> {
+    (1u32..=16).prop_map(|iterations| NonZeroU32::new(iterations).unwrap())
+}
+
+/// Generates printable nonces without commas.
+pub fn nonce() -> impl Strategy<Value = String> {
+    "[!-+--~]{1,32}"
+}
+
+/// Generates valid `server-first-message`s continuing the given client nonce.
+pub fn server_first(client_nonce: String) -> impl Strategy<Value = String> {
+    (nonce(), salt(), iterations()).prop_map(move |(server_nonce, salt, iterations)| {
+        format!(
+            "r={}{},s={},i={}",
+            client_nonce,
+            server_nonce,
+            STANDARD.encode(salt),
+            iterations
+        )
+    })
+}
+
+/// Generates `server-first-message`s which deviate from a valid message in exactly one place, for
+/// example a missing attribute, a foreign nonce, a mandatory extension or a corrupt value.
+pub fn invalid_server_first(client_nonce: String) -> impl Strategy<Value = String> {
+    let valid =
+        (nonce(), salt(), iterations()).prop_map(move |(server_nonce, salt, iterations)| {
+            (
+                format!("{}{}", client_nonce, server_nonce),
+                STANDARD.encode(salt),
+                iterations.to_string(),
+            )
+        });
+    (valid, 0..11usize).prop_map(|((nonce, salt, iterations), fault)| match fault {
+        0 => format!("s={},i={}", salt, iterations),
+        1 => format!("r={},i={}", nonce, iterations),
+        2 => format!("r={},s={}", nonce, salt),
+        3 => format!("m=ext,r={},s={},i={}", nonce, salt, iterations),
+        4 => format!("r=!{},s={},i={}", nonce, salt, iterations),
+        5 => format!("r={},s=*{},i={}", nonce, salt, iterations),
+        6 => format!("r={},s={},i=0", nonce, salt),
+        7 => format!("r={},s={},i=x{}", nonce, salt, iterations),
+        8 => format!("r={},i={},s={}", nonce, iterations, salt),
+        9 => format!("r={},s", nonce),
+        _ => String::new(),
+    })
+}
+
+/// Generates valid `server-final-message`s. Both successful verifiers and server errors are
+/// generated.
+pub fn server_final() -> impl Strategy<Value = String> {
+    prop_oneof![
+        vec(any::<u8>(), SHA256_OUTPUT_LEN)
+            .prop_map(|verifier| format!("v={}", STANDARD.encode(verifier))),
+        "[a-z-]{1,32}".prop_map(|error| format!("e={}", error)),
+    ]
+}
+
+/// Generates `server-final-message`s which are malformed, for example by missing the attribute
+/// name or carrying a verifier which isn't valid base64.
+pub fn invalid_server_final() -> impl Strategy<Value = String> {
+    let verifier =
+        vec(any::<u8>(), SHA256_OUTPUT_LEN).prop_map(|verifier| STANDARD.encode(verifier));
+    (verifier, 0..4usize).prop_map(|(verifier, fault)| match fault {
+        0 => verifier,
+        1 => format!("x={}", verifier),
+        2 => format!("v=*{}", verifier),
+        _ => "v".to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use proptest::prelude::*;
+
+    use super::{iterations, password, salt, username};
+    use crate::{hash_password, AuthenticationProvider, AuthenticationStatus, PasswordInfo};
+    use crate::{ScramClient, ScramServer};
+
+    struct SingleUser {
+        username: String,
+        salted_password: Vec<u8>,
+        salt: Vec<u8>,
+        iterations: NonZeroU32,
+    }
+
+    impl AuthenticationProvider for SingleUser {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username == self.username {
+                Some(PasswordInfo::new(
+                    self.salted_password.clone(),
+                    self.iterations.get() as u16,
+                    self.salt.clone(),
+                ))
+            } else {
+                None
+            }
+        }
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn test_handshake(
+            username in username(),
+            password in password(),
+            salt in salt(),
+            iterations in iterations(),
+        ) {
+            // The client prepares the password it is given, see `ScramClient::new`.
+            #[cfg(feature = "saslprep")]
+            let prepared = crate::saslprep::saslprep(&password, crate::saslprep::Mode::Lenient)
+                .unwrap_or(password.as_str().into());
+            #[cfg(not(feature = "saslprep"))]
+            let prepared = alloc::borrow::Cow::Borrowed(password.as_str());
+            let provider = SingleUser {
+                salted_password: hash_password(&prepared, iterations, &salt).to_vec(),
+                username: username.clone(),
+                salt,
+                iterations,
+            };
+            let scram_server = ScramServer::new(provider);
+            let scram_client = ScramClient::new(&username, &password, Some(&username))
+                .insecure_allow_low_iterations();
+
+            let (scram_client, client_first) = scram_client.client_first();
+            let scram_server = scram_server.handle_client_first(&client_first).unwrap();
+            let (scram_server, server_first) = scram_server.server_first();
+            let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+            let (scram_client, client_final) = scram_client.client_final();
+            let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+            let (status, server_final) = scram_server.server_final();
+
+            prop_assert_eq!(status, AuthenticationStatus::Authenticated);
+            prop_assert!(scram_client.handle_server_final(&server_final).is_ok());
+        }
+    }
+}
@@ -0,0 +1,63 @@
+//! The `Display` output of the handshake states.
+use core::fmt;
+
+use crate::mechanism::Mechanism;
+
+/// A short description of a handshake state for status pages and log lines, for example
+/// `SCRAM-SHA-256 client, phase server-first, user "user", nonce length 24`.
+///
+/// It never contains secrets: no password, salted password, proof or signature, and not the nonce
+/// itself. The username is quoted and escaped like a Rust string, so it can't forge log lines.
+pub(crate) struct Summary<'a> {
+    /// The name of the mechanism.
+    pub(crate) mechanism: &'a str,
+    pub(crate) role: &'static str,
+    /// The name of the state, which is named after the message it handles or produces next.
+    pub(crate) phase: &'static str,
+    pub(crate) user: Option<&'a str>,
+    pub(crate) nonce_length: Option<usize>,
+}
+
+impl<'a> Summary<'a> {
+    pub(crate) fn new(role: &'static str, phase: &'static str) -> Self {
+        Summary {
+            mechanism: Mechanism::ScramSha256.name(),
+            role,
+            phase,
+            user: None,
+            nonce_length: None,
+        }
+    }
+
+    pub(crate) fn mechanism(mut self, mechanism: &'a str) -> Self {
+        self.mechanism = mechanism;
+        self
+    }
+
+    pub(crate) fn user(mut self, user: &'a str) -> Self {
+        self.user = Some(user);
+        self
+    }
+
+    pub(crate) fn nonce_length(mut self, nonce_length: usize) -> Self {
+        self.nonce_length = Some(nonce_length);
+        self
+    }
+}
+
+impl<'a> fmt::Display for Summary<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{} {}, phase {}",
+            self.mechanism, self.role, self.phase
+        )?;
+        if let Some(user) = self.user {
+            write!(fmt, ", user {:?}", user)?;
+        }
+        if let Some(nonce_length) = self.nonce_length {
+            write!(fmt, ", nonce length {}", nonce_length)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Counters of the handshakes processed by a [`ScramServer`](crate::ScramServer).
+//!
+//! With the `telemetry` feature every server counts the handshakes it started, their outcomes and
+//! the time spent deriving keys. `ScramServer::telemetry` returns a [`TelemetrySnapshot`] of the
+//! counters. Without the feature the counters take no space and all functions compile to nothing.
+#[cfg(any(test, feature = "telemetry"))]
+use core::convert::TryFrom;
+#[cfg(any(test, feature = "telemetry"))]
+use core::sync::atomic::{AtomicU64, Ordering};
+#[cfg(any(test, feature = "telemetry"))]
+use core::time::Duration;
+#[cfg(any(test, feature = "telemetry"))]
+use std::time::Instant;
+
+use crate::error::Error;
+use crate::server::AuthenticationStatus;
+
+/// The counters of a server. All of them only ever increase.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    #[cfg(any(test, feature = "telemetry"))]
+    started: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    authenticated: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    unknown_user: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    not_authenticated: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    not_authorized: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    protocol_error: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    kdf_runs: AtomicU64,
+    #[cfg(any(test, feature = "telemetry"))]
+    kdf_nanos: AtomicU64,
+}
+
+/// Measures the duration of a key derivation.
+pub(crate) struct Timer {
+    #[cfg(any(test, feature = "telemetry"))]
+    start: Instant,
+}
+
+/// The values of the counters of a server at one point in time.
+#[cfg(any(test, feature = "telemetry"))]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TelemetrySnapshot {
+    /// The number of `client-first-message`s received.
+    pub started: u64,
+    /// The number of handshakes which ended with [`AuthenticationStatus::Authenticated`].
+    pub authenticated: u64,
+    /// The number of handshakes for users unknown to the provider.
+    pub unknown_user: u64,
+    /// The number of handshakes which ended with [`AuthenticationStatus::NotAuthenticated`].
+    pub not_authenticated: u64,
+    /// The number of handshakes which ended with [`AuthenticationStatus::NotAuthorized`].
+    pub not_authorized: u64,
+    /// The number of handshakes aborted because of a malformed message.
+    pub protocol_error: u64,
+    /// The average time spent deriving the client and server keys from the salted password, or
+    /// `None` if no key was derived yet.
+    pub average_kdf_time: Option<Duration>,
+}
+
+impl Counters {
+    /// Counts a received `client-first-message`.
+    pub(crate) fn started(&self) {
+        #[cfg(any(test, feature = "telemetry"))]
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a handshake aborted with `error`.
+    #[cfg_attr(not(any(test, feature = "telemetry")), allow(unused_variables))]
+    pub(crate) fn failed(&self, error: &Error) {
+        #[cfg(any(test, feature = "telemetry"))]
+        match *error {
+            Error::InvalidUser(_) => &self.unknown_user,
+            _ => &self.protocol_error,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a completed handshake.
+    #[cfg_attr(not(any(test, feature = "telemetry")), allow(unused_variables))]
+    pub(crate) fn finished(&self, status: AuthenticationStatus) {
+        #[cfg(any(test, feature = "telemetry"))]
+        match status {
+            AuthenticationStatus::Authenticated => &self.authenticated,
+            AuthenticationStatus::NotAuthenticated => &self.not_authenticated,
+            AuthenticationStatus::NotAuthorized => &self.not_authorized,
+        }
+        .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts measuring a key derivation.
+    pub(crate) fn start_kdf(&self) -> Timer {
+        Timer {
+            #[cfg(any(test, feature = "telemetry"))]
+            start: Instant::now(),
+        }
+    }
+
+    /// Records the duration of the key derivation started with `timer`.
+    #[cfg_attr(not(any(test, feature = "telemetry")), allow(unused_variables))]
+    pub(crate) fn finish_kdf(&self, timer: Timer) {
+        #[cfg(any(test, feature = "telemetry"))]
+        {
+            let nanos = u64::try_from(timer.start.elapsed().as_nanos()).unwrap_or(u64::MAX);
+            self.kdf_nanos.fetch_add(nanos, Ordering::Relaxed);
+            self.kdf_runs.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the current values of the counters.
+    #[cfg(any(test, feature = "telemetry"))]
+    pub(crate) fn snapshot(&self) -> TelemetrySnapshot {
+        let kdf_runs = self.kdf_runs.load(Ordering::Relaxed);
+        let kdf_nanos = self.kdf_nanos.load(Ordering::Relaxed);
+        TelemetrySnapshot {
+            started: self.started.load(Ordering::Relaxed),
+            authenticated: self.authenticated.load(Ordering::Relaxed),
+            unknown_user: self.unknown_user.load(Ordering::Relaxed),
+            not_authenticated: self.not_authenticated.load(Ordering::Relaxed),
+            not_authorized: self.not_authorized.load(Ordering::Relaxed),
+            protocol_error: self.protocol_error.load(Ordering::Relaxed),
+            average_kdf_time: kdf_nanos.checked_div(kdf_runs).map(Duration::from_nanos),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counters;
+    use crate::{AuthenticationStatus, Error};
+
+    #[test]
+    fn test_snapshot() {
+        let counters = Counters::default();
+        assert_eq!(counters.snapshot().average_kdf_time, None);
+        counters.started();
+        counters.failed(&Error::InvalidUser("user".to_string()));
+        counters.started();
+        counters.failed(&Error::InvalidServer);
+        counters.started();
+        let timer = counters.start_kdf();
+        counters.finish_kdf(timer);
+        counters.finished(AuthenticationStatus::NotAuthenticated);
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.started, 3);
+        assert_eq!(snapshot.authenticated, 0);
+        assert_eq!(snapshot.unknown_user, 1);
+        assert_eq!(snapshot.not_authenticated, 1);
+        assert_eq!(snapshot.protocol_error, 1);
+        assert!(snapshot.average_kdf_time.is_some());
+    }
+}
@@ -0,0 +1,93 @@
+//! Handshake diagnostics emitted with the `tracing` or the `log` feature.
+//!
+//! The functions only accept mechanisms, message sizes, iteration counts and outcomes, so secret
+//! material like passwords, proofs, signatures, salts and nonces can't end up in spans or events.
+//! `log` has no spans, so the start of a phase is logged as a record instead. Without either
+//! feature all of them compile to nothing.
+#![cfg_attr(
+    not(any(feature = "tracing", feature = "log")),
+    allow(unused_variables)
+)]
+
+#[cfg(feature = "tracing")]
+use tracing::{debug, debug_span, span::EnteredSpan, warn};
+
+use crate::error::Error;
+use crate::interop::InteropWarning;
+#[cfg(feature = "crypto")]
+use crate::server::AuthenticationStatus;
+
+/// A span covering a handshake phase. The span is closed when this value is dropped.
+pub struct Phase {
+    #[cfg(feature = "tracing")]
+    _span: EnteredSpan,
+}
+
+/// Enters the span of the handshake phase `name` of the mechanism named `mechanism`.
+pub fn phase(mechanism: &str, name: &'static str) -> Phase {
+    #[cfg(feature = "log")]
+    log::debug!("entering phase {} of {}", name, mechanism);
+    Phase {
+        #[cfg(feature = "tracing")]
+        _span: debug_span!("scram", phase = name, mechanism).entered(),
+    }
+}
+
+/// Records the size of a message received from the peer.
+pub fn received(size: usize) {
+    #[cfg(feature = "tracing")]
+    debug!(size, "received message");
+    #[cfg(feature = "log")]
+    log::debug!("received message, size={}", size);
+}
+
+/// Records the size of a message to be sent to the peer.
+pub fn sent(size: usize) {
+    #[cfg(feature = "tracing")]
+    debug!(size, "sending message");
+    #[cfg(feature = "log")]
+    log::debug!("sending message, size={}", size);
+}
+
+/// Records the iteration count of the key derivation.
+pub fn iterations(iterations: u32) {
+    #[cfg(feature = "tracing")]
+    debug!(iterations, "deriving salted password");
+    #[cfg(feature = "log")]
+    log::debug!("deriving salted password, iterations={}", iterations);
+}
+
+/// Records whether a phase succeeded.
+pub fn outcome<T>(result: &Result<T, Error>) {
+    #[cfg(feature = "tracing")]
+    match *result {
+        Ok(_) => debug!(outcome = "ok", "phase completed"),
+        Err(ref err) => debug!(outcome = "error", error = %err, "phase failed"),
+    }
+    #[cfg(feature = "log")]
+    match *result {
+        Ok(_) => log::debug!("phase completed, outcome=ok"),
+        Err(ref err) => log::debug!("phase failed, outcome=error, error={}", err),
+    }
+}
+
+/// Records the authentication status determined by the server.
+#[cfg(feature = "crypto")]
+pub fn status(status: AuthenticationStatus) {
+    #[cfg(feature = "tracing")]
+    debug!(outcome = ?status, "authentication completed");
+    #[cfg(feature = "log")]
+    log::debug!("authentication completed, outcome={:?}", status);
+}
+
+/// Records that a message of the peer violated the specification and was fixed up by a
+/// workaround.
+pub fn interop_warning(warning: &InteropWarning) {
+    #[cfg(feature = "tracing")]
+    warn!(warning = %warning, "fixed up message of non-conformant peer");
+    #[cfg(feature = "log")]
+    log::warn!(
+        "fixed up message of non-conformant peer, warning={}",
+        warning
+    );
+}
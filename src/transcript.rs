@@ -0,0 +1,296 @@
+//! Recording and replaying the messages of a SCRAM handshake.
+//!
+//! A [`Transcript`] records the messages exchanged by client and server. Replaying a transcript
+//! runs the state machine of one side again, feeds it the recorded messages of the peer and checks
+//! that it produces the recorded messages of its own side. This turns a captured handshake, for
+//! example of a failed login in production, into a regression test. This module is available with
+//! the `transcript` feature.
+//!
+//! ``` rust,ignore
+//! use scram::transcript::{Sender, Transcript};
+//!
+//! # fn captured() -> Vec<(Sender, String)> { unimplemented!() }
+//! let mut transcript = Transcript::new();
+//! for (sender, message) in captured() {
+//!     transcript.record(sender, &message);
+//! }
+//! transcript.replay_client("user", "password", None).unwrap();
+//! ```
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+#[cfg(feature = "std")]
+use std::error;
+
+use crate::client::ScramClient;
+use crate::error::Error;
+use crate::grammar::{ClientFirstMessage, ServerFirstMessage};
+use crate::server::{AuthenticationProvider, AuthenticationStatus, ScramServer};
+
+/// The value replacing secret attribute values in a redacted transcript.
+const REDACTED: &str = "REDACTED";
+
+/// The side of the handshake which sent a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Sender {
+    /// The message was sent by the client.
+    Client,
+    /// The message was sent by the server.
+    Server,
+}
+
+/// A message of a recorded handshake.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Message {
+    /// The side which sent the message.
+    pub sender: Sender,
+    /// The message as it was sent.
+    pub content: String,
+}
+
+/// The messages of a SCRAM handshake in the order they were exchanged.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Transcript {
+    messages: Vec<Message>,
+}
+
+/// The reasons a replay can fail.
+#[derive(Debug, PartialEq)]
+pub enum ReplayError {
+    /// The state machine returned an error while processing the message at `index`.
+    Handshake {
+        /// The index of the message which was processed.
+        index: usize,
+        /// The error returned by the state machine.
+        error: Error,
+    },
+    /// The state machine produced a message different from the recorded message at `index`.
+    Diverged {
+        /// The index of the recorded message.
+        index: usize,
+        /// The message produced by the state machine.
+        produced: String,
+    },
+    /// The transcript ends before the handshake is complete, or the messages of the transcript
+    /// aren't in the order of a SCRAM handshake.
+    Incomplete,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ReplayError::Handshake { index, ref error } => {
+                write!(fmt, "Handshake failed at message {}: {}", index, error)
+            }
+            ReplayError::Diverged { index, .. } => {
+                write!(fmt, "Replay diverged from message {}", index)
+            }
+            ReplayError::Incomplete => write!(fmt, "Incomplete transcript"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for ReplayError {}
+
+impl Transcript {
+    /// Creates an empty transcript.
+    pub fn new() -> Self {
+        Transcript::default()
+    }
+
+    /// Appends a message sent by `sender`.
+    pub fn record(&mut self, sender: Sender, message: &str) {
+        self.messages.push(Message {
+            sender,
+            content: message.to_string(),
+        });
+    }
+
+    /// Returns the recorded messages.
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    /// Returns a copy of the transcript with the client proof (`p=`) and the server signature
+    /// (`v=`) replaced by `REDACTED`. Together with the salt and the nonces these values allow an
+    /// offline dictionary attack on the password.
+    ///
+    /// Replaying a redacted transcript fails at the first redacted message.
+    pub fn redacted(&self) -> Transcript {
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| Message {
+                sender: message.sender,
+                content: message
+                    .content
+                    .split(',')
+                    .map(|part| {
+                        if part.starts_with("p=") || part.starts_with("v=") {
+                            format!("{}{}", &part[..2], REDACTED)
+                        } else {
+                            part.to_string()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+            })
+            .collect();
+        Transcript { messages }
+    }
+
+    /// Replays the client side of the transcript with the given credentials. The recorded client
+    /// nonce is reused, so the produced messages are identical to the recorded ones if the
+    /// credentials are the same.
+    pub fn replay_client(
+        &self,
+        authcid: &str,
+        password: &str,
+        authzid: Option<&str>,
+    ) -> Result<(), ReplayError> {
+        let client_first = self.message(0, Sender::Client)?;
+        let client_nonce = ClientFirstMessage::parse(client_first)
+            .map_err(|error| ReplayError::Handshake { index: 0, error })?
+            .nonce;
+        let scram = ScramClient::with_nonce(authcid, password, authzid, client_nonce.to_string());
+        let (scram, produced) = scram.client_first();
+        self.compare(0, produced)?;
+        let scram = scram
+            .handle_server_first(self.message(1, Sender::Server)?)
+            .map_err(|error| ReplayError::Handshake { index: 1, error })?;
+        let (scram, produced) = scram.client_final();
+        self.compare(2, produced)?;
+        scram
+            .handle_server_final(self.message(3, Sender::Server)?)
+            .map_err(|error| ReplayError::Handshake { index: 3, error })
+    }
+
+    /// Replays the server side of the transcript with the given provider and returns the
+    /// authentication status. The recorded server nonce is reused, so the produced messages are
+    /// identical to the recorded ones if the provider returns the same password information.
+    pub fn replay_server<P: AuthenticationProvider>(
+        &self,
+        provider: P,
+    ) -> Result<AuthenticationStatus, ReplayError> {
+        let scram = ScramServer::new(provider);
+        let client_first = self.message(0, Sender::Client)?;
+        let scram = scram
+            .handle_client_first(client_first)
+            .map_err(|error| ReplayError::Handshake { index: 0, error })?;
+        let server_first = self.message(1, Sender::Server)?;
+        let client_nonce = ClientFirstMessage::parse(client_first)
+            .map_err(|error| ReplayError::Handshake { index: 0, error })?
+            .nonce;
+        let server_nonce = ServerFirstMessage::parse(server_first)
+            .ok()
+            .and_then(|message| message.nonce.get(client_nonce.len()..))
+            .ok_or(ReplayError::Diverged {
+                index: 1,
+                produced: String::new(),
+            })?;
+        let (scram, produced) = scram.server_first_with_nonce(server_nonce);
+        self.compare(1, produced)?;
+        let scram = scram
+            .handle_client_final(self.message(2, Sender::Client)?)
+            .map_err(|error| ReplayError::Handshake { index: 2, error })?;
+        let (status, produced) = scram.server_final();
+        self.compare(3, produced)?;
+        Ok(status)
+    }
+
+    /// Returns the content of the message at `index` if it was sent by `sender`.
+    fn message(&self, index: usize, sender: Sender) -> Result<&str, ReplayError> {
+        match self.messages.get(index) {
+            Some(message) if message.sender == sender => Ok(&message.content),
+            _ => Err(ReplayError::Incomplete),
+        }
+    }
+
+    /// Checks that a produced message is identical to the recorded message at `index`.
+    fn compare(&self, index: usize, produced: String) -> Result<(), ReplayError> {
+        match self.messages.get(index) {
+            Some(message) if message.content == produced => Ok(()),
+            Some(_) => Err(ReplayError::Diverged { index, produced }),
+            None => Err(ReplayError::Incomplete),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{ReplayError, Sender, Transcript};
+    use crate::{hash_password, AuthenticationProvider, AuthenticationStatus, PasswordInfo};
+    use crate::{ScramClient, ScramServer};
+
+    struct TestProvider;
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            let iterations = NonZeroU32::new(4096).unwrap();
+            match username {
+                "user" => Some(PasswordInfo::new(
+                    hash_password("password", iterations, b"salt").to_vec(),
+                    4096,
+                    b"salt".to_vec(),
+                )),
+                _ => None,
+            }
+        }
+    }
+
+    fn record_handshake() -> Transcript {
+        let mut transcript = Transcript::new();
+        let scram_client = ScramClient::new("user", "password", None);
+        let scram_server = ScramServer::new(TestProvider);
+        let (scram_client, client_first) = scram_client.client_first();
+        transcript.record(Sender::Client, &client_first);
+        let scram_server = scram_server.handle_client_first(&client_first).unwrap();
+        let (scram_server, server_first) = scram_server.server_first();
+        transcript.record(Sender::Server, &server_first);
+        let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+        let (_, client_final) = scram_client.client_final();
+        transcript.record(Sender::Client, &client_final);
+        let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+        let (_, server_final) = scram_server.server_final();
+        transcript.record(Sender::Server, &server_final);
+        transcript
+    }
+
+    #[test]
+    fn test_replay() {
+        let transcript = record_handshake();
+        assert_eq!(transcript.replay_client("user", "password", None), Ok(()));
+        assert_eq!(
+            transcript.replay_server(TestProvider),
+            Ok(AuthenticationStatus::Authenticated)
+        );
+        match transcript.replay_client("user", "wrong", None) {
+            Err(ReplayError::Diverged { index: 2, .. }) => {}
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_replay_redacted() {
+        let transcript = record_handshake().redacted();
+        assert!(transcript.messages()[2].content.ends_with(",p=REDACTED"));
+        assert_eq!(transcript.messages()[3].content, "v=REDACTED");
+        assert!(transcript.replay_client("user", "password", None).is_err());
+    }
+
+    #[test]
+    fn test_replay_incomplete() {
+        let mut transcript = record_handshake();
+        transcript.messages.truncate(2);
+        assert_eq!(
+            transcript.replay_client("user", "password", None),
+            Err(ReplayError::Incomplete)
+        );
+    }
+}
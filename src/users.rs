@@ -0,0 +1,619 @@
+//! An in-memory user database for servers.
+//!
+//! [`UserDatabase`] keeps the [`ScramVerifier`] of every user for the mechanisms of the server and
+//! derives them from the plaintext password when a user is added, so test servers and small
+//! services don't need a credential store of their own. Clones of a database share the users, so
+//! one clone can be passed to the [`ScramServer`](crate::ScramServer) while the application keeps
+//! managing the users with another:
+//!
+//! ```
+//! use scram::users::UserDatabase;
+//! use scram::ScramServer;
+//!
+//! let database = UserDatabase::new();
+//! database.add("user", "password");
+//! let server = ScramServer::new(database.clone());
+//!
+//! database.update("user", "new password");
+//! ```
+//!
+//! A [`FileUserDatabase`] is loaded from a text file at startup and writes the changes back with
+//! [`save`](FileUserDatabase::save). The file has a line for every verifier, like an `htpasswd`
+//! file:
+//!
+//! ```text
+//! # username:mechanism:iterations:salt:stored-key:server-key
+//! user:SCRAM-SHA-256:4096:W22ZaJ0SNY7soEsUEjb6gQ==:WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=
+//! ```
+//!
+//! The salt and the keys are encoded in base64. A `=`, `:` or `#` and control characters in the
+//! username are written as `=` followed by two uppercase hexadecimal digits, for example `=3A`
+//! for `:`. Empty lines and lines starting with `#` are ignored.
+//!
+//! With the `saslprep` feature the usernames and the passwords are prepared with SASLprep like the
+//! clients and the server of this crate prepare them, see the [`saslprep` module](crate::saslprep).
+//!
+//! This module is available with the `users` feature.
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::ops::Deref;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::credentials::{generate_credentials, Credentials};
+use crate::file::write_atomically;
+use crate::mechanism::Mechanism;
+#[cfg(feature = "saslprep")]
+use crate::saslprep;
+use crate::server::{AuthenticationProvider, ConnectionContext, PasswordInfo};
+use crate::verifier::ScramVerifier;
+
+/// The default iteration count of derived credentials, the minimum recommended by RFC7677
+/// section 4.
+const DEFAULT_ITERATIONS: u32 = 4096;
+
+/// A thread-safe [`AuthenticationProvider`] which keeps the verifiers of its users in memory, see
+/// the [module documentation](self).
+///
+/// Only the verifiers are kept, the passwords and the salted passwords are dropped after the
+/// derivation.
+#[derive(Clone)]
+pub struct UserDatabase {
+    users: Arc<RwLock<BTreeMap<String, Vec<ScramVerifier>>>>,
+    mechanisms: Vec<Mechanism>,
+    iterations: NonZeroU32,
+}
+
+impl Default for UserDatabase {
+    fn default() -> Self {
+        UserDatabase::new()
+    }
+}
+
+impl UserDatabase {
+    /// Creates an empty database which derives the credentials for the [`Mechanism::DEFAULT`]
+    /// mechanisms with 4096 iterations.
+    pub fn new() -> Self {
+        UserDatabase {
+            users: Arc::default(),
+            mechanisms: Mechanism::DEFAULT.to_vec(),
+            iterations: NonZeroU32::new(DEFAULT_ITERATIONS).unwrap(),
+        }
+    }
+
+    /// Derives the credentials of users added from now on for `mechanisms`, which should be the
+    /// mechanisms the server advertises.
+    pub fn with_mechanisms(mut self, mechanisms: &[Mechanism]) -> Self {
+        self.mechanisms = mechanisms.to_vec();
+        self
+    }
+
+    /// Derives the credentials of users added from now on with `iterations`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `iterations` is above 65535, the maximum of the server.
+    pub fn with_iterations(mut self, iterations: NonZeroU32) -> Self {
+        assert!(
+            iterations.get() <= u32::from(u16::MAX),
+            "too many iterations"
+        );
+        self.iterations = iterations;
+        self
+    }
+
+    /// Adds `username` with the credentials derived from `password`. Returns `false` and leaves
+    /// the user unchanged if it already exists.
+    pub fn add(&self, username: &str, password: &str) -> bool {
+        let username = prepare(username);
+        if self.contains(&username) {
+            return false;
+        }
+        let credentials = self.derive(password);
+        let mut users = self.users_mut();
+        if users.contains_key(&*username) {
+            return false;
+        }
+        users.insert(username.into_owned(), credentials);
+        true
+    }
+
+    /// Replaces the credentials of `username` with those derived from `password`. Returns `false`
+    /// if the user doesn't exist.
+    pub fn update(&self, username: &str, password: &str) -> bool {
+        let username = prepare(username);
+        if !self.contains(&username) {
+            return false;
+        }
+        let credentials = self.derive(password);
+        match self.users_mut().get_mut(&*username) {
+            Some(current) => {
+                *current = credentials;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Adds `credentials` derived elsewhere, for example by a client changing its password, to
+    /// `username`, like [`insert_verifier`](Self::insert_verifier).
+    pub fn insert_credentials(&self, username: &str, credentials: &Credentials) {
+        self.insert_verifier(username, ScramVerifier::from(credentials));
+    }
+
+    /// Adds `verifier` to `username`. It replaces the verifier of the user for the same
+    /// mechanism, and the user is added if it doesn't exist.
+    pub fn insert_verifier(&self, username: &str, verifier: ScramVerifier) {
+        let mut users = self.users_mut();
+        let current = users.entry(prepare(username).into_owned()).or_default();
+        current.retain(|current| current.mechanism != verifier.mechanism);
+        current.push(verifier);
+    }
+
+    /// Returns the verifiers of `username`, one for every mechanism.
+    pub fn verifiers(&self, username: &str) -> Vec<ScramVerifier> {
+        self.users()
+            .get(&*prepare(username))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Removes `username`. Returns `false` if the user doesn't exist.
+    pub fn remove(&self, username: &str) -> bool {
+        self.users_mut().remove(&*prepare(username)).is_some()
+    }
+
+    /// Returns whether `username` exists.
+    pub fn contains(&self, username: &str) -> bool {
+        self.users().contains_key(&*prepare(username))
+    }
+
+    /// Returns the usernames in lexicographic order.
+    pub fn usernames(&self) -> Vec<String> {
+        self.users().keys().cloned().collect()
+    }
+
+    /// Returns the number of users.
+    pub fn len(&self) -> usize {
+        self.users().len()
+    }
+
+    /// Returns whether the database has no users.
+    pub fn is_empty(&self) -> bool {
+        self.users().is_empty()
+    }
+
+    /// Derives the verifiers of `password` for every mechanism of the database.
+    fn derive(&self, password: &str) -> Vec<ScramVerifier> {
+        self.mechanisms
+            .iter()
+            .map(|&mechanism| {
+                ScramVerifier::from(&generate_credentials(password, mechanism, self.iterations))
+            })
+            .collect()
+    }
+
+    fn users(&self) -> RwLockReadGuard<'_, BTreeMap<String, Vec<ScramVerifier>>> {
+        // The map is consistent after every operation, so a poisoned lock can be used.
+        self.users
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn users_mut(&self) -> RwLockWriteGuard<'_, BTreeMap<String, Vec<ScramVerifier>>> {
+        self.users
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl AuthenticationProvider for UserDatabase {
+    fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+        self.get_password_in_context(username, &ConnectionContext::new())
+    }
+
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<PasswordInfo> {
+        let mechanism = context.mechanism().unwrap_or(Mechanism::ScramSha256);
+        self.users()
+            .get(&*prepare(username))?
+            .iter()
+            .find(|verifier| verifier.mechanism == mechanism)?
+            .password_info()
+    }
+}
+
+impl fmt::Debug for UserDatabase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("UserDatabase")
+            .field("usernames", &self.usernames())
+            .field("mechanisms", &self.mechanisms)
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A [`UserDatabase`] which is read from a file and written back with [`save`](Self::save), see
+/// the [module documentation](self) for the format. The methods of the database are available
+/// through `Deref`, and clones of it share the users.
+#[derive(Clone, Debug)]
+pub struct FileUserDatabase {
+    path: PathBuf,
+    users: UserDatabase,
+}
+
+impl FileUserDatabase {
+    /// Reads the verifiers from the file at `path` into `users`, which is usually a new
+    /// [`UserDatabase`] configured with the mechanisms and the iteration count of the users added
+    /// later. A missing file results in a database without users, which is created by the first
+    /// [`save`](Self::save).
+    ///
+    /// # Return value
+    ///
+    /// An error of the kind `io::ErrorKind::InvalidData` is returned if a line of the file isn't a
+    /// verifier, naming the number of the line.
+    pub fn open<P: AsRef<Path>>(path: P, users: UserDatabase) -> io::Result<Self> {
+        let database = FileUserDatabase {
+            path: path.as_ref().to_path_buf(),
+            users,
+        };
+        let data = match fs::read_to_string(&database.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(database),
+            Err(err) => return Err(err),
+        };
+        for (number, line) in data.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (username, verifier) = parse_line(line).ok_or_else(|| {
+                let message = format!("invalid verifier on line {}", number + 1);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+            database.users.insert_verifier(&username, verifier);
+        }
+        Ok(database)
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Writes the verifiers to the file, replacing it atomically. The new file is only readable
+    /// by the owner on Unix and is on the disk when the method returns.
+    pub fn save(&self) -> io::Result<()> {
+        let mut data = String::from("# username:mechanism:iterations:salt:stored-key:server-key\n");
+        for (username, verifiers) in self.users.users().iter() {
+            for verifier in verifiers {
+                escape(username, &mut data);
+                data.push(':');
+                data.push_str(verifier.mechanism.name());
+                data.push(':');
+                data.push_str(&verifier.iterations.to_string());
+                for value in &[&verifier.salt, &verifier.stored_key, &verifier.server_key] {
+                    data.push(':');
+                    data.push_str(&STANDARD.encode(value));
+                }
+                data.push('\n');
+            }
+        }
+        write_atomically(&self.path, data.as_bytes())
+    }
+}
+
+impl Deref for FileUserDatabase {
+    type Target = UserDatabase;
+
+    fn deref(&self) -> &UserDatabase {
+        &self.users
+    }
+}
+
+impl AuthenticationProvider for FileUserDatabase {
+    fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+        self.users.get_password_for(username)
+    }
+
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<PasswordInfo> {
+        self.users.get_password_in_context(username, context)
+    }
+}
+
+/// Prepares `username` with SASLprep like the server prepares the usernames it receives.
+#[cfg(feature = "saslprep")]
+fn prepare(username: &str) -> Cow<'_, str> {
+    saslprep::prepare_or_keep(username)
+}
+
+#[cfg(not(feature = "saslprep"))]
+fn prepare(username: &str) -> Cow<'_, str> {
+    Cow::Borrowed(username)
+}
+
+/// Appends `username` to `data`, with the characters which would end the field or the line
+/// escaped.
+fn escape(username: &str, data: &mut String) {
+    for c in username.chars() {
+        match c {
+            '=' | ':' | '#' | '\0'..='\x1f' | '\x7f' => {
+                data.push_str(&format!("={:02X}", u32::from(c)));
+            }
+            c => data.push(c),
+        }
+    }
+}
+
+/// Reverses [`escape`], or returns `None` if an escape sequence is invalid.
+fn unescape(field: &str) -> Option<String> {
+    let mut username = String::with_capacity(field.len());
+    let mut parts = field.split('=');
+    username.push_str(parts.next()?);
+    for part in parts {
+        let code = part
+            .get(..2)
+            .and_then(|code| u8::from_str_radix(code, 16).ok())?;
+        if !code.is_ascii() {
+            return None;
+        }
+        username.push(char::from(code));
+        username.push_str(&part[2..]);
+    }
+    Some(username)
+}
+
+/// Parses a line of a user file into the username and the verifier.
+fn parse_line(line: &str) -> Option<(String, ScramVerifier)> {
+    let mut fields = line.split(':');
+    let username = unescape(fields.next()?).filter(|username| !username.is_empty())?;
+    let mechanism = Mechanism::from_name(fields.next()?)?;
+    let iterations: NonZeroU32 = fields.next()?.parse().ok()?;
+    if iterations.get() > u32::from(u16::MAX) {
+        return None;
+    }
+    let (salt, stored_key, server_key) = (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() {
+        return None;
+    }
+    let verifier =
+        ScramVerifier::decode(mechanism, iterations, salt, stored_key, server_key).ok()?;
+    Some((username, verifier))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+    use core::num::NonZeroU32;
+    use std::{env, fs, io, process, thread};
+
+    use super::{escape, unescape, FileUserDatabase, UserDatabase};
+    use crate::credentials::derive_credentials;
+    use crate::server::{AuthenticationStatus, ConnectionContext, ScramServer};
+    use crate::{Mechanism, ScramClient};
+
+    /// Runs the handshake of `username` with `password` and `mechanism` and returns the status of
+    /// the server, or `None` if the server doesn't know the user.
+    fn handshake(
+        database: &UserDatabase,
+        mechanism: Mechanism,
+        username: &str,
+        password: &str,
+    ) -> Option<AuthenticationStatus> {
+        let server = ScramServer::new(database.clone());
+        let context = ConnectionContext::new().with_mechanism(mechanism);
+        let client = ScramClient::new(username, password, None).with_mechanism(mechanism);
+        let (client, client_first) = client.client_first();
+        let server_first = server
+            .handle_client_first_in_context(&client_first, &context)
+            .ok()?;
+        let (server_client_final, server_first) = server_first.server_first();
+        let client = client.handle_server_first(&server_first).unwrap();
+        let (client, client_final) = client.client_final();
+        let server_final = server_client_final
+            .handle_client_final(&client_final)
+            .unwrap();
+        let (status, server_final) = server_final.server_final();
+        if status == AuthenticationStatus::Authenticated {
+            client.handle_server_final(&server_final).unwrap();
+        }
+        Some(status)
+    }
+
+    #[cfg(feature = "saslprep")]
+    #[test]
+    fn test_saslprep() {
+        let database = UserDatabase::new();
+        assert!(database.add("\u{2168}", "pen\u{AD}cil"));
+        assert!(database.add("user", "pass\u{7}word"));
+        assert_eq!(database.usernames(), ["IX", "user"]);
+        assert!(!database.add("IX", "pencil"));
+
+        let authenticated = Some(AuthenticationStatus::Authenticated);
+        let sha256 = Mechanism::ScramSha256;
+        for &(username, password) in &[("\u{2168}", "pen\u{AD}cil"), ("I\u{AD}X", "pencil")] {
+            assert_eq!(
+                handshake(&database, sha256, username, password),
+                authenticated
+            );
+        }
+        // Passwords which can't be prepared are used unchanged on both sides.
+        assert_eq!(
+            handshake(&database, sha256, "user", "pass\u{7}word"),
+            authenticated
+        );
+    }
+
+    #[test]
+    fn test_user_database() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let database = UserDatabase::new()
+            .with_mechanisms(&[Mechanism::ScramSha256, Mechanism::ScramSha512])
+            .with_iterations(iterations);
+        assert!(database.is_empty());
+        assert!(database.add("user", "password"));
+        assert!(!database.add("user", "other password"));
+        assert!(database.add("other", "password"));
+        assert_eq!(database.len(), 2);
+        assert_eq!(database.usernames(), ["other", "user"]);
+
+        let authenticated = Some(AuthenticationStatus::Authenticated);
+        let sha256 = Mechanism::ScramSha256;
+        let sha512 = Mechanism::ScramSha512;
+        assert_eq!(
+            handshake(&database, sha256, "user", "password"),
+            authenticated
+        );
+        assert_eq!(
+            handshake(&database, sha512, "user", "password"),
+            authenticated
+        );
+        assert_eq!(
+            handshake(&database, sha256, "user", "other password"),
+            Some(AuthenticationStatus::NotAuthenticated)
+        );
+        assert_eq!(
+            handshake(&database, Mechanism::ScramSha384, "user", "password"),
+            None
+        );
+        assert_eq!(handshake(&database, sha256, "nobody", "password"), None);
+
+        assert!(database.update("user", "new password"));
+        assert!(!database.update("nobody", "password"));
+        assert_eq!(
+            handshake(&database, sha256, "user", "new password"),
+            authenticated
+        );
+
+        let salt = b"salt".to_vec();
+        let credentials = derive_credentials("password", sha512, iterations, salt);
+        database.insert_credentials("user", &credentials);
+        assert_eq!(
+            handshake(&database, sha512, "user", "password"),
+            authenticated
+        );
+        assert_eq!(
+            handshake(&database, sha256, "user", "new password"),
+            authenticated
+        );
+
+        assert!(database.remove("other"));
+        assert!(!database.remove("other"));
+        assert_eq!(handshake(&database, sha256, "other", "password"), None);
+        assert_eq!(database.usernames(), ["user"]);
+
+        let debug = format!("{:?}", database);
+        assert!(debug.contains("\"user\""));
+        assert!(!debug.contains("salt"));
+    }
+
+    #[test]
+    fn test_shared_between_threads() {
+        let database = UserDatabase::new();
+        let threads: Vec<_> = (0..4)
+            .map(|i| {
+                let database = database.clone();
+                thread::spawn(move || database.add(&format!("user{}", i), "password"))
+            })
+            .collect();
+        for thread in threads {
+            assert!(thread.join().unwrap());
+        }
+        assert_eq!(database.len(), 4);
+        let status = handshake(&database, Mechanism::ScramSha256, "user3", "password");
+        assert_eq!(status, Some(AuthenticationStatus::Authenticated));
+    }
+
+    #[test]
+    #[should_panic(expected = "too many iterations")]
+    fn test_too_many_iterations() {
+        let _ = UserDatabase::new().with_iterations(NonZeroU32::new(65536).unwrap());
+    }
+
+    #[test]
+    fn test_escape() {
+        let mut escaped = String::new();
+        escape("us=er:#1\n", &mut escaped);
+        assert_eq!(escaped, "us=3Der=3A=231=0A");
+        assert_eq!(unescape(&escaped).unwrap(), "us=er:#1\n");
+        assert_eq!(unescape("user").unwrap(), "user");
+        assert_eq!(unescape("user=3"), None);
+        assert_eq!(unescape("user=C3=A4"), None);
+    }
+
+    #[test]
+    fn test_file_database() {
+        let directory = env::temp_dir().join(format!("scram-users-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("scram.users");
+        let sha256 = Mechanism::ScramSha256;
+        let sha512 = Mechanism::ScramSha512;
+        let users = UserDatabase::new().with_mechanisms(&[sha256, sha512]);
+        let database = FileUserDatabase::open(&path, users.clone()).unwrap();
+        assert!(database.is_empty());
+        database.add("user", "password");
+        database.add("us:er", "other password");
+        database.save().unwrap();
+        database.save().unwrap();
+        // Only the file itself is left, without a temporary file.
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        let loaded = FileUserDatabase::open(&path, UserDatabase::new()).unwrap();
+        assert_eq!(loaded.usernames(), ["us:er", "user"]);
+        assert_eq!(loaded.verifiers("user"), database.verifiers("user"));
+        assert_eq!(loaded.verifiers("user").len(), 2);
+        let authenticated = Some(AuthenticationStatus::Authenticated);
+        assert_eq!(
+            handshake(&loaded, sha512, "user", "password"),
+            authenticated
+        );
+        assert_eq!(
+            handshake(&loaded, sha256, "us:er", "other password"),
+            authenticated
+        );
+
+        // The verifier of "pencil" from RFC7677 section 3.
+        fs::write(
+            &path,
+            "# A comment.\n\n\
+             user:SCRAM-SHA-256:4096:W22ZaJ0SNY7soEsUEjb6gQ==:\
+             WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+             wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=\n",
+        )
+        .unwrap();
+        let loaded = FileUserDatabase::open(&path, UserDatabase::new()).unwrap();
+        assert_eq!(handshake(&loaded, sha256, "user", "pencil"), authenticated);
+
+        fs::write(
+            &path,
+            "# A comment.\nuser:SCRAM-SHA-256:0:c2FsdA==:a2V5:a2V5\n",
+        )
+        .unwrap();
+        let err = FileUserDatabase::open(&path, UserDatabase::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "invalid verifier on line 2");
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
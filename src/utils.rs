@@ -1,33 +1,215 @@
+use alloc::borrow::Cow;
+use alloc::string::String;
+#[cfg(feature = "crypto")]
+use alloc::vec::Vec;
+use core::num::NonZeroU32;
+#[cfg(feature = "crypto")]
+use core::ops::Deref;
+use core::{hint, mem};
+
 use base64::Engine;
 use base64::engine::general_purpose::STANDARD;
-use ring::digest::{self, digest, SHA256_OUTPUT_LEN};
-use ring::hmac::{self, Context, Key, HMAC_SHA256};
-use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256 as SHA256};
-use std::num::NonZeroU32;
-
-/// Parses a part of a SCRAM message, after it has been split on commas.
-/// Checks to make sure there's a key, and then verifies its the right key.
-/// Returns everything after the first '='.
-/// Returns a `ExpectedField` error when one of the above conditions fails.
-macro_rules! parse_part {
-    ($iter:expr, $field:ident, $key:expr) => {
-        if let Some(part) = $iter.next() {
-            if part.len() < 2 {
-                return Err(Error::Protocol(Kind::ExpectedField(Field::$field)));
-            } else if &part.as_bytes()[..2] == $key {
-                &part[2..]
-            } else {
-                return Err(Error::Protocol(Kind::ExpectedField(Field::$field)));
-            }
+#[cfg(feature = "crypto")]
+use core::fmt;
+#[cfg(feature = "crypto")]
+use ring::digest::SHA256_OUTPUT_LEN;
+#[cfg(feature = "crypto")]
+use ring::pbkdf2;
+
+#[cfg(feature = "crypto")]
+use crate::entropy::{reject, EntropySource};
+#[cfg(feature = "crypto")]
+use crate::hash::{ScramHash, MAX_OUTPUT_LEN};
+#[cfg(feature = "crypto")]
+use crate::mechanism::Mechanism;
+#[cfg(feature = "crypto")]
+use crate::NONCE_LENGTH;
+
+/// Generates a random nonce of printable characters excluding `,`.
+#[cfg(feature = "crypto")]
+pub fn generate_nonce<E: EntropySource + ?Sized>(source: &mut E) -> String {
+    generate_nonce_of(source, NONCE_LENGTH)
+}
+
+/// Generates a random nonce of `length` printable characters excluding `,`.
+#[cfg(feature = "crypto")]
+pub fn generate_nonce_of<E: EntropySource + ?Sized>(source: &mut E, length: usize) -> String {
+    let mut nonce = vec![0u8; length];
+    fill_nonce(source, &mut nonce);
+    nonce.iter().map(|&x| x as char).collect()
+}
+
+/// Fills `nonce` with random printable ASCII characters excluding `,`.
+#[cfg(feature = "crypto")]
+pub fn fill_nonce<E: EntropySource + ?Sized>(source: &mut E, nonce: &mut [u8]) {
+    source.fill_bytes(nonce);
+    for byte in nonce {
+        // 92 characters from `!` to `}`, skipping `,`.
+        reject(source, byte, 92);
+        let character = 33 + *byte % 92;
+        *byte = if character > 43 { character + 1 } else { character };
+    }
+}
+
+/// Overwrites `bytes` with zeros. `black_box` keeps the compiler from removing the writes to
+/// memory which isn't read afterwards.
+pub fn zeroize(bytes: &mut [u8]) {
+    for byte in bytes.iter_mut() {
+        *byte = 0;
+    }
+    hint::black_box(bytes);
+}
+
+/// Overwrites the contents of `string` with zeros and leaves it empty.
+pub fn zeroize_string(string: &mut String) {
+    let mut bytes = mem::take(string).into_bytes();
+    zeroize(&mut bytes);
+}
+
+/// Base64 encodes `data` without allocating and passes the encoded data to `sink` in pieces.
+pub fn encode_chunked<F: FnMut(&[u8])>(data: &[u8], mut sink: F) {
+    // Chunks of a multiple of three bytes encode without padding, so the pieces can be joined.
+    let mut buffer = [0u8; 64];
+    for chunk in data.chunks(48) {
+        let len = STANDARD
+            .encode_slice(chunk, &mut buffer)
+            .expect("64 bytes hold the encoding of 48 bytes");
+        sink(&buffer[..len]);
+    }
+}
+
+/// Escapes a username for use in the `n=` or `a=` attribute. The characters `,` and `=` are
+/// replaced by `=2C` and `=3D` as required by RFC5802 section 5.1.
+pub fn escape_username(username: &str) -> Cow<'_, str> {
+    if username.contains([',', '=']) {
+        username.replace('=', "=3D").replace(',', "=2C").into()
+    } else {
+        username.into()
+    }
+}
+
+/// Reverses [`escape_username`]. Returns `None` if the username contains a `=` which isn't part
+/// of one of the escape sequences `=2C` and `=3D`.
+pub fn unescape_username(username: &str) -> Option<Cow<'_, str>> {
+    if !username.contains('=') {
+        return Some(username.into());
+    }
+    let mut unescaped = String::with_capacity(username.len());
+    let mut parts = username.split('=');
+    unescaped.push_str(parts.next()?);
+    for part in parts {
+        if part.starts_with("2C") {
+            unescaped.push(',');
+        } else if part.starts_with("3D") {
+            unescaped.push('=');
         } else {
-            return Err(Error::Protocol(Kind::ExpectedField(Field::$field)));
+            return None;
         }
-    };
+        unescaped.push_str(&part[2..]);
+    }
+    Some(unescaped.into())
+}
+
+/// The output of the hash function of a mechanism or a [`ScramHash`], like a salted password or a proof, without an
+/// allocation. It's overwritten with zeros when dropped.
+#[cfg(feature = "crypto")]
+pub struct Output {
+    bytes: [u8; MAX_OUTPUT_LEN],
+    len: usize,
+}
+
+#[cfg(feature = "crypto")]
+impl Output {
+    /// Returns zeros of the output length of `hash`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the output length is longer than [`MAX_OUTPUT_LEN`].
+    fn zeroed<H: ScramHash + ?Sized>(hash: &H) -> Self {
+        let len = hash.output_len();
+        assert!(
+            len <= MAX_OUTPUT_LEN,
+            "output of the hash function too long"
+        );
+        Output {
+            bytes: [0; MAX_OUTPUT_LEN],
+            len,
+        }
+    }
+
+    /// Returns the bytes to write the output to.
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.bytes[..self.len]
+    }
+
+    /// Returns a copy of `bytes`, for example of a salted password from a cache.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is longer than [`MAX_OUTPUT_LEN`].
+    #[cfg(any(test, feature = "cache"))]
+    pub(crate) fn copy_from(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() <= MAX_OUTPUT_LEN,
+            "output of the hash function too long"
+        );
+        let mut output = Output {
+            bytes: [0; MAX_OUTPUT_LEN],
+            len: bytes.len(),
+        };
+        output.bytes_mut().copy_from_slice(bytes);
+        output
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl From<[u8; SHA256_OUTPUT_LEN]> for Output {
+    fn from(mut bytes: [u8; SHA256_OUTPUT_LEN]) -> Self {
+        let mut output = Output::zeroed(&Mechanism::ScramSha256);
+        output.bytes[..SHA256_OUTPUT_LEN].copy_from_slice(&bytes);
+        zeroize(&mut bytes);
+        output
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl Deref for Output {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl fmt::Debug for Output {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("Output")
+            .field("len", &self.len)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl Drop for Output {
+    fn drop(&mut self) {
+        zeroize(&mut self.bytes);
+    }
 }
 
 /// Hashes a password with SHA-256 with the given salt and number of iterations.  This should
 /// be used by [`AuthenticationProvider`](crate::server::AuthenticationProvider) implementors to
 /// hash any passwords prior to being saved.
+///
+/// For the other mechanisms use [`hash_password_for`].
+#[cfg(feature = "crypto")]
 pub fn hash_password(
     password: &str,
     iterations: NonZeroU32,
@@ -35,7 +217,7 @@ pub fn hash_password(
 ) -> [u8; SHA256_OUTPUT_LEN] {
     let mut salted_password = [0u8; SHA256_OUTPUT_LEN];
     pbkdf2::derive(
-        SHA256,
+        pbkdf2::PBKDF2_HMAC_SHA256,
         iterations,
         salt,
         password.as_bytes(),
@@ -44,47 +226,76 @@ pub fn hash_password(
     salted_password
 }
 
-/// Finds the client proof and server signature based on the shared hashed key.
-pub fn find_proofs(
-    gs2header: &str,
+/// Like [`hash_password`], but hashes the password with the hash function of `mechanism`. The
+/// result has the [`output_len`](Mechanism::output_len) of the mechanism.
+#[cfg(feature = "crypto")]
+pub fn hash_password_for(
+    mechanism: Mechanism,
+    password: &str,
+    iterations: NonZeroU32,
+    salt: &[u8],
+) -> Vec<u8> {
+    salt_password(&mechanism, password, iterations, salt).to_vec()
+}
+
+/// Computes `SaltedPassword := Hi(Normalize(password), salt, i)` with `hash`.
+#[cfg(feature = "crypto")]
+pub fn salt_password<H: ScramHash + ?Sized>(
+    hash: &H,
+    password: &str,
+    iterations: NonZeroU32,
+    salt: &[u8],
+) -> Output {
+    let mut salted_password = Output::zeroed(hash);
+    hash.hi(
+        password.as_bytes(),
+        salt,
+        iterations,
+        salted_password.bytes_mut(),
+    );
+    salted_password
+}
+
+/// Finds the client proof and server signature based on the shared hashed key. `channel_binding`
+/// is the base64 encoded value of the `c=` attribute as sent by the client.
+#[cfg(feature = "crypto")]
+pub fn find_proofs<H: ScramHash + ?Sized>(
+    hash: &H,
+    channel_binding: &str,
     client_first_bare: &str,
     server_first: &str,
     salted_password: &[u8],
     nonce: &str,
-) -> ([u8; SHA256_OUTPUT_LEN], hmac::Tag) {
-    fn sign_slice(key: &Key, slice: &[&[u8]]) -> hmac::Tag {
-        let mut signature_context = Context::with_key(key);
-        for item in slice {
-            signature_context.update(item);
-        }
-        signature_context.sign()
-    }
+) -> (Output, Output) {
+    let mut client_key = Output::zeroed(hash);
+    hash.hmac(salted_password, &[b"Client Key"], client_key.bytes_mut());
+    let mut server_key = Output::zeroed(hash);
+    hash.hmac(salted_password, &[b"Server Key"], server_key.bytes_mut());
+    let mut stored_key = Output::zeroed(hash);
+    hash.hash(&client_key, stored_key.bytes_mut());
 
-    let client_final_without_proof =
-        format!("c={},r={}", STANDARD.encode(gs2header.as_bytes()), nonce);
-    let auth_message = [
+    // The AuthMessage is passed to both signatures in pieces, instead of formatting it into a
+    // string.
+    let auth_message: [&[u8]; 7] = [
         client_first_bare.as_bytes(),
         b",",
         server_first.as_bytes(),
-        b",",
-        client_final_without_proof.as_bytes(),
+        b",c=",
+        channel_binding.as_bytes(),
+        b",r=",
+        nonce.as_bytes(),
     ];
+    let mut client_signature = Output::zeroed(hash);
+    hash.hmac(&stored_key, &auth_message, client_signature.bytes_mut());
+    let mut server_signature = Output::zeroed(hash);
+    hash.hmac(&server_key, &auth_message, server_signature.bytes_mut());
 
-    let salted_password_signing_key = Key::new(HMAC_SHA256, salted_password);
-    let client_key = hmac::sign(&salted_password_signing_key, b"Client Key");
-    let server_key = hmac::sign(&salted_password_signing_key, b"Server Key");
-    let stored_key = digest(&digest::SHA256, client_key.as_ref());
-    let stored_key_signing_key = Key::new(HMAC_SHA256, stored_key.as_ref());
-    let client_signature = sign_slice(&stored_key_signing_key, &auth_message);
-    let server_signature_signing_key = Key::new(HMAC_SHA256, server_key.as_ref());
-    let server_signature = sign_slice(&server_signature_signing_key, &auth_message);
-    let mut client_proof = [0u8; SHA256_OUTPUT_LEN];
+    let mut client_proof = Output::zeroed(hash);
     let xor_iter = client_key
-        .as_ref()
         .iter()
-        .zip(client_signature.as_ref())
+        .zip(client_signature.iter())
         .map(|(k, s)| k ^ s);
-    for (p, x) in client_proof.iter_mut().zip(xor_iter) {
+    for (p, x) in client_proof.bytes_mut().iter_mut().zip(xor_iter) {
         *p = x
     }
     (client_proof, server_signature)
@@ -0,0 +1,408 @@
+//! The stored verifiers of users.
+//!
+//! A [`ScramVerifier`] holds what a server needs to verify a user: the salt, the iteration count,
+//! the `StoredKey` and the `ServerKey` of RFC5802 section 3. Unlike the salted password, the keys
+//! don't allow authenticating as the user to other servers, so they are what credential stores
+//! usually keep:
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use scram::credentials::generate_credentials;
+//! use scram::verifier::ScramVerifier;
+//! use scram::Mechanism;
+//!
+//! let iterations = NonZeroU32::new(4096).unwrap();
+//! let credentials = generate_credentials("pencil", Mechanism::ScramSha256, iterations);
+//! let verifier = ScramVerifier::from(&credentials);
+//! let password_info = verifier.password_info().unwrap();
+//! ```
+//!
+//! Verifiers are formatted and parsed in the format PostgreSQL stores in `pg_authid`,
+//! `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>` with the binary values encoded in
+//! base64, so tools can provision PostgreSQL users and servers can use the secrets of PostgreSQL
+//! directly:
+//!
+//! ```
+//! use scram::verifier::ScramVerifier;
+//!
+//! let secret = "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$\
+//!               WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+//!               wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU=";
+//! let verifier: ScramVerifier = secret.parse().unwrap();
+//! assert_eq!(verifier.iterations.get(), 4096);
+//! assert_eq!(verifier.to_string(), secret);
+//! ```
+//!
+//! Directory servers like OpenLDAP and 389 Directory Server store verifiers in the
+//! `authPassword` attribute of RFC5803, which has the same structure with the mechanism as the
+//! scheme. [`ScramVerifier::to_auth_password`] formats and [`ScramVerifier::from_auth_password`]
+//! parses its values.
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use core::fmt;
+use core::num::NonZeroU32;
+use core::str::FromStr;
+#[cfg(feature = "std")]
+use std::error;
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::credentials::Credentials;
+use crate::mechanism::Mechanism;
+use crate::server::PasswordInfo;
+
+/// The verifier of a user for one mechanism.
+///
+/// The keys have the [`output_len`](Mechanism::output_len) of the mechanism.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ScramVerifier {
+    /// The mechanism the verifier was derived for.
+    pub mechanism: Mechanism,
+    /// The iteration count of the key derivation.
+    pub iterations: NonZeroU32,
+    /// The salt.
+    pub salt: Vec<u8>,
+    /// `StoredKey := H(ClientKey)`
+    pub stored_key: Vec<u8>,
+    /// `ServerKey := HMAC(SaltedPassword, "Server Key")`
+    pub server_key: Vec<u8>,
+}
+
+impl ScramVerifier {
+    /// Returns the [`PasswordInfo`] of the verifier for an
+    /// [`AuthenticationProvider`](crate::server::AuthenticationProvider), or `None` if the
+    /// iteration count is above 65535, the maximum of the server.
+    pub fn password_info(&self) -> Option<PasswordInfo> {
+        let iterations = u16::try_from(self.iterations.get()).ok()?;
+        Some(PasswordInfo::from_keys(
+            self.stored_key.clone(),
+            self.server_key.clone(),
+            iterations,
+            self.salt.clone(),
+        ))
+    }
+
+    /// Returns the value of the `authPassword` attribute of RFC5803, for example
+    /// `SCRAM-SHA-256$4096:c2FsdA==$<StoredKey>:<ServerKey>`.
+    pub fn to_auth_password(&self) -> String {
+        self.to_string()
+    }
+
+    /// Parses a value of the `authPassword` attribute of RFC5803. Unlike [`FromStr`], it allows
+    /// spaces around the `$` separators and at the ends as RFC3112 does, and rejects iteration
+    /// counts with leading zeros or a sign.
+    pub fn from_auth_password(value: &str) -> Result<Self, VerifierError> {
+        let mut parts = value.split('$').map(|part| part.trim_matches(' '));
+        let (scheme, auth_info, auth_value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(scheme), Some(auth_info), Some(auth_value)) if parts.next().is_none() => {
+                (scheme, auth_info, auth_value)
+            }
+            _ => return Err(VerifierError::Malformed),
+        };
+        let (iterations, salt) = auth_info.split_once(':').ok_or(VerifierError::Malformed)?;
+        let (stored_key, server_key) =
+            auth_value.split_once(':').ok_or(VerifierError::Malformed)?;
+        let mechanism = Mechanism::from_name(scheme).ok_or(VerifierError::UnsupportedMechanism)?;
+        if !iterations.starts_with(|c: char| ('1'..='9').contains(&c))
+            || !iterations.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(VerifierError::InvalidIterations);
+        }
+        let iterations = iterations
+            .parse()
+            .map_err(|_| VerifierError::InvalidIterations)?;
+        ScramVerifier::decode(mechanism, iterations, salt, stored_key, server_key)
+    }
+
+    /// Decodes the base64 encoded values of a verifier and checks them.
+    pub(crate) fn decode(
+        mechanism: Mechanism,
+        iterations: NonZeroU32,
+        salt: &str,
+        stored_key: &str,
+        server_key: &str,
+    ) -> Result<Self, VerifierError> {
+        let decode = |value: &str| {
+            STANDARD
+                .decode(value)
+                .map_err(|_| VerifierError::InvalidEncoding)
+        };
+        let salt = decode(salt)?;
+        if salt.is_empty() {
+            return Err(VerifierError::EmptySalt);
+        }
+        let stored_key = decode(stored_key)?;
+        let server_key = decode(server_key)?;
+        let len = mechanism.output_len();
+        if stored_key.len() != len || server_key.len() != len {
+            return Err(VerifierError::KeyLength);
+        }
+        Ok(ScramVerifier {
+            mechanism,
+            iterations,
+            salt,
+            stored_key,
+            server_key,
+        })
+    }
+}
+
+/// Formats the verifier as PostgreSQL stores it, see the [module documentation](self).
+impl fmt::Display for ScramVerifier {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "{}${}:{}${}:{}",
+            self.mechanism.name(),
+            self.iterations,
+            STANDARD.encode(&self.salt),
+            STANDARD.encode(&self.stored_key),
+            STANDARD.encode(&self.server_key)
+        )
+    }
+}
+
+/// Parses the verifier as PostgreSQL stores it, see the [module documentation](self).
+impl FromStr for ScramVerifier {
+    type Err = VerifierError;
+
+    fn from_str(verifier: &str) -> Result<Self, VerifierError> {
+        let mut parts = verifier.split('$');
+        let (mechanism, parameters, keys) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(mechanism), Some(parameters), Some(keys)) if parts.next().is_none() => {
+                (mechanism, parameters, keys)
+            }
+            _ => return Err(VerifierError::Malformed),
+        };
+        let (iterations, salt) = parameters.split_once(':').ok_or(VerifierError::Malformed)?;
+        let (stored_key, server_key) = keys.split_once(':').ok_or(VerifierError::Malformed)?;
+        let mechanism =
+            Mechanism::from_name(mechanism).ok_or(VerifierError::UnsupportedMechanism)?;
+        let iterations = iterations
+            .parse()
+            .map_err(|_| VerifierError::InvalidIterations)?;
+        ScramVerifier::decode(mechanism, iterations, salt, stored_key, server_key)
+    }
+}
+
+/// The reasons a verifier can't be parsed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifierError {
+    /// The verifier doesn't have the structure of the format.
+    Malformed,
+    /// The mechanism isn't supported by this build of the crate.
+    UnsupportedMechanism,
+    /// The iteration count isn't a positive number.
+    InvalidIterations,
+    /// The salt or a key isn't valid base64.
+    InvalidEncoding,
+    /// The salt is empty.
+    EmptySalt,
+    /// A key doesn't have the output length of the mechanism.
+    KeyLength,
+}
+
+impl fmt::Display for VerifierError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match *self {
+            VerifierError::Malformed => "Malformed verifier",
+            VerifierError::UnsupportedMechanism => "Unsupported mechanism",
+            VerifierError::InvalidIterations => "Invalid iteration count",
+            VerifierError::InvalidEncoding => "Invalid base64 encoding",
+            VerifierError::EmptySalt => "Empty salt",
+            VerifierError::KeyLength => "Key of the wrong length",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl error::Error for VerifierError {}
+
+impl<'a> From<&'a Credentials> for ScramVerifier {
+    fn from(credentials: &'a Credentials) -> Self {
+        ScramVerifier {
+            mechanism: credentials.mechanism,
+            iterations: credentials.iterations,
+            salt: credentials.salt.clone(),
+            stored_key: credentials.stored_key.clone(),
+            server_key: credentials.server_key.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for ScramVerifier {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("ScramVerifier")
+            .field("mechanism", &self.mechanism)
+            .field("iterations", &self.iterations)
+            .field("salt", &self.salt)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::num::NonZeroU32;
+
+    use super::{ScramVerifier, VerifierError};
+    use crate::credentials::derive_credentials;
+    use crate::server::{AuthenticationProvider, AuthenticationStatus, PasswordInfo, ScramServer};
+    use crate::{Mechanism, ScramClient};
+
+    struct TestProvider(ScramVerifier);
+
+    impl AuthenticationProvider for TestProvider {
+        fn get_password_for(&self, username: &str) -> Option<PasswordInfo> {
+            if username == "user" {
+                self.0.password_info()
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Runs the handshake of a client with `password` and returns the status of the server.
+    fn handshake(verifier: &ScramVerifier, password: &str) -> AuthenticationStatus {
+        let server = ScramServer::new(TestProvider(verifier.clone()));
+        let client = ScramClient::new("user", password, None);
+        let (client, client_first) = client.client_first();
+        let server_first = server.handle_client_first(&client_first).unwrap();
+        let (server_client_final, server_first) = server_first.server_first();
+        let client = client.handle_server_first(&server_first).unwrap();
+        let (client, client_final) = client.client_final();
+        let server_final = server_client_final
+            .handle_client_final(&client_final)
+            .unwrap();
+        let (status, server_final) = server_final.server_final();
+        if status == AuthenticationStatus::Authenticated {
+            client.handle_server_final(&server_final).unwrap();
+        }
+        status
+    }
+
+    #[test]
+    fn test_verifier() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let salt = b"salt".to_vec();
+        let credentials = derive_credentials("pencil", Mechanism::ScramSha256, iterations, salt);
+        let verifier = ScramVerifier::from(&credentials);
+        assert_eq!(verifier.stored_key, credentials.stored_key);
+        assert_eq!(verifier.server_key, credentials.server_key);
+        assert_eq!(
+            handshake(&verifier, "pencil"),
+            AuthenticationStatus::Authenticated
+        );
+        assert_eq!(
+            handshake(&verifier, "wrong"),
+            AuthenticationStatus::NotAuthenticated
+        );
+
+        let debug = format!("{:?}", verifier);
+        assert!(debug.contains("ScramSha256"));
+        assert!(!debug.contains("stored_key"));
+
+        let mut verifier = verifier;
+        verifier.iterations = NonZeroU32::new(65536).unwrap();
+        assert!(verifier.password_info().is_none());
+    }
+
+    #[test]
+    fn test_postgres_format() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        for &mechanism in Mechanism::ALL {
+            let credentials = derive_credentials("pencil", mechanism, iterations, b"salt".to_vec());
+            let verifier = ScramVerifier::from(&credentials);
+            let formatted = verifier.to_string();
+            assert!(formatted.starts_with(&format!("{}$4096:c2FsdA==$", mechanism.name())));
+            assert_eq!(formatted.parse(), Ok(verifier));
+        }
+
+        // The secret of PostgreSQL for the password "pencil" with the salt of RFC7677.
+        let verifier: ScramVerifier = "SCRAM-SHA-256$4096:W22ZaJ0SNY7soEsUEjb6gQ==$\
+                                       WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+                                       wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU="
+            .parse()
+            .unwrap();
+        assert_eq!(
+            handshake(&verifier, "pencil"),
+            AuthenticationStatus::Authenticated
+        );
+
+        let key = "WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=";
+        let invalid = [
+            ("SCRAM-SHA-256$4096:c2FsdA==", VerifierError::Malformed),
+            ("SCRAM-SHA-256$4096$a:b", VerifierError::Malformed),
+            ("SCRAM-SHA-256$4096:c2FsdA==$a:b$", VerifierError::Malformed),
+            (
+                "SCRAM-SHA-1$4096:c2FsdA==$a:b",
+                VerifierError::UnsupportedMechanism,
+            ),
+            (
+                "SCRAM-SHA-256$0:c2FsdA==$a:b",
+                VerifierError::InvalidIterations,
+            ),
+            (
+                "SCRAM-SHA-256$-1:c2FsdA==$a:b",
+                VerifierError::InvalidIterations,
+            ),
+            (
+                "SCRAM-SHA-256$4096:c2FsdA$a:b",
+                VerifierError::InvalidEncoding,
+            ),
+            ("SCRAM-SHA-256$4096:$a:b", VerifierError::EmptySalt),
+            (
+                "SCRAM-SHA-256$4096:c2FsdA==$c2FsdA==:c2FsdA==",
+                VerifierError::KeyLength,
+            ),
+        ];
+        for &(verifier, error) in &invalid {
+            assert_eq!(
+                verifier.parse::<ScramVerifier>(),
+                Err(error),
+                "{}",
+                verifier
+            );
+        }
+        let verifier = format!("SCRAM-SHA-256$4096:c2FsdA==${}:{}", key, key);
+        assert!(verifier.parse::<ScramVerifier>().is_ok());
+    }
+
+    #[test]
+    fn test_auth_password() {
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let credentials = derive_credentials(
+            "pencil",
+            Mechanism::ScramSha512,
+            iterations,
+            b"salt".to_vec(),
+        );
+        let verifier = ScramVerifier::from(&credentials);
+        let value = verifier.to_auth_password();
+        assert!(value.starts_with("SCRAM-SHA-512$4096:c2FsdA==$"));
+        assert_eq!(ScramVerifier::from_auth_password(&value), Ok(verifier));
+
+        let verifier = ScramVerifier::from_auth_password(
+            " SCRAM-SHA-256 $ 4096:W22ZaJ0SNY7soEsUEjb6gQ== $ \
+             WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=:\
+             wfPLwcE6nTWhTAmQ7tl2KeoiWGPlZqQxSrmfPwDl2dU= ",
+        )
+        .unwrap();
+        assert_eq!(
+            handshake(&verifier, "pencil"),
+            AuthenticationStatus::Authenticated
+        );
+
+        let key = "WG5d8oPm3OtcPnkdi4Uo7BkeZkBFzpcXkuLmtbsT4qY=";
+        for iterations in &["04096", "+4096", "0", "", "4096 "] {
+            let value = format!("SCRAM-SHA-256${}:c2FsdA==${}:{}", iterations, key, key);
+            let result = ScramVerifier::from_auth_password(&value);
+            assert_eq!(result, Err(VerifierError::InvalidIterations), "{}", value);
+        }
+        assert_eq!(
+            ScramVerifier::from_auth_password("SCRAM-SHA-256$4096:c2FsdA=="),
+            Err(VerifierError::Malformed)
+        );
+    }
+}
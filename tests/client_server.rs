@@ -18,8 +18,8 @@ impl TestProvider {
         let adm_iterations = NonZeroU32::new(8192).unwrap();
         let admin_password = hash_password("admin_password", adm_iterations, b"messy");
         TestProvider {
-            user_password: user_password,
-            admin_password: admin_password,
+            user_password,
+            admin_password,
         }
     }
 }
@@ -148,6 +148,31 @@ fn test_authorize_non_existent() {
     assert!(scram_client.handle_server_final(&server_final).is_err());
 }
 
+#[test]
+fn test_authorize_fail_escaped() {
+    let scram_client = ScramClient::new("user", "password", Some("ad,min"));
+    let scram_server = ScramServer::new(TestProvider::new());
+
+    let (scram_client, client_first) = scram_client.client_first();
+
+    let scram_server = scram_server.handle_client_first(&client_first).unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+
+    assert_eq!(status, AuthenticationStatus::NotAuthorized);
+    assert_eq!(
+        scram_client.handle_server_final_with_extensions(&server_final),
+        Err(Error::Authentication(
+            "User 'user' not authorized to act as 'ad=2Cmin'".to_string()
+        ))
+    );
+}
+
 #[test]
 fn test_invalid_user() {
     let scram_client = ScramClient::new("nobody", "password", None);
@@ -187,3 +212,512 @@ fn test_empty_password() {
     assert_eq!(status, AuthenticationStatus::NotAuthenticated);
     assert!(scram_client.handle_server_final(&server_final).is_err());
 }
+
+#[test]
+fn test_advertised_mechanisms() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    assert_eq!(scram_server.advertised_mechanisms(false), ["SCRAM-SHA-256"]);
+    assert_eq!(
+        scram_server.advertised_mechanisms(true),
+        ["SCRAM-SHA-256-PLUS", "SCRAM-SHA-256"]
+    );
+}
+
+#[test]
+fn test_advertised_plus_mechanism() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let advertised = scram_server.advertised_mechanisms(true);
+    assert_eq!(advertised[0], Mechanism::ScramSha256.plus_name());
+
+    // The client chose the `-PLUS` variant, so it binds to the channel of the server.
+    let exporter = [0x2a; channel_binding::TLS_EXPORTER_LENGTH];
+    let values = [&exporter[..]];
+    let context = ConnectionContext::new()
+        .with_mechanism(Mechanism::ScramSha256)
+        .with_channel_binding_data("tls-exporter", &values);
+    let binding = channel_binding::ChannelBinding::TlsExporter(&exporter);
+    let scram_client = ScramClient::new("user", "password", None).with_channel_binding(binding);
+    let (scram_client, client_first) = scram_client.client_first();
+    assert!(client_first.starts_with("p=tls-exporter,"));
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_advertised_mechanism_flags() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let exporter = [0x2a; channel_binding::TLS_EXPORTER_LENGTH];
+    let values = [&exporter[..]];
+    for &channel_binding in &[true, false] {
+        let context = if channel_binding {
+            ConnectionContext::new().with_channel_binding_data("tls-exporter", &values)
+        } else {
+            ConnectionContext::new()
+        };
+        for name in scram_server.advertised_mechanisms(channel_binding) {
+            // The flag a client which supports channel binding sends for the chosen mechanism.
+            let scram_client = ScramClient::new("user", "password", None);
+            let (scram_client, flag) = match (channel_binding, name.ends_with("-PLUS")) {
+                (true, true) => {
+                    let binding = channel_binding::ChannelBinding::TlsExporter(&exporter);
+                    (
+                        scram_client.with_channel_binding(binding),
+                        "p=tls-exporter,",
+                    )
+                }
+                (true, false) => (scram_client, "n,"),
+                (false, _) => (scram_client.supports_channel_binding(), "y,"),
+            };
+            let (scram_client, client_first) = scram_client.client_first();
+            assert!(client_first.starts_with(flag), "{}", name);
+            let scram_server = scram_server
+                .handle_client_first_in_context(&client_first, &context)
+                .unwrap();
+            let (scram_server, server_first) = scram_server.server_first();
+            let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+            let (scram_client, client_final) = scram_client.client_final();
+            let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+            let (status, server_final) = scram_server.server_final();
+            assert_eq!(status, AuthenticationStatus::Authenticated, "{}", name);
+            assert!(scram_client.handle_server_final(&server_final).is_ok());
+        }
+    }
+
+    // `y` for a mechanism without `-PLUS` on a connection with channel binding is a downgrade.
+    let context = ConnectionContext::new().with_channel_binding_data("tls-exporter", &values);
+    let scram_client = ScramClient::new("user", "password", None).supports_channel_binding();
+    let (_, client_first) = scram_client.client_first();
+    let err = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .err()
+        .unwrap();
+    assert_eq!(err, Error::ChannelBinding(ChannelBindingError::Downgrade));
+}
+
+/// Stores the credentials of `user` for the mechanism of the handshake.
+struct MechanismProvider;
+
+impl server::AuthenticationProvider for MechanismProvider {
+    fn get_password_for(&self, _: &str) -> Option<server::PasswordInfo> {
+        None
+    }
+
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<server::PasswordInfo> {
+        let mechanism = context.mechanism()?;
+        let iterations = NonZeroU32::new(4096).unwrap();
+        let password = hash_password_for(mechanism, "password", iterations, b"salt");
+        assert_eq!(password.len(), mechanism.output_len());
+        Some(server::PasswordInfo::new(password, 4096, b"salt".to_vec()))
+            .filter(|_| username == "user")
+    }
+}
+
+#[test]
+fn test_mechanisms() {
+    let config = ScramConfig {
+        mechanisms: Mechanism::ALL.to_vec(),
+        ..ScramConfig::default()
+    };
+    let scram_server = ScramServer::new(MechanismProvider).with_config(&config);
+    assert_eq!(
+        scram_server.advertised_mechanisms(false),
+        ["SCRAM-SHA-512", "SCRAM-SHA-384", "SCRAM-SHA-256"]
+    );
+    for &client_mechanism in Mechanism::ALL {
+        for &server_mechanism in Mechanism::ALL {
+            let scram_client =
+                ScramClient::new("user", "password", None).with_mechanism(client_mechanism);
+            let context = ConnectionContext::new().with_mechanism(server_mechanism);
+            let (scram_client, client_first) = scram_client.client_first();
+            let scram_server = scram_server
+                .handle_client_first_in_context(&client_first, &context)
+                .unwrap();
+            let (scram_server, server_first) = scram_server.server_first();
+            let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+            let (scram_client, client_final) = scram_client.client_final();
+            let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+            let (status, server_final) = scram_server.server_final();
+            let result = scram_client.handle_server_final_for_password_change(&server_final);
+            if client_mechanism == server_mechanism {
+                assert_eq!(status, AuthenticationStatus::Authenticated);
+                assert_eq!(result.unwrap().mechanism, client_mechanism);
+            } else {
+                assert_eq!(status, AuthenticationStatus::NotAuthenticated);
+                assert!(result.is_err());
+            }
+        }
+    }
+}
+
+/// SCRAM-SHA-512 computed with the default `Hi` of `ScramHash` instead of `ring`.
+#[derive(Debug)]
+struct CustomSha512;
+
+impl hash::ScramHash for CustomSha512 {
+    fn name(&self) -> &str {
+        "SCRAM-SHA-512"
+    }
+
+    fn output_len(&self) -> usize {
+        64
+    }
+
+    fn hash(&self, data: &[u8], output: &mut [u8]) {
+        output.copy_from_slice(ring::digest::digest(&ring::digest::SHA512, data).as_ref());
+    }
+
+    fn hmac(&self, key: &[u8], message: &[&[u8]], output: &mut [u8]) {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA512, key);
+        let mut context = ring::hmac::Context::with_key(&key);
+        for piece in message {
+            context.update(piece);
+        }
+        output.copy_from_slice(context.sign().as_ref());
+    }
+}
+
+#[test]
+fn test_custom_hash() {
+    let scram_server = ScramServer::new(MechanismProvider);
+    let context = ConnectionContext::new().with_mechanism(Mechanism::ScramSha512);
+    let scram_client = ScramClient::new("user", "password", None).with_hash(CustomSha512);
+    let (scram_client, client_first) = scram_client.client_first();
+    assert!(scram_client.to_string().starts_with("SCRAM-SHA-512 client"));
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    scram_client.handle_server_final(&server_final).unwrap();
+}
+
+struct AliasProvider(TestProvider);
+
+impl server::AuthenticationProvider for AliasProvider {
+    fn get_password_for(&self, username: &str) -> Option<server::PasswordInfo> {
+        let canonical = username.to_lowercase();
+        self.0
+            .get_password_for(&canonical)
+            .map(|info| info.with_identity(format!("{}@example.com", canonical)))
+    }
+
+    fn authorize(&self, authcid: &str, authzid: &str) -> bool {
+        authcid == "admin@example.com" && authzid == "user"
+    }
+}
+
+#[test]
+fn test_canonical_identity() {
+    let scram_server = ScramServer::new(AliasProvider(TestProvider::new()));
+    let scram_client = ScramClient::new("ADMIN", "admin_password", Some("user"));
+    let (scram_client, client_first) = scram_client.client_first();
+    let scram_server = scram_server.handle_client_first(&client_first).unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (_, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    assert_eq!(scram_server.identity(), "admin@example.com");
+    assert_eq!(scram_server.authzid(), Some("user"));
+    let (status, _) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+}
+
+struct TenantProvider(TestProvider);
+
+impl server::AuthenticationProvider for TenantProvider {
+    fn get_password_for(&self, _: &str) -> Option<server::PasswordInfo> {
+        None
+    }
+
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<server::PasswordInfo> {
+        assert_eq!(context.mechanism(), Some(Mechanism::ScramSha256));
+        assert_eq!(context.peer_address(), Some("192.0.2.1:5432"));
+        assert_eq!(context.data::<u32>(), Some(&42));
+        match context.tenant() {
+            Some("tenant") => self.0.get_password_for(username),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn test_connection_context() {
+    let scram_server = ScramServer::new(TenantProvider(TestProvider::new()));
+    let (_, client_first) = ScramClient::new("user", "password", None).client_first();
+    let context = ConnectionContext::new()
+        .with_peer_address("192.0.2.1:5432")
+        .with_data(&42u32);
+    assert!(scram_server
+        .handle_client_first_in_context(&client_first, &context.with_tenant("tenant"))
+        .is_ok());
+    assert_eq!(
+        scram_server
+            .handle_client_first_in_context(&client_first, &context.with_tenant("other"))
+            .err(),
+        Some(Error::InvalidUser("user".to_string()))
+    );
+}
+
+/// The subject of the TLS client certificate of a connection.
+struct PeerCertificate(&'static str);
+
+struct CertificateProvider(TestProvider);
+
+impl server::AuthenticationProvider for CertificateProvider {
+    fn get_password_for(&self, _: &str) -> Option<server::PasswordInfo> {
+        None
+    }
+
+    fn get_password_in_context(
+        &self,
+        username: &str,
+        context: &ConnectionContext,
+    ) -> Option<server::PasswordInfo> {
+        assert_eq!(username, "");
+        let PeerCertificate(subject) = context.data()?;
+        self.0
+            .get_password_for(subject)
+            .map(|info| info.with_identity(*subject))
+    }
+}
+
+#[test]
+fn test_external_identity() {
+    let (_, client_first) = ScramClient::with_external_identity("password", None).client_first();
+    assert!(client_first.starts_with("n,,n=,r="));
+    let scram_server = ScramServer::new(CertificateProvider(TestProvider::new()));
+    assert_eq!(
+        scram_server.handle_client_first(&client_first).err(),
+        Some(Error::Protocol(Kind::InvalidField(
+            Field::Authcid,
+            Some(Excerpt {
+                attribute: Some('n'),
+                value: String::new(),
+                length: 0,
+            })
+        )))
+    );
+
+    let scram_server = scram_server.allow_empty_authcid();
+    let certificate = PeerCertificate("user");
+    let context = ConnectionContext::new().with_data(&certificate);
+    let scram_client = ScramClient::with_external_identity("password", None);
+    let (scram_client, client_first) = scram_client.client_first();
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    assert_eq!(scram_server.identity(), "user");
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_channel_binding_flag() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let context = ConnectionContext::new().with_channel_binding(true);
+    let err = scram_server
+        .handle_client_first_in_context("y,,n=user,r=abc", &context)
+        .err()
+        .unwrap();
+    assert_eq!(err, Error::ChannelBinding(ChannelBindingError::Downgrade));
+    assert_eq!(
+        ChannelBindingError::Downgrade.server_error_value(),
+        "server-does-support-channel-binding"
+    );
+    let err = scram_server
+        .handle_client_first("p=tls-server-end-point,,n=user,r=abc")
+        .err()
+        .unwrap();
+    assert_eq!(
+        err,
+        Error::ChannelBinding(ChannelBindingError::NotSupported)
+    );
+    assert!(scram_server.handle_client_first("y,,n=user,r=abc").is_ok());
+}
+
+#[test]
+fn test_supports_channel_binding() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let scram_client = ScramClient::new("user", "password", None).supports_channel_binding();
+    let (scram_client, client_first) = scram_client.client_first();
+    assert!(client_first.starts_with("y,,"));
+    let scram_server = scram_server.handle_client_first(&client_first).unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+
+    // A server with channel binding detects the downgrade.
+    let scram_server = ScramServer::new(TestProvider::new());
+    let context = ConnectionContext::new().with_channel_binding(true);
+    let scram_client = ScramClient::new("user", "password", None).supports_channel_binding();
+    let (_, client_first) = scram_client.client_first();
+    let err = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .err()
+        .unwrap();
+    assert_eq!(err, Error::ChannelBinding(ChannelBindingError::Downgrade));
+    assert_eq!(
+        Error::from_server_error_value(err.server_error_value()),
+        Error::ChannelBinding(ChannelBindingError::Downgrade)
+    );
+}
+
+#[test]
+fn test_deferred_channel_binding_data() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let exporter: &[u8] = b"exporter";
+    let values = [exporter];
+    let context = ConnectionContext::new().with_channel_binding_data("tls-exporter", &values);
+    let scram_client = ScramClient::new("user", "password", None).channel_binding("tls-exporter");
+    let (scram_client, client_first) = scram_client.client_first();
+    assert!(client_first.starts_with("p=tls-exporter,,n=user,r="));
+    // The TLS handshake completes after the first message was built.
+    let scram_client = scram_client.channel_binding_data(exporter);
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_tls_unique() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let finished: &[u8] = b"finished";
+    let values = [finished];
+    let context = ConnectionContext::new().with_channel_binding_data("tls-unique", &values);
+    let binding = channel_binding::ChannelBinding::TlsUnique(finished);
+    let scram_client = ScramClient::new("user", "password", None).with_channel_binding(binding);
+    let (scram_client, client_first) = scram_client.client_first();
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_tls_server_end_point() {
+    // A certificate signed with ecdsa-with-SHA384, only its structure matters.
+    let mut certificate = vec![0x30, 0x19, 0x30, 0x02, 0x05, 0x00, 0x30, 0x0a, 0x06, 0x08];
+    certificate.extend_from_slice(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03]);
+    certificate.extend_from_slice(&[0x03, 0x07, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06]);
+    let hash = channel_binding::tls_server_end_point(&certificate).unwrap();
+    assert_eq!(hash.len(), 48);
+
+    let scram_server = ScramServer::new(TestProvider::new());
+    let values = [hash.as_slice()];
+    let context =
+        ConnectionContext::new().with_channel_binding_data("tls-server-end-point", &values);
+    let binding = channel_binding::ChannelBinding::TlsServerEndPoint(&certificate);
+    let scram_client = ScramClient::new("user", "password", None).with_channel_binding(binding);
+    let (scram_client, client_first) = scram_client.client_first();
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_tls_exporter() {
+    let scram_server = ScramServer::new(TestProvider::new());
+    let exporter = [0x2a; channel_binding::TLS_EXPORTER_LENGTH];
+    let values = [&exporter[..]];
+    let context = ConnectionContext::new().with_channel_binding_data("tls-exporter", &values);
+    let binding = channel_binding::ChannelBinding::TlsExporter(&exporter);
+    let scram_client = ScramClient::new("user", "password", None).with_channel_binding(binding);
+    let (scram_client, client_first) = scram_client.client_first();
+    assert!(client_first.starts_with("p=tls-exporter,,n=user,r="));
+    let scram_server = scram_server
+        .handle_client_first_in_context(&client_first, &context)
+        .unwrap();
+    let (scram_server, server_first) = scram_server.server_first();
+    let scram_client = scram_client.handle_server_first(&server_first).unwrap();
+    let (scram_client, client_final) = scram_client.client_final();
+    let scram_server = scram_server.handle_client_final(&client_final).unwrap();
+    let (status, server_final) = scram_server.server_final();
+    assert_eq!(status, AuthenticationStatus::Authenticated);
+    assert!(scram_client.handle_server_final(&server_final).is_ok());
+}
+
+#[test]
+fn test_fallback_from_plus() {
+    // The server binds with `tls-server-end-point`, the client only supports `tls-exporter`.
+    let scram_server = ScramServer::new(TestProvider::new());
+    let end_point = [0x2a; 32];
+    let values = [&end_point[..]];
+    let context =
+        ConnectionContext::new().with_channel_binding_data("tls-server-end-point", &values);
+    let exporter = [0x2b; channel_binding::TLS_EXPORTER_LENGTH];
+    let advertised = scram_server.advertised_mechanisms(true);
+
+    let mut flags = Vec::new();
+    let result = fallback::Fallback::new().run(&advertised, |mechanism, binding| {
+        let scram_client = ScramClient::new("user", "password", None).with_mechanism(mechanism);
+        let scram_client = match binding {
+            fallback::Binding::Required => {
+                let binding = channel_binding::ChannelBinding::TlsExporter(&exporter);
+                scram_client.with_channel_binding(binding)
+            }
+            fallback::Binding::Unbound => scram_client,
+            fallback::Binding::NotAdvertised => scram_client.supports_channel_binding(),
+        };
+        let (scram_client, client_first) = scram_client.client_first();
+        flags.push(client_first[..2].to_string());
+        let scram_server = scram_server.handle_client_first_in_context(&client_first, &context)?;
+        let (scram_server, server_first) = scram_server.server_first();
+        let scram_client = scram_client.handle_server_first(&server_first)?;
+        let (scram_client, client_final) = scram_client.client_final();
+        let scram_server = scram_server.handle_client_final(&client_final)?;
+        let (status, server_final) = scram_server.server_final();
+        assert_eq!(status, AuthenticationStatus::Authenticated);
+        scram_client.handle_server_final(&server_final)
+    });
+    assert_eq!(result, Ok((Mechanism::ScramSha256, ())));
+    assert_eq!(flags, ["p=", "n,"]);
+}
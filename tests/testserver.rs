@@ -0,0 +1,111 @@
+#![cfg(feature = "testserver")]
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+
+use scram::{Error, Mechanism, ScramClient};
+
+/// Starts the server with `args` and connects to it.
+fn connect(args: &[&str]) -> (Child, BufReader<TcpStream>, TcpStream) {
+    let mut server = Command::new(env!("CARGO_BIN_EXE_scram-testserver"))
+        .args(args)
+        .arg("--once")
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut banner = String::new();
+    BufReader::new(server.stdout.as_mut().unwrap())
+        .read_line(&mut banner)
+        .unwrap();
+    let addr = banner.trim().strip_prefix("listening on ").unwrap();
+    let stream = TcpStream::connect(addr).unwrap();
+    (server, BufReader::new(stream.try_clone().unwrap()), stream)
+}
+
+fn receive(reader: &mut BufReader<TcpStream>) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    line.trim_end().to_string()
+}
+
+/// Runs a handshake as `user` with `password` and returns the result of the client.
+fn handshake(args: &[&str], user: &str, password: &str) -> Result<(), Error> {
+    let (mut server, mut reader, mut writer) = connect(args);
+    assert_eq!(receive(&mut reader), "MECHANISMS SCRAM-SHA-256");
+    writeln!(writer, "SCRAM-SHA-256").unwrap();
+    let (client, client_first) = ScramClient::new(user, password, None).client_first();
+    writeln!(writer, "{}", client_first).unwrap();
+    let result = client
+        .handle_server_first(&receive(&mut reader))
+        .and_then(|client| {
+            let (client, client_final) = client.client_final();
+            writeln!(writer, "{}", client_final).unwrap();
+            client.handle_server_final(&receive(&mut reader))
+        });
+    server.wait().unwrap();
+    result
+}
+
+#[test]
+fn test_handshake() {
+    let args = [
+        "--user",
+        "alice",
+        "--password",
+        "secret",
+        "--iterations",
+        "4096",
+    ];
+    assert_eq!(handshake(&args, "alice", "secret"), Ok(()));
+    assert_eq!(
+        handshake(&args, "alice", "wrong"),
+        Err(Error::Authentication("invalid-proof".to_string()))
+    );
+}
+
+#[test]
+fn test_fault() {
+    let args = ["--fault", "wrong-server-signature"];
+    assert_eq!(
+        handshake(&args, "user", "pencil"),
+        Err(Error::InvalidServer)
+    );
+}
+
+#[test]
+fn test_unsupported_mechanism() {
+    let (mut server, mut reader, mut writer) = connect(&["--mechanism", "SCRAM-SHA-1"]);
+    assert_eq!(receive(&mut reader), "MECHANISMS SCRAM-SHA-1");
+    writeln!(writer, "SCRAM-SHA-1").unwrap();
+    assert_eq!(
+        receive(&mut reader),
+        "ERR unsupported mechanism SCRAM-SHA-1"
+    );
+    server.wait().unwrap();
+}
+
+#[test]
+fn test_mechanism() {
+    let args = [
+        "--mechanism",
+        "SCRAM-SHA-512",
+        "--mechanism",
+        "SCRAM-SHA-256",
+    ];
+    let (mut server, mut reader, mut writer) = connect(&args);
+    assert_eq!(
+        receive(&mut reader),
+        "MECHANISMS SCRAM-SHA-512 SCRAM-SHA-256"
+    );
+    writeln!(writer, "SCRAM-SHA-512").unwrap();
+    let (client, client_first) = ScramClient::new("user", "pencil", None)
+        .with_mechanism(Mechanism::ScramSha512)
+        .client_first();
+    writeln!(writer, "{}", client_first).unwrap();
+    let client = client.handle_server_first(&receive(&mut reader)).unwrap();
+    let (client, client_final) = client.client_final();
+    writeln!(writer, "{}", client_final).unwrap();
+    assert_eq!(client.handle_server_final(&receive(&mut reader)), Ok(()));
+    server.wait().unwrap();
+}